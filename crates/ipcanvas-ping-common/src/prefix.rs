@@ -2,7 +2,7 @@ use core::{convert::From, fmt::Display, net::Ipv6Addr};
 
 /// Structure to represent an IPv6 prefix (address + prefix length)
 /// Used in both eBPF and user-space code.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct Ipv6Prefix {
     /// 128-bit IPv6 address, in (network) big-endian byte order
@@ -1,4 +1,6 @@
-use std::str::FromStr;
+mod sink;
+
+use std::{path::PathBuf, str::FromStr};
 
 use anyhow::Context as _;
 use aya::{
@@ -9,7 +11,8 @@ use clap::Parser;
 use ipcanvas_ping_common::Ipv6Prefix;
 #[rustfmt::skip]
 use log::{debug, warn, info};
-use tokio::{io::unix::AsyncFd, signal};
+use sink::{Sink, SinkTls};
+use tokio::{io::unix::AsyncFd, signal, sync::mpsc};
 
 #[derive(Debug, Parser)]
 struct Opt {
@@ -22,8 +25,37 @@ struct Opt {
     /// Example: "2001:db8::/64"
     #[clap(short, long)]
     prefix: String,
+
+    /// Address of the ping service of ipcanvas to forward the events to, as <host>:<port>
+    ///
+    /// Without it, the events are only logged.
+    #[clap(short, long)]
+    service: Option<String>,
+
+    /// File holding the key shared with the ping service, answering its challenge
+    #[clap(long)]
+    key_file: Option<PathBuf>,
+
+    /// CA certificates (PEM) issuing the certificate of the service, enabling TLS
+    #[clap(long)]
+    tls_ca: Option<PathBuf>,
+
+    /// Certificate (PEM) presented to the service, for mutual TLS, requires --tls-key
+    #[clap(long, requires = "tls_key", requires = "tls_ca")]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of the certificate presented to the service
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Name expected in the certificate of the service, default is the host of --service
+    #[clap(long, requires = "tls_ca")]
+    tls_server_name: Option<String>,
 }
 
+/// Number of events buffered while the link to the service is down.
+const SINK_BUFFER_SIZE: usize = 4096;
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt = Opt::parse();
@@ -66,7 +98,50 @@ async fn main() -> anyhow::Result<()> {
             });
         }
     }
-    let Opt { iface, prefix } = opt;
+    let Opt {
+        iface,
+        prefix,
+        service,
+        key_file,
+        tls_ca,
+        tls_cert,
+        tls_key,
+        tls_server_name,
+    } = opt;
+
+    // Connect to the service, if any
+    let sink = match service {
+        Some(service) => {
+            let key = match key_file {
+                Some(path) => {
+                    let key = std::fs::read_to_string(&path)
+                        .with_context(|| format!("failed to read the key {}", path.display()))?;
+                    Some(key.trim().as_bytes().to_vec())
+                }
+                None => None,
+            };
+            let tls = match tls_ca {
+                Some(ca) => {
+                    let server_name = match &tls_server_name {
+                        Some(name) => name.as_str(),
+                        None => service
+                            .rsplit_once(':')
+                            .map_or(service.as_str(), |(host, _)| host)
+                            .trim_start_matches('[')
+                            .trim_end_matches(']'),
+                    };
+                    let identity = tls_cert.as_deref().zip(tls_key.as_deref());
+                    Some(SinkTls::new(&ca, identity, server_name)?)
+                }
+                None => None,
+            };
+            let (sender, receiver) = mpsc::channel(SINK_BUFFER_SIZE);
+            info!("Forwarding the ping events to {}", service);
+            tokio::spawn(Sink { service, key, tls }.run(receiver));
+            Some(sender)
+        }
+        None => None,
+    };
 
     // Get the prefix from the command line
     let ipv6_prefix = Ipv6Prefix::from_str(&prefix).map_err(|_| {
@@ -116,6 +191,11 @@ async fn main() -> anyhow::Result<()> {
                         event.source(),
                         event.destination()
                     );
+                    if let Some(sink) = &sink
+                        && sink.try_send(buf).is_err()
+                    {
+                        warn!("Link to the service is lagging, dropping a ping event");
+                    }
                 }
                 guard.clear_ready();
             }
@@ -0,0 +1,201 @@
+//! Sink forwarding the ping events to the ipcanvas service.
+//!
+//! The sink keeps a connection to the ping service of ipcanvas, optionally over TLS (with a
+//! client certificate for mutual TLS), answers its challenge when a key is shared with it, then
+//! writes each event as its 32 raw bytes. The connection is re-established on failure.
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::{Context as _, bail};
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use rustls::{
+    ClientConfig, RootCertStore,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_rustls::TlsConnector;
+
+/// Length of the challenge sent by the service.
+const CHALLENGE_LEN: usize = 32;
+/// Context of the responses to the challenges, as expected by the service.
+const RESPONSE_CONTEXT: &[u8] = b"ipcanvas-ping/1";
+/// Delay before reconnecting after the first failure, doubled on each failure.
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// Maximum delay between two connection attempts.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Time given to the connection, TLS handshake and challenge.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS settings of the link to the service.
+pub struct SinkTls {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl SinkTls {
+    /// Trust the service certificates issued by the CAs of `ca` (PEM), expecting `server_name`
+    /// in the certificate of the service.
+    ///
+    /// `identity` is the certificate chain and key (PEM) presented to the service, for mutual TLS.
+    pub fn new(
+        ca: &Path,
+        identity: Option<(&Path, &Path)>,
+        server_name: &str,
+    ) -> anyhow::Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca)? {
+            roots.add(cert)?;
+        }
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match identity {
+            Some((cert, key)) => {
+                let key = PrivateKeyDer::from_pem_file(key)
+                    .with_context(|| format!("failed to load the key {}", key.display()))?;
+                builder.with_client_auth_cert(load_certs(cert)?, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let server_name = ServerName::try_from(server_name.to_string())
+            .with_context(|| format!("invalid server name {:?}", server_name))?;
+        Ok(Self {
+            connector: TlsConnector::from(Arc::new(config)),
+            server_name,
+        })
+    }
+}
+
+/// Connection to the service, plain or TLS.
+trait Link: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Link for S {}
+
+/// Connection settings of the sink.
+pub struct Sink {
+    /// Address of the ping service, as `<host>:<port>`
+    pub service: String,
+    /// Key shared with the service, None if the service does not authenticate the listeners
+    pub key: Option<Vec<u8>>,
+    /// TLS of the link, None for plain TCP
+    pub tls: Option<SinkTls>,
+}
+
+impl Sink {
+    /// Forward the events received on `events` until the channel is closed.
+    pub async fn run(self, mut events: mpsc::Receiver<[u8; 32]>) {
+        // Event that failed to be written, sent first on the next connection
+        let mut pending = None;
+        let mut delay = MIN_RETRY_DELAY;
+        loop {
+            let result = match tokio::time::timeout(CONNECT_TIMEOUT, self.connect()).await {
+                Ok(Ok(stream)) => {
+                    self.forward(stream, &mut events, &mut pending, &mut delay)
+                        .await
+                }
+                Ok(Err(e)) => Err(e),
+                Err(_) => Err(anyhow::anyhow!("connection timed out")),
+            };
+            match result {
+                Ok(()) => return,
+                Err(e) => warn!("link to the service {} failed: {:#}", self.service, e),
+            }
+            info!("reconnecting to {} in {:?}", self.service, delay);
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+
+    /// Connect to the service, then authenticate over the (TLS) link.
+    async fn connect(&self) -> anyhow::Result<Box<dyn Link>> {
+        let socket = TcpStream::connect(&self.service).await?;
+        socket.set_nodelay(true)?;
+        let mut stream: Box<dyn Link> = match &self.tls {
+            Some(tls) => Box::new(
+                tls.connector
+                    .connect(tls.server_name.clone(), socket)
+                    .await
+                    .context("TLS handshake failed")?,
+            ),
+            None => Box::new(socket),
+        };
+        self.authenticate(&mut stream).await?;
+        Ok(stream)
+    }
+
+    /// Answer the challenge of the service with the shared key, see `PingKey` in ipcanvas-service.
+    async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> anyhow::Result<()> {
+        let Some(key) = &self.key else {
+            return Ok(());
+        };
+        let mut challenge = [0; CHALLENGE_LEN];
+        stream
+            .read_exact(&mut challenge)
+            .await
+            .context("no challenge received")?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(RESPONSE_CONTEXT);
+        mac.update(&challenge);
+        stream.write_all(&mac.finalize().into_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Write the events to an established connection, until it fails or the channel is closed.
+    async fn forward<S: AsyncWrite + Unpin>(
+        &self,
+        mut stream: S,
+        events: &mut mpsc::Receiver<[u8; 32]>,
+        pending: &mut Option<[u8; 32]>,
+        delay: &mut Duration,
+    ) -> anyhow::Result<()> {
+        info!("connected to the service {}", self.service);
+        *delay = MIN_RETRY_DELAY;
+        loop {
+            let event = match pending.take() {
+                Some(event) => event,
+                None => match events.recv().await {
+                    Some(event) => event,
+                    None => {
+                        stream.shutdown().await?;
+                        return Ok(());
+                    }
+                },
+            };
+            if let Err(e) = write_event(&mut stream, &event).await {
+                *pending = Some(event);
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+async fn write_event<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    event: &[u8; 32],
+) -> std::io::Result<()> {
+    stream.write_all(event).await?;
+    stream.flush().await
+}
+
+/// Read the certificates of a PEM file, failing if it has none.
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .with_context(|| format!("failed to open {}", path.display()))?
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("failed to load {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificate found in {}", path.display());
+    }
+    Ok(certs)
+}
@@ -0,0 +1,71 @@
+//! Compare the full-canvas clone-and-scan diffing with the incremental change tracking.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, criterion_group, criterion_main};
+use ipcanvas_service::canvas::{Canvas, PixelColor};
+
+const WIDTH: u16 = 4096;
+const HEIGHT: u16 = 4096;
+
+/// Write `count` pixels spread over the canvas.
+fn write_pixels(canvas: &mut Canvas, count: usize) {
+    for i in 0..count {
+        let x = ((i * 7919) % WIDTH as usize) as u16;
+        let y = ((i * 104729) % HEIGHT as usize) as u16;
+        let color = PixelColor {
+            r: i as u8,
+            g: (i >> 8) as u8,
+            b: 42,
+        };
+        canvas.set_pixel(x, y, color).unwrap();
+    }
+}
+
+fn bench_diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("canvas_diff");
+    group.sample_size(10);
+
+    for count in [1, 1_000, 100_000] {
+        // Previous approach: scan the whole canvas against a copy, then clone it again.
+        group.bench_with_input(
+            BenchmarkId::new("clone_and_scan", count),
+            &count,
+            |b, &count| {
+                b.iter_batched(
+                    || {
+                        let prev_canvas = Canvas::new(WIDTH, HEIGHT);
+                        let mut canvas = prev_canvas.clone();
+                        write_pixels(&mut canvas, count);
+                        (prev_canvas, canvas)
+                    },
+                    |(prev_canvas, canvas)| {
+                        let diff = prev_canvas.diff(&canvas);
+                        let prev_canvas = canvas.clone();
+                        (diff, prev_canvas)
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+
+        // Current approach: drain the change set of the dirty tiles.
+        group.bench_with_input(BenchmarkId::new("take_diff", count), &count, |b, &count| {
+            b.iter_batched(
+                || {
+                    let mut canvas = Canvas::new(WIDTH, HEIGHT);
+                    write_pixels(&mut canvas, count);
+                    canvas
+                },
+                |mut canvas| {
+                    let diff = canvas.take_diff();
+                    (diff, canvas)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_diff);
+criterion_main!(benches);
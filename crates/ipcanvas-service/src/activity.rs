@@ -0,0 +1,663 @@
+//! Activity: statistics of the players and colors of a canvas, kept per hour.
+//!
+//! The statistics are maintained as the events are applied (see [Activity::apply]), rather
+//! than computed from the event log: for each hour, the pixels placed by the players (pings),
+//! how many of them each player placed and how many still survive on the canvas, and how many
+//! times each color was placed. The pixels placed by the operator are not counted, but they
+//! still cover the pixels of the players.
+//!
+//! Players are identified by the prefix of their address, truncated to
+//! [Activity::with_prefix_len] bits when the pixel is placed. The players of an hour are only
+//! kept for [Activity::with_player_retention_days], the older hours only keep their pixel and
+//! color counts.
+//!
+//! The totals of the players and colors over all the hours are kept up to date along with the
+//! hours, so the all-time statistics are not summed again for each request.
+//!
+//! The activity also keeps the [Heatmap] of the pixels placed by the players.
+//!
+//! # Encoding
+//!
+//! The activity is persisted along with the [snapshots](crate::persistence::snapshot), the
+//! heatmap in a section of its own:
+//!
+//! - `hours: u32`, followed by the hours with activity, oldest first: `hour: u32` (hours since
+//!   the Unix epoch) and `pixels: u64`, then
+//! - `players: u32`, followed by the players: the 16 bytes of the prefix address,
+//!   `prefix_len: u8`, `placed: u64` and `surviving: u64`, then
+//! - `colors: u32`, followed by the colors: `r: u8, g: u8, b: u8` and `placed: u64`.
+//!
+//! All integers are big-endian.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    net::Ipv6Addr,
+};
+
+use ipcanvas_ping_common::Ipv6Prefix;
+
+use crate::{
+    canvas::{
+        Canvas, PixelColor,
+        provenance::{Placement, SOURCE_PREFIX_LEN},
+    },
+    cooldown,
+    events::{ApplyError, Event, EventSource, mask_address},
+    heatmap::Heatmap,
+};
+
+/// Length of an hour, in milliseconds.
+pub const HOUR_MILLIS: u64 = 3_600_000;
+
+/// Number of days the players of each hour are kept, unless configured otherwise.
+pub const DEFAULT_PLAYER_RETENTION_DAYS: u32 = 30;
+
+/// Pixels placed by a player.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PlayerActivity {
+    /// Pixels placed.
+    pub placed: u64,
+    /// Pixels placed which are still on the canvas.
+    pub surviving: u64,
+}
+
+/// Activity of a single hour.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Hour {
+    pixels: u64,
+    players: HashMap<Ipv6Prefix, PlayerActivity>,
+    colors: HashMap<PixelColor, u64>,
+}
+
+/// Statistics of the players and colors of a canvas (see the [module](self) documentation).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Activity {
+    // Keyed by hours since the Unix epoch
+    hours: BTreeMap<u32, Hour>,
+    // Sums of the players and colors of all the hours
+    players: HashMap<Ipv6Prefix, PlayerActivity>,
+    colors: HashMap<PixelColor, u64>,
+    heatmap: Heatmap,
+    prefix_len: u8,
+    player_retention_days: u32,
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Activity {
+    /// Create an empty activity, identifying the players by their
+    /// [default prefix](cooldown::DEFAULT_PREFIX_LEN).
+    pub fn new() -> Self {
+        Self {
+            hours: BTreeMap::new(),
+            players: HashMap::new(),
+            colors: HashMap::new(),
+            heatmap: Heatmap::new(),
+            prefix_len: cooldown::DEFAULT_PREFIX_LEN,
+            player_retention_days: DEFAULT_PLAYER_RETENTION_DAYS,
+        }
+    }
+
+    /// Seed the activity from the pixels of the canvas placed by the players, for a canvas
+    /// restored without its activity.
+    ///
+    /// Only the surviving pixels are known, so they are also counted as the placed ones.
+    pub fn from_canvas(canvas: &Canvas, prefix_len: u8) -> Self {
+        let mut activity = Self::new().with_prefix_len(prefix_len);
+        for pixel in canvas.pixels() {
+            if let Some(placement) = canvas.placement(pixel.x, pixel.y) {
+                activity.place(pixel.x, pixel.y, placement, pixel.color);
+            }
+        }
+        activity
+    }
+
+    /// Set the number of bits of the addresses identifying a player (at most
+    /// [SOURCE_PREFIX_LEN], the prefix of the sources kept by the canvas).
+    ///
+    /// It only applies to the pixels placed from now on.
+    pub fn with_prefix_len(mut self, prefix_len: u8) -> Self {
+        self.prefix_len = prefix_len.min(SOURCE_PREFIX_LEN);
+        self
+    }
+
+    /// Set the number of days the players of each hour are kept, counted from the latest hour
+    /// with activity (0 to keep them forever).
+    ///
+    /// The players of the older hours are dropped, so they no longer appear in the leaderboards.
+    pub fn with_player_retention_days(mut self, days: u32) -> Self {
+        self.player_retention_days = days;
+        self.expire_players();
+        self
+    }
+
+    /// Replace the heatmap, e.g. with the one persisted separately.
+    pub(crate) fn with_heatmap(mut self, heatmap: Heatmap) -> Self {
+        self.heatmap = heatmap;
+        self
+    }
+
+    /// Get the heatmap of the pixels placed by the players.
+    pub fn heatmap(&self) -> &Heatmap {
+        &self.heatmap
+    }
+
+    /// Apply an event to the canvas, recording its placement, and count the pixel it places.
+    pub fn apply(
+        &mut self,
+        canvas: &mut Canvas,
+        event: &Event,
+        placement: Placement,
+    ) -> Result<(), ApplyError> {
+        let Event::PlacePixel { x, y, color } = *event else {
+            return event.apply_placed(canvas, placement);
+        };
+        let previous = canvas.placement(x, y);
+        event.apply_placed(canvas, placement)?;
+        if let Some(previous) = previous {
+            self.cover(previous);
+        }
+        self.place(x, y, placement, color);
+        Ok(())
+    }
+
+    /// Count a pixel placed on the canvas, if placed by a player.
+    fn place(&mut self, x: u16, y: u16, placement: Placement, color: PixelColor) {
+        let Some(prefix) = placement.source.prefix(self.prefix_len) else {
+            return;
+        };
+        self.heatmap.record(x, y, placement.timestamp);
+        let key = hour_of(placement.timestamp);
+        let new_hour = !self.hours.contains_key(&key);
+        let hour = self.hours.entry(key).or_default();
+        hour.pixels += 1;
+        for player in [
+            hour.players.entry(prefix).or_default(),
+            self.players.entry(prefix).or_default(),
+        ] {
+            player.placed += 1;
+            player.surviving += 1;
+        }
+        *hour.colors.entry(color).or_default() += 1;
+        *self.colors.entry(color).or_default() += 1;
+        if new_hour {
+            self.expire_players();
+        }
+    }
+
+    /// Drop the players of the hours beyond the retention.
+    fn expire_players(&mut self) {
+        if self.player_retention_days == 0 {
+            return;
+        }
+        let Some(&latest) = self.hours.keys().next_back() else {
+            return;
+        };
+        let first = latest.saturating_sub(self.player_retention_days.saturating_mul(24) - 1);
+        // The hours before the first expired one have already been expired
+        for (_, hour) in self
+            .hours
+            .range_mut(..first)
+            .rev()
+            .take_while(|(_, hour)| !hour.players.is_empty())
+        {
+            for (prefix, expired) in std::mem::take(&mut hour.players) {
+                let Some(player) = self.players.get_mut(&prefix) else {
+                    continue;
+                };
+                player.placed = player.placed.saturating_sub(expired.placed);
+                player.surviving = player.surviving.saturating_sub(expired.surviving);
+                if player.placed == 0 {
+                    self.players.remove(&prefix);
+                }
+            }
+        }
+    }
+
+    /// Forget a pixel of a player covered by another pixel.
+    fn cover(&mut self, previous: Placement) {
+        let EventSource::Ping(address) = previous.source else {
+            return;
+        };
+        let Some(hour) = self.hours.get_mut(&hour_of(previous.timestamp)) else {
+            return;
+        };
+        let prefix = Ipv6Prefix::from((mask_address(address, self.prefix_len), self.prefix_len));
+        let prefix = match hour.players.get(&prefix) {
+            Some(player) if player.surviving > 0 => Some(prefix),
+            // The prefix length has changed since the pixel was placed
+            _ => hour
+                .players
+                .iter()
+                .filter(|(prefix, player)| contains(prefix, address) && player.surviving > 0)
+                .max_by_key(|(prefix, _)| prefix.prefix_len)
+                .map(|(prefix, _)| *prefix),
+        };
+        let Some(prefix) = prefix else {
+            return;
+        };
+        for players in [&mut hour.players, &mut self.players] {
+            if let Some(player) = players.get_mut(&prefix) {
+                player.surviving = player.surviving.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Sum the activity of the hours overlapping `[from, to)` (in milliseconds since the Unix
+    /// epoch, unbounded if None).
+    ///
+    /// Without bounds, the players and colors are the totals kept up to date, only a range of
+    /// hours is summed.
+    pub fn summary(&self, from: Option<u64>, to: Option<u64>) -> ActivitySummary {
+        if from.is_none() && to.is_none() {
+            return ActivitySummary {
+                hours: self
+                    .hours
+                    .iter()
+                    .map(|(&hour, activity)| (hour as u64 * HOUR_MILLIS, activity.pixels))
+                    .collect(),
+                players: self.players.clone(),
+                colors: self.colors.clone(),
+            };
+        }
+        let first = from.map_or(0, hour_of);
+        let end = to.map_or(u32::MAX as u64 + 1, |to| to.div_ceil(HOUR_MILLIS));
+        let mut summary = ActivitySummary::default();
+        if end <= first as u64 {
+            return summary;
+        }
+        for (&hour, activity) in self
+            .hours
+            .range(first..)
+            .take_while(|(h, _)| (**h as u64) < end)
+        {
+            summary
+                .hours
+                .push((hour as u64 * HOUR_MILLIS, activity.pixels));
+            for (prefix, player) in &activity.players {
+                let total = summary.players.entry(*prefix).or_default();
+                total.placed += player.placed;
+                total.surviving += player.surviving;
+            }
+            for (color, placed) in &activity.colors {
+                *summary.colors.entry(*color).or_default() += placed;
+            }
+        }
+        summary
+    }
+
+    /// Encode the activity, without the heatmap.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.hours.len() as u32).to_be_bytes());
+        for (hour, activity) in &self.hours {
+            buf.extend_from_slice(&hour.to_be_bytes());
+            buf.extend_from_slice(&activity.pixels.to_be_bytes());
+            buf.extend_from_slice(&(activity.players.len() as u32).to_be_bytes());
+            for (prefix, player) in &activity.players {
+                buf.extend_from_slice(&prefix.address);
+                buf.push(prefix.prefix_len);
+                buf.extend_from_slice(&player.placed.to_be_bytes());
+                buf.extend_from_slice(&player.surviving.to_be_bytes());
+            }
+            buf.extend_from_slice(&(activity.colors.len() as u32).to_be_bytes());
+            for (color, placed) in &activity.colors {
+                buf.extend_from_slice(&[color.r, color.g, color.b]);
+                buf.extend_from_slice(&placed.to_be_bytes());
+            }
+        }
+    }
+
+    /// Decode an activity, None if it is invalid.
+    ///
+    /// Its heatmap is empty, see [Activity::with_heatmap].
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut bytes = bytes;
+        let mut take = |n: usize| -> Option<&[u8]> {
+            if bytes.len() < n {
+                return None;
+            }
+            let (head, tail) = bytes.split_at(n);
+            bytes = tail;
+            Some(head)
+        };
+        let u32_at =
+            |b: &[u8], i: usize| u32::from_be_bytes(b[i..i + 4].try_into().expect("4 bytes"));
+        let u64_at =
+            |b: &[u8], i: usize| u64::from_be_bytes(b[i..i + 8].try_into().expect("8 bytes"));
+
+        let mut activity = Self::new();
+        let count = u32_at(take(4)?, 0);
+        for _ in 0..count {
+            let b = take(12)?;
+            let mut hour = Hour {
+                pixels: u64_at(b, 4),
+                ..Hour::default()
+            };
+            for _ in 0..u32_at(take(4)?, 0) {
+                let b = take(33)?;
+                let prefix = Ipv6Prefix::from(<[u8; 17]>::try_from(&b[..17]).ok()?);
+                if prefix.prefix_len > 128 {
+                    return None;
+                }
+                let player = PlayerActivity {
+                    placed: u64_at(b, 17),
+                    surviving: u64_at(b, 25),
+                };
+                hour.players.insert(prefix, player);
+            }
+            for _ in 0..u32_at(take(4)?, 0) {
+                let b = take(11)?;
+                let color = PixelColor {
+                    r: b[0],
+                    g: b[1],
+                    b: b[2],
+                };
+                hour.colors.insert(color, u64_at(b, 3));
+            }
+            activity.hours.insert(u32_at(b, 0), hour);
+        }
+        if !bytes.is_empty() {
+            return None;
+        }
+        for hour in activity.hours.values() {
+            for (prefix, player) in &hour.players {
+                let total = activity.players.entry(*prefix).or_default();
+                total.placed += player.placed;
+                total.surviving += player.surviving;
+            }
+            for (color, placed) in &hour.colors {
+                *activity.colors.entry(*color).or_default() += placed;
+            }
+        }
+        Some(activity)
+    }
+}
+
+/// Activity of a canvas over a time range, see [Activity::summary].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ActivitySummary {
+    /// Start of the hours with activity (in milliseconds since the Unix epoch), oldest first,
+    /// with the pixels placed during each.
+    pub hours: Vec<(u64, u64)>,
+    /// Pixels placed by each player.
+    pub players: HashMap<Ipv6Prefix, PlayerActivity>,
+    /// Number of times each color was placed.
+    pub colors: HashMap<PixelColor, u64>,
+}
+
+/// Order of a leaderboard of the players.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlayerOrder {
+    /// Most placed pixels first.
+    #[default]
+    Placed,
+    /// Most surviving pixels first.
+    Surviving,
+}
+
+impl ActivitySummary {
+    /// Total of pixels placed over the range.
+    pub fn pixels(&self) -> u64 {
+        self.hours.iter().map(|(_, pixels)| pixels).sum()
+    }
+
+    /// Leaderboard of the players, identified by their prefix truncated to `prefix_len` bits.
+    ///
+    /// Players with the same rank are sorted by prefix.
+    pub fn players(&self, prefix_len: u8, order: PlayerOrder) -> Vec<(Ipv6Prefix, PlayerActivity)> {
+        let mut merged: HashMap<Ipv6Prefix, PlayerActivity> = HashMap::new();
+        for (prefix, player) in &self.players {
+            let prefix_len = prefix_len.min(prefix.prefix_len);
+            let address = mask_address(Ipv6Addr::from(prefix.address), prefix_len);
+            let total = merged
+                .entry(Ipv6Prefix::from((address, prefix_len)))
+                .or_default();
+            total.placed += player.placed;
+            total.surviving += player.surviving;
+        }
+        let mut players: Vec<_> = merged.into_iter().collect();
+        players.sort_by_key(|(prefix, player)| {
+            let rank = match order {
+                PlayerOrder::Placed => (player.placed, player.surviving),
+                PlayerOrder::Surviving => (player.surviving, player.placed),
+            };
+            (Reverse(rank), prefix.address, prefix.prefix_len)
+        });
+        players
+    }
+
+    /// Colors by number of placements, the most placed first.
+    ///
+    /// Colors placed as many times are sorted by their value.
+    pub fn colors(&self) -> Vec<(PixelColor, u64)> {
+        let mut colors: Vec<_> = self.colors.iter().map(|(c, n)| (*c, *n)).collect();
+        colors.sort_by_key(|(color, placed)| (Reverse(*placed), color.r, color.g, color.b));
+        colors
+    }
+}
+
+/// Hour of a timestamp, in hours since the Unix epoch.
+fn hour_of(timestamp: u64) -> u32 {
+    (timestamp / HOUR_MILLIS).min(u32::MAX as u64) as u32
+}
+
+/// Whether an address is within a prefix.
+fn contains(prefix: &Ipv6Prefix, address: Ipv6Addr) -> bool {
+    mask_address(address, prefix.prefix_len).octets() == prefix.address
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{canvas::colors, heatmap::HeatmapWindow};
+
+    fn ping(address: &str, timestamp: u64) -> Placement {
+        Placement {
+            source: EventSource::Ping(address.parse().unwrap()),
+            timestamp,
+        }
+    }
+
+    fn place(x: u16, y: u16, color: PixelColor) -> Event {
+        Event::PlacePixel { x, y, color }
+    }
+
+    #[test]
+    fn activity_counts_placements() {
+        let mut canvas = Canvas::new(10, 10);
+        let mut activity = Activity::new().with_prefix_len(64);
+        let t0 = 1_700_000_000_000 / HOUR_MILLIS * HOUR_MILLIS;
+        let alice = "2001:db8:0:1::1";
+        let bob = "2001:db8:0:2::1";
+
+        activity
+            .apply(&mut canvas, &place(0, 0, colors::RED), ping(alice, t0))
+            .unwrap();
+        activity
+            .apply(&mut canvas, &place(1, 0, colors::RED), ping(alice, t0 + 10))
+            .unwrap();
+        activity
+            .apply(
+                &mut canvas,
+                &place(0, 0, colors::BLUE),
+                ping(bob, t0 + HOUR_MILLIS),
+            )
+            .unwrap();
+        let admin = Placement {
+            source: EventSource::Admin,
+            timestamp: t0 + 2 * HOUR_MILLIS,
+        };
+        activity
+            .apply(&mut canvas, &place(1, 0, colors::GREEN), admin)
+            .unwrap();
+        assert!(
+            activity
+                .apply(&mut canvas, &place(10, 0, colors::RED), ping(bob, t0))
+                .is_err()
+        );
+
+        let summary = activity.summary(None, None);
+        assert_eq!(summary.hours, vec![(t0, 2), (t0 + HOUR_MILLIS, 1)]);
+        assert_eq!(summary.pixels(), 3);
+        let alice_prefix = EventSource::Ping(alice.parse().unwrap())
+            .prefix(64)
+            .unwrap();
+        let bob_prefix = EventSource::Ping(bob.parse().unwrap()).prefix(64).unwrap();
+        assert_eq!(
+            summary.players(64, PlayerOrder::Placed),
+            vec![
+                (
+                    alice_prefix,
+                    PlayerActivity {
+                        placed: 2,
+                        surviving: 0
+                    }
+                ),
+                (
+                    bob_prefix,
+                    PlayerActivity {
+                        placed: 1,
+                        surviving: 1
+                    }
+                ),
+            ]
+        );
+        assert_eq!(summary.players(64, PlayerOrder::Surviving)[0].0, bob_prefix);
+        let merged = summary.players(48, PlayerOrder::Placed);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].0.to_string(), "2001:db8::/48");
+        assert_eq!(
+            merged[0].1,
+            PlayerActivity {
+                placed: 3,
+                surviving: 1
+            }
+        );
+        assert_eq!(summary.colors(), vec![(colors::RED, 2), (colors::BLUE, 1)]);
+        let heatmap = activity.heatmap().layer(HeatmapWindow::All, t0, 10, 10);
+        assert_eq!(
+            heatmap.cells,
+            vec![((0, 0), 3)],
+            "Only the pings are counted"
+        );
+
+        // The totals are the sums of the hours
+        assert_eq!(
+            activity.summary(Some(0), Some(u64::MAX)),
+            activity.summary(None, None)
+        );
+
+        // Only the hours overlapping the range
+        let summary = activity.summary(Some(t0 + HOUR_MILLIS + 1), None);
+        assert_eq!(summary.hours, vec![(t0 + HOUR_MILLIS, 1)]);
+        let summary = activity.summary(None, Some(t0 + HOUR_MILLIS));
+        assert_eq!(summary.pixels(), 2);
+        assert_eq!(
+            activity.summary(Some(t0 + 5), Some(t0)),
+            ActivitySummary::default()
+        );
+
+        let mut buf = Vec::new();
+        activity.encode(&mut buf);
+        let restored = Activity::decode(&buf)
+            .unwrap()
+            .with_heatmap(activity.heatmap().clone());
+        assert_eq!(restored, activity);
+        assert!(Activity::decode(&buf[..buf.len() - 1]).is_none());
+
+        // Pixels placed with a longer prefix are still covered
+        let mut activity = restored.with_prefix_len(128);
+        activity
+            .apply(
+                &mut canvas,
+                &place(0, 0, colors::RED),
+                ping(alice, t0 + 3 * HOUR_MILLIS),
+            )
+            .unwrap();
+        let summary = activity.summary(None, None);
+        assert_eq!(summary.players[&bob_prefix].surviving, 0);
+    }
+
+    #[test]
+    fn activity_drops_expired_players() {
+        let mut canvas = Canvas::new(10, 10);
+        let mut activity = Activity::new()
+            .with_prefix_len(64)
+            .with_player_retention_days(1);
+        let t0 = 1_700_000_000_000 / HOUR_MILLIS * HOUR_MILLIS;
+        for i in 0..10 {
+            let player = format!("2001:db8:0:{:x}::1", i);
+            activity
+                .apply(&mut canvas, &place(i, 0, colors::RED), ping(&player, t0))
+                .unwrap();
+        }
+        let mut buf = Vec::new();
+        activity.encode(&mut buf);
+        let size = buf.len();
+
+        // Still within the retention
+        let t1 = t0 + 23 * HOUR_MILLIS;
+        activity
+            .apply(
+                &mut canvas,
+                &place(0, 1, colors::BLUE),
+                ping("2001:db8:1::1", t1),
+            )
+            .unwrap();
+        assert_eq!(activity.summary(None, None).players.len(), 11);
+
+        // The players of the first hour are dropped, not its counts
+        let t2 = t0 + 24 * HOUR_MILLIS;
+        activity
+            .apply(
+                &mut canvas,
+                &place(0, 0, colors::BLUE),
+                ping("2001:db8:1::1", t2),
+            )
+            .unwrap();
+        let summary = activity.summary(None, None);
+        assert_eq!(summary.pixels(), 12);
+        assert_eq!(summary.players.len(), 1);
+        assert_eq!(summary.colors(), vec![(colors::RED, 10), (colors::BLUE, 2)]);
+        assert_eq!(activity.summary(Some(0), Some(u64::MAX)), summary);
+        let mut buf = Vec::new();
+        activity.encode(&mut buf);
+        assert!(buf.len() < size);
+        assert_eq!(Activity::decode(&buf).unwrap().hours, activity.hours);
+
+        // Kept forever
+        let mut activity = Activity::new().with_player_retention_days(0);
+        for hour in 0..100 {
+            let placement = ping("2001:db8::1", t0 + hour * 24 * HOUR_MILLIS);
+            activity
+                .apply(&mut canvas, &place(0, 0, colors::RED), placement)
+                .unwrap();
+        }
+        assert!(activity.hours.values().all(|hour| hour.players.len() == 1));
+    }
+
+    #[test]
+    fn activity_from_canvas() {
+        let mut canvas = Canvas::new(10, 10);
+        let alice = ping("2001:db8::1", 1_700_000_000_000);
+        canvas.place_pixel(0, 0, colors::RED, alice).unwrap();
+        canvas.place_pixel(1, 0, colors::RED, alice).unwrap();
+        canvas.set_pixel(2, 0, colors::BLUE).unwrap();
+
+        let summary = Activity::from_canvas(&canvas, 64).summary(None, None);
+        assert_eq!(summary.pixels(), 2);
+        assert_eq!(
+            summary.players[&alice.source.prefix(64).unwrap()],
+            PlayerActivity {
+                placed: 2,
+                surviving: 2
+            }
+        );
+        assert_eq!(summary.colors(), vec![(colors::RED, 2)]);
+    }
+}
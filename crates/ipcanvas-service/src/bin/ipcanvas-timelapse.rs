@@ -0,0 +1,169 @@
+use std::{fs::File, io::BufWriter, path::PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::{Parser, ValueEnum};
+use ipcanvas_service::{
+    canvas::{Canvas, Region},
+    persistence::{EventLog, SnapshotStore},
+    timelapse::{self, Format, FrameInterval, TimelapseOptions},
+};
+
+/// ipcanvas-timelapse: render the history of an ipcanvas as an animated image.
+///
+/// The history is read from the data directory of ipcanvas-service, which can be
+/// in use by a running service. Run the service with `--keep-event-log` to keep
+/// the whole history, otherwise the timelapse starts at the oldest snapshot
+/// still covered by the event log.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Opts {
+    /// Data directory of ipcanvas-service.
+    #[arg(long)]
+    data_dir: PathBuf,
+
+    /// Output file.
+    #[arg(long, short = 'o')]
+    output: PathBuf,
+
+    /// Output format, guessed from the output file extension if not set.
+    #[arg(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// Render a frame every given number of events.
+    #[arg(long, conflicts_with = "every_seconds")]
+    every_events: Option<u64>,
+
+    /// Render a frame every given number of seconds of history.
+    #[arg(long)]
+    every_seconds: Option<u64>,
+
+    /// Region of the canvas to render, as `x,y,width,height`.
+    #[arg(long, value_parser = parse_region)]
+    crop: Option<Region>,
+
+    /// Scale factor applied to the rendered region.
+    #[arg(long, default_value = "1.0")]
+    scale: f64,
+
+    /// Display time of each frame, in milliseconds.
+    #[arg(long, default_value = "100")]
+    frame_delay: u16,
+
+    /// Width of the canvas, if there is no snapshot to read it from.
+    #[arg(long = "width", default_value = "4096")]
+    canvas_width: u16,
+
+    /// Height of the canvas, if there is no snapshot to read it from.
+    #[arg(long = "height", default_value = "4096")]
+    canvas_height: u16,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum OutputFormat {
+    Gif,
+    Apng,
+}
+
+fn parse_region(s: &str) -> Result<Region, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<u16>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let [x, y, width, height] = values[..] else {
+        return Err("expected x,y,width,height".to_string());
+    };
+    Ok(Region {
+        x,
+        y,
+        width,
+        height,
+    })
+}
+
+fn main() -> Result<()> {
+    let opts = Opts::parse();
+
+    let format = match opts.format {
+        Some(OutputFormat::Gif) => Format::Gif,
+        Some(OutputFormat::Apng) => Format::Apng,
+        None => match opts.output.extension().and_then(|ext| ext.to_str()) {
+            Some("png" | "apng") => Format::Apng,
+            _ => Format::Gif,
+        },
+    };
+    let interval = match (opts.every_events, opts.every_seconds) {
+        (_, Some(seconds)) => FrameInterval::Millis(seconds.saturating_mul(1000)),
+        (Some(events), None) => FrameInterval::Events(events),
+        (None, None) => TimelapseOptions::default().interval,
+    };
+    let options = TimelapseOptions {
+        format,
+        interval,
+        region: opts.crop,
+        scale: opts.scale,
+        frame_delay_ms: opts.frame_delay,
+    };
+
+    // Read the history, without modifying the data directory
+    let mut entries = Vec::new();
+    let stats =
+        EventLog::read(&opts.data_dir, 0, |entry| entries.push(entry)).with_context(|| {
+            format!(
+                "Failed to read the event log in {}",
+                opts.data_dir.display()
+            )
+        })?;
+    for (path, dropped) in &stats.damaged {
+        eprintln!(
+            "Event log segment {} is damaged, skipped its last {} bytes",
+            path.display(),
+            dropped
+        );
+    }
+
+    // Find the starting point of the history
+    let snapshots = SnapshotStore::open_read_only(&opts.data_dir);
+    let sequences = snapshots.list().unwrap_or_default();
+    let start = match entries.first().map(|entry| entry.sequence) {
+        // Whole history available, start from a blank canvas
+        Some(1) => match sequences.first() {
+            Some(&sequence) => {
+                let canvas = snapshots.load(sequence)?.canvas;
+                Canvas::new(canvas.width(), canvas.height())
+            }
+            None => Canvas::new(opts.canvas_width, opts.canvas_height),
+        },
+        // No history at all, only the latest state
+        None => match sequences.last() {
+            Some(&sequence) => snapshots.load(sequence)?.canvas,
+            None => Canvas::new(opts.canvas_width, opts.canvas_height),
+        },
+        Some(first) => {
+            let Some(&sequence) = sequences.iter().find(|&&s| s + 1 >= first) else {
+                bail!(
+                    "The event log starts at #{}, and no snapshot is available to start from",
+                    first
+                );
+            };
+            eprintln!(
+                "The event log starts at #{}, starting the timelapse at snapshot #{}",
+                first, sequence
+            );
+            entries.retain(|entry| entry.sequence > sequence);
+            snapshots.load(sequence)?.canvas
+        }
+    };
+
+    let frames = timelapse::count_frames(&entries, options.interval);
+    eprintln!(
+        "Rendering {} events into {} frames to {}",
+        entries.len(),
+        frames,
+        opts.output.display()
+    );
+    let file = File::create(&opts.output)
+        .with_context(|| format!("Failed to create {}", opts.output.display()))?;
+    timelapse::render(&start, &entries, &options, BufWriter::new(file))?;
+    Ok(())
+}
@@ -1,8 +1,24 @@
-use crate::canvas::{Canvas, Pixel};
+use crate::canvas::{
+    Canvas, Pixel, PixelColor, colors,
+    label::{Label, MAX_TEXT_LEN},
+    tile::TileId,
+};
 
 /// Represents the difference between two canvas states.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CanvasDiff {
     pub(crate) changed_pixels: Vec<Pixel>,
+    pub(crate) changed_labels: Vec<Label>,
+    pub(crate) resized: Option<Resize>,
+}
+
+/// A change of the dimensions of the canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Resize {
+    pub width: u16,
+    pub height: u16,
+    /// Color of the pixels added by the resize
+    pub background: PixelColor,
 }
 
 impl CanvasDiff {
@@ -10,38 +26,198 @@ impl CanvasDiff {
     pub fn new() -> Self {
         Self {
             changed_pixels: Vec::new(),
+            changed_labels: Vec::new(),
+            resized: None,
         }
     }
 
+    /// Get the new dimensions of the canvas, if it was resized.
+    ///
+    /// The resize comes before the changed pixels and labels, which may lie in the added area.
+    /// If the canvas was resized several times, only the last resize is given.
+    pub fn resized(&self) -> Option<Resize> {
+        self.resized
+    }
+
     /// Get an iterator over the changed pixels.
     pub fn changed_pixels(&self) -> impl Iterator<Item = &Pixel> + ExactSizeIterator {
         self.changed_pixels.iter()
     }
 
+    /// Get an iterator over the changed labels, in placement order.
+    ///
+    /// Removed labels are given as empty labels at their anchor.
+    pub fn changed_labels(&self) -> impl ExactSizeIterator<Item = &Label> {
+        self.changed_labels.iter()
+    }
+
     /// Check if there are any changes in the diff.
     pub fn is_empty(&self) -> bool {
-        self.changed_pixels.is_empty()
+        self.changed_pixels.is_empty() && self.changed_labels.is_empty() && self.resized.is_none()
     }
 }
 
 impl Canvas {
     /// Calculate the diff between this canvas and another canvas.
+    ///
+    /// This scans every pixel of both canvases, prefer [Canvas::take_diff] to track
+    /// the changes made to a single canvas over time.
+    ///
+    /// If the canvases have different dimensions, the diff resizes the canvas to the
+    /// dimensions of `other`, with a white background.
     pub fn diff(&self, other: &Canvas) -> CanvasDiff {
         let mut diff = CanvasDiff::new();
+        if (self.width, self.height) != (other.width, other.height) {
+            diff.resized = Some(Resize {
+                width: other.width,
+                height: other.height,
+                background: colors::WHITE,
+            });
+        }
 
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let color_self = self.get_pixel(x, y);
-                let color_other = other.get_pixel(x, y);
+        for y in 0..other.height {
+            for x in 0..other.width {
+                let color_self = self.get_pixel(x, y).unwrap_or(colors::WHITE);
+                let color = other.get_pixel(x, y).expect("pixel within the canvas");
+
+                if color_self != color {
+                    diff.changed_pixels.push(Pixel { x, y, color });
+                }
+            }
+        }
 
-                if color_self != color_other {
-                    if let Some(color) = color_other {
-                        diff.changed_pixels.push(Pixel { x, y, color });
-                    }
+        // Labels are compared as whole stacks, a changed label also changes the order
+        if self.labels != other.labels {
+            for label in self.labels() {
+                if other.label(label.x, label.y).is_none() {
+                    diff.changed_labels.push(Label {
+                        text: [0; MAX_TEXT_LEN],
+                        ..*label
+                    });
                 }
             }
+            diff.changed_labels.extend(other.labels().copied());
         }
 
         diff
     }
+
+    /// Take the diff of the changes made since the last call, and clear the dirty flags.
+    ///
+    /// Only the dirty tiles are visited. Repeated writes to the same pixel collapse into
+    /// its latest color, and pixels written back to their original color are left out,
+    /// so the result is the same as [Canvas::diff] against the canvas at the previous call.
+    /// Pixels are returned in row-major order.
+    ///
+    /// After an [expansion](Canvas::expand), the diff resizes the canvas with the background
+    /// of the expansion, not necessarily white.
+    pub fn take_diff(&mut self) -> CanvasDiff {
+        let mut diff = CanvasDiff::new();
+        diff.resized = self.resized.take();
+        let tiles_x = self.tiles_x as usize;
+
+        for (i, tile) in self.tiles.iter_mut().enumerate() {
+            if !tile.is_dirty() {
+                continue;
+            }
+            let id = TileId {
+                tx: (i % tiles_x) as u16,
+                ty: (i / tiles_x) as u16,
+            };
+            tile.drain_changes(id.origin(), &mut diff.changed_pixels);
+        }
+
+        diff.changed_pixels.sort_unstable_by_key(|p| (p.y, p.x));
+        self.labels.drain_changes(&mut diff.changed_labels);
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::colors;
+
+    #[test]
+    fn take_diff_matches_full_diff() {
+        let mut canvas = Canvas::new(600, 300);
+        let previous = canvas.clone();
+        canvas.set_pixel(599, 299, colors::RED).unwrap();
+        canvas.set_pixel(3, 4, colors::GREEN).unwrap();
+        canvas.set_pixel(300, 4, colors::BLUE).unwrap();
+
+        let expected = previous.diff(&canvas);
+        assert_eq!(expected.changed_pixels().len(), 3);
+        assert_eq!(canvas.take_diff(), expected);
+        assert!(canvas.take_diff().is_empty(), "Changes should be drained");
+        assert_eq!(canvas.dirty_tiles().count(), 0);
+    }
+
+    #[test]
+    fn take_diff_after_expansion() {
+        let mut canvas = Canvas::new(300, 200);
+        let previous = canvas.clone();
+        canvas.set_pixel(299, 199, colors::RED).unwrap();
+        let version = canvas.version();
+
+        canvas.expand(600, 300, colors::WHITE).unwrap();
+        canvas.set_pixel(599, 299, colors::GREEN).unwrap();
+        assert_eq!(canvas.get_pixel(299, 199), Some(colors::RED));
+        assert!(canvas.region_version(canvas.bounds()).unwrap() > version);
+
+        // Pending changes are kept by the grown tiles
+        let diff = canvas.take_diff();
+        assert_eq!(diff, previous.diff(&canvas));
+        assert_eq!(
+            diff.resized(),
+            Some(Resize {
+                width: 600,
+                height: 300,
+                background: colors::WHITE
+            })
+        );
+        assert_eq!(diff.changed_pixels().len(), 2);
+        assert!(canvas.take_diff().is_empty());
+
+        assert!(
+            canvas.expand(599, 400, colors::BLACK).is_err(),
+            "Can not shrink"
+        );
+        canvas.expand(600, 400, colors::BLACK).unwrap();
+        assert_eq!(canvas.get_pixel(0, 399), Some(colors::BLACK));
+        assert_eq!(canvas.take_diff().changed_pixels().len(), 0);
+    }
+
+    #[test]
+    fn take_diff_collapses_repeated_writes() {
+        let mut canvas = Canvas::new(16, 16);
+        canvas.set_pixel(1, 1, colors::RED).unwrap();
+        canvas.set_pixel(1, 1, colors::GREEN).unwrap();
+        canvas.set_pixel(1, 1, colors::BLUE).unwrap();
+
+        let diff = canvas.take_diff();
+        assert_eq!(
+            diff.changed_pixels().copied().collect::<Vec<_>>(),
+            vec![Pixel {
+                x: 1,
+                y: 1,
+                color: colors::BLUE
+            }]
+        );
+    }
+
+    #[test]
+    fn take_diff_skips_pixels_written_back() {
+        let mut canvas = Canvas::new(16, 16);
+        canvas.set_pixel(2, 2, colors::RED).unwrap();
+        canvas.set_pixel(2, 2, colors::WHITE).unwrap();
+        canvas.set_pixel(3, 3, colors::WHITE).unwrap();
+        assert!(canvas.take_diff().is_empty());
+
+        // The original color is the one at the previous drain
+        canvas.set_pixel(2, 2, colors::RED).unwrap();
+        canvas.take_diff();
+        canvas.set_pixel(2, 2, colors::WHITE).unwrap();
+        assert_eq!(canvas.take_diff().changed_pixels().len(), 1);
+    }
 }
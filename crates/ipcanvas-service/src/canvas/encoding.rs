@@ -0,0 +1,794 @@
+//! Compact binary wire format for canvas snapshots and diffs.
+//!
+//! # Format (version 3)
+//!
+//! Every message starts with an 8-byte header:
+//!
+//! | Offset | Size | Field         | Description                             |
+//! |--------|------|---------------|-----------------------------------------|
+//! | 0      | 4    | `magic`       | Always `b"IPCW"`                        |
+//! | 4      | 1    | `version`     | Format version, currently `3`           |
+//! | 5      | 1    | `kind`        | `0` = snapshot, `1` = diff              |
+//! | 6      | 1    | `body`        | Body encoding (see below)               |
+//! | 7      | 1    | `compression` | `0` = none, `1` = deflate, `2` = zstd   |
+//!
+//! The header is followed by the body, compressed with the algorithm given in
+//! `compression` (zlib stream for deflate, zstd frame for zstd).
+//! All integers are unsigned and big-endian, colors are `r, g, b` bytes.
+//!
+//! ## Snapshot bodies
+//!
+//! A snapshot body starts with `width: u16` and `height: u16`, followed by:
+//!
+//! - `0` (raw): `width * height` colors, in row-major order.
+//! - `1` (run-length): a sequence of `run: u16` (non-zero) followed by a color, in row-major
+//!   order. The runs must cover exactly `width * height` pixels and may span several rows.
+//!
+//! ## Diff bodies
+//!
+//! A diff body starts with `resized: u8`, `1` if the canvas was resized, followed by the new
+//! `width: u16, height: u16` and the color of the added pixels, `0` otherwise. The resize
+//! applies before the pixels and labels of the diff. Version 1 and 2 diffs have no such prefix.
+//! Then:
+//!
+//! - `2` (row-spans): `count: u32`, then `count` spans of `x: u16, y: u16, len: u16` (non-zero)
+//!   each followed by `len` colors for the pixels `(x..x + len, y)`.
+//! - `3` (sparse): `count: u32`, then `count` entries of `x: u16, y: u16` followed by a color.
+//!
+//! ## Labels
+//!
+//! Every body is followed by the [labels](crate::canvas::label): `count: u32`, then `count`
+//! entries of `x: u16, y: u16` followed by the 8 bytes of the text (null-padded), from the
+//! bottom label to the top one. In a diff, an empty text removes the label at `(x, y)`.
+//! Version 1 messages have no labels, they are still decoded.
+//!
+//! The [Encoder] automatically picks the smallest body encoding for the data, and then the
+//! smallest of the compression algorithms it is allowed to use (including no compression).
+
+use std::fmt::Display;
+
+use crate::canvas::{
+    Canvas, Pixel, PixelColor,
+    diff::{CanvasDiff, Resize},
+    label::{Label, MAX_TEXT_LEN},
+};
+
+/// Magic bytes at the start of every message.
+pub const MAGIC: [u8; 4] = *b"IPCW";
+/// Current version of the wire format.
+pub const VERSION: u8 = 3;
+/// Size of the message header, in bytes.
+pub const HEADER_SIZE: usize = 8;
+/// Maximum size of a decompressed body, to guard against decompression bombs.
+pub const MAX_BODY_SIZE: usize = 256 * 1024 * 1024;
+
+const KIND_SNAPSHOT: u8 = 0;
+const KIND_DIFF: u8 = 1;
+
+const BODY_RAW: u8 = 0;
+const BODY_RUN_LENGTH: u8 = 1;
+const BODY_ROW_SPANS: u8 = 2;
+const BODY_SPARSE: u8 = 3;
+
+/// Compression algorithm applied to a message body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// All the compression algorithms compiled in this build.
+    pub const ALL: &'static [Compression] = &[
+        Compression::None,
+        #[cfg(feature = "deflate")]
+        Compression::Deflate,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd,
+    ];
+
+    fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => 1,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 2,
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => body.to_vec(),
+            #[cfg(feature = "deflate")]
+            Compression::Deflate => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(body)
+                    .and_then(|_| encoder.finish())
+                    .expect("writing to a Vec cannot fail")
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::bulk::compress(body, 3).expect("zstd compression of a slice cannot fail")
+            }
+        }
+    }
+}
+
+/// A decoded message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decoded {
+    Snapshot(Canvas),
+    Diff(CanvasDiff),
+}
+
+/// Errors that can occur while decoding a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message does not start with [MAGIC]
+    InvalidMagic,
+    /// The message uses a format version this decoder does not know
+    UnsupportedVersion(u8),
+    /// The message kind is unknown
+    InvalidKind(u8),
+    /// The body encoding is unknown, or not allowed for this kind of message
+    InvalidBody(u8),
+    /// The compression algorithm is unknown, or not compiled in this build
+    UnsupportedCompression(u8),
+    /// The body could not be decompressed
+    Decompression,
+    /// The message ended before the body was complete
+    Truncated,
+    /// The body is inconsistent (e.g. runs overflowing the canvas)
+    Corrupted,
+    /// The message has unexpected bytes after the end of the body
+    TrailingData,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::InvalidMagic => write!(f, "Invalid magic bytes"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "Unsupported format version {}", v),
+            DecodeError::InvalidKind(k) => write!(f, "Invalid message kind {}", k),
+            DecodeError::InvalidBody(b) => write!(f, "Invalid body encoding {}", b),
+            DecodeError::UnsupportedCompression(c) => {
+                write!(f, "Unsupported compression algorithm {}", c)
+            }
+            DecodeError::Decompression => write!(f, "Failed to decompress the body"),
+            DecodeError::Truncated => write!(f, "Message is truncated"),
+            DecodeError::Corrupted => write!(f, "Message body is corrupted"),
+            DecodeError::TrailingData => write!(f, "Unexpected data after the message body"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Encoder for snapshots and diffs.
+///
+/// By default, the encoder is allowed to use every compression algorithm compiled in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Encoder {
+    compressions: Vec<Compression>,
+}
+
+impl Encoder {
+    /// Create a new encoder that never compresses its output.
+    pub fn new() -> Self {
+        Self {
+            compressions: vec![Compression::None],
+        }
+    }
+
+    /// Allow the encoder to use the given compression algorithm.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        if !self.compressions.contains(&compression) {
+            self.compressions.push(compression);
+        }
+        self
+    }
+
+    /// Encode a full snapshot of the canvas.
+    pub fn encode_snapshot(&self, canvas: &Canvas) -> Vec<u8> {
+        let runs = snapshot_runs(canvas);
+        let raw_size = canvas.pixels().len() * 3;
+        let run_length_size = runs.len() * 5;
+
+        let mut body = Vec::with_capacity(4 + raw_size.min(run_length_size));
+        body.extend_from_slice(&canvas.width().to_be_bytes());
+        body.extend_from_slice(&canvas.height().to_be_bytes());
+        let body_id = if run_length_size < raw_size {
+            for (run, color) in runs {
+                body.extend_from_slice(&run.to_be_bytes());
+                push_color(&mut body, color);
+            }
+            BODY_RUN_LENGTH
+        } else {
+            for pixel in canvas.pixels() {
+                push_color(&mut body, pixel.color);
+            }
+            BODY_RAW
+        };
+        push_labels(&mut body, canvas.labels());
+
+        self.finish(KIND_SNAPSHOT, body_id, &body)
+    }
+
+    /// Encode a diff.
+    pub fn encode_diff(&self, diff: &CanvasDiff) -> Vec<u8> {
+        let mut pixels: Vec<Pixel> = diff.changed_pixels().copied().collect();
+        // Stable sort, so the last write to a pixel stays the last one.
+        pixels.sort_by_key(|p| (p.y, p.x));
+        let spans = diff_spans(&pixels);
+
+        let sparse_size = pixels.len() * 7;
+        let spans_size = spans.len() * 6 + pixels.len() * 3;
+
+        let mut body = Vec::with_capacity(12 + sparse_size.min(spans_size));
+        match diff.resized() {
+            Some(resize) => {
+                body.push(1);
+                body.extend_from_slice(&resize.width.to_be_bytes());
+                body.extend_from_slice(&resize.height.to_be_bytes());
+                push_color(&mut body, resize.background);
+            }
+            None => body.push(0),
+        }
+        let body_id = if spans_size < sparse_size {
+            body.extend_from_slice(&(spans.len() as u32).to_be_bytes());
+            for span in spans {
+                let first = span[0];
+                body.extend_from_slice(&first.x.to_be_bytes());
+                body.extend_from_slice(&first.y.to_be_bytes());
+                body.extend_from_slice(&(span.len() as u16).to_be_bytes());
+                for pixel in span {
+                    push_color(&mut body, pixel.color);
+                }
+            }
+            BODY_ROW_SPANS
+        } else {
+            body.extend_from_slice(&(pixels.len() as u32).to_be_bytes());
+            for pixel in &pixels {
+                body.extend_from_slice(&pixel.x.to_be_bytes());
+                body.extend_from_slice(&pixel.y.to_be_bytes());
+                push_color(&mut body, pixel.color);
+            }
+            BODY_SPARSE
+        };
+        push_labels(&mut body, diff.changed_labels());
+
+        self.finish(KIND_DIFF, body_id, &body)
+    }
+
+    /// Compress the body with the smallest allowed algorithm and prepend the header.
+    fn finish(&self, kind: u8, body_id: u8, body: &[u8]) -> Vec<u8> {
+        let (compression, payload) = self
+            .compressions
+            .iter()
+            .map(|&c| (c, c.compress(body)))
+            .min_by_key(|(_, payload)| payload.len())
+            .unwrap_or_else(|| (Compression::None, body.to_vec()));
+
+        let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+        message.extend_from_slice(&MAGIC);
+        message.push(VERSION);
+        message.push(kind);
+        message.push(body_id);
+        message.push(compression.id());
+        message.extend_from_slice(&payload);
+        message
+    }
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        Self {
+            compressions: Compression::ALL.to_vec(),
+        }
+    }
+}
+
+/// Compute the runs of identical colors of the canvas, in row-major order.
+fn snapshot_runs(canvas: &Canvas) -> Vec<(u16, PixelColor)> {
+    let mut runs: Vec<(u16, PixelColor)> = Vec::new();
+    for pixel in canvas.pixels() {
+        match runs.last_mut() {
+            Some((run, color)) if *color == pixel.color && *run < u16::MAX => *run += 1,
+            _ => runs.push((1, pixel.color)),
+        }
+    }
+    runs
+}
+
+/// Split sorted pixels into spans of horizontally contiguous pixels.
+fn diff_spans(pixels: &[Pixel]) -> Vec<&[Pixel]> {
+    let mut spans = Vec::new();
+    let mut start = 0;
+    for i in 1..=pixels.len() {
+        let contiguous = i < pixels.len()
+            && i - start < u16::MAX as usize
+            && pixels[i].y == pixels[i - 1].y
+            && pixels[i - 1].x.checked_add(1) == Some(pixels[i].x);
+        if !contiguous {
+            spans.push(&pixels[start..i]);
+            start = i;
+        }
+    }
+    spans
+}
+
+fn push_color(buf: &mut Vec<u8>, color: PixelColor) {
+    buf.extend_from_slice(&[color.r, color.g, color.b]);
+}
+
+fn push_labels<'a>(buf: &mut Vec<u8>, labels: impl ExactSizeIterator<Item = &'a Label>) {
+    buf.extend_from_slice(&(labels.len() as u32).to_be_bytes());
+    for label in labels {
+        buf.extend_from_slice(&label.x.to_be_bytes());
+        buf.extend_from_slice(&label.y.to_be_bytes());
+        buf.extend_from_slice(&label.text);
+    }
+}
+
+/// Decode a message, either a snapshot or a diff.
+pub fn decode(message: &[u8]) -> Result<Decoded, DecodeError> {
+    if message.len() < HEADER_SIZE {
+        return Err(DecodeError::Truncated);
+    }
+    if message[..4] != MAGIC {
+        return Err(DecodeError::InvalidMagic);
+    }
+    let version = message[4];
+    if !(1..=VERSION).contains(&version) {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+    let (kind, body_id, compression) = (message[5], message[6], message[7]);
+    let body = decompress(compression, &message[HEADER_SIZE..])?;
+    let mut reader = Reader::new(&body);
+
+    let decoded = match (kind, body_id) {
+        (KIND_SNAPSHOT, BODY_RAW | BODY_RUN_LENGTH) => {
+            let mut canvas = decode_snapshot_body(body_id, &mut reader)?;
+            if version >= 2 {
+                for label in decode_labels(&mut reader)? {
+                    canvas
+                        .set_label(label)
+                        .map_err(|_| DecodeError::Corrupted)?;
+                }
+                // Labels are part of the initial state, not of the first diff
+                canvas.labels.drain_changes(&mut Vec::new());
+            }
+            Decoded::Snapshot(canvas)
+        }
+        (KIND_DIFF, BODY_ROW_SPANS | BODY_SPARSE) => {
+            let resized = if version >= 3 {
+                decode_resize(&mut reader)?
+            } else {
+                None
+            };
+            let mut diff = decode_diff_body(body_id, &mut reader)?;
+            diff.resized = resized;
+            if version >= 2 {
+                diff.changed_labels = decode_labels(&mut reader)?;
+            }
+            Decoded::Diff(diff)
+        }
+        (KIND_SNAPSHOT | KIND_DIFF, _) => return Err(DecodeError::InvalidBody(body_id)),
+        _ => return Err(DecodeError::InvalidKind(kind)),
+    };
+
+    if !reader.is_empty() {
+        return Err(DecodeError::TrailingData);
+    }
+    Ok(decoded)
+}
+
+/// Decode a message that must be a snapshot.
+pub fn decode_snapshot(message: &[u8]) -> Result<Canvas, DecodeError> {
+    match decode(message)? {
+        Decoded::Snapshot(canvas) => Ok(canvas),
+        Decoded::Diff(_) => Err(DecodeError::InvalidKind(KIND_DIFF)),
+    }
+}
+
+/// Decode a message that must be a diff.
+pub fn decode_diff(message: &[u8]) -> Result<CanvasDiff, DecodeError> {
+    match decode(message)? {
+        Decoded::Diff(diff) => Ok(diff),
+        Decoded::Snapshot(_) => Err(DecodeError::InvalidKind(KIND_SNAPSHOT)),
+    }
+}
+
+fn decompress(compression: u8, payload: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    match compression {
+        0 => Ok(payload.to_vec()),
+        #[cfg(feature = "deflate")]
+        1 => {
+            use std::io::Read;
+
+            let mut body = Vec::new();
+            flate2::read::ZlibDecoder::new(payload)
+                .take(MAX_BODY_SIZE as u64 + 1)
+                .read_to_end(&mut body)
+                .map_err(|_| DecodeError::Decompression)?;
+            if body.len() > MAX_BODY_SIZE {
+                return Err(DecodeError::Decompression);
+            }
+            Ok(body)
+        }
+        #[cfg(feature = "zstd")]
+        2 => {
+            use std::io::Read;
+
+            let mut body = Vec::new();
+            zstd::stream::read::Decoder::new(payload)
+                .map_err(|_| DecodeError::Decompression)?
+                .take(MAX_BODY_SIZE as u64 + 1)
+                .read_to_end(&mut body)
+                .map_err(|_| DecodeError::Decompression)?;
+            if body.len() > MAX_BODY_SIZE {
+                return Err(DecodeError::Decompression);
+            }
+            Ok(body)
+        }
+        _ => Err(DecodeError::UnsupportedCompression(compression)),
+    }
+}
+
+fn decode_snapshot_body(body_id: u8, reader: &mut Reader) -> Result<Canvas, DecodeError> {
+    let width = reader.u16()?;
+    let height = reader.u16()?;
+    let total = width as usize * height as usize;
+    // Check the body is plausible before allocating the canvas.
+    if total * 3 > MAX_BODY_SIZE {
+        return Err(DecodeError::Corrupted);
+    }
+    let min_size = if body_id == BODY_RAW {
+        total * 3
+    } else {
+        total.div_ceil(u16::MAX as usize) * 5
+    };
+    if reader.remaining() < min_size {
+        return Err(DecodeError::Truncated);
+    }
+
+    let mut canvas = Canvas::new(width, height);
+    let mut index = 0;
+    while index < total {
+        let (run, color) = if body_id == BODY_RAW {
+            (1, reader.color()?)
+        } else {
+            (reader.u16()? as usize, reader.color()?)
+        };
+        if run == 0 || index + run > total {
+            return Err(DecodeError::Corrupted);
+        }
+        for i in index..index + run {
+            let x = (i % width as usize) as u16;
+            let y = (i / width as usize) as u16;
+            *canvas.pixel_mut(x, y) = color;
+        }
+        index += run;
+    }
+    Ok(canvas)
+}
+
+fn decode_diff_body(body_id: u8, reader: &mut Reader) -> Result<CanvasDiff, DecodeError> {
+    let count = reader.u32()? as usize;
+    let min_entry_size = if body_id == BODY_SPARSE { 7 } else { 9 };
+    if reader.remaining() / min_entry_size < count {
+        return Err(DecodeError::Truncated);
+    }
+
+    let mut diff = CanvasDiff::new();
+    for _ in 0..count {
+        let x = reader.u16()?;
+        let y = reader.u16()?;
+        if body_id == BODY_SPARSE {
+            let color = reader.color()?;
+            diff.changed_pixels.push(Pixel { x, y, color });
+            continue;
+        }
+
+        let len = reader.u16()?;
+        if len == 0 || x.checked_add(len - 1).is_none() {
+            return Err(DecodeError::Corrupted);
+        }
+        for offset in 0..len {
+            let color = reader.color()?;
+            diff.changed_pixels.push(Pixel {
+                x: x + offset,
+                y,
+                color,
+            });
+        }
+    }
+    Ok(diff)
+}
+
+fn decode_resize(reader: &mut Reader) -> Result<Option<Resize>, DecodeError> {
+    match reader.take::<1>()? {
+        [0] => Ok(None),
+        [1] => {
+            let width = reader.u16()?;
+            let height = reader.u16()?;
+            let background = reader.color()?;
+            Ok(Some(Resize {
+                width,
+                height,
+                background,
+            }))
+        }
+        _ => Err(DecodeError::Corrupted),
+    }
+}
+
+fn decode_labels(reader: &mut Reader) -> Result<Vec<Label>, DecodeError> {
+    let count = reader.u32()? as usize;
+    if reader.remaining() / (4 + MAX_TEXT_LEN) < count {
+        return Err(DecodeError::Truncated);
+    }
+    let mut labels = Vec::with_capacity(count);
+    for _ in 0..count {
+        let x = reader.u16()?;
+        let y = reader.u16()?;
+        let text = reader.take()?;
+        labels.push(Label { x, y, text });
+    }
+    Ok(labels)
+}
+
+/// Minimal big-endian cursor over a body.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        if self.buf.len() < N {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, tail) = self.buf.split_at(N);
+        self.buf = tail;
+        Ok(head.try_into().expect("N-byte slice = [u8; N]"))
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        self.take().map(u16::from_be_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        self.take().map(u32::from_be_bytes)
+    }
+
+    fn color(&mut self) -> Result<PixelColor, DecodeError> {
+        let [r, g, b] = self.take()?;
+        Ok(PixelColor { r, g, b })
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::colors;
+
+    fn sorted(diff: &CanvasDiff) -> Vec<Pixel> {
+        let mut pixels: Vec<Pixel> = diff.changed_pixels().copied().collect();
+        pixels.sort_by_key(|p| (p.y, p.x));
+        pixels
+    }
+
+    #[test]
+    fn snapshot_roundtrip_uses_run_length_for_flat_canvas() {
+        let mut canvas = Canvas::new(512, 256);
+        canvas.set_pixel(10, 20, colors::RED).unwrap();
+        canvas.set_pixel(511, 255, colors::BLUE).unwrap();
+
+        let message = Encoder::new().encode_snapshot(&canvas);
+        assert_eq!(message[6], BODY_RUN_LENGTH);
+        assert!(
+            message.len() < 100,
+            "Flat canvas should encode in a few runs"
+        );
+        assert_eq!(decode_snapshot(&message).unwrap(), canvas);
+    }
+
+    #[test]
+    fn snapshot_roundtrip_uses_raw_for_noisy_canvas() {
+        let mut canvas = Canvas::new(64, 64);
+        for y in 0..64 {
+            for x in 0..64 {
+                let color = PixelColor {
+                    r: x as u8,
+                    g: y as u8,
+                    b: (x * y) as u8,
+                };
+                canvas.set_pixel(x, y, color).unwrap();
+            }
+        }
+
+        let message = Encoder::new().encode_snapshot(&canvas);
+        assert_eq!(message[6], BODY_RAW);
+        assert_eq!(message.len(), HEADER_SIZE + 4 + 64 * 64 * 3 + 4);
+        assert_eq!(decode_snapshot(&message).unwrap(), canvas);
+    }
+
+    #[test]
+    fn diff_roundtrip_uses_sparse_for_scattered_pixels() {
+        let mut diff = CanvasDiff::new();
+        diff.changed_pixels.push(Pixel {
+            x: 300,
+            y: 4000,
+            color: colors::GREEN,
+        });
+        diff.changed_pixels.push(Pixel {
+            x: 12,
+            y: 5,
+            color: colors::RED,
+        });
+
+        let message = Encoder::new().encode_diff(&diff);
+        assert_eq!(message[6], BODY_SPARSE);
+        assert_eq!(message.len(), HEADER_SIZE + 1 + 4 + 2 * 7 + 4);
+        assert_eq!(sorted(&decode_diff(&message).unwrap()), sorted(&diff));
+    }
+
+    #[test]
+    fn diff_roundtrip_uses_row_spans_for_dense_pixels() {
+        let mut diff = CanvasDiff::new();
+        for y in 100..110 {
+            for x in 20..60 {
+                diff.changed_pixels.push(Pixel {
+                    x,
+                    y,
+                    color: colors::YELLOW,
+                });
+            }
+        }
+
+        let message = Encoder::new().encode_diff(&diff);
+        assert_eq!(message[6], BODY_ROW_SPANS);
+        assert_eq!(message.len(), HEADER_SIZE + 1 + 4 + 10 * 6 + 400 * 3 + 4);
+        assert_eq!(decode_diff(&message).unwrap(), diff);
+    }
+
+    #[test]
+    fn empty_diff_roundtrip() {
+        let diff = CanvasDiff::new();
+        let message = Encoder::default().encode_diff(&diff);
+        assert_eq!(decode_diff(&message).unwrap(), diff);
+    }
+
+    #[test]
+    fn labels_roundtrip() {
+        let mut canvas = Canvas::new(64, 64);
+        canvas
+            .set_label(Label::new(1, 2, "hello").unwrap())
+            .unwrap();
+        canvas.set_label(Label::new(0, 0, "top").unwrap()).unwrap();
+        let decoded = decode_snapshot(&Encoder::new().encode_snapshot(&canvas)).unwrap();
+        assert_eq!(decoded, canvas);
+        assert_eq!(
+            decoded.labels().last(),
+            Some(&Label::new(0, 0, "top").unwrap())
+        );
+
+        let diff = canvas.take_diff();
+        assert_eq!(diff.changed_labels().len(), 2);
+        assert_eq!(
+            decode_diff(&Encoder::new().encode_diff(&diff)).unwrap(),
+            diff
+        );
+
+        // Version 1 messages have no labels (nor resize)
+        let mut message = Encoder::new().encode_diff(&CanvasDiff::new());
+        message[4] = 1;
+        message.remove(HEADER_SIZE);
+        message.truncate(message.len() - 4);
+        assert_eq!(decode_diff(&message).unwrap(), CanvasDiff::new());
+    }
+
+    #[test]
+    fn resize_roundtrip() {
+        let mut canvas = Canvas::new(64, 64);
+        canvas.expand(128, 64, colors::BLACK).unwrap();
+        canvas.set_pixel(100, 0, colors::RED).unwrap();
+        let diff = canvas.take_diff();
+        let message = Encoder::new().encode_diff(&diff);
+        assert_eq!(message.len(), HEADER_SIZE + 8 + 4 + 7 + 4);
+        let decoded = decode_diff(&message).unwrap();
+        assert_eq!(decoded, diff);
+        assert_eq!(
+            decoded.resized(),
+            Some(Resize {
+                width: 128,
+                height: 64,
+                background: colors::BLACK
+            })
+        );
+
+        let mut message = message;
+        message[HEADER_SIZE] = 2;
+        assert_eq!(decode(&message), Err(DecodeError::Corrupted));
+    }
+
+    #[test]
+    fn compressed_roundtrip() {
+        let mut canvas = Canvas::new(256, 256);
+        for x in (0..256).step_by(2) {
+            canvas.set_pixel(x, x, colors::MAGENTA).unwrap();
+        }
+        let uncompressed = Encoder::new().encode_snapshot(&canvas);
+
+        for &compression in Compression::ALL {
+            let message = Encoder::new()
+                .with_compression(compression)
+                .encode_snapshot(&canvas);
+            assert!(message.len() <= uncompressed.len());
+            assert_eq!(decode_snapshot(&message).unwrap(), canvas);
+        }
+    }
+
+    #[test]
+    fn decode_rejects_invalid_messages() {
+        let message = Encoder::new().encode_diff(&CanvasDiff::new());
+
+        assert_eq!(decode(&message[..4]), Err(DecodeError::Truncated));
+
+        let mut bad_magic = message.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(decode(&bad_magic), Err(DecodeError::InvalidMagic));
+
+        let mut bad_version = message.clone();
+        bad_version[4] = 42;
+        assert_eq!(
+            decode(&bad_version),
+            Err(DecodeError::UnsupportedVersion(42))
+        );
+
+        let mut bad_body = message.clone();
+        bad_body[6] = BODY_RAW;
+        assert_eq!(decode(&bad_body), Err(DecodeError::InvalidBody(BODY_RAW)));
+
+        let mut bad_compression = message.clone();
+        bad_compression[7] = 200;
+        assert_eq!(
+            decode(&bad_compression),
+            Err(DecodeError::UnsupportedCompression(200))
+        );
+
+        let mut trailing = message.clone();
+        trailing.push(0);
+        assert_eq!(decode(&trailing), Err(DecodeError::TrailingData));
+    }
+
+    #[test]
+    fn decode_rejects_overflowing_runs() {
+        let canvas = Canvas::new(2, 2);
+        let mut message = Encoder::new().encode_snapshot(&canvas);
+        assert_eq!(message[6], BODY_RUN_LENGTH);
+        // Single run of 4 pixels, make it 5
+        message[HEADER_SIZE + 5] = 5;
+        assert_eq!(decode(&message), Err(DecodeError::Corrupted));
+    }
+}
@@ -0,0 +1,279 @@
+//! Conversion of the canvas to and from common image formats.
+
+use std::{fmt::Display, io::Cursor};
+
+use crate::{
+    canvas::{Canvas, PixelColor},
+    events::Event,
+};
+
+/// Pixels of an imported image with an alpha below this threshold are transparent.
+pub const ALPHA_THRESHOLD: u8 = 128;
+
+/// Maximum memory the PNG decoder may allocate, in bytes.
+///
+/// It also bounds the buffer the image is decoded to, so a small file claiming huge
+/// dimensions is rejected before anything is allocated for it.
+const DECODER_MEMORY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// Encode the canvas as an 8-bit RGB PNG image.
+pub fn encode_png(canvas: &Canvas) -> Result<Vec<u8>, png::EncodingError> {
+    let mut data = Vec::with_capacity(canvas.width() as usize * canvas.height() as usize * 3);
+    for pixel in canvas.pixels() {
+        data.extend_from_slice(&[pixel.color.r, pixel.color.g, pixel.color.b]);
+    }
+
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, canvas.width() as u32, canvas.height() as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Fast);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(out)
+}
+
+/// Errors that can occur while importing an image.
+#[derive(Debug)]
+pub enum ImportError {
+    /// The image could not be decoded
+    Decoding(png::DecodingError),
+    /// The image does not fit in the canvas coordinates
+    TooLarge { width: u32, height: u32 },
+}
+
+impl Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Decoding(e) => write!(f, "Failed to decode the image: {}", e),
+            ImportError::TooLarge { width, height } => {
+                write!(f, "Image is too large ({}x{})", width, height)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<png::DecodingError> for ImportError {
+    fn from(e: png::DecodingError) -> Self {
+        ImportError::Decoding(e)
+    }
+}
+
+/// An image to paint on the canvas.
+///
+/// Transparent pixels are kept as None, they leave the canvas untouched.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportImage {
+    width: u16,
+    height: u16,
+    // Pixel (x, y) is at index (y * width + x)
+    pixels: Box<[Option<PixelColor>]>,
+}
+
+impl ImportImage {
+    /// Decode a PNG image, of any color type and bit depth.
+    ///
+    /// Pixels with an alpha below [ALPHA_THRESHOLD] are transparent.
+    pub fn decode_png(bytes: &[u8]) -> Result<Self, ImportError> {
+        let limits = png::Limits {
+            bytes: DECODER_MEMORY_LIMIT,
+        };
+        let mut decoder = png::Decoder::new_with_limits(Cursor::new(bytes), limits);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+
+        let (width, height) = (reader.info().width, reader.info().height);
+        let (Ok(w), Ok(h)) = (u16::try_from(width), u16::try_from(height)) else {
+            return Err(ImportError::TooLarge { width, height });
+        };
+        let buffer_size = reader
+            .output_buffer_size()
+            .filter(|&size| size <= DECODER_MEMORY_LIMIT)
+            .ok_or(ImportError::TooLarge { width, height })?;
+        let mut buf = vec![0; buffer_size];
+        let info = reader.next_frame(&mut buf)?;
+
+        let channels = info.color_type.samples();
+        let mut pixels = Vec::with_capacity(w as usize * h as usize);
+        for row in buf[..info.buffer_size()].chunks_exact(info.line_size) {
+            for p in row.chunks_exact(channels).take(w as usize) {
+                let (color, alpha) = match info.color_type {
+                    png::ColorType::Grayscale => (gray(p[0]), 255),
+                    png::ColorType::GrayscaleAlpha => (gray(p[0]), p[1]),
+                    png::ColorType::Rgb => (rgb(p), 255),
+                    png::ColorType::Rgba => (rgb(p), p[3]),
+                    // Palettes are expanded by the decoder
+                    png::ColorType::Indexed => unreachable!("indexed colors are expanded"),
+                };
+                pixels.push((alpha >= ALPHA_THRESHOLD).then_some(color));
+            }
+        }
+
+        Ok(Self {
+            width: w,
+            height: h,
+            pixels: pixels.into_boxed_slice(),
+        })
+    }
+
+    /// Get the width of the image.
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    /// Get the height of the image.
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Get the color of the pixel at the given coordinates, None if it is transparent.
+    pub fn get_pixel(&self, x: u16, y: u16) -> Option<PixelColor> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.pixels[(y as usize) * (self.width as usize) + (x as usize)]
+    }
+
+    /// Get the events painting the image with its top-left corner at (x, y).
+    ///
+    /// Transparent pixels are skipped, as well as pixels beyond the largest coordinates.
+    /// Pixels outside of the canvas still produce events, which will fail to apply.
+    pub fn events(&self, x: u16, y: u16) -> impl Iterator<Item = Event> + '_ {
+        (0..self.height).flat_map(move |dy| {
+            (0..self.width).filter_map(move |dx| {
+                let color = self.get_pixel(dx, dy)?;
+                Some(Event::PlacePixel {
+                    x: x.checked_add(dx)?,
+                    y: y.checked_add(dy)?,
+                    color,
+                })
+            })
+        })
+    }
+}
+
+fn gray(v: u8) -> PixelColor {
+    PixelColor { r: v, g: v, b: v }
+}
+
+fn rgb(p: &[u8]) -> PixelColor {
+    PixelColor {
+        r: p[0],
+        g: p[1],
+        b: p[2],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::colors;
+
+    #[test]
+    fn encode_png_roundtrip() {
+        let mut canvas = Canvas::new(300, 2);
+        canvas.set_pixel(0, 0, colors::RED).unwrap();
+        canvas.set_pixel(299, 1, colors::BLUE).unwrap();
+
+        let bytes = encode_png(&canvas).unwrap();
+        let decoder = png::Decoder::new(std::io::Cursor::new(bytes));
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!((info.width, info.height), (300, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(&buf[..3], &[255, 0, 0]);
+        assert_eq!(&buf[3..6], &[255, 255, 255]);
+        assert_eq!(
+            &buf[info.buffer_size() - 3..info.buffer_size()],
+            &[0, 0, 255]
+        );
+    }
+
+    /// Encode a 2x2 RGBA image, with a transparent bottom-right pixel.
+    fn rgba_png() -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, 2, 2);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        #[rustfmt::skip]
+        writer.write_image_data(&[
+            255, 0, 0, 255,   0, 255, 0, 200,
+            0, 0, 255, 255,   9, 9, 9, 10,
+        ]).unwrap();
+        writer.finish().unwrap();
+        out
+    }
+
+    #[test]
+    fn import_png_skips_transparent_pixels() {
+        let image = ImportImage::decode_png(&rgba_png()).unwrap();
+        assert_eq!((image.width(), image.height()), (2, 2));
+        assert_eq!(image.get_pixel(1, 0), Some(colors::GREEN));
+        assert_eq!(image.get_pixel(1, 1), None);
+
+        let events: Vec<_> = image.events(10, 65535).collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::PlacePixel {
+                    x: 10,
+                    y: 65535,
+                    color: colors::RED
+                },
+                Event::PlacePixel {
+                    x: 11,
+                    y: 65535,
+                    color: colors::GREEN
+                },
+            ],
+            "Transparent pixels and pixels beyond the coordinates are skipped"
+        );
+    }
+
+    #[test]
+    fn import_png_roundtrip() {
+        let mut canvas = Canvas::new(3, 3);
+        canvas.set_pixel(1, 2, colors::MAGENTA).unwrap();
+        let image = ImportImage::decode_png(&encode_png(&canvas).unwrap()).unwrap();
+
+        let mut imported = Canvas::new(3, 3);
+        for event in image.events(0, 0) {
+            event.apply(&mut imported).unwrap();
+        }
+        assert_eq!(imported, canvas);
+        assert!(ImportImage::decode_png(b"not a png").is_err());
+    }
+
+    #[test]
+    fn import_png_rejects_huge_dimensions() {
+        // Only the header of a 65535x65535 RGBA image, and the start of its data
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        let mut chunk = |kind: &[u8], data: &[u8]| {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            let start = png.len();
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            let crc = crc32fast::hash(&png[start..]);
+            png.extend_from_slice(&crc.to_be_bytes());
+        };
+        let mut header = Vec::new();
+        header.extend_from_slice(&65535u32.to_be_bytes());
+        header.extend_from_slice(&65535u32.to_be_bytes());
+        header.extend_from_slice(&[8, 6, 0, 0, 0]);
+        chunk(b"IHDR", &header);
+        chunk(b"IDAT", &[]);
+
+        assert!(matches!(
+            ImportImage::decode_png(&png),
+            Err(ImportError::TooLarge {
+                width: 65535,
+                height: 65535
+            })
+        ));
+    }
+}
@@ -0,0 +1,207 @@
+//! Labels: short texts placed on the canvas, on top of its pixels.
+//!
+//! A label is anchored at a pixel of the canvas (the top-left corner of its text) and
+//! holds up to [MAX_TEXT_LEN] bytes of text. Labels may overlap, the most recently placed
+//! one being on top, and a label placed at the anchor of another one replaces it.
+//! Placing a label with an empty text removes the label at its anchor.
+//!
+//! Labels are not part of the pixels, but they can be rasterised into them with the
+//! built-in [font](super::font), see [Label::events].
+
+use std::collections::BTreeSet;
+
+use crate::{
+    canvas::{PixelColor, Region, font, provenance::Cell},
+    events::Event,
+};
+
+/// Maximum length of the text of a label, in bytes.
+pub const MAX_TEXT_LEN: usize = 8;
+
+/// A label with its anchor and text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Label {
+    pub x: u16,
+    pub y: u16,
+    /// Text of the label, null-padded
+    pub text: [u8; MAX_TEXT_LEN],
+}
+
+impl Label {
+    /// Create a label from a text, None if it is longer than [MAX_TEXT_LEN] bytes.
+    pub fn new(x: u16, y: u16, text: &str) -> Option<Self> {
+        let bytes = text.as_bytes();
+        if bytes.len() > MAX_TEXT_LEN {
+            return None;
+        }
+        let mut text = [0; MAX_TEXT_LEN];
+        text[..bytes.len()].copy_from_slice(bytes);
+        Some(Self { x, y, text })
+    }
+
+    /// Get the bytes of the text, without the padding.
+    pub fn text_bytes(&self) -> &[u8] {
+        let len = self
+            .text
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(MAX_TEXT_LEN);
+        &self.text[..len]
+    }
+
+    /// Get the text, with invalid UTF-8 sequences replaced.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(self.text_bytes()).into_owned()
+    }
+
+    /// Check if the label has no text (i.e. it removes the label at its anchor).
+    pub fn is_empty(&self) -> bool {
+        self.text[0] == 0
+    }
+
+    /// Get the area covered by the rasterised text, clipped to the coordinate space.
+    pub fn region(&self) -> Region {
+        let width = font::text_width(self.text_bytes());
+        Region {
+            x: self.x,
+            y: self.y,
+            width: width.min(u16::MAX - self.x),
+            height: font::GLYPH_HEIGHT.min(u16::MAX - self.y),
+        }
+    }
+
+    /// Get the pixels of the rasterised text.
+    ///
+    /// Pixels past the end of the coordinate space are skipped, not those outside of the canvas.
+    pub fn pixels(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        font::text_pixels(self.text_bytes())
+            .filter_map(|(dx, dy)| Some((self.x.checked_add(dx)?, self.y.checked_add(dy)?)))
+    }
+
+    /// Rasterise the label: get the events painting its text with the given color.
+    pub fn events(&self, color: PixelColor) -> impl Iterator<Item = Event> + '_ {
+        self.pixels()
+            .map(move |(x, y)| Event::PlacePixel { x, y, color })
+    }
+}
+
+/// Labels of a canvas, with their placements.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Labels {
+    // In placement order, the last one is on top
+    pub(crate) labels: Vec<(Label, Cell)>,
+    // Anchors of the labels changed since the last diff
+    changed: BTreeSet<(u16, u16)>,
+}
+
+impl Labels {
+    /// Place a label, replacing the one at the same anchor (removing it if the label is empty).
+    pub(crate) fn place(&mut self, label: Label, cell: Cell) {
+        self.labels
+            .retain(|(other, _)| (other.x, other.y) != (label.x, label.y));
+        if !label.is_empty() {
+            self.labels.push((label, cell));
+        }
+        self.changed.insert((label.x, label.y));
+    }
+
+    /// Get the label at the given anchor, and its placement.
+    pub(crate) fn get(&self, x: u16, y: u16) -> Option<&(Label, Cell)> {
+        self.labels
+            .iter()
+            .find(|(label, _)| (label.x, label.y) == (x, y))
+    }
+
+    /// Take the labels changed since the last call: an empty label for each removed one,
+    /// then the others in placement order (they are all above the unchanged ones).
+    pub(crate) fn drain_changes(&mut self, changes: &mut Vec<Label>) {
+        let changed = std::mem::take(&mut self.changed);
+        for &(x, y) in &changed {
+            if self.get(x, y).is_none() {
+                changes.push(Label {
+                    x,
+                    y,
+                    text: [0; MAX_TEXT_LEN],
+                });
+            }
+        }
+        changes.extend(
+            self.labels
+                .iter()
+                .map(|(label, _)| *label)
+                .filter(|label| changed.contains(&(label.x, label.y))),
+        );
+    }
+}
+
+impl PartialEq for Labels {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|((a, _), (b, _))| a == b)
+    }
+}
+
+impl Eq for Labels {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::colors;
+
+    #[test]
+    fn labels_replace_each_other() {
+        let mut labels = Labels::default();
+        let hello = Label::new(1, 2, "hello").unwrap();
+        assert_eq!(hello.text(), "hello");
+        assert!(Label::new(0, 0, "too long!").is_none());
+
+        labels.place(hello, Cell::default());
+        labels.place(Label::new(3, 2, "world").unwrap(), Cell::default());
+        labels.place(Label::new(1, 2, "bye").unwrap(), Cell::default());
+        let texts: Vec<_> = labels.labels.iter().map(|(l, _)| l.text()).collect();
+        assert_eq!(texts, vec!["world", "bye"], "Replaced label is on top");
+
+        labels.place(Label::new(3, 2, "").unwrap(), Cell::default());
+        let mut changes = Vec::new();
+        labels.drain_changes(&mut changes);
+        assert_eq!(
+            changes,
+            vec![
+                Label::new(3, 2, "").unwrap(),
+                Label::new(1, 2, "bye").unwrap()
+            ]
+        );
+        assert_eq!(labels.labels.len(), 1);
+    }
+
+    #[test]
+    fn label_rasterisation() {
+        let label = Label::new(10, 20, "Hi").unwrap();
+        assert_eq!(
+            label.region(),
+            Region {
+                x: 10,
+                y: 20,
+                width: 11,
+                height: 7
+            }
+        );
+        let events: Vec<_> = label.events(colors::RED).collect();
+        assert_eq!(events.len(), label.pixels().count());
+        assert!(events.contains(&Event::PlacePixel {
+            x: 10,
+            y: 20,
+            color: colors::RED
+        }));
+        assert!(label.pixels().all(|(x, y)| label.region().contains(x, y)));
+
+        // Clipped to the coordinate space
+        let edge = Label::new(u16::MAX - 2, 0, "W").unwrap();
+        assert!(edge.pixels().all(|(x, _)| x >= u16::MAX - 2));
+        assert_eq!(edge.region().width, 2);
+    }
+}
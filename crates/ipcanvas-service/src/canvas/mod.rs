@@ -1,17 +1,46 @@
 //! Canvas-related functionality and operations
 
 pub mod diff;
+pub mod encoding;
+pub mod font;
+pub mod image;
+pub mod label;
+pub mod provenance;
+pub mod tile;
+
+use diff::Resize;
+use label::{Label, Labels};
+use provenance::{Placement, Sources};
+use tile::{TILE_SIZE, Tile, TileId};
 
 /// Color of a pixel on the canvas.
 ///
 /// Simple RGB representation.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct PixelColor {
     pub r: u8,
     pub g: u8,
     pub b: u8,
 }
 
+impl PixelColor {
+    /// Parse a color written as `#rrggbb`.
+    pub fn from_hex(color: &str) -> Option<Self> {
+        let hex = color.strip_prefix('#').filter(|hex| hex.len() == 6)?;
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        Some(Self {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        })
+    }
+
+    /// Write the color as `#rrggbb`.
+    pub fn to_hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+    }
+}
+
 /// A pixel on the canvas with its coordinates and color.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Pixel {
@@ -20,6 +49,27 @@ pub struct Pixel {
     pub color: PixelColor,
 }
 
+/// A rectangular area of the canvas.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Region {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl Region {
+    /// Check if the region contains the pixel at the given coordinates.
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        x >= self.x && y >= self.y && (x - self.x) < self.width && (y - self.y) < self.height
+    }
+
+    /// Check if the region has no pixel.
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+}
+
 pub mod colors {
     use super::PixelColor;
 
@@ -51,47 +101,242 @@ pub mod colors {
 }
 
 /// Canvas state
-#[derive(Clone, Debug, PartialEq, Eq)]
+///
+/// The pixels are organised into tiles of [TILE_SIZE]x[TILE_SIZE] pixels, each with
+/// its own version and dirty flag.
+///
+/// The canvas also keeps the [provenance](provenance) of its pixels, who placed them and when,
+/// and the [labels](label) placed on top of them.
+///
+/// Two canvases are equal if they have the same dimensions, pixels and labels, whatever their
+/// versions and provenance.
+#[derive(Clone, Debug)]
 pub struct Canvas {
     width: u16,
     height: u16,
-    // Pixel data stored as a flat array.
-    // Cell (x, y) is at index (y * width + x)
-    data: Box<[PixelColor]>,
+    // Number of tiles per row
+    tiles_x: u16,
+    // Tiles stored as a flat array.
+    // Tile (tx, ty) is at index (ty * tiles_x + tx)
+    tiles: Box<[Tile]>,
+    // Incremented on every write
+    version: u64,
+    // Sources of the pixel and label placements
+    sources: Sources,
+    labels: Labels,
+    // Last expansion since the last diff
+    resized: Option<Resize>,
 }
 
 impl Canvas {
     /// Create a new canvas with the given width and height.
     pub fn new(width: u16, height: u16) -> Self {
-        let data = vec![colors::WHITE; (width as usize) * (height as usize)].into_boxed_slice();
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+        let mut tiles = Vec::with_capacity(tiles_x as usize * tiles_y as usize);
+        for ty in 0..tiles_y {
+            for tx in 0..tiles_x {
+                let tile_width = (width - tx * TILE_SIZE).min(TILE_SIZE);
+                let tile_height = (height - ty * TILE_SIZE).min(TILE_SIZE);
+                tiles.push(Tile::new(tile_width, tile_height));
+            }
+        }
         Self {
             width,
             height,
-            data,
+            tiles_x,
+            tiles: tiles.into_boxed_slice(),
+            version: 0,
+            sources: Sources::default(),
+            labels: Labels::default(),
+            resized: None,
         }
     }
 
+    /// Expand the canvas to the given dimensions, filling the added area with `background`.
+    ///
+    /// The pixels, their provenance and the labels are kept. The canvas version is incremented,
+    /// along with the version of the tiles which grew or were added, and the expansion is
+    /// recorded for the next [Canvas::take_diff].
+    ///
+    /// Returns Err(()) if the canvas would shrink in either dimension.
+    #[allow(clippy::result_unit_err)]
+    pub fn expand(&mut self, width: u16, height: u16, background: PixelColor) -> Result<(), ()> {
+        if width < self.width || height < self.height {
+            return Err(());
+        }
+        if (width, height) == (self.width, self.height) {
+            return Ok(());
+        }
+        self.version += 1;
+        let version = self.version;
+
+        let (old_tiles_x, old_tiles_y) = (self.tiles_x, self.tiles_y());
+        let tiles_x = width.div_ceil(TILE_SIZE);
+        let tiles_y = height.div_ceil(TILE_SIZE);
+        let mut old_tiles: Vec<Option<Tile>> = std::mem::take(&mut self.tiles)
+            .into_vec()
+            .into_iter()
+            .map(Some)
+            .collect();
+        let mut tiles = Vec::with_capacity(tiles_x as usize * tiles_y as usize);
+        for ty in 0..tiles_y {
+            for tx in 0..tiles_x {
+                let tile_width = (width - tx * TILE_SIZE).min(TILE_SIZE);
+                let tile_height = (height - ty * TILE_SIZE).min(TILE_SIZE);
+                let old = (tx < old_tiles_x && ty < old_tiles_y)
+                    .then(|| old_tiles[ty as usize * old_tiles_x as usize + tx as usize].take())
+                    .flatten();
+                let tile = match old {
+                    Some(tile) if (tile.width(), tile.height()) == (tile_width, tile_height) => {
+                        tile
+                    }
+                    Some(tile) => {
+                        let mut tile = tile.expanded(tile_width, tile_height, background);
+                        tile.set_version(version);
+                        tile
+                    }
+                    None => {
+                        let mut tile = Tile::filled(tile_width, tile_height, background);
+                        tile.set_version(version);
+                        tile
+                    }
+                };
+                tiles.push(tile);
+            }
+        }
+
+        self.width = width;
+        self.height = height;
+        self.tiles_x = tiles_x;
+        self.tiles = tiles.into_boxed_slice();
+        self.resized = Some(Resize {
+            width,
+            height,
+            background,
+        });
+        Ok(())
+    }
+
     /// Get the pixel color at the given coordinates.
     pub fn get_pixel(&self, x: u16, y: u16) -> Option<PixelColor> {
         if x >= self.width || y >= self.height {
             return None;
         }
-        let index = (y as usize) * (self.width as usize) + (x as usize);
-        Some(self.data[index])
+        let tile = &self.tiles[self.tile_index(TileId::of_pixel(x, y))];
+        tile.get_pixel(x % TILE_SIZE, y % TILE_SIZE)
     }
 
     /// Set the pixel color at the given coordinates.
     ///
+    /// The canvas version is incremented, the tile containing the pixel is marked dirty,
+    /// and the change is recorded for the next [Canvas::take_diff].
+    ///
+    /// The origin of the pixel becomes unknown, see [Canvas::place_pixel] to record it.
+    ///
     /// Returns Err(()) if the coordinates are out of bounds.
     pub fn set_pixel(&mut self, x: u16, y: u16, color: PixelColor) -> Result<(), ()> {
         if x >= self.width || y >= self.height {
             return Err(());
         }
-        let index = (y as usize) * (self.width as usize) + (x as usize);
-        self.data[index] = color;
+        self.write(x, y, color, None);
+        Ok(())
+    }
+
+    /// Set the pixel color at the given coordinates, recording who placed it and when.
+    ///
+    /// Same as [Canvas::set_pixel] otherwise.
+    ///
+    /// Returns Err(()) if the coordinates are out of bounds.
+    #[allow(clippy::result_unit_err)]
+    pub fn place_pixel(
+        &mut self,
+        x: u16,
+        y: u16,
+        color: PixelColor,
+        placement: Placement,
+    ) -> Result<(), ()> {
+        if x >= self.width || y >= self.height {
+            return Err(());
+        }
+        self.collect_sources();
+        let cell = self.sources.cell(&placement);
+        self.write(x, y, color, Some(cell));
+        Ok(())
+    }
+
+    /// Get who placed the pixel at the given coordinates, and when.
+    ///
+    /// Returns None if the coordinates are out of bounds, or the origin of the pixel is unknown.
+    pub fn placement(&self, x: u16, y: u16) -> Option<Placement> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let tile = &self.tiles[self.tile_index(TileId::of_pixel(x, y))];
+        self.sources
+            .placement(tile.placement(x % TILE_SIZE, y % TILE_SIZE))
+    }
+
+    /// Place a label, anchored at a pixel of the canvas.
+    ///
+    /// The label replaces the one with the same anchor, or removes it if the label is empty,
+    /// and the change is recorded for the next [Canvas::take_diff].
+    /// The origin of the label is unknown, see [Canvas::place_label] to record it.
+    ///
+    /// Returns Err(()) if the anchor is out of bounds.
+    #[allow(clippy::result_unit_err)]
+    pub fn set_label(&mut self, label: Label) -> Result<(), ()> {
+        if label.x >= self.width || label.y >= self.height {
+            return Err(());
+        }
+        self.labels.place(label, Default::default());
         Ok(())
     }
 
+    /// Place a label, recording who placed it and when.
+    ///
+    /// Same as [Canvas::set_label] otherwise.
+    ///
+    /// Returns Err(()) if the anchor is out of bounds.
+    #[allow(clippy::result_unit_err)]
+    pub fn place_label(&mut self, label: Label, placement: Placement) -> Result<(), ()> {
+        if label.x >= self.width || label.y >= self.height {
+            return Err(());
+        }
+        self.collect_sources();
+        let cell = self.sources.cell(&placement);
+        self.labels.place(label, cell);
+        Ok(())
+    }
+
+    /// Get the label anchored at the given coordinates.
+    pub fn label(&self, x: u16, y: u16) -> Option<Label> {
+        self.labels.get(x, y).map(|(label, _)| *label)
+    }
+
+    /// Get who placed the label anchored at the given coordinates, and when.
+    ///
+    /// Returns None if there is no label there, or its origin is unknown.
+    pub fn label_placement(&self, x: u16, y: u16) -> Option<Placement> {
+        let (_, cell) = self.labels.get(x, y)?;
+        self.sources.placement(*cell)
+    }
+
+    /// Get an iterator over the labels, from the bottom one to the top one.
+    pub fn labels(&self) -> impl ExactSizeIterator<Item = &Label> {
+        self.labels.labels.iter().map(|(label, _)| label)
+    }
+
+    /// Get a mutable reference to the pixel at the given coordinates,
+    /// without tracking the change (no version bump, no dirty flag).
+    ///
+    /// Panics if the coordinates are out of bounds.
+    pub(crate) fn pixel_mut(&mut self, x: u16, y: u16) -> &mut PixelColor {
+        assert!(x < self.width && y < self.height, "pixel out of bounds");
+        let index = self.tile_index(TileId::of_pixel(x, y));
+        self.tiles[index].pixel_mut(x % TILE_SIZE, y % TILE_SIZE)
+    }
+
     /// Get the width of the canvas.
     pub fn width(&self) -> u16 {
         self.width
@@ -102,12 +347,145 @@ impl Canvas {
         self.height
     }
 
+    /// Get the version of the canvas.
+    ///
+    /// The version is incremented on every pixel write.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Override the version of the canvas and of all its tiles, e.g. when restoring a canvas.
+    pub(crate) fn set_version(&mut self, version: u64) {
+        self.version = version;
+        for tile in self.tiles.iter_mut() {
+            tile.set_version(version);
+        }
+    }
+
+    /// Get the number of tiles per row.
+    pub fn tiles_x(&self) -> u16 {
+        self.tiles_x
+    }
+
+    /// Get the number of tiles per column.
+    pub fn tiles_y(&self) -> u16 {
+        self.height.div_ceil(TILE_SIZE)
+    }
+
+    /// Get the tile with the given identifier.
+    pub fn tile(&self, id: TileId) -> Option<&Tile> {
+        if id.tx >= self.tiles_x || id.ty >= self.tiles_y() {
+            return None;
+        }
+        Some(&self.tiles[self.tile_index(id)])
+    }
+
+    /// Get an iterator over all tiles, in row-major order.
+    pub fn tiles(&self) -> impl ExactSizeIterator<Item = (TileId, &Tile)> {
+        let tiles_x = self.tiles_x;
+        self.tiles.iter().enumerate().map(move |(i, tile)| {
+            let id = TileId {
+                tx: (i % tiles_x as usize) as u16,
+                ty: (i / tiles_x as usize) as u16,
+            };
+            (id, tile)
+        })
+    }
+
+    /// Get an iterator over the identifiers of the dirty tiles, in row-major order.
+    pub fn dirty_tiles(&self) -> impl Iterator<Item = TileId> {
+        self.tiles()
+            .filter(|(_, tile)| tile.is_dirty())
+            .map(|(id, _)| id)
+    }
+
+    /// Get the region covering the whole canvas.
+    pub fn bounds(&self) -> Region {
+        Region {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Check if the given region is non-empty and lies entirely within the canvas.
+    pub fn contains_region(&self, region: Region) -> bool {
+        !region.is_empty()
+            && (region.x as u32 + region.width as u32) <= self.width as u32
+            && (region.y as u32 + region.height as u32) <= self.height as u32
+    }
+
+    /// Get the version of a region of the canvas.
+    ///
+    /// This is the highest version of the tiles overlapping the region, so it changes
+    /// whenever a pixel of the region may have changed.
+    ///
+    /// Returns None if the region is not within the canvas.
+    pub fn region_version(&self, region: Region) -> Option<u64> {
+        if !self.contains_region(region) {
+            return None;
+        }
+        let first = TileId::of_pixel(region.x, region.y);
+        let last = TileId::of_pixel(region.x + region.width - 1, region.y + region.height - 1);
+        let mut version = 0;
+        for ty in first.ty..=last.ty {
+            for tx in first.tx..=last.tx {
+                version = version.max(self.tiles[self.tile_index(TileId { tx, ty })].version());
+            }
+        }
+        Some(version)
+    }
+
+    /// Copy a region of the canvas into a new canvas.
+    ///
+    /// The new canvas starts at version 0, with no pending changes.
+    ///
+    /// Returns None if the region is not within the canvas.
+    pub fn crop(&self, region: Region) -> Option<Canvas> {
+        if !self.contains_region(region) {
+            return None;
+        }
+        let mut cropped = Canvas::new(region.width, region.height);
+        for y in 0..region.height {
+            for x in 0..region.width {
+                let color = self
+                    .get_pixel(region.x + x, region.y + y)
+                    .expect("region is within the canvas");
+                *cropped.pixel_mut(x, y) = color;
+            }
+        }
+        Some(cropped)
+    }
+
     /// Get an iterator over all pixels in the canvas.
     pub fn pixels<'a>(&'a self) -> CanvasPixelIter<'a> {
         CanvasPixelIter::new(self)
     }
+
+    fn write(&mut self, x: u16, y: u16, color: PixelColor, placement: Option<provenance::Cell>) {
+        self.version += 1;
+        let version = self.version;
+        let index = self.tile_index(TileId::of_pixel(x, y));
+        self.tiles[index].write(x % TILE_SIZE, y % TILE_SIZE, color, version, placement);
+    }
+
+    fn tile_index(&self, id: TileId) -> usize {
+        (id.ty as usize) * (self.tiles_x as usize) + (id.tx as usize)
+    }
+}
+
+impl PartialEq for Canvas {
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.tiles == other.tiles
+            && self.labels == other.labels
+    }
 }
 
+impl Eq for Canvas {}
+
 impl<'a> IntoIterator for &'a Canvas {
     type Item = Pixel;
     type IntoIter = CanvasPixelIter<'a>;
@@ -244,4 +622,116 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_canvas_tiles_layout() {
+        let canvas = Canvas::new(600, 256);
+        assert_eq!(canvas.tiles_x(), 3);
+        assert_eq!(canvas.tiles_y(), 1);
+        assert_eq!(canvas.tiles().len(), 3);
+
+        let edge = canvas.tile(TileId { tx: 2, ty: 0 }).unwrap();
+        assert_eq!((edge.width(), edge.height()), (600 - 512, 256));
+        assert!(canvas.tile(TileId { tx: 0, ty: 1 }).is_none());
+        assert_eq!(TileId::of_pixel(599, 255), TileId { tx: 2, ty: 0 });
+        assert_eq!(TileId { tx: 2, ty: 0 }.origin(), (512, 0));
+    }
+
+    #[test]
+    fn test_canvas_tiles_dirty_and_versions() {
+        let mut canvas = Canvas::new(512, 512);
+        let red = PixelColor { r: 255, g: 0, b: 0 };
+        assert_eq!(canvas.version(), 0);
+        assert_eq!(canvas.dirty_tiles().count(), 0);
+
+        canvas.set_pixel(300, 10, red).unwrap();
+        canvas.set_pixel(10, 300, red).unwrap();
+        assert_eq!(canvas.version(), 2);
+        assert_eq!(
+            canvas.dirty_tiles().collect::<Vec<_>>(),
+            vec![TileId { tx: 1, ty: 0 }, TileId { tx: 0, ty: 1 }]
+        );
+        assert_eq!(canvas.tile(TileId { tx: 1, ty: 0 }).unwrap().version(), 1);
+        assert_eq!(canvas.tile(TileId { tx: 0, ty: 1 }).unwrap().version(), 2);
+        assert_eq!(canvas.tile(TileId { tx: 0, ty: 0 }).unwrap().version(), 0);
+
+        canvas.take_diff();
+        assert_eq!(canvas.dirty_tiles().count(), 0);
+        canvas.set_pixel(301, 11, red).unwrap();
+        assert_eq!(
+            canvas.dirty_tiles().collect::<Vec<_>>(),
+            vec![TileId { tx: 1, ty: 0 }]
+        );
+        assert_eq!(canvas.tile(TileId { tx: 1, ty: 0 }).unwrap().version(), 3);
+
+        // Out of bounds writes do not bump the version
+        assert!(canvas.set_pixel(512, 0, red).is_err());
+        assert_eq!(canvas.version(), 3);
+    }
+
+    #[test]
+    fn test_canvas_crop_and_region_version() {
+        let red = PixelColor { r: 255, g: 0, b: 0 };
+        let mut canvas = Canvas::new(600, 300);
+        canvas.set_pixel(300, 10, red).unwrap();
+        canvas.set_pixel(599, 299, red).unwrap();
+
+        let region = Region {
+            x: 299,
+            y: 9,
+            width: 301,
+            height: 291,
+        };
+        let cropped = canvas.crop(region).unwrap();
+        assert_eq!((cropped.width(), cropped.height()), (301, 291));
+        assert_eq!(cropped.get_pixel(1, 1), Some(red));
+        assert_eq!(cropped.get_pixel(300, 290), Some(red));
+        assert_eq!(cropped.get_pixel(0, 0), Some(colors::WHITE));
+        assert_eq!(cropped.version(), 0);
+        assert_eq!(cropped.dirty_tiles().count(), 0);
+
+        // Only the tiles overlapping the region are considered
+        let top_left = Region {
+            x: 0,
+            y: 0,
+            width: 256,
+            height: 256,
+        };
+        assert_eq!(canvas.region_version(top_left), Some(0));
+        assert_eq!(canvas.region_version(region), Some(2));
+        assert_eq!(
+            canvas.region_version(canvas.bounds()),
+            Some(canvas.version())
+        );
+
+        // Regions must be non-empty and within the canvas
+        let outside = Region {
+            x: 599,
+            y: 0,
+            width: 2,
+            height: 1,
+        };
+        assert!(canvas.crop(outside).is_none());
+        assert!(canvas.region_version(outside).is_none());
+        assert!(
+            canvas
+                .crop(Region {
+                    width: 0,
+                    ..top_left
+                })
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_canvas_equality_ignores_versions() {
+        let red = PixelColor { r: 255, g: 0, b: 0 };
+        let mut a = Canvas::new(300, 300);
+        let mut b = Canvas::new(300, 300);
+        a.set_pixel(1, 1, red).unwrap();
+        a.set_pixel(1, 1, red).unwrap();
+        b.set_pixel(1, 1, red).unwrap();
+        assert_ne!(a.version(), b.version());
+        assert_eq!(a, b);
+    }
 }
@@ -0,0 +1,373 @@
+//! Provenance: who placed each pixel (and label) of the canvas, and when.
+//!
+//! The sources are interned in a table shared by the whole canvas, and every tile keeps
+//! the placement of its pixels in a compact array (8 bytes per pixel), only allocated
+//! once a pixel of the tile is placed with a known origin.
+//!
+//! Ping sources are easily spoofed, so only their [prefix](SOURCE_PREFIX_LEN) is interned, and
+//! the sources no longer referenced by any pixel or label are regularly dropped from the table:
+//! it never holds much more than twice the sources still on the canvas.
+//!
+//! # Encoding
+//!
+//! The provenance of a canvas is persisted along with its [snapshots](crate::persistence::snapshot):
+//!
+//! - `sources: u32`, followed by the sources: `0` and the 16 bytes of the IPv6 address for a
+//!   ping, `1` for an image import, `2` for another operator action.
+//! - `tiles: u32`, followed by the tiles with placements: `tx: u16, ty: u16`, then one cell per
+//!   pixel of the tile in row-major order, `source: u32` (index in the table plus one, `0` if
+//!   unknown) and `time: u32` (seconds since the Unix epoch).
+//! - `labels: u32`, followed by one cell per label of the canvas, from the bottom one to the
+//!   top one. This section is missing from the provenance written before labels existed.
+//!
+//! All integers are big-endian.
+
+use std::{collections::HashMap, net::Ipv6Addr};
+
+use crate::{
+    canvas::{Canvas, tile::TileId},
+    events::{EventSource, mask_address},
+};
+
+/// Number of bits of the addresses of the ping sources kept by the canvas.
+///
+/// It is the usual size of the network of a single player, finer prefixes are not disclosed.
+pub const SOURCE_PREFIX_LEN: u8 = 64;
+
+// Size the table of the sources may reach before the unreferenced sources are dropped
+const MIN_COLLECTED_SOURCES: usize = 1024;
+
+/// Who placed a pixel, and when.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Placement {
+    /// Origin of the event which placed the pixel.
+    pub source: EventSource,
+    /// Time the pixel was placed, in milliseconds since the Unix epoch.
+    ///
+    /// The canvas only keeps it to the second.
+    pub timestamp: u64,
+}
+
+/// Placement of a pixel, as stored in the tiles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Cell {
+    // Index of the source in the table plus one, 0 if unknown
+    source: u32,
+    // Seconds since the Unix epoch
+    time: u32,
+}
+
+/// Table of the sources which placed pixels on the canvas.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Sources {
+    sources: Vec<EventSource>,
+    index: HashMap<EventSource, u32>,
+    // Number of sources still referenced at the last collection
+    referenced: usize,
+}
+
+impl Sources {
+    /// Get the cell recording the given placement, adding its source to the table if needed.
+    ///
+    /// The address of a ping source is truncated to its first [SOURCE_PREFIX_LEN] bits.
+    pub(crate) fn cell(&mut self, placement: &Placement) -> Cell {
+        let placed_by = match placement.source {
+            EventSource::Ping(address) => {
+                EventSource::Ping(mask_address(address, SOURCE_PREFIX_LEN))
+            }
+            source => source,
+        };
+        let next = self.sources.len() as u32 + 1;
+        let source = *self.index.entry(placed_by).or_insert_with(|| {
+            self.sources.push(placed_by);
+            next
+        });
+        Cell {
+            source,
+            time: (placement.timestamp / 1000).min(u32::MAX as u64) as u32,
+        }
+    }
+
+    /// Get the placement recorded in a cell, None if it is unknown.
+    pub(crate) fn placement(&self, cell: Cell) -> Option<Placement> {
+        let source = *self.sources.get(cell.source.checked_sub(1)? as usize)?;
+        Some(Placement {
+            source,
+            timestamp: cell.time as u64 * 1000,
+        })
+    }
+}
+
+impl Canvas {
+    /// Drop the sources no longer referenced by any pixel or label, once the table has doubled
+    /// since the last collection.
+    ///
+    /// Called before placing a pixel or a label, so the cost of a collection (a scan of the
+    /// placements) is spread over the placements which grew the table.
+    pub(crate) fn collect_sources(&mut self) {
+        let sources = &self.sources;
+        if sources.sources.len() < MIN_COLLECTED_SOURCES.max(2 * sources.referenced) {
+            return;
+        }
+
+        // New index of each source plus one, 0 if unreferenced
+        let mut remap = vec![0u32; sources.sources.len() + 1];
+        for tile in self.tiles.iter() {
+            for cell in tile.placements().into_iter().flatten() {
+                remap[cell.source as usize] = 1;
+            }
+        }
+        for (_, cell) in &self.labels.labels {
+            remap[cell.source as usize] = 1;
+        }
+        let mut collected = Sources::default();
+        for (i, source) in sources.sources.iter().enumerate() {
+            if remap[i + 1] != 0 {
+                collected.sources.push(*source);
+                remap[i + 1] = collected.sources.len() as u32;
+                collected.index.insert(*source, remap[i + 1]);
+            }
+        }
+        collected.referenced = collected.sources.len();
+
+        for tile in self.tiles.iter_mut() {
+            for cell in tile.placements_if_any_mut().into_iter().flatten() {
+                cell.source = remap[cell.source as usize];
+            }
+        }
+        for (_, cell) in self.labels.labels.iter_mut() {
+            cell.source = remap[cell.source as usize];
+        }
+        self.sources = collected;
+    }
+}
+
+/// Encode the provenance of the canvas.
+pub(crate) fn encode(canvas: &Canvas, buf: &mut Vec<u8>) {
+    let sources = &canvas.sources.sources;
+    buf.extend_from_slice(&(sources.len() as u32).to_be_bytes());
+    for source in sources {
+        match source {
+            EventSource::Ping(address) => {
+                buf.push(0);
+                buf.extend_from_slice(&address.octets());
+            }
+            EventSource::Import => buf.push(1),
+            EventSource::Admin => buf.push(2),
+        }
+    }
+
+    let tiles: Vec<_> = canvas
+        .tiles()
+        .filter_map(|(id, tile)| Some((id, tile.placements()?)))
+        .collect();
+    buf.extend_from_slice(&(tiles.len() as u32).to_be_bytes());
+    for (id, cells) in tiles {
+        buf.extend_from_slice(&id.tx.to_be_bytes());
+        buf.extend_from_slice(&id.ty.to_be_bytes());
+        for cell in cells {
+            buf.extend_from_slice(&cell.source.to_be_bytes());
+            buf.extend_from_slice(&cell.time.to_be_bytes());
+        }
+    }
+
+    let labels = &canvas.labels.labels;
+    buf.extend_from_slice(&(labels.len() as u32).to_be_bytes());
+    for (_, cell) in labels {
+        buf.extend_from_slice(&cell.source.to_be_bytes());
+        buf.extend_from_slice(&cell.time.to_be_bytes());
+    }
+}
+
+/// Decode the provenance of the canvas, replacing the current one.
+///
+/// Returns None if the encoded provenance is invalid or does not match the canvas,
+/// in which case the canvas may be left with part of it.
+pub(crate) fn decode(canvas: &mut Canvas, bytes: &[u8]) -> Option<()> {
+    let mut bytes = bytes;
+    let mut take = |n: usize| -> Option<&[u8]> {
+        if bytes.len() < n {
+            return None;
+        }
+        let (head, tail) = bytes.split_at(n);
+        bytes = tail;
+        Some(head)
+    };
+    let u16_at = |b: &[u8], i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+    let u32_at = |b: &[u8], i: usize| u32::from_be_bytes(b[i..i + 4].try_into().expect("4 bytes"));
+
+    let mut sources = Sources::default();
+    let count = u32_at(take(4)?, 0);
+    for _ in 0..count {
+        let source = match take(1)?[0] {
+            0 => {
+                let octets: [u8; 16] = take(16)?.try_into().ok()?;
+                EventSource::Ping(Ipv6Addr::from(octets))
+            }
+            1 => EventSource::Import,
+            2 => EventSource::Admin,
+            _ => return None,
+        };
+        sources.sources.push(source);
+        sources.index.insert(source, sources.sources.len() as u32);
+    }
+
+    let count = u32_at(take(4)?, 0);
+    for _ in 0..count {
+        let b = take(4)?;
+        let id = TileId {
+            tx: u16_at(b, 0),
+            ty: u16_at(b, 2),
+        };
+        canvas.tile(id)?;
+        let index = canvas.tile_index(id);
+        let cells = canvas.tiles[index].placements_mut();
+        let b = take(cells.len() * 8)?;
+        for (cell, b) in cells.iter_mut().zip(b.chunks_exact(8)) {
+            *cell = Cell {
+                source: u32_at(b, 0),
+                time: u32_at(b, 4),
+            };
+            if cell.source as usize > sources.sources.len() {
+                return None;
+            }
+        }
+    }
+
+    // Missing if there are no bytes left
+    if let Some(b) = take(4) {
+        let count = u32_at(b, 0) as usize;
+        if count != canvas.labels.labels.len() {
+            return None;
+        }
+        let b = take(count * 8)?;
+        for ((_, cell), b) in canvas.labels.labels.iter_mut().zip(b.chunks_exact(8)) {
+            *cell = Cell {
+                source: u32_at(b, 0),
+                time: u32_at(b, 4),
+            };
+            if cell.source as usize > sources.sources.len() {
+                return None;
+            }
+        }
+    }
+    if !bytes.is_empty() {
+        return None;
+    }
+    sources.referenced = sources.sources.len();
+    canvas.sources = sources;
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{colors, label::Label};
+
+    #[test]
+    fn provenance_roundtrip() {
+        let alice = Placement {
+            source: EventSource::Ping("2001:db8::1".parse().unwrap()),
+            timestamp: 1_700_000_000_123,
+        };
+        let import = Placement {
+            source: EventSource::Import,
+            timestamp: 1_700_000_001_000,
+        };
+        let mut canvas = Canvas::new(300, 300);
+        canvas.place_pixel(1, 2, colors::RED, alice).unwrap();
+        canvas.place_pixel(280, 290, colors::BLUE, import).unwrap();
+        canvas.place_pixel(3, 4, colors::RED, alice).unwrap();
+        canvas.set_pixel(3, 4, colors::GREEN).unwrap();
+        canvas
+            .place_label(Label::new(5, 5, "hi").unwrap(), import)
+            .unwrap();
+
+        assert_eq!(
+            canvas.placement(1, 2),
+            Some(Placement {
+                source: EventSource::Ping("2001:db8::".parse().unwrap()),
+                timestamp: 1_700_000_000_000,
+            }),
+            "Placements are kept to the second, and to the prefix of their source"
+        );
+        assert_eq!(
+            canvas.placement(280, 290).unwrap().source,
+            EventSource::Import
+        );
+        assert_eq!(canvas.placement(3, 4), None, "Unknown origin");
+        assert_eq!(canvas.placement(0, 0), None);
+        assert_eq!(canvas.placement(300, 0), None);
+
+        let mut buf = Vec::new();
+        encode(&canvas, &mut buf);
+        // 2 sources, 2 tiles of 256x256 and 44x44 pixels, 1 label
+        assert_eq!(
+            buf.len(),
+            4 + 17 + 1 + 4 + (4 + 256 * 256 * 8) + (4 + 44 * 44 * 8) + 4 + 8
+        );
+
+        let mut restored = Canvas::new(300, 300);
+        restored.set_label(Label::new(5, 5, "hi").unwrap()).unwrap();
+        decode(&mut restored, &buf).unwrap();
+        for (x, y) in [(1, 2), (280, 290), (3, 4), (0, 0)] {
+            assert_eq!(restored.placement(x, y), canvas.placement(x, y));
+        }
+        assert_eq!(
+            restored.label_placement(5, 5),
+            Some(Placement {
+                timestamp: 1_700_000_001_000,
+                ..import
+            })
+        );
+
+        // The tiles and labels must match the canvas
+        assert!(decode(&mut Canvas::new(256, 256), &buf).is_none());
+        assert!(decode(&mut Canvas::new(300, 300), &buf).is_none());
+        assert!(decode(&mut Canvas::new(300, 300), &buf[..buf.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn unreferenced_sources_are_collected() {
+        let mut canvas = Canvas::new(16, 16);
+        let import = Placement {
+            source: EventSource::Import,
+            timestamp: 1_700_000_000_000,
+        };
+        canvas.place_pixel(1, 1, colors::RED, import).unwrap();
+        for i in 0..10_000u128 {
+            // Spoofed sources, all in distinct networks
+            let address = (0x2001_0db8u128 << 96) | (i << 64) | i;
+            let placement = Placement {
+                source: EventSource::Ping(address.into()),
+                timestamp: 1_700_000_000_000 + i as u64,
+            };
+            canvas.place_pixel(0, 0, colors::RED, placement).unwrap();
+            assert!(canvas.sources.sources.len() <= MIN_COLLECTED_SOURCES);
+        }
+        assert_eq!(canvas.placement(1, 1), Some(import));
+        let last: Ipv6Addr = ((0x2001_0db8u128 << 96) | (9_999 << 64)).into();
+        assert_eq!(
+            canvas.placement(0, 0).unwrap().source,
+            EventSource::Ping(last)
+        );
+
+        // Sources of the same network share their entry
+        let mut canvas = Canvas::new(16, 16);
+        for i in 0..10 {
+            let address: Ipv6Addr = format!("2001:db8::{}", i + 1).parse().unwrap();
+            canvas
+                .place_pixel(
+                    i,
+                    0,
+                    colors::RED,
+                    Placement {
+                        source: EventSource::Ping(address),
+                        ..import
+                    },
+                )
+                .unwrap();
+        }
+        assert_eq!(canvas.sources.sources.len(), 1);
+    }
+}
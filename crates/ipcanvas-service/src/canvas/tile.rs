@@ -0,0 +1,232 @@
+//! Tiles: fixed-size square blocks the canvas is organised into.
+
+use crate::canvas::{Pixel, PixelColor, colors, provenance::Cell};
+
+/// Size (width and height) of a tile, in pixels.
+///
+/// Tiles on the right and bottom edges of the canvas are smaller when the canvas
+/// dimensions are not a multiple of this size.
+pub const TILE_SIZE: u16 = 256;
+
+/// Position of a tile in the grid of tiles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TileId {
+    pub tx: u16,
+    pub ty: u16,
+}
+
+impl TileId {
+    /// Get the tile containing the pixel at the given coordinates.
+    pub fn of_pixel(x: u16, y: u16) -> Self {
+        Self {
+            tx: x / TILE_SIZE,
+            ty: y / TILE_SIZE,
+        }
+    }
+
+    /// Get the coordinates of the top-left pixel of the tile.
+    pub fn origin(&self) -> (u16, u16) {
+        (self.tx * TILE_SIZE, self.ty * TILE_SIZE)
+    }
+}
+
+/// A block of pixels of the canvas, with its own version and dirty flag.
+///
+/// The tile also tracks the pixels written since its changes were last drained,
+/// so a diff can be produced without scanning (or keeping a copy of) the whole tile.
+#[derive(Clone, Debug)]
+pub struct Tile {
+    width: u16,
+    height: u16,
+    // Cell (x, y), relative to the tile origin, is at index (y * width + x)
+    data: Box<[PixelColor]>,
+    version: u64,
+    dirty: bool,
+    // Bitmap of the cells present in `changes`
+    recorded: Box<[u64]>,
+    // Cells written since the last drain, with their color before the first write
+    changes: Vec<(u16, PixelColor)>,
+    // Placement of each cell, allocated on the first write with a known origin
+    placements: Option<Box<[Cell]>>,
+}
+
+impl Tile {
+    /// Create a new white tile with the given dimensions.
+    pub(crate) fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![colors::WHITE; (width as usize) * (height as usize)].into_boxed_slice(),
+            version: 0,
+            dirty: false,
+            recorded: vec![0; ((width as usize) * (height as usize)).div_ceil(64)]
+                .into_boxed_slice(),
+            changes: Vec::new(),
+            placements: None,
+        }
+    }
+
+    /// Create a new tile with the given dimensions, filled with `color`.
+    pub(crate) fn filled(width: u16, height: u16, color: PixelColor) -> Self {
+        let mut tile = Self::new(width, height);
+        tile.data.fill(color);
+        tile
+    }
+
+    /// Get a copy of the tile grown to the given dimensions (at least its own),
+    /// the new cells being filled with `background`.
+    ///
+    /// Pixels, placements, pending changes, the version and the dirty flag are kept.
+    pub(crate) fn expanded(&self, width: u16, height: u16, background: PixelColor) -> Self {
+        let mut expanded = Self::filled(width, height, background);
+        let old_width = self.width as usize;
+        let new_index = |index: usize| (index / old_width) * width as usize + index % old_width;
+        for (y, row) in self.data.chunks_exact(old_width).enumerate() {
+            let start = y * width as usize;
+            expanded.data[start..start + old_width].copy_from_slice(row);
+        }
+        if let Some(placements) = &self.placements {
+            let cells = expanded.placements_mut();
+            for (y, row) in placements.chunks_exact(old_width).enumerate() {
+                let start = y * width as usize;
+                cells[start..start + old_width].copy_from_slice(row);
+            }
+        }
+        for &(index, original) in &self.changes {
+            let index = new_index(index as usize);
+            expanded.recorded[index / 64] |= 1 << (index % 64);
+            expanded.changes.push((index as u16, original));
+        }
+        expanded.version = self.version;
+        expanded.dirty = self.dirty;
+        expanded
+    }
+
+    /// Get the width of the tile.
+    pub fn width(&self) -> u16 {
+        self.width
+    }
+
+    /// Get the height of the tile.
+    pub fn height(&self) -> u16 {
+        self.height
+    }
+
+    /// Get the version of the tile.
+    ///
+    /// The version is the canvas version at the time of the last write to this tile,
+    /// so it only ever increases.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Check if the tile has been written to since its changes were last drained.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Get the pixel color at the given coordinates, relative to the tile origin.
+    pub fn get_pixel(&self, x: u16, y: u16) -> Option<PixelColor> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        Some(self.data[(y as usize) * (self.width as usize) + (x as usize)])
+    }
+
+    /// Get a mutable reference to the pixel at the given (in-bounds) relative coordinates,
+    /// without touching the version or the dirty flag.
+    pub(crate) fn pixel_mut(&mut self, x: u16, y: u16) -> &mut PixelColor {
+        &mut self.data[(y as usize) * (self.width as usize) + (x as usize)]
+    }
+
+    /// Write a pixel at the given (in-bounds) relative coordinates, at the given canvas version,
+    /// along with its placement (None if its origin is unknown).
+    ///
+    /// Only the color before the first write since the last drain is recorded,
+    /// so repeated writes to the same pixel collapse into a single change.
+    pub(crate) fn write(
+        &mut self,
+        x: u16,
+        y: u16,
+        color: PixelColor,
+        version: u64,
+        placement: Option<Cell>,
+    ) {
+        let index = (y as usize) * (self.width as usize) + (x as usize);
+        let (word, bit) = (index / 64, index % 64);
+        if self.recorded[word] & (1 << bit) == 0 {
+            self.recorded[word] |= 1 << bit;
+            self.changes.push((index as u16, self.data[index]));
+        }
+        self.data[index] = color;
+        self.version = version;
+        self.dirty = true;
+        match placement {
+            Some(cell) => self.placements_mut()[index] = cell,
+            None => {
+                if let Some(placements) = self.placements.as_mut() {
+                    placements[index] = Cell::default();
+                }
+            }
+        }
+    }
+
+    /// Get the placement of the pixel at the given (in-bounds) relative coordinates.
+    pub(crate) fn placement(&self, x: u16, y: u16) -> Cell {
+        let index = (y as usize) * (self.width as usize) + (x as usize);
+        self.placements
+            .as_ref()
+            .map_or(Cell::default(), |placements| placements[index])
+    }
+
+    /// Get the placements of the cells, None if no pixel has a known origin.
+    pub(crate) fn placements(&self) -> Option<&[Cell]> {
+        self.placements.as_deref()
+    }
+
+    /// Get a mutable reference to the placements of the cells, None if no pixel has a known
+    /// origin.
+    pub(crate) fn placements_if_any_mut(&mut self) -> Option<&mut [Cell]> {
+        self.placements.as_deref_mut()
+    }
+
+    /// Get a mutable reference to the placements of the cells, allocating them if needed.
+    pub(crate) fn placements_mut(&mut self) -> &mut [Cell] {
+        let len = (self.width as usize) * (self.height as usize);
+        self.placements
+            .get_or_insert_with(|| vec![Cell::default(); len].into_boxed_slice())
+    }
+
+    /// Override the version, e.g. when restoring a canvas.
+    pub(crate) fn set_version(&mut self, version: u64) {
+        self.version = version;
+    }
+
+    /// Drain the pending changes, pushing the pixels whose color actually changed to `out`.
+    ///
+    /// `origin` is the position of the tile on the canvas. Clears the dirty flag.
+    pub(crate) fn drain_changes(&mut self, origin: (u16, u16), out: &mut Vec<Pixel>) {
+        for (index, original) in self.changes.drain(..) {
+            let index = index as usize;
+            self.recorded[index / 64] &= !(1 << (index % 64));
+            let color = self.data[index];
+            if color != original {
+                out.push(Pixel {
+                    x: origin.0 + (index % self.width as usize) as u16,
+                    y: origin.1 + (index / self.width as usize) as u16,
+                    color,
+                });
+            }
+        }
+        self.dirty = false;
+    }
+}
+
+impl PartialEq for Tile {
+    /// Tiles are equal if they hold the same pixels, whatever their versions.
+    fn eq(&self, other: &Self) -> bool {
+        self.width == other.width && self.height == other.height && self.data == other.data
+    }
+}
+
+impl Eq for Tile {}
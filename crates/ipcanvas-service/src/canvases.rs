@@ -0,0 +1,331 @@
+//! Canvases: several independent canvases served by a single instance.
+//!
+//! The canvases are read from a TOML file, each with its own id, destination prefix,
+//! dimensions, rules and persistence:
+//!
+//! ```toml
+//! [[canvas]]
+//! id = "main"
+//! prefix = "2001:db8::/48"
+//! width = 4096
+//! height = 4096
+//!
+//! [[canvas]]
+//! id = "sandbox"
+//! prefix = "2001:db8:1::/48"
+//! width = 512
+//! height = 512
+//! cooldown = 0                           # seconds
+//! rules = "sandbox-rules.toml"
+//! ```
+//!
+//! A ping goes to the canvas with the longest prefix matching its destination (see
+//! [PingRoutes]), or to the canvas without prefix if none matches. As the pixel is encoded
+//! after the first 48 bits of the destination, prefixes are at most 48 bits long.
+//!
+//! The first canvas is the default one. The other fields are optional: the dimensions and
+//! cooldown default to the ones of the command line, the data directory of a canvas is the
+//! directory named after its id within the data directory of the command line (if any), and
+//! relative paths are relative to the directory of the file.
+
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+use ipcanvas_ping_common::Ipv6Prefix;
+use serde::Deserialize;
+
+use crate::{events::mask_address, ping::PingRoutes};
+
+/// Longest prefix of a canvas, the following bits of the destination encode the pixel.
+pub const MAX_PREFIX_LEN: u8 = 48;
+
+/// Settings of a canvas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CanvasConfig {
+    /// Identifier of the canvas, used in the paths of the HTTP and admin services
+    pub id: String,
+    /// Destination prefix of the pings placing pixels on the canvas,
+    /// None to receive the pings matching no other canvas
+    pub prefix: Option<Ipv6Prefix>,
+    pub width: u16,
+    pub height: u16,
+    /// Directory where the canvas state is persisted, None to only keep it in memory
+    pub data_dir: Option<PathBuf>,
+    /// TOML file of the region rules of the canvas
+    pub rules: Option<PathBuf>,
+    /// PNG image painted on the canvas when it is created
+    pub init_image: Option<PathBuf>,
+    /// Minimum time between two pixels placed by the same player, in seconds
+    pub cooldown: u64,
+    /// Length of the prefix of the sender addresses identifying a player, in bits
+    pub cooldown_prefix_len: u8,
+}
+
+/// Error while loading the canvases.
+#[derive(Debug)]
+pub enum CanvasesError {
+    /// The canvases file could not be read.
+    Io(io::Error),
+    /// The canvases file is not valid TOML, or has unknown fields.
+    Parse(toml::de::Error),
+    /// The file has no canvas.
+    Empty,
+    /// A canvas has invalid settings.
+    Invalid { canvas: String, reason: String },
+}
+
+/// On-disk format of the canvases file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CanvasesFile {
+    #[serde(default)]
+    canvas: Vec<CanvasEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CanvasEntry {
+    id: String,
+    prefix: Option<String>,
+    width: Option<u16>,
+    height: Option<u16>,
+    data_dir: Option<PathBuf>,
+    rules: Option<PathBuf>,
+    init_image: Option<PathBuf>,
+    cooldown: Option<u64>,
+    cooldown_prefix_len: Option<u8>,
+}
+
+impl Display for CanvasesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CanvasesError::Io(e) => write!(f, "I/O error: {}", e),
+            CanvasesError::Parse(e) => write!(f, "Invalid canvases file: {}", e),
+            CanvasesError::Empty => write!(f, "No canvas defined"),
+            CanvasesError::Invalid { canvas, reason } => {
+                write!(f, "Invalid settings for canvas {:?}: {}", canvas, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CanvasesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CanvasesError::Io(e) => Some(e),
+            CanvasesError::Parse(e) => Some(e),
+            CanvasesError::Empty | CanvasesError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for CanvasesError {
+    fn from(e: io::Error) -> Self {
+        CanvasesError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for CanvasesError {
+    fn from(e: toml::de::Error) -> Self {
+        CanvasesError::Parse(e)
+    }
+}
+
+impl CanvasConfig {
+    /// Read the canvases from a TOML file.
+    ///
+    /// `defaults` gives the settings of the canvases missing from the file
+    /// (its id, prefix, rules and initial image are not used).
+    pub fn load(path: &Path, defaults: &CanvasConfig) -> Result<Vec<Self>, CanvasesError> {
+        let base = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&std::fs::read_to_string(path)?, base, defaults)
+    }
+
+    /// Parse the canvases from the content of a TOML file, whose relative paths are
+    /// relative to `base`.
+    pub fn parse(
+        content: &str,
+        base: &Path,
+        defaults: &CanvasConfig,
+    ) -> Result<Vec<Self>, CanvasesError> {
+        let file: CanvasesFile = toml::from_str(content)?;
+        if file.canvas.is_empty() {
+            return Err(CanvasesError::Empty);
+        }
+        let mut canvases: Vec<CanvasConfig> = Vec::with_capacity(file.canvas.len());
+        for entry in file.canvas {
+            let canvas = Self::from_entry(entry, base, defaults)?;
+            let invalid = |reason: &str| CanvasesError::Invalid {
+                canvas: canvas.id.clone(),
+                reason: reason.to_string(),
+            };
+            for other in &canvases {
+                if other.id == canvas.id {
+                    return Err(invalid("duplicate id"));
+                }
+                if other.prefix == canvas.prefix {
+                    return Err(invalid("another canvas has the same prefix"));
+                }
+                if canvas.data_dir.is_some() && other.data_dir == canvas.data_dir {
+                    return Err(invalid("another canvas has the same data directory"));
+                }
+            }
+            canvases.push(canvas);
+        }
+        Ok(canvases)
+    }
+
+    /// Get the routes of the pings to the canvases, by their index.
+    pub fn routes(canvases: &[CanvasConfig]) -> PingRoutes {
+        canvases
+            .iter()
+            .enumerate()
+            .fold(PingRoutes::new(), |routes, (index, canvas)| {
+                routes.with_route(canvas.prefix, index)
+            })
+    }
+
+    fn from_entry(
+        entry: CanvasEntry,
+        base: &Path,
+        defaults: &CanvasConfig,
+    ) -> Result<Self, CanvasesError> {
+        let invalid = |reason: String| CanvasesError::Invalid {
+            canvas: entry.id.clone(),
+            reason,
+        };
+        let valid_id = !entry.id.is_empty()
+            && entry
+                .id
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid_id {
+            return Err(invalid(
+                "the id must be made of letters, digits, '-' and '_'".to_string(),
+            ));
+        }
+        let prefix = match &entry.prefix {
+            Some(prefix) => {
+                let parsed = prefix
+                    .parse::<Ipv6Prefix>()
+                    .ok()
+                    .filter(|prefix| prefix.prefix_len <= MAX_PREFIX_LEN)
+                    .ok_or_else(|| {
+                        invalid(format!(
+                            "invalid prefix {:?} (at most /{})",
+                            prefix, MAX_PREFIX_LEN
+                        ))
+                    })?;
+                let address = mask_address(parsed.address.into(), parsed.prefix_len);
+                Some(Ipv6Prefix::from((address, parsed.prefix_len)))
+            }
+            None => None,
+        };
+        let width = entry.width.unwrap_or(defaults.width);
+        let height = entry.height.unwrap_or(defaults.height);
+        if width == 0 || height == 0 {
+            return Err(invalid("the canvas has no pixel".to_string()));
+        }
+        let cooldown_prefix_len = entry
+            .cooldown_prefix_len
+            .unwrap_or(defaults.cooldown_prefix_len);
+        if cooldown_prefix_len > 128 {
+            return Err(invalid(format!(
+                "invalid prefix length {}",
+                cooldown_prefix_len
+            )));
+        }
+        let data_dir = match entry.data_dir {
+            Some(dir) => Some(base.join(dir)),
+            None => defaults.data_dir.as_ref().map(|dir| dir.join(&entry.id)),
+        };
+        Ok(Self {
+            prefix,
+            width,
+            height,
+            data_dir,
+            rules: entry.rules.map(|path| base.join(path)),
+            init_image: entry.init_image.map(|path| base.join(path)),
+            cooldown: entry.cooldown.unwrap_or(defaults.cooldown),
+            cooldown_prefix_len,
+            id: entry.id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> CanvasConfig {
+        CanvasConfig {
+            id: "main".to_string(),
+            prefix: None,
+            width: 4096,
+            height: 4096,
+            data_dir: Some(PathBuf::from("/var/lib/ipcanvas")),
+            rules: None,
+            init_image: None,
+            cooldown: 30,
+            cooldown_prefix_len: 64,
+        }
+    }
+
+    #[test]
+    fn canvases_file() {
+        let content = r#"
+            [[canvas]]
+            id = "main"
+            prefix = "2001:db8::/48"
+
+            [[canvas]]
+            id = "class-1"
+            prefix = "2001:db8:1::1/48"
+            width = 256
+            height = 128
+            cooldown = 0
+            data_dir = "class"
+            rules = "rules.toml"
+        "#;
+        let canvases =
+            CanvasConfig::parse(content, Path::new("/etc/ipcanvas"), &defaults()).unwrap();
+        assert_eq!(canvases.len(), 2);
+        assert_eq!(
+            canvases[0].data_dir,
+            Some(PathBuf::from("/var/lib/ipcanvas/main"))
+        );
+        assert_eq!((canvases[0].width, canvases[0].cooldown), (4096, 30));
+
+        let class = &canvases[1];
+        assert_eq!(class.prefix, Some("2001:db8:1::/48".parse().unwrap()));
+        assert_eq!((class.width, class.height, class.cooldown), (256, 128, 0));
+        assert_eq!(class.data_dir, Some(PathBuf::from("/etc/ipcanvas/class")));
+        assert_eq!(class.rules, Some(PathBuf::from("/etc/ipcanvas/rules.toml")));
+
+        let routes = CanvasConfig::routes(&canvases);
+        assert_eq!(routes.route(&"2001:db8:1:2::".parse().unwrap()), Some(1));
+        assert_eq!(routes.route(&"2001:db9::".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn invalid_canvases() {
+        for content in [
+            "",
+            "[[canvas]]\nid = \"a b\"",
+            "[[canvas]]\nid = \"a\"\nprefix = \"2001:db8::/56\"",
+            "[[canvas]]\nid = \"a\"\nwidth = 0",
+            "[[canvas]]\nid = \"a\"\n[[canvas]]\nid = \"a\"\nprefix = \"2001:db8::/48\"",
+            "[[canvas]]\nid = \"a\"\n[[canvas]]\nid = \"b\"",
+            "[[canvas]]\nid = \"a\"\nsize = 3",
+        ] {
+            assert!(
+                CanvasConfig::parse(content, Path::new(""), &defaults()).is_err(),
+                "{}",
+                content
+            );
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Commands: requests sent to the canvas task by the other parts of the service.
+//!
+//! The canvas is owned by a single task, other tasks interact with it through
+//! [CanvasCommand]s and get their answer back on a oneshot channel.
+
+use std::path::PathBuf;
+
+use tokio::sync::oneshot;
+
+use crate::{
+    activity::ActivitySummary,
+    canvas::{
+        Canvas, PixelColor, Region, image::ImportImage, label::Label, provenance::Placement,
+        tile::TileId,
+    },
+    events::AdminAction,
+    heatmap::{HeatmapLayer, HeatmapWindow},
+    moderation::{RollbackFilter, RollbackPlan},
+    rules::RegionRules,
+};
+
+/// A request to the canvas task.
+#[derive(Debug)]
+pub enum CanvasCommand {
+    /// Get a copy of a region of the canvas (the whole canvas if `region` is None).
+    ///
+    /// The copy is skipped if the version of the region is still `unless_version`.
+    Crop {
+        region: Option<Region>,
+        unless_version: Option<u64>,
+        reply: oneshot::Sender<CropResponse>,
+    },
+    /// Paint an image on the canvas, with its top-left corner at (x, y).
+    ///
+    /// The pixels go through the same path as any other event
+    /// ([EventSource::Import](crate::events::EventSource::Import)): they are logged,
+    /// and part of the next diff.
+    Import {
+        image: ImportImage,
+        x: u16,
+        y: u16,
+        reply: oneshot::Sender<ImportResponse>,
+    },
+    /// Expand the canvas to the given dimensions, filling the added area with `background`,
+    /// as an [EventSource::Admin](crate::events::EventSource::Admin) event.
+    ///
+    /// The resize is sent to the clients right away, in a diff of its own.
+    Resize {
+        width: u16,
+        height: u16,
+        background: PixelColor,
+        reply: oneshot::Sender<ResizeResponse>,
+    },
+    /// Paint a region with a color, as [EventSource::Admin](crate::events::EventSource::Admin)
+    /// events.
+    Fill {
+        region: Region,
+        color: PixelColor,
+        reply: oneshot::Sender<FillResponse>,
+    },
+    /// Reject (or accept again) the ingested events, recorded as an
+    /// [Event::AdminMarker](crate::events::Event::AdminMarker) when the state changes.
+    ///
+    /// The answer is false if the canvas was already in the requested state.
+    SetFrozen {
+        frozen: bool,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Write a snapshot of the canvas now, recorded as an
+    /// [Event::AdminMarker](crate::events::Event::AdminMarker).
+    Snapshot {
+        reply: oneshot::Sender<SnapshotResponse>,
+    },
+    /// Record an operator action in the event log, as an
+    /// [Event::AdminMarker](crate::events::Event::AdminMarker).
+    Mark { action: AdminAction },
+    /// Get the color of the pixel at (x, y), and who placed it.
+    ///
+    /// The answer is None if the pixel is not within the canvas.
+    Pixel {
+        x: u16,
+        y: u16,
+        reply: oneshot::Sender<Option<PixelInfo>>,
+    },
+    /// Get a copy of the given tiles, with the labels anchored within them.
+    ///
+    /// The tiles which are not within the canvas are left out of the answer.
+    Tiles {
+        tiles: Vec<TileId>,
+        reply: oneshot::Sender<Vec<TileSnapshot>>,
+    },
+    /// Get the labels anchored within a region (the whole canvas if `region` is None),
+    /// from the bottom one to the top one.
+    Labels {
+        region: Option<Region>,
+        reply: oneshot::Sender<Vec<LabelInfo>>,
+    },
+    /// Place a label as an [EventSource::Admin](crate::events::EventSource::Admin) event,
+    /// an empty label removes the one at its anchor.
+    ///
+    /// The answer is false if the anchor is not within the canvas.
+    PlaceLabel {
+        label: Label,
+        reply: oneshot::Sender<bool>,
+    },
+    /// Rasterise all the labels into the pixels with the given color, and remove them.
+    ///
+    /// Both the pixels and the removals are [EventSource::Admin](crate::events::EventSource::Admin)
+    /// events.
+    RasteriseLabels {
+        color: PixelColor,
+        reply: oneshot::Sender<RasteriseResponse>,
+    },
+    /// Get the counters of the canvas task.
+    Stats { reply: oneshot::Sender<CanvasStats> },
+    /// Get the activity of the players over the hours overlapping `[from, to)`
+    /// (in milliseconds since the Unix epoch, unbounded if None).
+    Activity {
+        from: Option<u64>,
+        to: Option<u64>,
+        reply: oneshot::Sender<ActivitySummary>,
+    },
+    /// Get the placement counts of the cells of the canvas over a window.
+    Heatmap {
+        window: HeatmapWindow,
+        reply: oneshot::Sender<HeatmapLayer>,
+    },
+    /// Make all the applied events durable in the event log, before reading it.
+    ///
+    /// The answer is false if the canvas is not persisted.
+    FlushLog { reply: oneshot::Sender<bool> },
+    /// Revert the pixels of a rollback plan, as [EventSource::Admin](crate::events::EventSource::Admin)
+    /// events.
+    ///
+    /// Pixels whose placement is no longer the expected one are skipped. The filter the plan
+    /// was made from is reported to the webhooks.
+    Rollback {
+        filter: RollbackFilter,
+        plan: RollbackPlan,
+        reply: oneshot::Sender<RollbackResponse>,
+    },
+    /// Replace the region rules (e.g. after the rules file changed).
+    ///
+    /// The region cooldowns start over.
+    SetRules { rules: RegionRules },
+}
+
+/// Answer to a [CanvasCommand::Crop].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CropResponse {
+    /// The region is not within the canvas, which has the given dimensions.
+    OutOfBounds { width: u16, height: u16 },
+    /// The region has not changed since the given version.
+    NotModified { version: u64 },
+    /// Copy of the region, at the given version.
+    Cropped { version: u64, canvas: Canvas },
+}
+
+/// Answer to a [CanvasCommand::Resize].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResizeResponse {
+    /// The canvas has the requested dimensions.
+    Resized,
+    /// The canvas would shrink, it has the given dimensions.
+    TooSmall { width: u16, height: u16 },
+}
+
+/// Answer to a [CanvasCommand::Fill].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FillResponse {
+    /// The region has been painted, with the given number of pixels.
+    Filled { pixels: u64 },
+    /// The region is not within the canvas, which has the given dimensions.
+    OutOfBounds { width: u16, height: u16 },
+}
+
+/// Answer to a [CanvasCommand::Snapshot].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotResponse {
+    /// The snapshot of the given sequence number has been written to the given file.
+    Written { sequence: u64, path: PathBuf },
+    /// The canvas is not persisted.
+    Disabled,
+    /// Another snapshot is being written.
+    Busy,
+    /// The snapshot could not be written (the reason is logged).
+    Failed,
+}
+
+/// Answer to a [CanvasCommand::Import].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImportResponse {
+    /// Number of pixels painted
+    pub applied: u64,
+    /// Number of pixels which could not be painted (e.g. outside of the canvas)
+    pub rejected: u64,
+}
+
+/// Answer to a [CanvasCommand::Pixel].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelInfo {
+    /// Current color of the pixel
+    pub color: PixelColor,
+    /// Who placed the pixel and when, None if it is unknown (e.g. never placed)
+    pub placement: Option<Placement>,
+}
+
+/// A label, and who placed it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LabelInfo {
+    pub label: Label,
+    /// Who placed the label and when, None if it is unknown
+    pub placement: Option<Placement>,
+}
+
+/// A copy of a tile, part of the answer to a [CanvasCommand::Tiles].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileSnapshot {
+    pub tile: TileId,
+    /// Region of the canvas covered by the tile
+    pub region: Region,
+    /// Pixels of the tile, at its version
+    pub canvas: Canvas,
+    pub version: u64,
+    /// Labels anchored within the tile, from the bottom one to the top one
+    pub labels: Vec<Label>,
+}
+
+/// Answer to a [CanvasCommand::RasteriseLabels].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RasteriseResponse {
+    /// Number of labels rasterised
+    pub labels: u64,
+    /// Number of pixels painted (pixels outside of the canvas are skipped)
+    pub pixels: u64,
+}
+
+/// Answer to a [CanvasCommand::Rollback].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RollbackResponse {
+    /// Number of pixels reverted
+    pub reverted: u64,
+    /// Number of pixels placed again since the plan was computed
+    pub skipped: u64,
+}
+
+/// Answer to a [CanvasCommand::Stats].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CanvasStats {
+    /// Number of events applied to the canvas since its creation
+    pub applied: u64,
+    /// Number of ingested events rejected since the service started, by reason
+    pub rejected: RejectedEvents,
+    /// Number of players in the cooldown table
+    pub cooling_down: usize,
+    /// Whether the ingested events are rejected (see [CanvasCommand::SetFrozen])
+    pub frozen: bool,
+}
+
+/// Number of ingested events rejected, by reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RejectedEvents {
+    /// The event targets coordinates outside of the canvas
+    pub out_of_bounds: u64,
+    /// The player of the event is still cooling down
+    pub cooldown: u64,
+    /// The event is not supported yet
+    pub unsupported: u64,
+    /// The pixel is within a read-only region
+    pub read_only: u64,
+    /// The player of the event is still cooling down in the region of the pixel
+    pub region_cooldown: u64,
+    /// The color is not part of the palette of the region of the pixel
+    pub palette: u64,
+    /// The source is not allowed in the region of the pixel
+    pub allowed_prefixes: u64,
+    /// The canvas is frozen
+    pub frozen: u64,
+}
+
+impl RejectedEvents {
+    /// Get the counters, along with the name of their reason.
+    pub fn by_reason(&self) -> [(&'static str, u64); 8] {
+        [
+            ("out_of_bounds", self.out_of_bounds),
+            ("cooldown", self.cooldown),
+            ("unsupported", self.unsupported),
+            ("read_only", self.read_only),
+            ("region_cooldown", self.region_cooldown),
+            ("palette", self.palette),
+            ("allowed_prefixes", self.allowed_prefixes),
+            ("frozen", self.frozen),
+        ]
+    }
+}
+
+impl PixelInfo {
+    /// Build the answer to a [CanvasCommand::Pixel] from the current canvas.
+    pub fn new(canvas: &Canvas, x: u16, y: u16) -> Option<Self> {
+        Some(Self {
+            color: canvas.get_pixel(x, y)?,
+            placement: canvas.placement(x, y),
+        })
+    }
+}
+
+impl LabelInfo {
+    /// Build the answer to a [CanvasCommand::Labels] from the current canvas.
+    pub fn list(canvas: &Canvas, region: Option<Region>) -> Vec<Self> {
+        canvas
+            .labels()
+            .filter(|label| region.is_none_or(|region| region.contains(label.x, label.y)))
+            .map(|&label| Self {
+                label,
+                placement: canvas.label_placement(label.x, label.y),
+            })
+            .collect()
+    }
+}
+
+impl TileSnapshot {
+    /// Build the answer to a [CanvasCommand::Tiles] from the current canvas.
+    pub fn list(canvas: &Canvas, tiles: &[TileId]) -> Vec<Self> {
+        tiles
+            .iter()
+            .filter_map(|&id| {
+                let tile = canvas.tile(id)?;
+                let (x, y) = id.origin();
+                let region = Region {
+                    x,
+                    y,
+                    width: tile.width(),
+                    height: tile.height(),
+                };
+                Some(Self {
+                    tile: id,
+                    region,
+                    canvas: canvas.crop(region).expect("tile is within the canvas"),
+                    version: tile.version(),
+                    labels: canvas
+                        .labels()
+                        .filter(|label| region.contains(label.x, label.y))
+                        .copied()
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl CropResponse {
+    /// Build the answer to a [CanvasCommand::Crop] from the current canvas.
+    pub fn new(canvas: &Canvas, region: Option<Region>, unless_version: Option<u64>) -> Self {
+        let region = region.unwrap_or_else(|| canvas.bounds());
+        let Some(version) = canvas.region_version(region) else {
+            return CropResponse::OutOfBounds {
+                width: canvas.width(),
+                height: canvas.height(),
+            };
+        };
+        if unless_version == Some(version) {
+            return CropResponse::NotModified { version };
+        }
+        let canvas = canvas.crop(region).expect("region is within the canvas");
+        CropResponse::Cropped { version, canvas }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::colors;
+
+    #[test]
+    fn crop_response() {
+        let mut canvas = Canvas::new(10, 10);
+        canvas.set_pixel(2, 3, colors::RED).unwrap();
+        let region = Region {
+            x: 2,
+            y: 3,
+            width: 4,
+            height: 4,
+        };
+
+        let CropResponse::Cropped {
+            version,
+            canvas: cropped,
+        } = CropResponse::new(&canvas, Some(region), None)
+        else {
+            panic!("Expected a cropped canvas");
+        };
+        assert_eq!(version, 1);
+        assert_eq!(cropped.get_pixel(0, 0), Some(colors::RED));
+        assert_eq!(
+            CropResponse::new(&canvas, Some(region), Some(1)),
+            CropResponse::NotModified { version: 1 }
+        );
+        assert_eq!(
+            CropResponse::new(&canvas, Some(Region { x: 8, ..region }), None),
+            CropResponse::OutOfBounds {
+                width: 10,
+                height: 10
+            }
+        );
+        assert!(matches!(
+            CropResponse::new(&canvas, None, Some(0)),
+            CropResponse::Cropped { version: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn tile_snapshots() {
+        let mut canvas = Canvas::new(300, 10);
+        canvas.set_pixel(260, 3, colors::RED).unwrap();
+        let label = Label::new(270, 5, "hi").unwrap();
+        canvas.set_label(label).unwrap();
+
+        let tiles = [TileId { tx: 1, ty: 0 }, TileId { tx: 0, ty: 1 }];
+        let snapshots = TileSnapshot::list(&canvas, &tiles);
+        assert_eq!(snapshots.len(), 1);
+        let snapshot = &snapshots[0];
+        assert_eq!(
+            snapshot.region,
+            Region {
+                x: 256,
+                y: 0,
+                width: 44,
+                height: 10
+            }
+        );
+        assert_eq!(snapshot.canvas.get_pixel(4, 3), Some(colors::RED));
+        assert_eq!(snapshot.labels, vec![label]);
+        assert_eq!(snapshot.version, canvas.version());
+    }
+}
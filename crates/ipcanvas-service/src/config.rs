@@ -0,0 +1,374 @@
+//! Config: the settings of the service read from a TOML file.
+//!
+//! The settings of the service are layered, highest precedence first:
+//! 1. the command line options,
+//! 2. the `IPCANVAS_*` environment variables (e.g. `IPCANVAS_PING_ADDR`),
+//! 3. the configuration file,
+//! 4. the built-in defaults.
+//!
+//! The keys of the file are the names of the command line options, with `_` instead of `-`.
+//! Every key is optional:
+//!
+//! ```toml
+//! ping_addr = "[::]:7894"
+//! http_addr = "[::]:7896"
+//! width = 8192
+//! height = 4096
+//! data_dir = "data"
+//! snapshot_interval = 300                # seconds
+//! tick_interval_ms = 100
+//! event_buffer_size = 1024
+//! rules = "rules.toml"
+//! webhooks = "webhooks.toml"
+//! ```
+//!
+//! Relative paths are relative to the directory of the file. Invalid settings are reported
+//! with their position in the file.
+
+use std::{
+    fmt::Display,
+    io,
+    ops::{Range, RangeInclusive},
+    path::{Path, PathBuf},
+};
+
+use ipcanvas_ping_common::Ipv6Prefix;
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::ping::parse_allowed_prefix;
+
+/// Shortest interval between two diffs, in milliseconds.
+pub const MIN_TICK_INTERVAL_MS: u64 = 10;
+/// Longest interval between two diffs, in milliseconds.
+pub const MAX_TICK_INTERVAL_MS: u64 = 60_000;
+/// Largest capacity of the queues of the service.
+pub const MAX_BUFFER_SIZE: usize = 1 << 20;
+
+/// Settings read from a configuration file, None for the settings it does not set.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConfigFile {
+    pub ping_addr: Option<String>,
+    pub websocket_addr: Option<String>,
+    pub http_addr: Option<String>,
+    pub admin_addr: Option<String>,
+    pub admin_token_file: Option<PathBuf>,
+    pub source_prefix_len: Option<u8>,
+    /// Minimum time between two pixels placed by the same player, in seconds
+    pub cooldown: Option<u64>,
+    pub cooldown_prefix_len: Option<u8>,
+    pub rules: Option<PathBuf>,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub data_dir: Option<PathBuf>,
+    /// Interval between two snapshots, in seconds
+    pub snapshot_interval: Option<u64>,
+    /// Number of days the players of each hour are kept for the statistics
+    pub player_retention_days: Option<u32>,
+    pub keep_event_log: Option<bool>,
+    pub canvases: Option<PathBuf>,
+    pub init_image: Option<PathBuf>,
+    pub ping_key_file: Option<PathBuf>,
+    pub ping_no_auth: Option<bool>,
+    /// Prefixes of the addresses allowed to connect as ping listeners
+    pub ping_allow: Option<Vec<Ipv6Prefix>>,
+    pub ping_tls_cert: Option<PathBuf>,
+    pub ping_tls_key: Option<PathBuf>,
+    pub ping_tls_client_ca: Option<PathBuf>,
+    pub webhooks: Option<PathBuf>,
+    /// Interval between two diffs sent to the clients, in milliseconds
+    pub tick_interval_ms: Option<u64>,
+    /// Capacity of the queue of the events of each canvas
+    pub event_buffer_size: Option<usize>,
+    /// Capacity of the queues of the diffs
+    pub diff_buffer_size: Option<usize>,
+    /// Capacity of the queue of the commands of each canvas
+    pub command_buffer_size: Option<usize>,
+}
+
+/// Error while loading a configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read.
+    Io { path: PathBuf, error: io::Error },
+    /// The file is not valid TOML, has unknown keys or invalid values.
+    Invalid {
+        path: PathBuf,
+        /// Position of the error in the file, starting at 1
+        line: usize,
+        column: usize,
+        reason: String,
+    },
+}
+
+/// On-disk format of the configuration file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    ping_addr: Option<Spanned<String>>,
+    websocket_addr: Option<Spanned<String>>,
+    http_addr: Option<Spanned<String>>,
+    admin_addr: Option<Spanned<String>>,
+    admin_token_file: Option<Spanned<PathBuf>>,
+    source_prefix_len: Option<Spanned<u8>>,
+    cooldown: Option<Spanned<u64>>,
+    cooldown_prefix_len: Option<Spanned<u8>>,
+    rules: Option<Spanned<PathBuf>>,
+    width: Option<Spanned<u16>>,
+    height: Option<Spanned<u16>>,
+    data_dir: Option<Spanned<PathBuf>>,
+    snapshot_interval: Option<Spanned<u64>>,
+    player_retention_days: Option<Spanned<u32>>,
+    keep_event_log: Option<Spanned<bool>>,
+    canvases: Option<Spanned<PathBuf>>,
+    init_image: Option<Spanned<PathBuf>>,
+    ping_key_file: Option<Spanned<PathBuf>>,
+    ping_no_auth: Option<Spanned<bool>>,
+    ping_allow: Option<Vec<Spanned<String>>>,
+    ping_tls_cert: Option<Spanned<PathBuf>>,
+    ping_tls_key: Option<Spanned<PathBuf>>,
+    ping_tls_client_ca: Option<Spanned<PathBuf>>,
+    webhooks: Option<Spanned<PathBuf>>,
+    tick_interval_ms: Option<Spanned<u64>>,
+    event_buffer_size: Option<Spanned<usize>>,
+    diff_buffer_size: Option<Spanned<usize>>,
+    command_buffer_size: Option<Spanned<usize>>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "Failed to read {}: {}", path.display(), error)
+            }
+            ConfigError::Invalid {
+                path,
+                line,
+                column,
+                reason,
+            } => write!(f, "{}:{}:{}: {}", path.display(), line, column, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io { error, .. } => Some(error),
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl ConfigFile {
+    /// Read the settings from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|error| ConfigError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Self::parse(&content, path)
+    }
+
+    /// Parse the settings from the content of the TOML file at `path`.
+    ///
+    /// The file is not read, its path names it in the errors and its directory is the base of
+    /// the relative paths.
+    pub fn parse(content: &str, path: &Path) -> Result<Self, ConfigError> {
+        let invalid = |span: Option<Range<usize>>, reason: String| {
+            let (line, column) = position(content, span.map_or(0, |span| span.start));
+            ConfigError::Invalid {
+                path: path.to_path_buf(),
+                line,
+                column,
+                reason,
+            }
+        };
+        let raw: RawConfig =
+            toml::from_str(content).map_err(|e| invalid(e.span(), e.message().to_string()))?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        let path = |value: Option<Spanned<PathBuf>>| value.map(|value| base.join(value.as_ref()));
+        let value = |value: Option<Spanned<String>>| value.map(Spanned::into_inner);
+
+        Ok(Self {
+            ping_addr: value(raw.ping_addr),
+            websocket_addr: value(raw.websocket_addr),
+            http_addr: value(raw.http_addr),
+            admin_addr: value(raw.admin_addr),
+            admin_token_file: path(raw.admin_token_file),
+            source_prefix_len: bounded(
+                raw.source_prefix_len,
+                0..=128,
+                "source_prefix_len",
+                &invalid,
+            )?,
+            cooldown: raw.cooldown.map(Spanned::into_inner),
+            cooldown_prefix_len: bounded(
+                raw.cooldown_prefix_len,
+                0..=128,
+                "cooldown_prefix_len",
+                &invalid,
+            )?,
+            rules: path(raw.rules),
+            width: bounded(raw.width, 1..=u16::MAX, "width", &invalid)?,
+            height: bounded(raw.height, 1..=u16::MAX, "height", &invalid)?,
+            data_dir: path(raw.data_dir),
+            snapshot_interval: bounded(
+                raw.snapshot_interval,
+                1..=u64::MAX,
+                "snapshot_interval",
+                &invalid,
+            )?,
+            player_retention_days: raw.player_retention_days.map(Spanned::into_inner),
+            keep_event_log: raw.keep_event_log.map(Spanned::into_inner),
+            canvases: path(raw.canvases),
+            init_image: path(raw.init_image),
+            ping_key_file: path(raw.ping_key_file),
+            ping_no_auth: raw.ping_no_auth.map(Spanned::into_inner),
+            ping_allow: raw
+                .ping_allow
+                .map(|prefixes| {
+                    prefixes
+                        .into_iter()
+                        .map(|prefix| {
+                            parse_allowed_prefix(prefix.get_ref())
+                                .map_err(|reason| invalid(Some(prefix.span()), reason))
+                        })
+                        .collect::<Result<_, _>>()
+                })
+                .transpose()?,
+            ping_tls_cert: path(raw.ping_tls_cert),
+            ping_tls_key: path(raw.ping_tls_key),
+            ping_tls_client_ca: path(raw.ping_tls_client_ca),
+            webhooks: path(raw.webhooks),
+            tick_interval_ms: bounded(
+                raw.tick_interval_ms,
+                MIN_TICK_INTERVAL_MS..=MAX_TICK_INTERVAL_MS,
+                "tick_interval_ms",
+                &invalid,
+            )?,
+            event_buffer_size: bounded(
+                raw.event_buffer_size,
+                1..=MAX_BUFFER_SIZE,
+                "event_buffer_size",
+                &invalid,
+            )?,
+            diff_buffer_size: bounded(
+                raw.diff_buffer_size,
+                1..=MAX_BUFFER_SIZE,
+                "diff_buffer_size",
+                &invalid,
+            )?,
+            command_buffer_size: bounded(
+                raw.command_buffer_size,
+                1..=MAX_BUFFER_SIZE,
+                "command_buffer_size",
+                &invalid,
+            )?,
+        })
+    }
+}
+
+/// Check that the value of a setting is within `bounds`.
+fn bounded<T: PartialOrd + Display>(
+    value: Option<Spanned<T>>,
+    bounds: RangeInclusive<T>,
+    key: &str,
+    invalid: &dyn Fn(Option<Range<usize>>, String) -> ConfigError,
+) -> Result<Option<T>, ConfigError> {
+    match value {
+        Some(value) if !bounds.contains(value.get_ref()) => {
+            let reason = format!(
+                "{} must be between {} and {}",
+                key,
+                bounds.start(),
+                bounds.end()
+            );
+            Err(invalid(Some(value.span()), reason))
+        }
+        value => Ok(value.map(Spanned::into_inner)),
+    }
+}
+
+/// Get the line and column (starting at 1) of a byte offset in `content`.
+fn position(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line_start = before.rfind('\n').map_or(0, |at| at + 1);
+    let line = before.matches('\n').count() + 1;
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file() {
+        let content = r#"
+            ping_addr = "[::]:7894"
+            ping_allow = ["2001:db8::/32", "192.0.2.1"]
+            width = 8192
+            height = 4096
+            data_dir = "data"
+            rules = "/etc/ipcanvas/rules.toml"
+            keep_event_log = true
+            tick_interval_ms = 100
+            event_buffer_size = 1024
+        "#;
+        let config = ConfigFile::parse(content, Path::new("/srv/ipcanvas/config.toml")).unwrap();
+        assert_eq!(
+            config,
+            ConfigFile {
+                ping_addr: Some("[::]:7894".to_string()),
+                ping_allow: Some(vec![
+                    "2001:db8::/32".parse().unwrap(),
+                    "::ffff:192.0.2.1/128".parse().unwrap(),
+                ]),
+                width: Some(8192),
+                height: Some(4096),
+                data_dir: Some(PathBuf::from("/srv/ipcanvas/data")),
+                rules: Some(PathBuf::from("/etc/ipcanvas/rules.toml")),
+                keep_event_log: Some(true),
+                tick_interval_ms: Some(100),
+                event_buffer_size: Some(1024),
+                ..ConfigFile::default()
+            }
+        );
+        assert_eq!(
+            ConfigFile::parse("", Path::new("config.toml")).unwrap(),
+            ConfigFile::default()
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        let error = |content: &str| match ConfigFile::parse(content, Path::new("config.toml"))
+            .unwrap_err()
+        {
+            ConfigError::Invalid { line, column, .. } => (line, column),
+            e => panic!("Unexpected error: {}", e),
+        };
+        // Unknown key
+        assert_eq!(error("width = 512\nheigth = 512\n"), (2, 1));
+        // Invalid type
+        assert_eq!(error("width = 512\nheight = \"512\"\n"), (2, 10));
+        // Out of bounds
+        assert_eq!(error("\n  width = 0\n"), (2, 11));
+        assert_eq!(error("source_prefix_len = 129"), (1, 21));
+        assert_eq!(error("tick_interval_ms = 0"), (1, 20));
+        assert_eq!(error("event_buffer_size = 0"), (1, 21));
+        assert_eq!(error("ping_allow = [\"::1\", \"::2/200\"]"), (1, 22));
+        // Invalid TOML
+        assert_eq!(error("width = 512\nheight ="), (2, 9));
+
+        let message = ConfigFile::parse("width = 0", Path::new("config.toml"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            message,
+            "config.toml:1:9: width must be between 1 and 65535"
+        );
+    }
+}
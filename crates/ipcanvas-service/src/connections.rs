@@ -0,0 +1,116 @@
+//! PingConnections: registry of the open connections from ping listeners.
+//!
+//! Each connection is registered while it is handled, and can be listed or closed by the
+//! operator (see the [InstanceApi](crate::http::instance::InstanceApi)).
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::oneshot;
+
+/// An open connection from a ping listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Identifier of the connection, unique within the process
+    pub id: u64,
+    /// Address of the ping listener
+    pub peer: SocketAddr,
+    /// Time the connection has been accepted, in milliseconds since the Unix epoch
+    pub connected_at: u64,
+}
+
+/// Registry of the open ping connections.
+///
+/// It is cheap to clone, the clones share the same registry.
+#[derive(Clone, Debug, Default)]
+pub struct PingConnections {
+    inner: Arc<Mutex<Registry>>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: u64,
+    connections: BTreeMap<u64, (ConnectionInfo, oneshot::Sender<()>)>,
+}
+
+/// Registration of a connection, removed from the registry when dropped.
+#[derive(Debug)]
+pub struct Registration {
+    pub info: ConnectionInfo,
+    /// Resolves when the operator asks to close the connection
+    pub disconnected: oneshot::Receiver<()>,
+    connections: PingConnections,
+}
+
+impl PingConnections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a new connection from `peer`, accepted at `connected_at`.
+    pub fn register(&self, peer: SocketAddr, connected_at: u64) -> Registration {
+        let (disconnect, disconnected) = oneshot::channel();
+        let mut registry = self.lock();
+        registry.next_id += 1;
+        let info = ConnectionInfo {
+            id: registry.next_id,
+            peer,
+            connected_at,
+        };
+        registry.connections.insert(info.id, (info, disconnect));
+        Registration {
+            info,
+            disconnected,
+            connections: self.clone(),
+        }
+    }
+
+    /// List the open connections, by increasing id.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        self.lock()
+            .connections
+            .values()
+            .map(|(info, _)| *info)
+            .collect()
+    }
+
+    /// Ask a connection to close, returns it if it was open.
+    pub fn disconnect(&self, id: u64) -> Option<ConnectionInfo> {
+        let (info, disconnect) = self.lock().connections.remove(&id)?;
+        let _ = disconnect.send(());
+        Some(info)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.inner.lock().expect("registry lock is not poisoned")
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.lock().connections.remove(&self.info.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_and_disconnect() {
+        let connections = PingConnections::new();
+        let first = connections.register("192.0.2.1:1000".parse().unwrap(), 1);
+        let mut second = connections.register("192.0.2.2:1000".parse().unwrap(), 2);
+        assert_eq!(connections.list(), vec![first.info, second.info]);
+
+        assert_eq!(connections.disconnect(second.info.id), Some(second.info));
+        assert_eq!(second.disconnected.try_recv(), Ok(()));
+        assert_eq!(connections.disconnect(second.info.id), None);
+
+        drop(first);
+        assert!(connections.list().is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! Cooldown: limit each player to one pixel every given period.
+//!
+//! A player is identified by the address of its pings masked to a prefix length,
+//! as a single host usually gets a whole range of addresses (e.g. a /64).
+//! Placements which do not come from a ping (e.g. image imports) are never limited.
+
+use std::{collections::HashMap, fmt::Display, net::Ipv6Addr, time::Duration};
+
+use crate::events::{EventSource, mask_address};
+
+/// Default length of the prefix identifying a player, in bits.
+pub const DEFAULT_PREFIX_LEN: u8 = 64;
+
+/// Table of the players cooling down.
+///
+/// Expired entries are purged along the way, at most once per period.
+#[derive(Clone, Debug)]
+pub struct Cooldown {
+    // Period between two placements of a player, in milliseconds (0 if disabled)
+    period: u64,
+    prefix_len: u8,
+    // Time of the last placement of each player, in milliseconds since the Unix epoch
+    players: HashMap<Ipv6Addr, u64>,
+    last_purge: u64,
+}
+
+/// A placement rejected because its player is still cooling down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoolingDown {
+    /// Prefix identifying the player
+    pub player: Ipv6Addr,
+    /// Time left before the player can place a pixel again, in milliseconds
+    pub remaining: u64,
+}
+
+impl Display for CoolingDown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is cooling down for {} ms",
+            self.player, self.remaining
+        )
+    }
+}
+
+impl std::error::Error for CoolingDown {}
+
+impl Cooldown {
+    /// Create a cooldown of the given period, disabled if the period is zero.
+    pub fn new(period: Duration) -> Self {
+        Self {
+            period: period.as_millis() as u64,
+            prefix_len: DEFAULT_PREFIX_LEN,
+            players: HashMap::new(),
+            last_purge: 0,
+        }
+    }
+
+    /// Set the length of the prefix identifying a player, in bits (at most 128).
+    pub fn with_prefix_len(mut self, prefix_len: u8) -> Self {
+        self.prefix_len = prefix_len.min(128);
+        self
+    }
+
+    /// Check if the cooldown is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.period > 0
+    }
+
+    /// Check whether the source may place a pixel at `now` (in ms since the Unix epoch).
+    pub fn check(&self, source: EventSource, now: u64) -> Result<(), CoolingDown> {
+        let Some(player) = self.player(source) else {
+            return Ok(());
+        };
+        match self.players.get(&player) {
+            Some(&last) if now.saturating_sub(last) < self.period => Err(CoolingDown {
+                player,
+                remaining: self.period - now.saturating_sub(last),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Start the cooldown of the source, after it placed a pixel at `now` (in ms since the Unix epoch).
+    pub fn record(&mut self, source: EventSource, now: u64) {
+        let Some(player) = self.player(source) else {
+            return;
+        };
+        if now.saturating_sub(self.last_purge) >= self.period {
+            let period = self.period;
+            self.players
+                .retain(|_, last| now.saturating_sub(*last) < period);
+            self.last_purge = now;
+        }
+        self.players.insert(player, now);
+    }
+
+    /// Get the number of players in the table, some of which may have finished cooling down.
+    pub fn len(&self) -> usize {
+        self.players.len()
+    }
+
+    /// Check if no player is cooling down.
+    pub fn is_empty(&self) -> bool {
+        self.players.is_empty()
+    }
+
+    /// Get the player behind a source, None if it is not limited.
+    fn player(&self, source: EventSource) -> Option<Ipv6Addr> {
+        match source {
+            EventSource::Ping(address) if self.is_enabled() => {
+                Some(mask_address(address, self.prefix_len))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping(address: &str) -> EventSource {
+        EventSource::Ping(address.parse().unwrap())
+    }
+
+    #[test]
+    fn cooldown_per_player_prefix() {
+        let mut cooldown = Cooldown::new(Duration::from_secs(5)).with_prefix_len(64);
+        let alice = ping("2001:db8:0:1::1");
+        cooldown.check(alice, 1_000).unwrap();
+        cooldown.record(alice, 1_000);
+
+        // Same /64, still cooling down
+        assert_eq!(
+            cooldown.check(ping("2001:db8:0:1::2"), 4_000),
+            Err(CoolingDown {
+                player: "2001:db8:0:1::".parse().unwrap(),
+                remaining: 2_000,
+            })
+        );
+        // Other players and imports are not limited
+        assert!(cooldown.check(ping("2001:db8:0:2::1"), 4_000).is_ok());
+        assert!(cooldown.check(EventSource::Import, 4_000).is_ok());
+        assert!(cooldown.check(alice, 6_000).is_ok());
+
+        // Expired entries are purged
+        cooldown.record(ping("2001:db8:0:2::1"), 5_000);
+        cooldown.record(ping("2001:db8:0:3::1"), 12_000);
+        assert_eq!(cooldown.len(), 1);
+    }
+
+    #[test]
+    fn cooldown_disabled() {
+        let mut cooldown = Cooldown::new(Duration::ZERO);
+        let alice = ping("2001:db8::1");
+        cooldown.record(alice, 1_000);
+        assert!(cooldown.check(alice, 1_000).is_ok());
+        assert!(cooldown.is_empty());
+    }
+}
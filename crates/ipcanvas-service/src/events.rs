@@ -1,4 +1,11 @@
-use crate::canvas::PixelColor;
+use std::{
+    fmt::Display,
+    net::{Ipv6Addr, SocketAddr},
+};
+
+use ipcanvas_ping_common::Ipv6Prefix;
+
+use crate::canvas::{Canvas, PixelColor, label::Label, provenance::Placement};
 
 /// Events that can be performed on the canvas.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -11,4 +18,179 @@ pub enum Event {
     /// The text is limited to 8 bytes. If the text is shorter than 8 bytes,
     /// it should be null-padded.
     PlaceLabel { x: u16, y: u16, text: [u8; 8] },
+    /// Expand the canvas to the specified dimensions, filling the added area with the
+    /// background color.
+    ///
+    /// The canvas can not shrink.
+    Resize {
+        width: u16,
+        height: u16,
+        background: PixelColor,
+    },
+    /// Record an operator action which does not change the canvas.
+    AdminMarker { action: AdminAction },
+}
+
+/// Operator actions recorded by an [Event::AdminMarker].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AdminAction {
+    /// Ping events are rejected from now on.
+    Freeze,
+    /// Ping events are accepted again.
+    Unfreeze,
+    /// A snapshot of the canvas has been requested.
+    Snapshot,
+    /// The connection of a ping listener has been closed.
+    Disconnect { peer: SocketAddr },
+}
+
+/// Origin of an [Event].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum EventSource {
+    /// The event comes from a ping sent by the given address.
+    Ping(Ipv6Addr),
+    /// The event comes from an image imported by the operator.
+    Import,
+    /// The event comes from another operator action (e.g. a rollback).
+    Admin,
+}
+
+impl EventSource {
+    /// Get the address of the source truncated to its first `prefix_len` bits,
+    /// None if the source has no address.
+    pub fn prefix(&self, prefix_len: u8) -> Option<Ipv6Prefix> {
+        match *self {
+            EventSource::Ping(address) => {
+                let prefix_len = prefix_len.min(128);
+                Some(Ipv6Prefix::from((
+                    mask_address(address, prefix_len),
+                    prefix_len,
+                )))
+            }
+            EventSource::Import | EventSource::Admin => None,
+        }
+    }
+}
+
+/// Keep the first `prefix_len` bits of an address, zeroing the others.
+pub fn mask_address(address: Ipv6Addr, prefix_len: u8) -> Ipv6Addr {
+    let mask = u128::MAX
+        .checked_shl(128 - prefix_len.min(128) as u32)
+        .unwrap_or(0);
+    Ipv6Addr::from(u128::from(address) & mask)
+}
+
+/// An [Event] along with its origin.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourcedEvent {
+    pub source: EventSource,
+    pub event: Event,
+}
+
+/// Errors that can occur while applying an [Event] to the canvas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The event targets coordinates outside of the canvas
+    OutOfBounds { x: u16, y: u16 },
+    /// The event would shrink the canvas
+    InvalidSize { width: u16, height: u16 },
+    /// The event is not supported yet
+    Unsupported,
+}
+
+impl Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApplyError::OutOfBounds { x, y } => write!(f, "({}, {}) is out of bounds", x, y),
+            ApplyError::InvalidSize { width, height } => {
+                write!(f, "{}x{} is smaller than the canvas", width, height)
+            }
+            ApplyError::Unsupported => write!(f, "Unsupported event"),
+        }
+    }
+}
+
+impl Display for AdminAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminAction::Freeze => write!(f, "freeze"),
+            AdminAction::Unfreeze => write!(f, "unfreeze"),
+            AdminAction::Snapshot => write!(f, "snapshot"),
+            AdminAction::Disconnect { peer } => write!(f, "disconnect {}", peer),
+        }
+    }
+}
+
+impl Event {
+    /// Apply the event to the canvas.
+    ///
+    /// Applying an event is deterministic, so replaying the applied events
+    /// on top of a snapshot gives back the same canvas.
+    ///
+    /// The origin of the changed pixels becomes unknown, see [Event::apply_placed] to record it.
+    pub fn apply(&self, canvas: &mut Canvas) -> Result<(), ApplyError> {
+        self.apply_with(canvas, None)
+    }
+
+    /// Apply the event to the canvas, recording the placement of the changed pixels.
+    pub fn apply_placed(
+        &self,
+        canvas: &mut Canvas,
+        placement: Placement,
+    ) -> Result<(), ApplyError> {
+        self.apply_with(canvas, Some(placement))
+    }
+
+    fn apply_with(
+        &self,
+        canvas: &mut Canvas,
+        placement: Option<Placement>,
+    ) -> Result<(), ApplyError> {
+        match *self {
+            Event::PlacePixel { x, y, color } => match placement {
+                Some(placement) => canvas.place_pixel(x, y, color, placement),
+                None => canvas.set_pixel(x, y, color),
+            }
+            .map_err(|_| ApplyError::OutOfBounds { x, y }),
+            Event::PlaceLabel { x, y, text } => {
+                let label = Label { x, y, text };
+                match placement {
+                    Some(placement) => canvas.place_label(label, placement),
+                    None => canvas.set_label(label),
+                }
+                .map_err(|_| ApplyError::OutOfBounds { x, y })
+            }
+            Event::Resize {
+                width,
+                height,
+                background,
+            } => canvas
+                .expand(width, height, background)
+                .map_err(|_| ApplyError::InvalidSize { width, height }),
+            Event::AdminMarker { .. } => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_prefix() {
+        let address: Ipv6Addr = "2001:db8:aaaa:bbbb::1".parse().unwrap();
+        assert_eq!(mask_address(address, 128), address);
+        assert_eq!(
+            mask_address(address, 36),
+            "2001:db8:a000::".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(mask_address(address, 0), Ipv6Addr::UNSPECIFIED);
+
+        let source = EventSource::Ping(address);
+        assert_eq!(source.prefix(48).unwrap().to_string(), "2001:db8:aaaa::/48");
+        assert_eq!(source.prefix(200).unwrap().prefix_len, 128);
+        assert_eq!(EventSource::Import.prefix(48), None);
+    }
 }
@@ -0,0 +1,200 @@
+//! DiffFeed: fan-out of the diffs of a canvas to its followers.
+//!
+//! Each diff published by the main loop is numbered, and the latest ones are kept so a follower
+//! which lost its connection can resume from the last diff it received
+//! (see the `GET /events` endpoint of the [HttpApi](crate::http::HttpApi)).
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::broadcast;
+
+use crate::{canvas::diff::CanvasDiff, persistence};
+
+/// Number of diffs kept to resume the followers, unless configured otherwise.
+pub const DEFAULT_HISTORY_LEN: usize = 256;
+
+/// A diff published to the feed.
+#[derive(Debug)]
+pub struct FeedEntry {
+    /// Number of the diff, starting at 1 for the first diff of the feed
+    pub sequence: u64,
+    pub diff: CanvasDiff,
+}
+
+/// Where a follower resumes the feed (see [DiffFeed::follow]).
+#[derive(Debug)]
+pub enum Resume {
+    /// The follower starts with the next published diff.
+    Live,
+    /// The follower first receives the diffs it missed, in order.
+    Backlog(Vec<Arc<FeedEntry>>),
+    /// The diffs missed by the follower are no longer known: it should reload the whole canvas.
+    Reset,
+}
+
+/// Feed of the diffs of a canvas.
+///
+/// It is cheap to clone, the clones share the same feed.
+#[derive(Clone, Debug)]
+pub struct DiffFeed {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    // Distinguishes the ids of this process from the ones of a previous run
+    instance: u64,
+    history_len: usize,
+    state: Mutex<State>,
+    sender: broadcast::Sender<Arc<FeedEntry>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last_sequence: u64,
+    history: VecDeque<Arc<FeedEntry>>,
+}
+
+impl DiffFeed {
+    /// Create a feed, keeping the last [DEFAULT_HISTORY_LEN] diffs.
+    pub fn new() -> Self {
+        Self::with_history_len(DEFAULT_HISTORY_LEN)
+    }
+
+    /// Create a feed, keeping the last `history_len` diffs.
+    ///
+    /// A follower lagging behind by more than `history_len` diffs has to reload the canvas.
+    pub fn with_history_len(history_len: usize) -> Self {
+        let history_len = history_len.max(1);
+        let (sender, _) = broadcast::channel(history_len);
+        Self {
+            inner: Arc::new(Inner {
+                instance: persistence::now_millis(),
+                history_len,
+                state: Mutex::new(State::default()),
+                sender,
+            }),
+        }
+    }
+
+    /// Publish a diff to the followers, returns its sequence number.
+    pub fn publish(&self, diff: CanvasDiff) -> u64 {
+        let mut state = self.lock();
+        state.last_sequence += 1;
+        let entry = Arc::new(FeedEntry {
+            sequence: state.last_sequence,
+            diff,
+        });
+        if state.history.len() == self.inner.history_len {
+            state.history.pop_front();
+        }
+        state.history.push_back(entry.clone());
+        // Sent under the lock, so a new follower gets each diff either in its backlog or live
+        let _ = self.inner.sender.send(entry);
+        state.last_sequence
+    }
+
+    /// Follow the feed, resuming after the diff identified by `last_id` (see [DiffFeed::id]).
+    pub fn follow(&self, last_id: Option<&str>) -> (Resume, broadcast::Receiver<Arc<FeedEntry>>) {
+        let state = self.lock();
+        let receiver = self.inner.sender.subscribe();
+        let Some(last_id) = last_id else {
+            return (Resume::Live, receiver);
+        };
+        let Some(last) = self.parse_id(last_id) else {
+            return (Resume::Reset, receiver);
+        };
+        let first = state.last_sequence + 1 - state.history.len() as u64;
+        let resume = if last == state.last_sequence {
+            Resume::Live
+        } else if last < state.last_sequence && last + 1 >= first {
+            // The sequence comes from the client, it is only added to once known to be issued
+            let skip = (last + 1 - first) as usize;
+            Resume::Backlog(state.history.iter().skip(skip).cloned().collect())
+        } else {
+            Resume::Reset
+        };
+        (resume, receiver)
+    }
+
+    /// Get the id of the diff with the given sequence number, unique across the runs.
+    pub fn id(&self, sequence: u64) -> String {
+        format!("{:x}-{:x}", self.inner.instance, sequence)
+    }
+
+    /// Get the sequence number of a diff id issued by this feed, if any.
+    fn parse_id(&self, id: &str) -> Option<u64> {
+        let (instance, sequence) = id.trim().split_once('-')?;
+        if u64::from_str_radix(instance, 16).ok()? != self.inner.instance {
+            return None;
+        }
+        u64::from_str_radix(sequence, 16).ok()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.inner.state.lock().expect("feed lock is not poisoned")
+    }
+}
+
+impl Default for DiffFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{Canvas, colors};
+
+    fn diff(x: u16) -> CanvasDiff {
+        let mut canvas = Canvas::new(16, 16);
+        canvas.take_diff();
+        canvas.set_pixel(x, 0, colors::BLACK).unwrap();
+        canvas.take_diff()
+    }
+
+    fn sequences(resume: Resume) -> Vec<u64> {
+        match resume {
+            Resume::Backlog(entries) => entries.iter().map(|entry| entry.sequence).collect(),
+            resume => panic!("expected a backlog, got {:?}", resume),
+        }
+    }
+
+    #[test]
+    fn follow_and_resume() {
+        let feed = DiffFeed::with_history_len(3);
+        let (resume, mut live) = feed.follow(None);
+        assert!(matches!(resume, Resume::Live));
+        for x in 0..4 {
+            assert_eq!(feed.publish(diff(x)), x as u64 + 1);
+        }
+        let entry = live.try_recv().unwrap();
+        assert_eq!((entry.sequence, &entry.diff), (1, &diff(0)));
+
+        // Diffs 2 to 4 are kept
+        assert_eq!(sequences(feed.follow(Some(&feed.id(1))).0), vec![2, 3, 4]);
+        assert_eq!(sequences(feed.follow(Some(&feed.id(3))).0), vec![4]);
+        assert!(matches!(feed.follow(Some(&feed.id(4))).0, Resume::Live));
+        assert!(matches!(feed.follow(Some(&feed.id(0))).0, Resume::Reset));
+        // Unknown diffs, or ids of another run
+        assert!(matches!(feed.follow(Some(&feed.id(5))).0, Resume::Reset));
+        assert!(matches!(feed.follow(Some("0-1")).0, Resume::Reset));
+        assert!(matches!(feed.follow(Some("nope")).0, Resume::Reset));
+    }
+
+    #[test]
+    fn follow_unknown_last_sequence() {
+        let feed = DiffFeed::with_history_len(3);
+        feed.publish(diff(0));
+        let last_id = feed.id(u64::MAX);
+        assert!(matches!(feed.follow(Some(&last_id)).0, Resume::Reset));
+
+        // The feed is still usable
+        assert_eq!(feed.publish(diff(1)), 2);
+        assert_eq!(sequences(feed.follow(Some(&feed.id(1))).0), vec![2]);
+    }
+}
@@ -0,0 +1,338 @@
+//! Heatmap: where the players place their pixels, over sliding windows.
+//!
+//! The canvas is divided into cells of [CELL_SIZE]x[CELL_SIZE] pixels, and the pixels placed by
+//! the players are counted per cell: since the canvas exists, and per bucket of [BUCKET_MILLIS]
+//! over the last day. The buckets give the counts of the last hour and of the last 24 hours
+//! (see [HeatmapWindow]), sliding by a bucket.
+//!
+//! # Encoding
+//!
+//! The heatmap is persisted along with the [snapshots](crate::persistence::snapshot):
+//!
+//! - `cells: u32`, followed by the cells counted since the canvas exists:
+//!   `cx: u16, cy: u16, count: u64`, then
+//! - `buckets: u32`, followed by the buckets of the last day, oldest first: `start: u64` (in ms
+//!   since the Unix epoch) and `cells: u32`, followed by the cells placed during the bucket:
+//!   `cx: u16, cy: u16, count: u32`.
+//!
+//! All integers are big-endian.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+use crate::{canvas::Canvas, events::EventSource};
+
+/// Width and height of the cells of the heatmap, in pixels.
+pub const CELL_SIZE: u16 = 8;
+/// Length of the buckets of the sliding windows, in milliseconds.
+pub const BUCKET_MILLIS: u64 = 5 * 60 * 1000;
+
+const DAY_MILLIS: u64 = 24 * 3600 * 1000;
+
+/// Coordinates of a cell of the heatmap, `(cx, cy)`.
+pub type Cell = (u16, u16);
+
+/// Time window of a heatmap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeatmapWindow {
+    /// The last hour.
+    Hour,
+    /// The last 24 hours.
+    Day,
+    /// Since the canvas exists.
+    All,
+}
+
+impl HeatmapWindow {
+    /// Every window.
+    pub const ALL: [HeatmapWindow; 3] =
+        [HeatmapWindow::Hour, HeatmapWindow::Day, HeatmapWindow::All];
+
+    /// Name of the window in the API (`1h`, `24h` or `all`).
+    pub fn name(&self) -> &'static str {
+        match self {
+            HeatmapWindow::Hour => "1h",
+            HeatmapWindow::Day => "24h",
+            HeatmapWindow::All => "all",
+        }
+    }
+
+    /// Parse the name of a window.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|window| window.name() == name)
+    }
+
+    /// Length of the window, None for all time.
+    fn millis(&self) -> Option<u64> {
+        match self {
+            HeatmapWindow::Hour => Some(3600 * 1000),
+            HeatmapWindow::Day => Some(DAY_MILLIS),
+            HeatmapWindow::All => None,
+        }
+    }
+}
+
+impl Display for HeatmapWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Placement counts of the cells of a canvas (see the [module](self) documentation).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Heatmap {
+    all_time: HashMap<Cell, u64>,
+    // Keyed by start of the bucket, in BUCKET_MILLIS since the Unix epoch
+    buckets: BTreeMap<u64, HashMap<Cell, u32>>,
+}
+
+impl Heatmap {
+    /// Create an empty heatmap.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seed the heatmap from the pixels of the canvas placed by the players, for a canvas
+    /// restored without its heatmap.
+    ///
+    /// Only the surviving pixels are known, the covered ones are not counted.
+    pub fn from_canvas(canvas: &Canvas) -> Self {
+        let mut heatmap = Self::new();
+        for pixel in canvas.pixels() {
+            if let Some(placement) = canvas.placement(pixel.x, pixel.y)
+                && let EventSource::Ping(_) = placement.source
+            {
+                heatmap.record(pixel.x, pixel.y, placement.timestamp);
+            }
+        }
+        heatmap
+    }
+
+    /// Count a pixel placed at (x, y) at the given time (in milliseconds since the Unix epoch).
+    ///
+    /// The buckets older than a day before the latest one are dropped.
+    pub fn record(&mut self, x: u16, y: u16, timestamp: u64) {
+        let cell = (x / CELL_SIZE, y / CELL_SIZE);
+        *self.all_time.entry(cell).or_default() += 1;
+        let bucket = timestamp / BUCKET_MILLIS;
+        if let Some((&latest, _)) = self.buckets.last_key_value()
+            && bucket + DAY_MILLIS / BUCKET_MILLIS <= latest
+        {
+            return;
+        }
+        *self
+            .buckets
+            .entry(bucket)
+            .or_default()
+            .entry(cell)
+            .or_default() += 1;
+        let first = bucket.saturating_sub(DAY_MILLIS / BUCKET_MILLIS - 1);
+        if self
+            .buckets
+            .first_key_value()
+            .is_some_and(|(&oldest, _)| oldest < first)
+        {
+            self.buckets = self.buckets.split_off(&first);
+        }
+    }
+
+    /// Get the counts of the cells over a window ending at `now` (in milliseconds since the
+    /// Unix epoch), for a canvas of the given dimensions.
+    ///
+    /// The windows slide by [BUCKET_MILLIS]: they cover every bucket started within them.
+    pub fn layer(&self, window: HeatmapWindow, now: u64, width: u16, height: u16) -> HeatmapLayer {
+        let mut counts: HashMap<Cell, u64> = HashMap::new();
+        match window.millis() {
+            Some(millis) => {
+                let first = now.saturating_sub(millis).div_ceil(BUCKET_MILLIS);
+                for cells in self.buckets.range(first..).map(|(_, cells)| cells) {
+                    for (cell, count) in cells {
+                        *counts.entry(*cell).or_default() += *count as u64;
+                    }
+                }
+            }
+            None => counts.clone_from(&self.all_time),
+        }
+        let mut cells: Vec<_> = counts.into_iter().collect();
+        cells.sort_unstable_by_key(|((cx, cy), _)| (*cy, *cx));
+        HeatmapLayer {
+            window,
+            width: width.div_ceil(CELL_SIZE),
+            height: height.div_ceil(CELL_SIZE),
+            cells,
+        }
+    }
+
+    /// Encode the heatmap.
+    pub(crate) fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(self.all_time.len() as u32).to_be_bytes());
+        for ((cx, cy), count) in &self.all_time {
+            buf.extend_from_slice(&cx.to_be_bytes());
+            buf.extend_from_slice(&cy.to_be_bytes());
+            buf.extend_from_slice(&count.to_be_bytes());
+        }
+        buf.extend_from_slice(&(self.buckets.len() as u32).to_be_bytes());
+        for (bucket, cells) in &self.buckets {
+            buf.extend_from_slice(&(bucket * BUCKET_MILLIS).to_be_bytes());
+            buf.extend_from_slice(&(cells.len() as u32).to_be_bytes());
+            for ((cx, cy), count) in cells {
+                buf.extend_from_slice(&cx.to_be_bytes());
+                buf.extend_from_slice(&cy.to_be_bytes());
+                buf.extend_from_slice(&count.to_be_bytes());
+            }
+        }
+    }
+
+    /// Decode a heatmap, None if it is invalid.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut bytes = bytes;
+        let mut take = |n: usize| -> Option<&[u8]> {
+            if bytes.len() < n {
+                return None;
+            }
+            let (head, tail) = bytes.split_at(n);
+            bytes = tail;
+            Some(head)
+        };
+        let u16_at = |b: &[u8], i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+        let u32_at =
+            |b: &[u8], i: usize| u32::from_be_bytes(b[i..i + 4].try_into().expect("4 bytes"));
+        let u64_at =
+            |b: &[u8], i: usize| u64::from_be_bytes(b[i..i + 8].try_into().expect("8 bytes"));
+
+        let mut heatmap = Self::new();
+        for _ in 0..u32_at(take(4)?, 0) {
+            let b = take(12)?;
+            heatmap
+                .all_time
+                .insert((u16_at(b, 0), u16_at(b, 2)), u64_at(b, 4));
+        }
+        for _ in 0..u32_at(take(4)?, 0) {
+            let start = u64_at(take(8)?, 0);
+            if start % BUCKET_MILLIS != 0 {
+                return None;
+            }
+            let mut cells = HashMap::new();
+            for _ in 0..u32_at(take(4)?, 0) {
+                let b = take(8)?;
+                cells.insert((u16_at(b, 0), u16_at(b, 2)), u32_at(b, 4));
+            }
+            heatmap.buckets.insert(start / BUCKET_MILLIS, cells);
+        }
+        if !bytes.is_empty() {
+            return None;
+        }
+        Some(heatmap)
+    }
+}
+
+/// Counts of the cells of a canvas over a window, see [Heatmap::layer].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeatmapLayer {
+    /// Window of the counts.
+    pub window: HeatmapWindow,
+    /// Number of columns of cells of the canvas.
+    pub width: u16,
+    /// Number of rows of cells of the canvas.
+    pub height: u16,
+    /// Cells with placements and their counts, row by row.
+    pub cells: Vec<(Cell, u64)>,
+}
+
+impl HeatmapLayer {
+    /// Highest count of the cells, 0 if nothing was placed.
+    pub fn max(&self) -> u64 {
+        self.cells
+            .iter()
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Render the layer as an 8-bit RGBA PNG image, with a pixel per cell.
+    ///
+    /// The cells without placements are transparent, the others go from a translucent dark
+    /// red to an opaque white for the highest count, on a logarithmic scale.
+    pub fn encode_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut data = vec![0; self.width as usize * self.height as usize * 4];
+        let max = ((self.max() + 1) as f64).ln();
+        for ((cx, cy), count) in &self.cells {
+            if *cx >= self.width || *cy >= self.height {
+                continue;
+            }
+            let heat = ((count + 1) as f64).ln() / max;
+            let channel = |offset: f64| ((heat * 3.0 - offset).clamp(0.0, 1.0) * 255.0) as u8;
+            let at = (*cy as usize * self.width as usize + *cx as usize) * 4;
+            data[at..at + 4].copy_from_slice(&[
+                channel(0.0).max(64),
+                channel(1.0),
+                channel(2.0),
+                (96.0 + heat * 159.0) as u8,
+            ]);
+        }
+
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Fast);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+        writer.finish()?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heatmap_windows() {
+        let mut heatmap = Heatmap::new();
+        let t0 = 1_700_000_000_000 / BUCKET_MILLIS * BUCKET_MILLIS;
+        heatmap.record(0, 0, t0);
+        heatmap.record(7, 7, t0 + 1);
+        heatmap.record(8, 0, t0 + 23 * 3600 * 1000);
+        heatmap.record(100, 50, t0 + DAY_MILLIS);
+
+        let now = t0 + DAY_MILLIS + 1000;
+        let hour = heatmap.layer(HeatmapWindow::Hour, now, 101, 51);
+        assert_eq!((hour.width, hour.height), (13, 7));
+        assert_eq!(hour.cells, vec![((12, 6), 1)]);
+        let day = heatmap.layer(HeatmapWindow::Day, now, 101, 51);
+        assert_eq!(day.cells, vec![((1, 0), 1), ((12, 6), 1)]);
+        let all = heatmap.layer(HeatmapWindow::All, now, 101, 51);
+        assert_eq!(all.cells, vec![((0, 0), 2), ((1, 0), 1), ((12, 6), 1)]);
+        assert_eq!(all.max(), 2);
+        // The buckets of more than a day ago are dropped, and not counted again
+        assert_eq!(heatmap.buckets.len(), 2);
+        heatmap.record(0, 0, t0);
+        assert_eq!(heatmap.buckets.len(), 2);
+        assert_eq!(heatmap.all_time[&(0, 0)], 3);
+
+        let mut buf = Vec::new();
+        heatmap.encode(&mut buf);
+        assert_eq!(Heatmap::decode(&buf), Some(heatmap));
+        assert!(Heatmap::decode(&buf[..buf.len() - 1]).is_none());
+
+        let png = all.encode_png().unwrap();
+        let decoder = png::Decoder::new(std::io::Cursor::new(png));
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size().unwrap()];
+        reader.next_frame(&mut data).unwrap();
+        assert_eq!(&data[..4], &[255, 255, 255, 255], "Hottest cell");
+        assert_eq!(data[4 * 2 + 3], 0, "Cell without placements");
+        assert!(data[4 + 3] > 96 && data[4 + 3] < 255);
+    }
+
+    #[test]
+    fn heatmap_window_names() {
+        for window in HeatmapWindow::ALL {
+            assert_eq!(HeatmapWindow::from_name(window.name()), Some(window));
+        }
+        assert_eq!(HeatmapWindow::from_name("2h"), None);
+    }
+}
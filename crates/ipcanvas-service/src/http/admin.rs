@@ -0,0 +1,770 @@
+//! AdminApi: operator actions over HTTP.
+//!
+//! Endpoints:
+//! - `POST /import?x=&y=`: paint the PNG image of the request body on the canvas,
+//!   with its top-left corner at (x, y) (defaults to (0, 0)). Transparent pixels are skipped.
+//! - `POST /rollback?prefix=&from=&to=`: undo the placements of the sources within `prefix`
+//!   (e.g. `2001:db8::/48`) between the timestamps `from` and `to` (defaults to now), in
+//!   milliseconds since the Unix epoch. Only the history still in the event log can be undone.
+//! - `POST /resize?width=&height=&background=`: expand the canvas, filling the added area with
+//!   `background` (percent-encoded `#rrggbb`, white by default). The canvas can not shrink.
+//! - `POST /label?x=&y=&text=`: place a label anchored at (x, y), replacing the one already
+//!   there. The text is percent-encoded, at most 8 bytes long, and an empty text removes the label.
+//! - `POST /labels/rasterise?color=`: paint all the labels into the pixels with `color`
+//!   (percent-encoded `#rrggbb`, black by default), then remove them.
+//! - `POST /pixels?x=&y=&w=&h=&color=`: paint the region of `w`x`h` pixels at (x, y) with `color`
+//!   (percent-encoded `#rrggbb`). The dimensions default to a single pixel.
+//! - `POST /pixels/clear?x=&y=&w=&h=`: paint the region in white.
+//! - `POST /freeze` and `POST /unfreeze`: reject the ping events, or accept them again.
+//!   Operator actions are still applied on a frozen canvas, which is not frozen after a restart.
+//! - `POST /snapshot`: write a snapshot of the canvas now.
+//!
+//! Every action is recorded in the event log: the changed pixels and labels as
+//! [EventSource::Admin](crate::events::EventSource::Admin) events (or `Import` for imports),
+//! the other actions as [Event::AdminMarker](crate::events::Event::AdminMarker)s.
+//!
+//! Requests must be authenticated with a bearer token, see [unauthorized].
+
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::Body as HttpBody,
+    header::{self, HeaderValue},
+};
+use ipcanvas_ping_common::Ipv6Prefix;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    canvas::{
+        PixelColor, Region, colors,
+        image::ImportImage,
+        label::{Label, MAX_TEXT_LEN},
+    },
+    command::{
+        CanvasCommand, FillResponse, ImportResponse, RasteriseResponse, ResizeResponse,
+        RollbackResponse, SnapshotResponse,
+    },
+    http::{Body, method_not_allowed, percent_decode, query_pairs, text},
+    moderation::{RollbackFilter, RollbackPlan},
+    persistence,
+};
+
+/// Maximum size of an imported image, in bytes.
+pub const MAX_IMPORT_SIZE: usize = 32 * 1024 * 1024;
+
+/// Handler of the admin HTTP requests.
+///
+/// It is cheap to clone, and meant to be shared between the HTTP connections.
+#[derive(Clone, Debug)]
+pub struct AdminApi {
+    commands: mpsc::Sender<CanvasCommand>,
+    // Data directory of the canvas, to read its history from
+    data_dir: Option<PathBuf>,
+}
+
+impl AdminApi {
+    /// Create a new handler, sending its requests to the canvas task through `commands`.
+    pub fn new(commands: mpsc::Sender<CanvasCommand>) -> Self {
+        Self {
+            commands,
+            data_dir: None,
+        }
+    }
+
+    /// Set the data directory of the canvas, needed to undo placements from its history.
+    pub fn with_data_dir(mut self, data_dir: PathBuf) -> Self {
+        self.data_dir = Some(data_dir);
+        self
+    }
+
+    /// Handle an admin HTTP request.
+    pub async fn handle<B>(&self, req: Request<B>) -> Response<Body>
+    where
+        B: HttpBody<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        match req.uri().path() {
+            "/import" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.import(req).await
+            }
+            "/rollback" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.rollback(req.uri().query()).await
+            }
+            "/resize" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.resize(req.uri().query()).await
+            }
+            "/label" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.label(req.uri().query()).await
+            }
+            "/labels/rasterise" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.rasterise_labels(req.uri().query()).await
+            }
+            "/pixels" | "/pixels/clear" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                let clear = req.uri().path() == "/pixels/clear";
+                self.fill(req.uri().query(), clear).await
+            }
+            "/freeze" | "/unfreeze" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.set_frozen(req.uri().path() == "/freeze").await
+            }
+            "/snapshot" => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.snapshot().await
+            }
+            _ => text(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    async fn import<B>(&self, req: Request<B>) -> Response<Body>
+    where
+        B: HttpBody<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let (x, y) = match parse_offset(req.uri().query()) {
+            Ok(offset) => offset,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+        let body = match Limited::new(req.into_body(), MAX_IMPORT_SIZE)
+            .collect()
+            .await
+        {
+            Ok(body) => body.to_bytes(),
+            Err(e) if e.is::<LengthLimitError>() => {
+                return text(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("Image must be at most {} bytes", MAX_IMPORT_SIZE),
+                );
+            }
+            Err(_) => return text(StatusCode::BAD_REQUEST, "Failed to read the request body"),
+        };
+        let decoded = tokio::task::spawn_blocking(move || ImportImage::decode_png(&body)).await;
+        let image = match decoded {
+            Ok(Ok(image)) => image,
+            Ok(Err(e)) => return text(StatusCode::BAD_REQUEST, e.to_string()),
+            Err(_) => return text(StatusCode::INTERNAL_SERVER_ERROR, "Failed to decode image"),
+        };
+
+        let (reply, response) = oneshot::channel();
+        let command = CanvasCommand::Import { image, x, y, reply };
+        if self.commands.send(command).await.is_err() {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(ImportResponse { applied, rejected }) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+        text(
+            StatusCode::OK,
+            format!(
+                "Imported {} pixels ({} outside of the canvas)\n",
+                applied, rejected
+            ),
+        )
+    }
+}
+
+impl AdminApi {
+    async fn rollback(&self, query: Option<&str>) -> Response<Body> {
+        let filter = match parse_rollback_filter(query, persistence::now_millis()) {
+            Ok(filter) => filter,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+        let Some(data_dir) = self.data_dir.clone() else {
+            return text(
+                StatusCode::CONFLICT,
+                "The canvas has no history to undo placements from (no data directory)",
+            );
+        };
+
+        // Make sure the history is complete before reading it
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::FlushLog { reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        if response.await != Ok(true) {
+            return text(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Failed to flush the event log",
+            );
+        }
+
+        let planned =
+            tokio::task::spawn_blocking(move || RollbackPlan::from_data_dir(&data_dir, &filter))
+                .await;
+        let plan = match planned {
+            Ok(Ok(plan)) => plan,
+            Ok(Err(e)) => {
+                return text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to read the history: {}", e),
+                );
+            }
+            Err(_) => {
+                return text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to read the history",
+                );
+            }
+        };
+        let matched = plan.matched;
+        let history_since = plan.history_since;
+
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::Rollback {
+                filter,
+                plan,
+                reply,
+            })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(RollbackResponse { reverted, skipped }) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+        let mut msg = format!(
+            "Undone {} placements: {} pixels reverted, {} placed again since\n",
+            matched, reverted, skipped
+        );
+        if let Some(since) = history_since.filter(|&since| since > filter.from) {
+            msg.push_str(&format!(
+                "The history starts at {}, earlier placements were not undone\n",
+                since
+            ));
+        }
+        text(StatusCode::OK, msg)
+    }
+}
+
+impl AdminApi {
+    async fn resize(&self, query: Option<&str>) -> Response<Body> {
+        let (width, height) = match parse_dimensions(query) {
+            Ok(dimensions) => dimensions,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+        let background = match parse_color(query, colors::WHITE) {
+            Ok(color) => color,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+
+        let (reply, response) = oneshot::channel();
+        let command = CanvasCommand::Resize {
+            width,
+            height,
+            background,
+            reply,
+        };
+        if self.commands.send(command).await.is_err() {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        match response.await {
+            Ok(ResizeResponse::Resized) => text(
+                StatusCode::OK,
+                format!("Canvas resized to {}x{}\n", width, height),
+            ),
+            Ok(ResizeResponse::TooSmall {
+                width: current_width,
+                height: current_height,
+            }) => text(
+                StatusCode::CONFLICT,
+                format!(
+                    "The {}x{} canvas can not shrink to {}x{}",
+                    current_width, current_height, width, height
+                ),
+            ),
+            Err(_) => text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable"),
+        }
+    }
+
+    async fn label(&self, query: Option<&str>) -> Response<Body> {
+        let label = match parse_label(query) {
+            Ok(label) => label,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::PlaceLabel { label, reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        match response.await {
+            Ok(true) if label.is_empty() => text(StatusCode::OK, "Label removed\n"),
+            Ok(true) => text(StatusCode::OK, "Label placed\n"),
+            Ok(false) => text(StatusCode::BAD_REQUEST, "Label is not within the canvas"),
+            Err(_) => text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable"),
+        }
+    }
+
+    async fn rasterise_labels(&self, query: Option<&str>) -> Response<Body> {
+        let color = match parse_color(query, colors::BLACK) {
+            Ok(color) => color,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::RasteriseLabels { color, reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(RasteriseResponse { labels, pixels }) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+        text(
+            StatusCode::OK,
+            format!("Rasterised {} labels ({} pixels)\n", labels, pixels),
+        )
+    }
+}
+
+impl AdminApi {
+    async fn fill(&self, query: Option<&str>, clear: bool) -> Response<Body> {
+        let region = match parse_fill_region(query) {
+            Ok(region) => region,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+        let color = if clear {
+            colors::WHITE
+        } else if query_pairs(query).any(|(key, _)| key == "color") {
+            match parse_color(query, colors::WHITE) {
+                Ok(color) => color,
+                Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+            }
+        } else {
+            return text(StatusCode::BAD_REQUEST, "color is required");
+        };
+
+        let (reply, response) = oneshot::channel();
+        let command = CanvasCommand::Fill {
+            region,
+            color,
+            reply,
+        };
+        if self.commands.send(command).await.is_err() {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        match response.await {
+            Ok(FillResponse::Filled { pixels }) => {
+                text(StatusCode::OK, format!("Painted {} pixels\n", pixels))
+            }
+            Ok(FillResponse::OutOfBounds { width, height }) => text(
+                StatusCode::BAD_REQUEST,
+                format!("Region is not within the {}x{} canvas", width, height),
+            ),
+            Err(_) => text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable"),
+        }
+    }
+
+    async fn set_frozen(&self, frozen: bool) -> Response<Body> {
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::SetFrozen { frozen, reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        match response.await {
+            Ok(true) if frozen => text(StatusCode::OK, "Canvas frozen\n"),
+            Ok(true) => text(StatusCode::OK, "Canvas unfrozen\n"),
+            Ok(false) if frozen => text(StatusCode::OK, "Canvas already frozen\n"),
+            Ok(false) => text(StatusCode::OK, "Canvas not frozen\n"),
+            Err(_) => text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable"),
+        }
+    }
+
+    async fn snapshot(&self) -> Response<Body> {
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::Snapshot { reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        match response.await {
+            Ok(SnapshotResponse::Written { sequence, path }) => text(
+                StatusCode::OK,
+                format!("Snapshot #{} written to {}\n", sequence, path.display()),
+            ),
+            Ok(SnapshotResponse::Disabled) => {
+                text(StatusCode::CONFLICT, "The canvas is not persisted")
+            }
+            Ok(SnapshotResponse::Busy) => text(
+                StatusCode::CONFLICT,
+                "Another snapshot is being written, retry later",
+            ),
+            Ok(SnapshotResponse::Failed) => text(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to write the snapshot",
+            ),
+            Err(_) => text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable"),
+        }
+    }
+}
+
+/// Check the bearer token of an admin request (`Authorization: Bearer <token>`).
+///
+/// Returns the `401 Unauthorized` response to send if the token is missing or wrong.
+pub fn unauthorized<B>(req: &Request<B>, token: &str) -> Option<Response<Body>> {
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => None,
+        _ => {
+            let mut response = text(StatusCode::UNAUTHORIZED, "Unauthorized");
+            response.headers_mut().insert(
+                header::WWW_AUTHENTICATE,
+                HeaderValue::from_static("Bearer realm=\"ipcanvas-admin\""),
+            );
+            Some(response)
+        }
+    }
+}
+
+/// Compare two byte strings in a time independent of their content.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Parse the region of a query string (`x=&y=&w=&h=`), the dimensions defaulting to 1.
+fn parse_fill_region(query: Option<&str>) -> Result<Region, String> {
+    let (mut x, mut y, mut width, mut height) = (None, None, Some(1), Some(1));
+    for (key, value) in query_pairs(query) {
+        let slot = match key {
+            "x" => &mut x,
+            "y" => &mut y,
+            "w" => &mut width,
+            "h" => &mut height,
+            _ => continue,
+        };
+        let value = value
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid value for {}: {:?}", key, value))?;
+        *slot = Some(value);
+    }
+    match (x, y, width, height) {
+        (Some(x), Some(y), Some(width), Some(height)) if width > 0 && height > 0 => Ok(Region {
+            x,
+            y,
+            width,
+            height,
+        }),
+        (Some(_), Some(_), _, _) => Err("The region is empty".to_string()),
+        _ => Err("x and y are required".to_string()),
+    }
+}
+
+/// Parse a label from a query string (`x=&y=&text=`).
+fn parse_label(query: Option<&str>) -> Result<Label, String> {
+    let (mut x, mut y, mut label_text) = (None, None, None);
+    for (key, value) in query_pairs(query) {
+        let invalid = || format!("Invalid value for {}: {:?}", key, value);
+        match key {
+            "x" => x = Some(value.parse::<u16>().map_err(|_| invalid())?),
+            "y" => y = Some(value.parse::<u16>().map_err(|_| invalid())?),
+            "text" => label_text = Some(percent_decode(value).ok_or_else(invalid)?),
+            _ => continue,
+        }
+    }
+    let (Some(x), Some(y), Some(label_text)) = (x, y, label_text) else {
+        return Err("x, y and text are required".to_string());
+    };
+    Label::new(x, y, &label_text)
+        .ok_or_else(|| format!("The text is longer than {} bytes", MAX_TEXT_LEN))
+}
+
+/// Parse the new dimensions of the canvas from a query string (`width=&height=`).
+fn parse_dimensions(query: Option<&str>) -> Result<(u16, u16), String> {
+    let (mut width, mut height) = (None, None);
+    for (key, value) in query_pairs(query) {
+        let slot = match key {
+            "width" => &mut width,
+            "height" => &mut height,
+            _ => continue,
+        };
+        let value = value
+            .parse::<u16>()
+            .ok()
+            .filter(|&value| value > 0)
+            .ok_or_else(|| format!("Invalid value for {}: {:?}", key, value))?;
+        *slot = Some(value);
+    }
+    match (width, height) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err("width and height are required".to_string()),
+    }
+}
+
+/// Parse the optional color of a query string (`color=` or `background=`).
+fn parse_color(query: Option<&str>, default: PixelColor) -> Result<PixelColor, String> {
+    let mut color = default;
+    for (key, value) in query_pairs(query) {
+        if key == "color" || key == "background" {
+            color = percent_decode(value)
+                .and_then(|value| PixelColor::from_hex(&value))
+                .ok_or_else(|| format!("Invalid value for {}: {:?}", key, value))?;
+        }
+    }
+    Ok(color)
+}
+
+/// Parse the placements to undo from a query string (`prefix=&from=&to=`).
+fn parse_rollback_filter(query: Option<&str>, now: u64) -> Result<RollbackFilter, String> {
+    let (mut prefix, mut from, mut to) = (None, None, None);
+    for (key, value) in query_pairs(query) {
+        let invalid = || format!("Invalid value for {}: {:?}", key, value);
+        match key {
+            "prefix" => {
+                let parsed = percent_decode(value)
+                    .and_then(|value| value.parse::<Ipv6Prefix>().ok())
+                    .filter(|prefix| prefix.prefix_len <= 128)
+                    .ok_or_else(invalid)?;
+                prefix = Some(parsed);
+            }
+            "from" => from = Some(value.parse::<u64>().map_err(|_| invalid())?),
+            "to" => to = Some(value.parse::<u64>().map_err(|_| invalid())?),
+            _ => continue,
+        }
+    }
+    let (Some(prefix), Some(from)) = (prefix, from) else {
+        return Err("prefix and from are required".to_string());
+    };
+    let to = to.unwrap_or(now);
+    if from >= to {
+        return Err("from must be before to".to_string());
+    }
+    Ok(RollbackFilter { prefix, from, to })
+}
+
+/// Parse the offset of an import from a query string (`x=&y=`).
+fn parse_offset(query: Option<&str>) -> Result<(u16, u16), String> {
+    let (mut x, mut y) = (0, 0);
+    for (key, value) in query_pairs(query) {
+        let slot = match key {
+            "x" => &mut x,
+            "y" => &mut y,
+            _ => continue,
+        };
+        *slot = value
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid value for {}: {:?}", key, value))?;
+    }
+    Ok((x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{Canvas, colors, image::encode_png};
+    use http_body_util::Full;
+
+    #[tokio::test]
+    async fn import_sends_image_to_canvas() {
+        let (sender, mut receiver) = mpsc::channel(4);
+        let api = AdminApi::new(sender);
+        let mut image = Canvas::new(2, 1);
+        image.set_pixel(1, 0, colors::RED).unwrap();
+        let png = Bytes::from(encode_png(&image).unwrap());
+
+        let canvas_task = tokio::spawn(async move {
+            let Some(CanvasCommand::Import { image, x, y, reply }) = receiver.recv().await else {
+                panic!("Expected an import command");
+            };
+            assert_eq!((x, y), (5, 7));
+            assert_eq!(image.get_pixel(1, 0), Some(colors::RED));
+            let _ = reply.send(ImportResponse {
+                applied: 2,
+                rejected: 0,
+            });
+        });
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/import?x=5&y=7")
+            .body(Full::new(png))
+            .unwrap();
+        let response = api.handle(req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Imported 2 pixels (0 outside of the canvas)\n");
+        canvas_task.await.unwrap();
+    }
+
+    #[test]
+    fn rollback_filter_query() {
+        let filter = parse_rollback_filter(Some("prefix=2001:db8::%2F48&from=10"), 99).unwrap();
+        assert_eq!(filter.prefix, "2001:db8::/48".parse().unwrap());
+        assert_eq!((filter.from, filter.to), (10, 99));
+
+        for query in [
+            "prefix=2001:db8::/48",
+            "from=10",
+            "prefix=2001:db8::/129&from=10",
+            "prefix=2001:db8::/48&from=10&to=10",
+        ] {
+            assert!(parse_rollback_filter(Some(query), 99).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn label_query() {
+        let label = parse_label(Some("x=3&y=4&text=hi%20there")).unwrap();
+        assert_eq!(label, Label::new(3, 4, "hi there").unwrap());
+        assert!(parse_label(Some("x=3&y=4&text=")).unwrap().is_empty());
+        for query in ["x=3&y=4", "x=3&y=4&text=123456789", "x=-1&y=4&text=a"] {
+            assert!(parse_label(Some(query)).is_err(), "{}", query);
+        }
+
+        assert_eq!(parse_color(None, colors::BLACK), Ok(colors::BLACK));
+        assert_eq!(
+            parse_color(Some("color=%23ff0000"), colors::BLACK),
+            Ok(colors::RED)
+        );
+        assert!(parse_color(Some("color=red"), colors::BLACK).is_err());
+    }
+
+    #[test]
+    fn fill_query() {
+        assert_eq!(
+            parse_fill_region(Some("x=3&y=4")),
+            Ok(Region {
+                x: 3,
+                y: 4,
+                width: 1,
+                height: 1
+            })
+        );
+        assert_eq!(
+            parse_fill_region(Some("x=3&y=4&w=10&h=2")).map(|r| (r.width, r.height)),
+            Ok((10, 2))
+        );
+        for query in ["x=3", "x=3&y=4&w=0", "x=3&y=4&h=-1"] {
+            assert!(parse_fill_region(Some(query)).is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn bearer_token() {
+        let request = |authorization: Option<&str>| {
+            let mut builder = Request::builder().uri("/snapshot");
+            if let Some(authorization) = authorization {
+                builder = builder.header(header::AUTHORIZATION, authorization);
+            }
+            builder.body(()).unwrap()
+        };
+        assert!(unauthorized(&request(Some("Bearer s3cret")), "s3cret").is_none());
+        for authorization in [
+            None,
+            Some("Bearer s3cre"),
+            Some("Basic s3cret"),
+            Some("s3cret"),
+        ] {
+            let response = unauthorized(&request(authorization), "s3cret").unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+        }
+    }
+
+    #[test]
+    fn resize_query() {
+        assert_eq!(
+            parse_dimensions(Some("width=8192&height=4096")),
+            Ok((8192, 4096))
+        );
+        for query in [
+            "width=8192",
+            "width=65536&height=4096",
+            "width=0&height=4096",
+            "width=-1&height=4096",
+        ] {
+            assert!(parse_dimensions(Some(query)).is_err(), "{}", query);
+        }
+    }
+
+    #[tokio::test]
+    async fn rollback_needs_history() {
+        let (sender, _receiver) = mpsc::channel(4);
+        let api = AdminApi::new(sender);
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/rollback?prefix=2001:db8::/48&from=0")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert_eq!(api.handle(req).await.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn import_rejects_invalid_requests() {
+        let (sender, _receiver) = mpsc::channel(4);
+        let api = AdminApi::new(sender);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/import")
+            .body(Full::new(Bytes::from_static(b"not a png")))
+            .unwrap();
+        assert_eq!(api.handle(req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/import?x=-1")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert_eq!(api.handle(req).await.status(), StatusCode::BAD_REQUEST);
+
+        let req = Request::builder()
+            .uri("/import")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        assert_eq!(
+            api.handle(req).await.status(),
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
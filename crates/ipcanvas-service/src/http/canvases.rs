@@ -0,0 +1,165 @@
+//! CanvasRouter: routing of the HTTP requests to the canvases of the instance.
+//!
+//! Each canvas is served under `/canvases/{id}/`, with the endpoints of its API
+//! (e.g. `GET /canvases/sandbox/canvas.png`). The other paths are served by the default
+//! canvas, so a single-canvas instance keeps its usual endpoints.
+//!
+//! `GET /canvases` lists the canvases of the instance, as JSON.
+
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use ipcanvas_ping_common::Ipv6Prefix;
+use serde_json::json;
+
+use super::{Body, json_response, method_not_allowed, text};
+
+/// Router of the HTTP requests to the API of their canvas (see the [module](self) documentation).
+///
+/// The first canvas is the default one.
+#[derive(Clone, Debug)]
+pub struct CanvasRouter<T> {
+    canvases: Vec<RoutedCanvas<T>>,
+}
+
+#[derive(Clone, Debug)]
+struct RoutedCanvas<T> {
+    id: String,
+    prefix: Option<Ipv6Prefix>,
+    api: T,
+}
+
+/// Outcome of the routing of a request.
+pub enum Routed<'a, T, B> {
+    /// The request is for a canvas, with the canvas part of its path removed.
+    Canvas(&'a T, Request<B>),
+    /// The request is answered by the router itself.
+    Response(Response<Body>),
+}
+
+impl<T> CanvasRouter<T> {
+    /// Create a router without canvas, answering 404 to every request.
+    pub fn new() -> Self {
+        Self {
+            canvases: Vec::new(),
+        }
+    }
+
+    /// Add a canvas, served by `api`, with the destination prefix of its pings.
+    pub fn with_canvas(
+        mut self,
+        id: impl Into<String>,
+        prefix: Option<Ipv6Prefix>,
+        api: T,
+    ) -> Self {
+        self.canvases.push(RoutedCanvas {
+            id: id.into(),
+            prefix,
+            api,
+        });
+        self
+    }
+
+    /// Route a request to the API of its canvas.
+    pub fn route<B>(&self, mut req: Request<B>) -> Routed<'_, T, B> {
+        let path = req.uri().path();
+        if path == "/canvases" || path == "/canvases/" {
+            if req.method() != Method::GET && req.method() != Method::HEAD {
+                return Routed::Response(method_not_allowed("GET, HEAD"));
+            }
+            return Routed::Response(self.list());
+        }
+        let Some(rest) = path.strip_prefix("/canvases/") else {
+            return match self.canvases.first() {
+                Some(canvas) => Routed::Canvas(&canvas.api, req),
+                None => Routed::Response(text(StatusCode::NOT_FOUND, "Not found")),
+            };
+        };
+
+        let (id, path) = match rest.find('/') {
+            Some(at) => rest.split_at(at),
+            None => (rest, "/"),
+        };
+        let Some(canvas) = self.canvases.iter().find(|canvas| canvas.id == id) else {
+            return Routed::Response(text(StatusCode::NOT_FOUND, "Unknown canvas"));
+        };
+        let uri = match req.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.to_string(),
+        };
+        match uri.parse::<Uri>() {
+            Ok(uri) => *req.uri_mut() = uri,
+            Err(_) => return Routed::Response(text(StatusCode::BAD_REQUEST, "Invalid path")),
+        }
+        Routed::Canvas(&canvas.api, req)
+    }
+
+    fn list(&self) -> Response<Body> {
+        let canvases: Vec<_> = self
+            .canvases
+            .iter()
+            .enumerate()
+            .map(|(i, canvas)| {
+                json!({
+                    "id": canvas.id,
+                    "prefix": canvas.prefix.map(|prefix| prefix.to_string()),
+                    "default": i == 0,
+                })
+            })
+            .collect();
+        json_response(json!({ "canvases": canvases }))
+    }
+}
+
+impl<T> Default for CanvasRouter<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    fn get(uri: &str) -> Request<()> {
+        Request::builder().uri(uri).body(()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn route_to_canvases() {
+        let router = CanvasRouter::new()
+            .with_canvas("main", None, 0)
+            .with_canvas("sandbox", Some("2001:db8:1::/48".parse().unwrap()), 1);
+        let routed = |uri: &str| match router.route(get(uri)) {
+            Routed::Canvas(&api, req) => Ok((api, req.uri().to_string())),
+            Routed::Response(response) => Err(response.status()),
+        };
+
+        assert_eq!(
+            routed("/canvas.png?x=1"),
+            Ok((0, "/canvas.png?x=1".to_string()))
+        );
+        assert_eq!(
+            routed("/canvases/sandbox/canvas.png?x=1"),
+            Ok((1, "/canvas.png?x=1".to_string()))
+        );
+        assert_eq!(
+            routed("/canvases/main/pixel/1/2"),
+            Ok((0, "/pixel/1/2".to_string()))
+        );
+        assert_eq!(routed("/canvases/sandbox"), Ok((1, "/".to_string())));
+        assert_eq!(routed("/canvases/other/stats"), Err(StatusCode::NOT_FOUND));
+
+        let Routed::Response(response) = router.route(get("/canvases")) else {
+            panic!("The router lists the canvases");
+        };
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "canvases": [
+                { "id": "main", "prefix": null, "default": true },
+                { "id": "sandbox", "prefix": "2001:db8:1::/48", "default": false },
+            ]})
+        );
+    }
+}
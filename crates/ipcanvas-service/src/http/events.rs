@@ -0,0 +1,644 @@
+//! `GET /events`: the diffs of the canvas, as a stream of Server-Sent Events.
+//!
+//! Each diff is sent as a `diff` event, identified by its id in the [DiffFeed]. The data of the
+//! event is the diff as JSON (`format=json`, the default):
+//!
+//! ```json
+//! {"resized":null,"pixels":[[3,4,"#ff0000"]],"labels":[{"x":8,"y":8,"text":"hello"}]}
+//! ```
+//!
+//! or encoded like the diffs of the other clients (see [Encoder](crate::canvas::encoding::Encoder)),
+//! in base64 (`format=binary`). Removed labels are given with an empty text.
+//!
+//! A client reconnecting with a `Last-Event-ID` header first receives the diffs it missed.
+//! When they are no longer known (or the client is too slow to follow), a `reset` event tells it
+//! to reload the whole canvas (`GET /canvas.png` and `GET /labels`) before applying the next diffs.
+//!
+//! # Viewports
+//!
+//! A client only looking at a part of the canvas can follow a [Viewport] instead, given as a
+//! region (`x=&y=&w=&h=`) or as a list of tiles (`tiles=0:0,1:0`, by tile column and row).
+//! The stream starts with a `viewport` event giving the id of the viewer:
+//!
+//! ```json
+//! {"id":"5f0c3e9a1b2d4c6e8f0a1b2c3d4e5f60","tile_size":256}
+//! ```
+//!
+//! The `diff` events then only carry the changes within the viewport (and the resizes of the
+//! canvas), and the viewport is moved with `POST /events/{id}?x=&y=&w=&h=` (or `?tiles=`).
+//! Each tile coming into view, and each tile of the viewport when the stream starts or is reset,
+//! is sent as a `tile` event, with its pixels as a PNG image in base64 and its labels:
+//!
+//! ```json
+//! {"tx":1,"ty":0,"x":256,"y":0,"width":256,"height":256,"png":"iVBORw0KGgo...","labels":[]}
+//! ```
+//!
+//! A comment is sent every [KEEP_ALIVE_INTERVAL] so idle connections are not closed by proxies.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use hyper::{HeaderMap, Response, StatusCode, header};
+use serde_json::json;
+use tokio::sync::{broadcast, broadcast::error::RecvError, mpsc, oneshot, watch};
+
+use super::{Body, HttpApi, parse_region, query_pairs, text};
+use crate::{
+    canvas::{
+        diff::CanvasDiff,
+        encoding::Encoder,
+        image,
+        tile::{TILE_SIZE, TileId},
+    },
+    command::{CanvasCommand, TileSnapshot},
+    feed::{DiffFeed, FeedEntry, Resume},
+    metrics::Metrics,
+    viewport::Viewport,
+};
+
+/// Interval between two keep-alive comments of an event stream.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+// Number of events buffered for a slow client, before it lags behind the feed
+const STREAM_BUFFER_SIZE: usize = 16;
+
+/// Format of the data of the `diff` events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventFormat {
+    #[default]
+    Json,
+    /// The encoded diff, in base64
+    Binary,
+}
+
+impl EventFormat {
+    /// Parse the name of a format, as given to `format=`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(EventFormat::Json),
+            "binary" => Some(EventFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// Registry of the streams following a viewport, by viewer id.
+///
+/// It is cheap to clone, the clones share the same registry.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Viewers {
+    inner: Arc<Mutex<HashMap<String, watch::Sender<Viewport>>>>,
+}
+
+/// Registration of a viewer, removed from the registry when dropped.
+struct ViewerRegistration {
+    id: String,
+    viewers: Viewers,
+}
+
+impl Viewers {
+    /// Register a new viewer, returns None if no id could be drawn.
+    fn register(
+        &self,
+        viewport: Viewport,
+    ) -> Option<(ViewerRegistration, watch::Receiver<Viewport>)> {
+        let mut id = [0u8; 16];
+        // The id is all it takes to move the viewport, it must not be guessed
+        getrandom::fill(&mut id).ok()?;
+        let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+        let (sender, receiver) = watch::channel(viewport);
+        self.lock().insert(id.clone(), sender);
+        let registration = ViewerRegistration {
+            id,
+            viewers: self.clone(),
+        };
+        Some((registration, receiver))
+    }
+
+    /// Move the viewport of a viewer, returns false if it is unknown.
+    fn move_to(&self, id: &str, viewport: Viewport) -> bool {
+        match self.lock().get(id) {
+            Some(sender) => {
+                sender.send_replace(viewport);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, watch::Sender<Viewport>>> {
+        self.inner.lock().expect("viewers lock is not poisoned")
+    }
+}
+
+impl Drop for ViewerRegistration {
+    fn drop(&mut self) {
+        self.viewers.lock().remove(&self.id);
+    }
+}
+
+/// A viewer counted in the `ipcanvas_viewers` gauge, until dropped.
+struct ConnectedViewer(Arc<Metrics>);
+
+impl ConnectedViewer {
+    fn new(metrics: Arc<Metrics>) -> Self {
+        metrics.viewers.inc();
+        Self(metrics)
+    }
+}
+
+impl Drop for ConnectedViewer {
+    fn drop(&mut self) {
+        self.0.viewers.dec();
+    }
+}
+
+impl HttpApi {
+    /// Answer a `GET /events` request, following `feed` until the client disconnects.
+    pub(super) fn events(
+        &self,
+        feed: &DiffFeed,
+        query: Option<&str>,
+        headers: &HeaderMap,
+    ) -> Response<Body> {
+        let mut format = EventFormat::default();
+        for (key, value) in query_pairs(query) {
+            if key == "format" {
+                let Some(parsed) = EventFormat::from_name(value) else {
+                    return text(
+                        StatusCode::BAD_REQUEST,
+                        format!("Invalid value for format: {:?} (json or binary)", value),
+                    );
+                };
+                format = parsed;
+            }
+        }
+        let viewport = match parse_viewport(query) {
+            Ok(viewport) => viewport,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+        let last_id = headers
+            .get("last-event-id")
+            .and_then(|value| value.to_str().ok());
+
+        let (chunks, body) = mpsc::channel(STREAM_BUFFER_SIZE);
+        let mut stream = EventStream {
+            feed: feed.clone(),
+            format,
+            commands: self.commands.clone(),
+            viewport: None,
+            chunks,
+        };
+        let viewer = match viewport {
+            Some(viewport) => {
+                let Some(viewer) = self.viewers.register(viewport.clone()) else {
+                    return text(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to register the viewer",
+                    );
+                };
+                stream.viewport = Some(viewport);
+                Some(viewer)
+            }
+            None => None,
+        };
+        let (mut resume, entries) = feed.follow(last_id);
+        if stream.viewport.is_some() && last_id.is_none() {
+            // A new viewer starts with the tiles of its viewport
+            resume = Resume::Reset;
+        }
+        let connected = self.metrics.clone().map(ConnectedViewer::new);
+        tokio::spawn(async move {
+            stream.run(resume, entries, viewer).await;
+            drop(connected);
+        });
+
+        Response::builder()
+            .header(header::CONTENT_TYPE, "text/event-stream")
+            .header(header::CACHE_CONTROL, "no-store")
+            .body(Body::stream(body))
+            .expect("valid response")
+    }
+
+    /// Answer a `POST /events/{id}` request, moving the viewport of a viewer.
+    pub(super) fn move_viewport(&self, id: &str, query: Option<&str>) -> Response<Body> {
+        let viewport = match parse_viewport(query) {
+            Ok(Some(viewport)) => viewport,
+            Ok(None) => {
+                return text(
+                    StatusCode::BAD_REQUEST,
+                    "x, y, w and h, or tiles, are required to move the viewport",
+                );
+            }
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+        if self.viewers.move_to(id, viewport) {
+            Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::default())
+                .expect("valid response")
+        } else {
+            text(StatusCode::NOT_FOUND, "Unknown viewer")
+        }
+    }
+}
+
+/// An event stream, sending its events to `chunks`.
+struct EventStream {
+    feed: DiffFeed,
+    format: EventFormat,
+    commands: mpsc::Sender<CanvasCommand>,
+    // The whole canvas is followed if None
+    viewport: Option<Viewport>,
+    chunks: mpsc::Sender<Bytes>,
+}
+
+impl EventStream {
+    /// Send the events until the client disconnects (or the canvas is gone).
+    async fn run(
+        mut self,
+        resume: Resume,
+        mut entries: broadcast::Receiver<Arc<FeedEntry>>,
+        mut viewer: Option<(ViewerRegistration, watch::Receiver<Viewport>)>,
+    ) -> Option<()> {
+        if let Some((registration, _)) = &viewer {
+            let data = json!({ "id": registration.id, "tile_size": TILE_SIZE });
+            self.send(format!("event: viewport\ndata: {}\n\n", data).into())
+                .await?;
+        }
+        match resume {
+            Resume::Live => {}
+            Resume::Backlog(backlog) => {
+                for entry in backlog {
+                    self.send_diff(&entry).await?;
+                }
+            }
+            Resume::Reset => self.reset().await?,
+        }
+
+        let start = tokio::time::Instant::now() + KEEP_ALIVE_INTERVAL;
+        let mut keep_alive = tokio::time::interval_at(start, KEEP_ALIVE_INTERVAL);
+        loop {
+            tokio::select! {
+                entry = entries.recv() => match entry {
+                    Ok(entry) => self.send_diff(&entry).await?,
+                    Err(RecvError::Lagged(_)) => self.reset().await?,
+                    Err(RecvError::Closed) => return None,
+                },
+                moved = async { viewer.as_mut()?.1.changed().await.ok() }, if viewer.is_some() => {
+                    moved?;
+                    let next = viewer.as_mut()?.1.borrow_and_update().clone();
+                    let previous = self.viewport.replace(next.clone()).unwrap_or_default();
+                    self.send_tiles(previous.entering(&next).collect()).await?;
+                }
+                _ = keep_alive.tick() => self.send(Bytes::from_static(b": keep-alive\n\n")).await?,
+                _ = self.chunks.closed() => return None,
+            }
+        }
+    }
+
+    async fn send(&self, chunk: Bytes) -> Option<()> {
+        self.chunks.send(chunk).await.ok()
+    }
+
+    /// Send a diff of the feed, restricted to the viewport.
+    async fn send_diff(&self, entry: &FeedEntry) -> Option<()> {
+        let event = match &self.viewport {
+            Some(viewport) => match viewport.filter(&entry.diff) {
+                Some(diff) => diff_event(&self.feed, entry.sequence, &diff, self.format),
+                // Nothing changed in view
+                None => return Some(()),
+            },
+            None => diff_event(&self.feed, entry.sequence, &entry.diff, self.format),
+        };
+        self.send(event).await
+    }
+
+    /// Get the client to reload what it follows: the tiles of its viewport, or the whole canvas.
+    async fn reset(&self) -> Option<()> {
+        match &self.viewport {
+            Some(viewport) => self.send_tiles(viewport.tiles().collect()).await,
+            None => {
+                self.send(Bytes::from_static(b"event: reset\ndata: {}\n\n"))
+                    .await
+            }
+        }
+    }
+
+    /// Send the given tiles, skipping the ones which are not within the canvas.
+    async fn send_tiles(&self, tiles: Vec<TileId>) -> Option<()> {
+        if tiles.is_empty() {
+            return Some(());
+        }
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(CanvasCommand::Tiles { tiles, reply })
+            .await
+            .ok()?;
+        for snapshot in response.await.ok()? {
+            let event = tokio::task::spawn_blocking(move || tile_event(&snapshot))
+                .await
+                .ok()??;
+            self.send(event).await?;
+        }
+        Some(())
+    }
+}
+
+/// Parse the viewport of a query (`x=&y=&w=&h=` or `tiles=`), if any.
+fn parse_viewport(query: Option<&str>) -> Result<Option<Viewport>, String> {
+    let region = parse_region(query)?;
+    let tiles = query_pairs(query).find(|(key, _)| *key == "tiles");
+    let viewport = match (region, tiles) {
+        (None, None) => return Ok(None),
+        (Some(_), Some(_)) => return Err("Either a region or tiles are expected".to_string()),
+        (Some(region), None) => Viewport::from_region(region),
+        (None, Some((_, tiles))) => {
+            let tiles = tiles
+                .split(',')
+                .map(|tile| {
+                    let (tx, ty) = tile.split_once(':')?;
+                    Some(TileId {
+                        tx: tx.parse().ok()?,
+                        ty: ty.parse().ok()?,
+                    })
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("Invalid value for tiles: {:?}", tiles))?;
+            Viewport::from_tiles(tiles)
+        }
+    };
+    viewport.map(Some).map_err(|e| e.to_string())
+}
+
+/// Describe a diff as JSON.
+fn diff_json(diff: &CanvasDiff) -> serde_json::Value {
+    let pixels: Vec<_> = diff
+        .changed_pixels()
+        .map(|pixel| json!([pixel.x, pixel.y, pixel.color.to_hex()]))
+        .collect();
+    let labels: Vec<_> = diff
+        .changed_labels()
+        .map(|label| json!({ "x": label.x, "y": label.y, "text": label.text() }))
+        .collect();
+    json!({
+        "resized": diff.resized().map(|resize| json!({
+            "width": resize.width,
+            "height": resize.height,
+            "background": resize.background.to_hex(),
+        })),
+        "pixels": pixels,
+        "labels": labels,
+    })
+}
+
+/// Build the `diff` event of a diff of the feed.
+fn diff_event(feed: &DiffFeed, sequence: u64, diff: &CanvasDiff, format: EventFormat) -> Bytes {
+    let data = match format {
+        EventFormat::Json => diff_json(diff).to_string(),
+        EventFormat::Binary => STANDARD.encode(Encoder::default().encode_diff(diff)),
+    };
+    Bytes::from(format!(
+        "id: {}\nevent: diff\ndata: {}\n\n",
+        feed.id(sequence),
+        data
+    ))
+}
+
+/// Build the `tile` event of a tile, or None if it could not be encoded.
+fn tile_event(snapshot: &TileSnapshot) -> Option<Bytes> {
+    let png = image::encode_png(&snapshot.canvas).ok()?;
+    let labels: Vec<_> = snapshot
+        .labels
+        .iter()
+        .map(|label| json!({ "x": label.x, "y": label.y, "text": label.text() }))
+        .collect();
+    let data = json!({
+        "tx": snapshot.tile.tx,
+        "ty": snapshot.tile.ty,
+        "x": snapshot.region.x,
+        "y": snapshot.region.y,
+        "width": snapshot.region.width,
+        "height": snapshot.region.height,
+        "png": STANDARD.encode(png),
+        "labels": labels,
+    });
+    Some(format!("event: tile\ndata: {}\n\n", data).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{Canvas, colors, encoding, label::Label};
+    use http_body_util::BodyExt;
+    use hyper::Request;
+
+    fn diff(x: u16) -> CanvasDiff {
+        let mut canvas = Canvas::new(16, 16);
+        canvas.take_diff();
+        canvas.set_pixel(x, 0, colors::RED).unwrap();
+        canvas.take_diff()
+    }
+
+    fn get(uri: &str, last_id: Option<&str>) -> Request<()> {
+        let mut req = Request::builder().uri(uri);
+        if let Some(last_id) = last_id {
+            req = req.header("Last-Event-ID", last_id);
+        }
+        req.body(()).unwrap()
+    }
+
+    /// Read the next event of a stream.
+    async fn next_event(body: &mut Body) -> String {
+        let frame = body.frame().await.unwrap().unwrap();
+        String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn events() {
+        let (commands, _) = mpsc::channel(1);
+        let feed = DiffFeed::new();
+        let api = HttpApi::new(commands).with_feed(feed.clone());
+
+        let response = api.handle(&get("/events", None)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        let mut body = response.into_body();
+        feed.publish(diff(3));
+        feed.publish(diff(4));
+        let event = next_event(&mut body).await;
+        let (head, data) = event.split_once("data: ").unwrap();
+        assert_eq!(head, format!("id: {}\nevent: diff\n", feed.id(1)));
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(data.trim_end()).unwrap(),
+            json!({ "resized": null, "pixels": [[3, 0, "#ff0000"]], "labels": [] })
+        );
+        assert!(event.ends_with("\n\n"));
+        assert!(
+            next_event(&mut body)
+                .await
+                .starts_with(&format!("id: {}\n", feed.id(2)))
+        );
+
+        // Resume after the first diff, in binary
+        let response = api
+            .handle(&get("/events?format=binary", Some(&feed.id(1))))
+            .await;
+        let mut body = response.into_body();
+        let event = next_event(&mut body).await;
+        let data = event
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let decoded = encoding::decode_diff(&STANDARD.decode(data).unwrap()).unwrap();
+        assert_eq!(decoded, diff(4));
+
+        // The diffs of another run are unknown
+        let response = api.handle(&get("/events", Some("0-1"))).await;
+        let mut body = response.into_body();
+        assert_eq!(next_event(&mut body).await, "event: reset\ndata: {}\n\n");
+
+        let response = api.handle(&get("/events?format=xml", None)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = HttpApi::new(mpsc::channel(1).0)
+            .handle(&get("/events", None))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn events_count_viewers() {
+        let metrics = Arc::new(Metrics::new());
+        let api = HttpApi::new(mpsc::channel(1).0)
+            .with_feed(DiffFeed::new())
+            .with_metrics(metrics.clone());
+
+        let body = api.handle(&get("/events", None)).await.into_body();
+        assert_eq!(metrics.viewers.get(), 1);
+        let response = api.handle(&get("/canvas.png", None)).await;
+        drop(response);
+        assert_eq!(
+            metrics.viewers.get(),
+            1,
+            "Only the event streams are viewers"
+        );
+
+        drop(body);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while metrics.viewers.get() != 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    /// Read the data of the next event of a stream, which should be of the given type.
+    async fn next_data(body: &mut Body, kind: &str) -> serde_json::Value {
+        let event = next_event(body).await;
+        let data = event
+            .strip_prefix(&format!("event: {}\ndata: ", kind))
+            .unwrap_or_else(|| panic!("expected a {} event, got {:?}", kind, event));
+        serde_json::from_str(data.trim_end()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn viewport_events() {
+        let mut canvas = Canvas::new(512, 300);
+        canvas.set_pixel(300, 10, colors::RED).unwrap();
+        canvas
+            .set_label(Label::new(260, 20, "hello").unwrap())
+            .unwrap();
+        canvas.take_diff();
+        let (commands, mut receiver) = mpsc::channel(4);
+        let tiles_canvas = canvas.clone();
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                if let CanvasCommand::Tiles { tiles, reply } = command {
+                    let _ = reply.send(TileSnapshot::list(&tiles_canvas, &tiles));
+                }
+            }
+        });
+        let feed = DiffFeed::new();
+        let api = HttpApi::new(commands).with_feed(feed.clone());
+
+        let response = api.handle(&get("/events?x=0&y=0&w=10&h=10", None)).await;
+        let mut body = response.into_body();
+        let viewport = next_data(&mut body, "viewport").await;
+        assert_eq!(viewport["tile_size"], 256);
+        let id = viewport["id"].as_str().unwrap().to_string();
+        let tile = next_data(&mut body, "tile").await;
+        assert_eq!(
+            (&tile["tx"], &tile["ty"], &tile["width"], &tile["height"]),
+            (&json!(0), &json!(0), &json!(256), &json!(256))
+        );
+        assert!(STANDARD.decode(tile["png"].as_str().unwrap()).is_ok());
+
+        // Only the changes in view are sent
+        canvas.set_pixel(3, 0, colors::BLUE).unwrap();
+        canvas.set_pixel(301, 0, colors::BLUE).unwrap();
+        feed.publish(canvas.take_diff());
+        canvas.set_pixel(302, 0, colors::BLUE).unwrap();
+        feed.publish(canvas.take_diff());
+        canvas.set_pixel(4, 0, colors::BLUE).unwrap();
+        feed.publish(canvas.take_diff());
+        let event = next_event(&mut body).await;
+        assert!(event.starts_with(&format!("id: {}\n", feed.id(1))));
+        assert!(event.contains(r##""pixels":[[3,0,"#0000ff"]]"##));
+        let event = next_event(&mut body).await;
+        assert!(event.starts_with(&format!("id: {}\n", feed.id(3))));
+
+        // Panning to the right sends the new tile, the bottom one is out of the canvas
+        let post = |uri: &str| Request::post(uri).body(()).unwrap();
+        let uri = format!("/events/{}?tiles=0:0,1:0,1:2", id);
+        let response = api.handle(&post(&uri)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let tile = next_data(&mut body, "tile").await;
+        assert_eq!((&tile["tx"], &tile["x"]), (&json!(1), &json!(256)));
+        assert_eq!(
+            tile["labels"],
+            json!([{ "x": 260, "y": 20, "text": "hello" }])
+        );
+        canvas.set_pixel(303, 0, colors::BLUE).unwrap();
+        feed.publish(canvas.take_diff());
+        assert!(
+            next_event(&mut body)
+                .await
+                .starts_with(&format!("id: {}\n", feed.id(4)))
+        );
+
+        let response = api.handle(&post("/events/nope?tiles=0:0")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = api.handle(&post(&format!("/events/{}", id))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        for uri in [
+            "/events?tiles=0:0&x=0&y=0&w=1&h=1",
+            "/events?tiles=0-0",
+            "/events?x=0&y=0&w=4096&h=4096",
+            "/events?x=0&y=0&w=0&h=1",
+        ] {
+            let response = api.handle(&get(uri, None)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        let response = api.handle(&get(&format!("/events/{}", id), None)).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        // The viewer is forgotten with its stream
+        drop(body);
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while api.viewers.lock().contains_key(&id) {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+//! InstanceApi: operator actions on the whole instance, served by the admin service.
+//!
+//! Endpoints:
+//! - `GET /connections`: the open connections from ping listeners, as JSON.
+//! - `DELETE /connections/{id}`: close a connection from a ping listener. The listener may
+//!   connect again, the disconnection is recorded in the event log of every canvas.
+//! - `GET /config`: the configuration of the instance, as JSON.
+//!
+//! The other admin endpoints are served by the [AdminApi](super::admin::AdminApi) of each canvas.
+
+use hyper::{Method, Request, Response, StatusCode};
+use serde_json::json;
+use tokio::sync::mpsc;
+use tracing::info;
+
+use crate::{
+    command::CanvasCommand,
+    connections::{ConnectionInfo, PingConnections},
+    events::AdminAction,
+    http::{Body, json_response, method_not_allowed, text},
+};
+
+/// Handler of the admin requests about the whole instance.
+///
+/// It is cheap to clone, and meant to be shared between the HTTP connections.
+#[derive(Clone, Debug)]
+pub struct InstanceApi {
+    connections: PingConnections,
+    // Configuration of the instance, as served by `GET /config`
+    config: serde_json::Value,
+    // Canvas tasks recording the operator actions
+    canvases: Vec<mpsc::Sender<CanvasCommand>>,
+}
+
+impl InstanceApi {
+    /// Create a new handler, recording its actions in the event logs of `canvases`.
+    pub fn new(
+        connections: PingConnections,
+        config: serde_json::Value,
+        canvases: Vec<mpsc::Sender<CanvasCommand>>,
+    ) -> Self {
+        Self {
+            connections,
+            config,
+            canvases,
+        }
+    }
+
+    /// Handle an admin request, None if the path is not an endpoint of the instance.
+    pub async fn handle<B>(&self, req: &Request<B>) -> Option<Response<Body>> {
+        let path = req.uri().path();
+        let response = match path {
+            "/connections" => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return Some(method_not_allowed("GET, HEAD"));
+                }
+                self.list_connections()
+            }
+            path if path.starts_with("/connections/") => {
+                if req.method() != Method::DELETE {
+                    return Some(method_not_allowed("DELETE"));
+                }
+                self.disconnect(&path["/connections/".len()..]).await
+            }
+            "/config" => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return Some(method_not_allowed("GET, HEAD"));
+                }
+                json_response(self.config.clone())
+            }
+            _ => return None,
+        };
+        Some(response)
+    }
+
+    fn list_connections(&self) -> Response<Body> {
+        let connections: Vec<_> = self
+            .connections
+            .list()
+            .iter()
+            .map(|connection| {
+                json!({
+                    "id": connection.id,
+                    "peer": connection.peer.to_string(),
+                    "connected_at": connection.connected_at,
+                })
+            })
+            .collect();
+        json_response(json!({ "connections": connections }))
+    }
+
+    async fn disconnect(&self, id: &str) -> Response<Body> {
+        let Ok(id) = id.parse::<u64>() else {
+            return text(StatusCode::BAD_REQUEST, "Expected /connections/{id}");
+        };
+        let Some(ConnectionInfo { peer, .. }) = self.connections.disconnect(id) else {
+            return text(StatusCode::NOT_FOUND, "No such connection");
+        };
+        info!(
+            "Ping connection #{} from {} closed by the operator",
+            id, peer
+        );
+        for canvas in &self.canvases {
+            let action = AdminAction::Disconnect { peer };
+            let _ = canvas.send(CanvasCommand::Mark { action }).await;
+        }
+        text(
+            StatusCode::OK,
+            format!("Connection #{} from {} closed\n", id, peer),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn list_and_disconnect() {
+        let connections = PingConnections::new();
+        let (sender, mut receiver) = mpsc::channel(4);
+        let api = InstanceApi::new(
+            connections.clone(),
+            json!({ "ping_addr": "[::]:7894" }),
+            vec![sender],
+        );
+        let mut registration = connections.register("192.0.2.1:4242".parse().unwrap(), 10);
+        let request = |method: Method, uri: &str| {
+            Request::builder().method(method).uri(uri).body(()).unwrap()
+        };
+
+        let response = api
+            .handle(&request(Method::GET, "/connections"))
+            .await
+            .unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "connections": [{ "id": 1, "peer": "192.0.2.1:4242", "connected_at": 10 }] })
+        );
+
+        let uri = format!("/connections/{}", registration.info.id);
+        let response = api.handle(&request(Method::DELETE, &uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(registration.disconnected.try_recv(), Ok(()));
+        let Some(CanvasCommand::Mark { action }) = receiver.recv().await else {
+            panic!("The disconnection is recorded");
+        };
+        assert_eq!(
+            action,
+            AdminAction::Disconnect {
+                peer: "192.0.2.1:4242".parse().unwrap()
+            }
+        );
+        let response = api.handle(&request(Method::DELETE, &uri)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = api.handle(&request(Method::GET, "/config")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(api.handle(&request(Method::GET, "/stats")).await.is_none());
+    }
+}
@@ -0,0 +1,1149 @@
+//! HttpApi: read-only access to the canvas over HTTP.
+//!
+//! Endpoints:
+//! - `GET /canvas.png`: the whole canvas, as a PNG image.
+//! - `GET /canvas.png?x=&y=&w=&h=`: a region of the canvas, as a PNG image.
+//! - `GET /pixel/{x}/{y}`: the color of a pixel, when it was placed and by whom, as JSON.
+//! - `GET /labels`: the labels of the canvas, from the bottom one to the top one, as JSON.
+//! - `GET /labels?x=&y=&w=&h=`: the labels anchored within a region of the canvas, as JSON.
+//! - `GET /stats`: counters of the applied and rejected events, as JSON.
+//! - `GET /stats/players`, `GET /stats/colors` and `GET /stats/hours`: the leaderboard of the
+//!   players, the most placed colors and the pixels placed per hour, over the hours overlapping
+//!   an optional time range (`from=&to=`, in ms since the Unix epoch), as JSON pages
+//!   (`offset=&limit=`). The players are ranked by placed pixels, or by surviving pixels with
+//!   `sort=surviving`, and identified by their source prefix.
+//! - `GET /heatmap.png?window=`: where the players placed their pixels over the last hour (`1h`),
+//!   the last day (`24h`, the default) or since the canvas exists (`all`), as a PNG image with a
+//!   pixel per [cell](crate::heatmap::CELL_SIZE) of the canvas.
+//! - `GET /heatmap?window=`: the same counts, per cell, as JSON.
+//! - `GET /events?format=`: the diffs of the canvas, as a stream of Server-Sent Events
+//!   (see [events]).
+//! - `GET /events?x=&y=&w=&h=` or `GET /events?tiles=`: the same, restricted to a viewport,
+//!   which is moved with `POST /events/{id}?x=&y=&w=&h=` (or `?tiles=`).
+//! - `GET /metrics`: metrics of the whole service, in the Prometheus text format
+//!   (see [serve_metrics]).
+//!
+//! Images carry an ETag derived from the version of the requested region, so conditional
+//! requests (`If-None-Match`) for an unchanged region are answered with `304 Not Modified`.
+//!
+//! The origin of a pixel (or label) placed by a ping is only given as a prefix of the address of its
+//! sender, truncated to [HttpApi::with_source_prefix_len] bits.
+//!
+//! Operator actions are served separately, by the [AdminApi](admin::AdminApi) and the
+//! [InstanceApi](instance::InstanceApi). When the instance serves several canvases, the requests
+//! are routed to their canvas by a [CanvasRouter](canvases::CanvasRouter).
+
+pub mod admin;
+pub mod canvases;
+pub mod events;
+pub mod instance;
+
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    body::{Body as HttpBody, Frame, SizeHint},
+    header::{self, HeaderValue},
+};
+use serde_json::json;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    activity::PlayerOrder,
+    canvas::{
+        Region, image,
+        provenance::{Placement, SOURCE_PREFIX_LEN},
+    },
+    command::{CanvasCommand, CanvasStats, CropResponse, LabelInfo, PixelInfo},
+    events::EventSource,
+    feed::DiffFeed,
+    heatmap::{CELL_SIZE, HeatmapWindow},
+    metrics::Metrics,
+    persistence,
+};
+
+/// Default length of the source prefixes disclosed by the [HttpApi], in bits.
+pub const DEFAULT_SOURCE_PREFIX_LEN: u8 = 48;
+/// Number of items of the pages of statistics, unless requested otherwise.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+/// Maximum number of items of the pages of statistics.
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+/// Body of the responses of the [HttpApi]: either whole, or streamed (e.g. by `/events`).
+#[derive(Debug, Default)]
+pub struct Body(BodyKind);
+
+#[derive(Debug)]
+enum BodyKind {
+    Full(Full<Bytes>),
+    Stream(mpsc::Receiver<Bytes>),
+}
+
+impl Default for BodyKind {
+    fn default() -> Self {
+        BodyKind::Full(Full::default())
+    }
+}
+
+impl Body {
+    /// Stream the chunks received from `chunks`, until it is closed.
+    pub(crate) fn stream(chunks: mpsc::Receiver<Bytes>) -> Self {
+        Body(BodyKind::Stream(chunks))
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(bytes: Bytes) -> Self {
+        Body(BodyKind::Full(Full::new(bytes)))
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::from(Bytes::from(bytes))
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::from(Bytes::from(text))
+    }
+}
+
+impl From<&'static str> for Body {
+    fn from(text: &'static str) -> Self {
+        Body::from(Bytes::from_static(text.as_bytes()))
+    }
+}
+
+impl HttpBody for Body {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        match &mut self.get_mut().0 {
+            BodyKind::Full(full) => Pin::new(full).poll_frame(cx),
+            BodyKind::Stream(chunks) => chunks
+                .poll_recv(cx)
+                .map(|chunk| chunk.map(|chunk| Ok(Frame::data(chunk)))),
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        match &self.0 {
+            BodyKind::Full(full) => full.is_end_stream(),
+            BodyKind::Stream(_) => false,
+        }
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        match &self.0 {
+            BodyKind::Full(full) => full.size_hint(),
+            BodyKind::Stream(_) => SizeHint::default(),
+        }
+    }
+}
+
+/// Handler of the HTTP requests.
+///
+/// It is cheap to clone, and meant to be shared between the HTTP connections.
+#[derive(Clone, Debug)]
+pub struct HttpApi {
+    commands: mpsc::Sender<CanvasCommand>,
+    // Distinguishes the ETags of this process from the ones of a previous run,
+    // as the canvas version may start over when the canvas is not persisted.
+    instance: u64,
+    // Number of bits of the source addresses disclosed
+    source_prefix_len: u8,
+    feed: Option<DiffFeed>,
+    viewers: events::Viewers,
+    // Counts the viewers following `/events`
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl HttpApi {
+    /// Create a new handler, sending its requests to the canvas task through `commands`.
+    pub fn new(commands: mpsc::Sender<CanvasCommand>) -> Self {
+        Self {
+            commands,
+            instance: persistence::now_millis(),
+            source_prefix_len: DEFAULT_SOURCE_PREFIX_LEN,
+            feed: None,
+            viewers: events::Viewers::default(),
+            metrics: None,
+        }
+    }
+
+    /// Set the number of bits of the source addresses disclosed by `/pixel/{x}/{y}` and `/labels`
+    /// (at most [SOURCE_PREFIX_LEN], the prefix of the sources kept by the canvas).
+    ///
+    /// With 0, only the kind of source (ping or import) is disclosed.
+    pub fn with_source_prefix_len(mut self, source_prefix_len: u8) -> Self {
+        self.source_prefix_len = source_prefix_len.min(SOURCE_PREFIX_LEN);
+        self
+    }
+
+    /// Serve the diffs published to `feed` on `/events`.
+    ///
+    /// Without a feed, `/events` is not found.
+    pub fn with_feed(mut self, feed: DiffFeed) -> Self {
+        self.feed = Some(feed);
+        self
+    }
+
+    /// Count the viewers following `/events` in the `ipcanvas_viewers` gauge of `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Handle an HTTP request.
+    ///
+    /// The request body is ignored, all the endpoints are read-only (moving a viewport only
+    /// changes what its viewer is sent).
+    pub async fn handle<B>(&self, req: &Request<B>) -> Response<Body> {
+        match req.uri().path() {
+            "/canvas.png" => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return method_not_allowed("GET, HEAD");
+                }
+                self.canvas_png(req.uri().query(), req.headers()).await
+            }
+            path if path.starts_with("/pixel/") => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return method_not_allowed("GET, HEAD");
+                }
+                self.pixel(&path["/pixel/".len()..]).await
+            }
+            "/labels" => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return method_not_allowed("GET, HEAD");
+                }
+                self.labels(req.uri().query()).await
+            }
+            "/stats" => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return method_not_allowed("GET, HEAD");
+                }
+                self.stats().await
+            }
+            "/heatmap.png" | "/heatmap" => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return method_not_allowed("GET, HEAD");
+                }
+                self.heatmap(req.uri().query(), req.uri().path() == "/heatmap.png")
+                    .await
+            }
+            path if path.starts_with("/stats/") => {
+                if req.method() != Method::GET && req.method() != Method::HEAD {
+                    return method_not_allowed("GET, HEAD");
+                }
+                self.activity(&path["/stats/".len()..], req.uri().query())
+                    .await
+            }
+            "/events" if self.feed.is_some() => {
+                if req.method() != Method::GET {
+                    return method_not_allowed("GET");
+                }
+                let feed = self.feed.as_ref().expect("feed is set");
+                self.events(feed, req.uri().query(), req.headers())
+            }
+            path if path.starts_with("/events/") && self.feed.is_some() => {
+                if req.method() != Method::POST {
+                    return method_not_allowed("POST");
+                }
+                self.move_viewport(&path["/events/".len()..], req.uri().query())
+            }
+            _ => text(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+
+    async fn canvas_png(&self, query: Option<&str>, headers: &HeaderMap) -> Response<Body> {
+        let region = match parse_region(query) {
+            Ok(region) => region,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+        let unless_version = self.if_none_match(headers);
+
+        let (reply, response) = oneshot::channel();
+        let command = CanvasCommand::Crop {
+            region,
+            unless_version,
+            reply,
+        };
+        if self.commands.send(command).await.is_err() {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(response) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+
+        match response {
+            CropResponse::OutOfBounds { width, height } => text(
+                StatusCode::BAD_REQUEST,
+                format!("Region is not within the {}x{} canvas", width, height),
+            ),
+            CropResponse::NotModified { version } => Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, self.etag(version))
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(Body::default())
+                .expect("valid response"),
+            CropResponse::Cropped { version, canvas } => {
+                // Encoding a large canvas takes a while, keep it off the runtime threads
+                let encoded = tokio::task::spawn_blocking(move || image::encode_png(&canvas)).await;
+                let Ok(Ok(png)) = encoded else {
+                    return text(StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image");
+                };
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "image/png")
+                    .header(header::ETAG, self.etag(version))
+                    .header(header::CACHE_CONTROL, "no-cache")
+                    .body(Body::from(png))
+                    .expect("valid response")
+            }
+        }
+    }
+
+    async fn pixel(&self, coordinates: &str) -> Response<Body> {
+        let Some((x, y)) = coordinates
+            .split_once('/')
+            .and_then(|(x, y)| Some((x.parse::<u16>().ok()?, y.parse::<u16>().ok()?)))
+        else {
+            return text(StatusCode::BAD_REQUEST, "Expected /pixel/{x}/{y}");
+        };
+
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::Pixel { x, y, reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(info) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+        let Some(PixelInfo { color, placement }) = info else {
+            return text(StatusCode::NOT_FOUND, "Pixel is not within the canvas");
+        };
+
+        json_response(json!({
+            "x": x,
+            "y": y,
+            "color": color.to_hex(),
+            "placed_at": placement.map(|placement| placement.timestamp),
+            "source": placement.map(|placement| self.source_json(&placement)),
+        }))
+    }
+
+    async fn labels(&self, query: Option<&str>) -> Response<Body> {
+        let region = match parse_region(query) {
+            Ok(region) => region,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::Labels { region, reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(labels) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+
+        let labels: Vec<_> = labels
+            .iter()
+            .map(|LabelInfo { label, placement }| {
+                json!({
+                    "x": label.x,
+                    "y": label.y,
+                    "text": label.text(),
+                    "placed_at": placement.map(|placement| placement.timestamp),
+                    "source": placement.map(|placement| self.source_json(&placement)),
+                })
+            })
+            .collect();
+        json_response(json!({ "labels": labels }))
+    }
+
+    /// Describe the origin of a placement, as disclosed by the API.
+    fn source_json(&self, placement: &Placement) -> serde_json::Value {
+        match placement.source {
+            EventSource::Ping(_) => {
+                let prefix = placement
+                    .source
+                    .prefix(self.source_prefix_len)
+                    .filter(|prefix| prefix.prefix_len > 0)
+                    .map(|prefix| prefix.to_string());
+                json!({ "kind": "ping", "prefix": prefix })
+            }
+            EventSource::Import => json!({ "kind": "import" }),
+            EventSource::Admin => json!({ "kind": "admin" }),
+        }
+    }
+
+    async fn stats(&self) -> Response<Body> {
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::Stats { reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(CanvasStats {
+            applied,
+            rejected,
+            cooling_down,
+            frozen,
+        }) = response.await
+        else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+        json_response(json!({
+            "applied": applied,
+            "rejected": {
+                "out_of_bounds": rejected.out_of_bounds,
+                "cooldown": rejected.cooldown,
+                "unsupported": rejected.unsupported,
+                "read_only": rejected.read_only,
+                "region_cooldown": rejected.region_cooldown,
+                "palette": rejected.palette,
+                "allowed_prefixes": rejected.allowed_prefixes,
+                "frozen": rejected.frozen,
+            },
+            "cooling_down": cooling_down,
+            "frozen": frozen,
+        }))
+    }
+
+    async fn activity(&self, view: &str, query: Option<&str>) -> Response<Body> {
+        if !matches!(view, "players" | "colors" | "hours") {
+            return text(StatusCode::NOT_FOUND, "Not found");
+        }
+        if view == "players" && self.source_prefix_len == 0 {
+            return text(StatusCode::FORBIDDEN, "The players are not disclosed");
+        }
+        let page = match Page::parse(query) {
+            Ok(page) => page,
+            Err(msg) => return text(StatusCode::BAD_REQUEST, msg),
+        };
+
+        let (reply, response) = oneshot::channel();
+        let command = CanvasCommand::Activity {
+            from: page.from,
+            to: page.to,
+            reply,
+        };
+        if self.commands.send(command).await.is_err() {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(summary) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+
+        let (total, items): (usize, Vec<_>) = match view {
+            "players" => {
+                let players = summary.players(self.source_prefix_len, page.order);
+                let items = page.items(&players).map(|(prefix, player)| {
+                    json!({
+                        "prefix": prefix.to_string(),
+                        "placed": player.placed,
+                        "surviving": player.surviving,
+                    })
+                });
+                (players.len(), items.collect())
+            }
+            "colors" => {
+                let colors = summary.colors();
+                let items = page
+                    .items(&colors)
+                    .map(|(color, placed)| json!({ "color": color.to_hex(), "placed": placed }));
+                (colors.len(), items.collect())
+            }
+            _ => {
+                let items = page
+                    .items(&summary.hours)
+                    .map(|(start, pixels)| json!({ "start": start, "pixels": pixels }));
+                (summary.hours.len(), items.collect())
+            }
+        };
+        json_response(json!({
+            "from": page.from,
+            "to": page.to,
+            "pixels": summary.pixels(),
+            "total": total,
+            "offset": page.offset,
+            "items": items,
+        }))
+    }
+
+    async fn heatmap(&self, query: Option<&str>, png: bool) -> Response<Body> {
+        let mut window = HeatmapWindow::Day;
+        for (key, value) in query_pairs(query) {
+            if key == "window" {
+                let Some(parsed) = HeatmapWindow::from_name(value) else {
+                    return text(
+                        StatusCode::BAD_REQUEST,
+                        format!("Invalid value for window: {:?} (1h, 24h or all)", value),
+                    );
+                };
+                window = parsed;
+            }
+        }
+
+        let (reply, response) = oneshot::channel();
+        if self
+            .commands
+            .send(CanvasCommand::Heatmap { window, reply })
+            .await
+            .is_err()
+        {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        }
+        let Ok(layer) = response.await else {
+            return text(StatusCode::SERVICE_UNAVAILABLE, "Canvas unavailable");
+        };
+
+        if !png {
+            let cells: Vec<_> = layer
+                .cells
+                .iter()
+                .map(|((cx, cy), count)| json!([cx, cy, count]))
+                .collect();
+            return json_response(json!({
+                "window": window.name(),
+                "cell_size": CELL_SIZE,
+                "width": layer.width,
+                "height": layer.height,
+                "max": layer.max(),
+                "cells": cells,
+            }));
+        }
+        let encoded = tokio::task::spawn_blocking(move || layer.encode_png()).await;
+        let Ok(Ok(png)) = encoded else {
+            return text(StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image");
+        };
+        Response::builder()
+            .header(header::CONTENT_TYPE, "image/png")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::from(png))
+            .expect("valid response")
+    }
+
+    /// Build the ETag of the given region version.
+    fn etag(&self, version: u64) -> HeaderValue {
+        HeaderValue::from_str(&format!("\"{:x}-{:x}\"", self.instance, version))
+            .expect("valid header value")
+    }
+
+    /// Get the region version of the first ETag of If-None-Match issued by this process, if any.
+    fn if_none_match(&self, headers: &HeaderMap) -> Option<u64> {
+        let prefix = format!("{:x}-", self.instance);
+        headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|tag| {
+                let tag = tag.trim();
+                let tag = tag.strip_prefix("W/").unwrap_or(tag);
+                let tag = tag.strip_prefix('"')?.strip_suffix('"')?;
+                u64::from_str_radix(tag.strip_prefix(&prefix)?, 16).ok()
+            })
+            .next()
+    }
+}
+
+/// Answer a `GET /metrics` request with the metrics of the service.
+///
+/// The metrics cover every canvas, so they are served once rather than by each [HttpApi].
+pub fn serve_metrics<B>(req: &Request<B>, metrics: &Metrics) -> Response<Body> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return method_not_allowed("GET, HEAD");
+    }
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(Body::from(metrics.render()))
+        .expect("valid response")
+}
+
+/// Parse the optional region of a query string (`x=&y=&w=&h=`).
+///
+/// Returns None if no coordinate is given, all four are required otherwise.
+fn parse_region(query: Option<&str>) -> Result<Option<Region>, String> {
+    let (mut x, mut y, mut w, mut h) = (None, None, None, None);
+    for (key, value) in query_pairs(query) {
+        let slot = match key {
+            "x" => &mut x,
+            "y" => &mut y,
+            "w" => &mut w,
+            "h" => &mut h,
+            // Unknown parameters are ignored (e.g. cache busters)
+            _ => continue,
+        };
+        let value = value
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid value for {}: {:?}", key, value))?;
+        *slot = Some(value);
+    }
+
+    match (x, y, w, h) {
+        (None, None, None, None) => Ok(None),
+        (Some(x), Some(y), Some(width), Some(height)) => Ok(Some(Region {
+            x,
+            y,
+            width,
+            height,
+        })),
+        _ => Err("x, y, w and h are all required to crop the canvas".to_string()),
+    }
+}
+
+/// Time range and page of the statistics requested by a query string
+/// (`from=&to=&offset=&limit=&sort=`).
+///
+/// The range is in milliseconds since the Unix epoch, `to` excluded. The pages hold
+/// [DEFAULT_PAGE_SIZE] items unless a `limit` (at most [MAX_PAGE_SIZE]) is given. The players
+/// are sorted by placed pixels, or by surviving pixels with `sort=surviving`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Page {
+    from: Option<u64>,
+    to: Option<u64>,
+    offset: usize,
+    limit: usize,
+    order: PlayerOrder,
+}
+
+impl Page {
+    fn parse(query: Option<&str>) -> Result<Self, String> {
+        let mut page = Page {
+            from: None,
+            to: None,
+            offset: 0,
+            limit: DEFAULT_PAGE_SIZE,
+            order: PlayerOrder::Placed,
+        };
+        let invalid = |key: &str, value: &str| format!("Invalid value for {}: {:?}", key, value);
+        for (key, value) in query_pairs(query) {
+            match key {
+                "from" | "to" => {
+                    let time = value.parse::<u64>().map_err(|_| invalid(key, value))?;
+                    if key == "from" {
+                        page.from = Some(time);
+                    } else {
+                        page.to = Some(time);
+                    }
+                }
+                "offset" => page.offset = value.parse().map_err(|_| invalid(key, value))?,
+                "limit" => {
+                    page.limit = value
+                        .parse()
+                        .ok()
+                        .filter(|limit| (1..=MAX_PAGE_SIZE).contains(limit))
+                        .ok_or_else(|| invalid(key, value))?;
+                }
+                "sort" => {
+                    page.order = match value {
+                        "placed" => PlayerOrder::Placed,
+                        "surviving" => PlayerOrder::Surviving,
+                        _ => return Err(invalid(key, value)),
+                    };
+                }
+                // Unknown parameters are ignored (e.g. cache busters)
+                _ => {}
+            }
+        }
+        Ok(page)
+    }
+
+    /// Get the items of the page.
+    fn items<'a, T>(&self, items: &'a [T]) -> impl Iterator<Item = &'a T> {
+        items.iter().skip(self.offset).take(self.limit)
+    }
+}
+
+/// Iterate over the key-value pairs of a query string.
+///
+/// Values are not percent-decoded, most parameters of the API are plain numbers
+/// (see [percent_decode] for the others).
+fn query_pairs(query: Option<&str>) -> impl Iterator<Item = (&str, &str)> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+}
+
+/// Decode a percent-encoded query value, None if it is not valid UTF-8 once decoded.
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = value.get(i + 1..i + 3)?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Build a plain-text response.
+fn text(status: StatusCode, msg: impl Into<String>) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(msg.into()))
+        .expect("valid response")
+}
+
+/// Build a JSON response, which should not be cached.
+fn json_response(body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(body.to_string()))
+        .expect("valid response")
+}
+
+/// Build a 405 response, listing the allowed methods.
+fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    let mut response = text(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    response
+        .headers_mut()
+        .insert(header::ALLOW, HeaderValue::from_static(allow));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        activity::{Activity, HOUR_MILLIS},
+        canvas::{Canvas, colors, label::Label},
+        command::TileSnapshot,
+    };
+    use http_body_util::BodyExt;
+
+    /// Spawn a minimal canvas task, answering the commands from the given canvas.
+    fn spawn_canvas(canvas: Canvas) -> HttpApi {
+        let (sender, mut receiver) = mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Some(command) = receiver.recv().await {
+                match command {
+                    CanvasCommand::Crop {
+                        region,
+                        unless_version,
+                        reply,
+                    } => {
+                        let _ = reply.send(CropResponse::new(&canvas, region, unless_version));
+                    }
+                    CanvasCommand::Pixel { x, y, reply } => {
+                        let _ = reply.send(PixelInfo::new(&canvas, x, y));
+                    }
+                    CanvasCommand::Labels { region, reply } => {
+                        let _ = reply.send(LabelInfo::list(&canvas, region));
+                    }
+                    CanvasCommand::Tiles { tiles, reply } => {
+                        let _ = reply.send(TileSnapshot::list(&canvas, &tiles));
+                    }
+                    CanvasCommand::Stats { reply } => {
+                        let _ = reply.send(CanvasStats {
+                            applied: canvas.version(),
+                            ..Default::default()
+                        });
+                    }
+                    CanvasCommand::Activity { from, to, reply } => {
+                        let activity = Activity::from_canvas(&canvas, 64);
+                        let _ = reply.send(activity.summary(from, to));
+                    }
+                    CanvasCommand::Heatmap { window, reply } => {
+                        let heatmap = Activity::from_canvas(&canvas, 64).heatmap().clone();
+                        let (width, height) = (canvas.width(), canvas.height());
+                        let now = 1_700_000_000_000 + HOUR_MILLIS;
+                        let _ = reply.send(heatmap.layer(window, now, width, height));
+                    }
+                    _ => {}
+                }
+            }
+        });
+        HttpApi::new(sender)
+    }
+
+    fn get(uri: &str) -> Request<()> {
+        Request::builder().uri(uri).body(()).unwrap()
+    }
+
+    async fn decode_png(response: Response<Body>) -> (u32, u32, Vec<u8>) {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let decoder = png::Decoder::new(std::io::Cursor::new(body));
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        buf.truncate(info.buffer_size());
+        (info.width, info.height, buf)
+    }
+
+    #[tokio::test]
+    async fn canvas_png_etag_and_not_modified() {
+        let mut canvas = Canvas::new(20, 10);
+        canvas.set_pixel(3, 4, colors::RED).unwrap();
+        let api = spawn_canvas(canvas);
+
+        let response = api.handle(&get("/canvas.png")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let etag = response.headers()[header::ETAG].clone();
+        let (width, height, data) = decode_png(response).await;
+        assert_eq!((width, height), (20, 10));
+        let offset = (4 * 20 + 3) * 3;
+        assert_eq!(&data[offset..offset + 3], &[255, 0, 0]);
+
+        let mut req = get("/canvas.png");
+        req.headers_mut()
+            .insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"other\""));
+        req.headers_mut()
+            .append(header::IF_NONE_MATCH, etag.clone());
+        let response = api.handle(&req).await;
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], etag);
+
+        // ETags of another process are not trusted
+        let other = HttpApi {
+            instance: api.instance + 1,
+            ..api.clone()
+        };
+        let mut req = get("/canvas.png");
+        req.headers_mut()
+            .insert(header::IF_NONE_MATCH, other.etag(1));
+        assert_eq!(api.handle(&req).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn canvas_png_crop() {
+        let mut canvas = Canvas::new(300, 300);
+        canvas.set_pixel(260, 10, colors::BLUE).unwrap();
+        let api = spawn_canvas(canvas);
+
+        let response = api.handle(&get("/canvas.png?x=259&y=9&w=2&h=3")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let (width, height, data) = decode_png(response).await;
+        assert_eq!((width, height), (2, 3));
+        assert_eq!(&data[9..12], &[0, 0, 255]);
+
+        // The ETag only depends on the tiles overlapping the region
+        let untouched = api.handle(&get("/canvas.png?x=0&y=0&w=10&h=10")).await;
+        let touched = api.handle(&get("/canvas.png?x=250&y=0&w=10&h=10")).await;
+        assert_ne!(
+            untouched.headers()[header::ETAG],
+            touched.headers()[header::ETAG]
+        );
+    }
+
+    #[tokio::test]
+    async fn canvas_png_invalid_requests() {
+        let api = spawn_canvas(Canvas::new(16, 16));
+
+        for uri in [
+            "/canvas.png?x=0&y=0&w=17&h=1",
+            "/canvas.png?x=0&y=0&w=0&h=1",
+            "/canvas.png?x=0&y=0",
+            "/canvas.png?x=-1&y=0&w=1&h=1",
+        ] {
+            let response = api.handle(&get(uri)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        let response = api.handle(&get("/nope")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let post = Request::builder()
+            .method(Method::POST)
+            .uri("/canvas.png")
+            .body(())
+            .unwrap();
+        let response = api.handle(&post).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[header::ALLOW], "GET, HEAD");
+    }
+
+    #[tokio::test]
+    async fn pixel_provenance() {
+        let mut canvas = Canvas::new(16, 16);
+        let placement = Placement {
+            source: EventSource::Ping("2001:db8:aaaa:bbbb::1".parse().unwrap()),
+            timestamp: 1_700_000_000_000,
+        };
+        canvas.place_pixel(3, 4, colors::RED, placement).unwrap();
+        let api = spawn_canvas(canvas);
+
+        let response = api.handle(&get("/pixel/3/4")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "x": 3,
+                "y": 4,
+                "color": "#ff0000",
+                "placed_at": 1_700_000_000_000u64,
+                "source": { "kind": "ping", "prefix": "2001:db8:aaaa::/48" },
+            })
+        );
+
+        // Sources can be hidden entirely
+        let api = api.with_source_prefix_len(0);
+        let response = api.handle(&get("/pixel/3/4")).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["source"], json!({ "kind": "ping", "prefix": null }));
+
+        let response = api.handle(&get("/pixel/0/0")).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["color"], "#ffffff");
+        assert_eq!(body["placed_at"], json!(null));
+        assert_eq!(body["source"], json!(null));
+
+        let response = api.handle(&get("/pixel/16/0")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        for uri in ["/pixel/1", "/pixel/1/x", "/pixel/1/2/3"] {
+            let response = api.handle(&get(uri)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let mut canvas = Canvas::new(16, 16);
+        canvas.set_pixel(3, 4, colors::RED).unwrap();
+        let api = spawn_canvas(canvas);
+
+        let response = api.handle(&get("/stats")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["applied"], 1);
+        assert_eq!(body["rejected"]["cooldown"], 0);
+        assert_eq!(body["rejected"]["read_only"], 0);
+    }
+
+    #[tokio::test]
+    async fn activity_pages() {
+        let mut canvas = Canvas::new(16, 16);
+        let t0 = 1_700_000_000_000 / HOUR_MILLIS * HOUR_MILLIS;
+        for (x, address, timestamp, color) in [
+            (0, "2001:db8:1::1", t0, colors::RED),
+            (1, "2001:db8:1::2", t0, colors::RED),
+            (2, "2001:db8:2::1", t0 + HOUR_MILLIS, colors::BLUE),
+        ] {
+            let placement = Placement {
+                source: EventSource::Ping(address.parse().unwrap()),
+                timestamp,
+            };
+            canvas.place_pixel(x, 0, color, placement).unwrap();
+        }
+        let api = spawn_canvas(canvas);
+        let body = |response: Response<Body>| async {
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let response = api.handle(&get("/stats/players")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            body(response).await,
+            json!({
+                "from": null,
+                "to": null,
+                "pixels": 3,
+                "total": 2,
+                "offset": 0,
+                "items": [
+                    { "prefix": "2001:db8:1::/48", "placed": 2, "surviving": 2 },
+                    { "prefix": "2001:db8:2::/48", "placed": 1, "surviving": 1 },
+                ],
+            })
+        );
+        let uri = format!("/stats/players?from={}&offset=0&limit=1", t0 + HOUR_MILLIS);
+        let players = body(api.handle(&get(&uri)).await).await;
+        assert_eq!(players["total"], 1);
+        assert_eq!(players["items"][0]["prefix"], "2001:db8:2::/48");
+        let players = body(api.handle(&get("/stats/players?offset=1&limit=1")).await).await;
+        assert_eq!(players["total"], 2);
+        assert_eq!(players["items"][0]["prefix"], "2001:db8:2::/48");
+
+        let colors = body(api.handle(&get("/stats/colors")).await).await;
+        assert_eq!(
+            colors["items"],
+            json!([{ "color": "#ff0000", "placed": 2 }, { "color": "#0000ff", "placed": 1 }])
+        );
+        let uri = format!("/stats/hours?to={}", t0 + HOUR_MILLIS);
+        let hours = body(api.handle(&get(&uri)).await).await;
+        assert_eq!(hours["items"], json!([{ "start": t0, "pixels": 2 }]));
+        assert_eq!(hours["pixels"], 2);
+
+        for uri in [
+            "/stats/players?limit=0",
+            "/stats/players?limit=1001",
+            "/stats/players?sort=oldest",
+            "/stats/hours?from=yesterday",
+        ] {
+            let response = api.handle(&get(uri)).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+        let response = api.handle(&get("/stats/nope")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        // The players are hidden along with the sources
+        let api = api.with_source_prefix_len(0);
+        let response = api.handle(&get("/stats/players")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = api.handle(&get("/stats/colors")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn heatmap() {
+        let mut canvas = Canvas::new(20, 10);
+        for (x, timestamp) in [
+            (0, 1_700_000_000_000),
+            (1, 1_600_000_000_000),
+            (9, 1_700_000_000_000),
+        ] {
+            let placement = Placement {
+                source: EventSource::Ping("2001:db8::1".parse().unwrap()),
+                timestamp,
+            };
+            canvas.place_pixel(x, 0, colors::RED, placement).unwrap();
+        }
+        let api = spawn_canvas(canvas);
+
+        let response = api.handle(&get("/heatmap")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "window": "24h",
+                "cell_size": 8,
+                "width": 3,
+                "height": 2,
+                "max": 1,
+                "cells": [[0, 0, 1], [1, 0, 1]],
+            })
+        );
+        let response = api.handle(&get("/heatmap?window=all")).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["cells"], json!([[0, 0, 2], [1, 0, 1]]));
+
+        let response = api.handle(&get("/heatmap.png?window=1h")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let decoder = png::Decoder::new(std::io::Cursor::new(body));
+        let info = decoder.read_info().unwrap().info().clone();
+        assert_eq!((info.width, info.height), (3, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+
+        let response = api.handle(&get("/heatmap.png?window=2h")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn labels() {
+        let mut canvas = Canvas::new(16, 16);
+        let placement = Placement {
+            source: EventSource::Admin,
+            timestamp: 1_700_000_000_000,
+        };
+        canvas
+            .place_label(Label::new(1, 2, "hello").unwrap(), placement)
+            .unwrap();
+        canvas.set_label(Label::new(9, 9, "bye").unwrap()).unwrap();
+        let api = spawn_canvas(canvas);
+
+        let response = api.handle(&get("/labels")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "labels": [
+                    {
+                        "x": 1,
+                        "y": 2,
+                        "text": "hello",
+                        "placed_at": 1_700_000_000_000u64,
+                        "source": { "kind": "admin" },
+                    },
+                    { "x": 9, "y": 9, "text": "bye", "placed_at": null, "source": null },
+                ]
+            })
+        );
+
+        let response = api.handle(&get("/labels?x=8&y=8&w=8&h=8")).await;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["labels"].as_array().unwrap().len(), 1);
+        assert_eq!(body["labels"][0]["text"], "bye");
+
+        let response = api.handle(&get("/labels?x=8")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn parse_region_query() {
+        assert_eq!(parse_region(None), Ok(None));
+        assert_eq!(parse_region(Some("t=123")), Ok(None));
+        assert_eq!(
+            parse_region(Some("x=1&y=2&w=3&h=4&t=5")),
+            Ok(Some(Region {
+                x: 1,
+                y: 2,
+                width: 3,
+                height: 4
+            }))
+        );
+        assert!(parse_region(Some("x=1&y=2&w=3")).is_err());
+        assert_eq!(
+            percent_decode("2001%3Adb8::%2f48").as_deref(),
+            Some("2001:db8::/48")
+        );
+        assert_eq!(percent_decode("%4"), None);
+        assert!(parse_region(Some("x=70000&y=2&w=3&h=4")).is_err());
+    }
+}
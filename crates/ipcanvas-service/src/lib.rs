@@ -1,3 +1,20 @@
+pub mod activity;
 pub mod canvas;
+pub mod canvases;
+pub mod command;
+pub mod config;
+pub mod connections;
+pub mod cooldown;
 pub mod events;
+pub mod feed;
+pub mod heatmap;
+pub mod http;
+pub mod metrics;
+pub mod moderation;
+pub mod persistence;
 pub mod ping;
+pub mod rules;
+pub mod timelapse;
+pub mod tls;
+pub mod viewport;
+pub mod webhooks;
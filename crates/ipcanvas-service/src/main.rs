@@ -1,21 +1,73 @@
-use std::time::Duration;
+use std::{
+    convert::Infallible,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{self, Poll, Waker},
+    time::Duration,
+};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result, bail};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, parser::ValueSource};
+use hyper::{Request, Response, body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use ipcanvas_ping_common::Ipv6Prefix;
 use ipcanvas_service::{
-    canvas::{Canvas, diff::CanvasDiff},
-    events::Event,
-    ping::{PingServer, PingServerError},
+    activity::{self, Activity},
+    canvas::{
+        Canvas, Region,
+        diff::CanvasDiff,
+        image::ImportImage,
+        label::{Label, MAX_TEXT_LEN},
+        provenance::Placement,
+    },
+    canvases::CanvasConfig,
+    command::{
+        CanvasCommand, CanvasStats, CropResponse, FillResponse, ImportResponse, LabelInfo,
+        PixelInfo, RasteriseResponse, RejectedEvents, ResizeResponse, RollbackResponse,
+        SnapshotResponse, TileSnapshot,
+    },
+    config::{ConfigFile, MAX_BUFFER_SIZE, MAX_TICK_INTERVAL_MS, MIN_TICK_INTERVAL_MS},
+    connections::PingConnections,
+    cooldown::{self, Cooldown},
+    events::{AdminAction, ApplyError, Event, EventSource, SourcedEvent},
+    feed::DiffFeed,
+    http::{
+        self, Body, HttpApi,
+        admin::{self, AdminApi},
+        canvases::{CanvasRouter, Routed},
+        instance::InstanceApi,
+    },
+    metrics::{CanvasMetrics, Metrics},
+    moderation::RevertedPixel,
+    persistence::{self, EventLog, LogEntry, Snapshot, SnapshotStore},
+    ping::{
+        PingAllowlist, PingKey, PingRoutes, PingServer, PingServerError, RESPONSE_LEN,
+        parse_allowed_prefix,
+    },
+    rules::{RegionRules, Rule},
+    tls::TlsFiles,
+    webhooks::{RegionProgress, WebhookEvent, WebhookTarget, Webhooks},
 };
+use serde_json::json;
 use tokio::{
-    io::AsyncReadExt,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    signal::unix::{SignalKind, signal},
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
 };
+use tokio_rustls::TlsAcceptor;
 use tracing::{debug, event, info, span, trace, warn};
 
-const EVENT_BUFFER_SIZE: usize = 128;
-const DIFF_BUFFER_SIZE: usize = 10;
+/// Largest batch of events forwarded at once from a ping connection to the canvases.
+const PING_BATCH_SIZE: usize = 128;
+/// Time given to a ping listener to complete the TLS handshake, and to answer its challenge.
+const PING_AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Number of events applied at once by the bulk commands (fills, imports, rollbacks and label
+/// rasterisations), before the canvas task appends them to the event log and yields.
+const BULK_CHUNK_SIZE: usize = 4096;
 
 /// ipcanvas-service: operation center of ipcanvas.
 ///
@@ -25,54 +77,414 @@ const DIFF_BUFFER_SIZE: usize = 10;
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Opts {
+    /// TOML file of settings, with the names of the options as keys.
+    ///
+    /// The options given on the command line or in the environment (`IPCANVAS_<OPTION>`)
+    /// take precedence over the file.
+    #[arg(long, env = "IPCANVAS_CONFIG")]
+    config: Option<PathBuf>,
+
     /// Address to bind for the ping-service.
-    #[arg(long, short = 'p', default_value = "0.0.0.0:7894")]
+    #[arg(
+        long,
+        short = 'p',
+        env = "IPCANVAS_PING_ADDR",
+        default_value = "0.0.0.0:7894"
+    )]
     ping_addr: String,
 
     /// Address to bind for the WebSocket service.
-    #[arg(long, short = 'w', default_value = "0.0.0.0:7895")]
+    #[arg(
+        long,
+        short = 'w',
+        env = "IPCANVAS_WEBSOCKET_ADDR",
+        default_value = "0.0.0.0:7895"
+    )]
     websocket_addr: String,
 
+    /// Address to bind for the HTTP service (canvas images).
+    #[arg(long, env = "IPCANVAS_HTTP_ADDR", default_value = "0.0.0.0:7896")]
+    http_addr: String,
+
+    /// Address to bind for the admin HTTP service.
+    ///
+    /// Requests must carry the token of `--admin-token-file` (`Authorization: Bearer <token>`).
+    /// If not set, the admin service is disabled.
+    #[arg(long, env = "IPCANVAS_ADMIN_ADDR")]
+    admin_addr: Option<String>,
+
+    /// File holding the token of the admin service (leading and trailing whitespace is ignored).
+    #[arg(long, env = "IPCANVAS_ADMIN_TOKEN_FILE")]
+    admin_token_file: Option<PathBuf>,
+
+    /// Number of bits of the sender addresses disclosed with the origin of the pixels.
+    ///
+    /// With 0, the HTTP service only tells whether a pixel comes from a ping or an import.
+    /// The canvas does not keep more than the first 64 bits of the addresses.
+    #[arg(long, env = "IPCANVAS_SOURCE_PREFIX_LEN", default_value_t = http::DEFAULT_SOURCE_PREFIX_LEN,
+          value_parser = clap::value_parser!(u8).range(0..=128))]
+    source_prefix_len: u8,
+
+    /// Minimum time between two pixels placed by the same player, in seconds.
+    ///
+    /// With 0, players are not limited.
+    #[arg(long, env = "IPCANVAS_COOLDOWN", default_value = "0")]
+    cooldown: u64,
+
+    /// Length of the prefix of the sender addresses identifying a player, in bits.
+    #[arg(long, env = "IPCANVAS_COOLDOWN_PREFIX_LEN", default_value_t = cooldown::DEFAULT_PREFIX_LEN,
+          value_parser = clap::value_parser!(u8).range(0..=128))]
+    cooldown_prefix_len: u8,
+
+    /// TOML file of the region rules (read-only areas, region cooldowns, palettes...).
+    ///
+    /// The file is read again when the service receives SIGHUP.
+    #[arg(long, env = "IPCANVAS_RULES")]
+    rules: Option<PathBuf>,
+
     /// Width of the canvas in pixels.
     ///
-    /// Should be a multiple of 256.
-    #[arg(long = "width", default_value = "4096")]
-    canvas_width: u32,
+    /// Should be a multiple of 256. A canvas restored from the data directory keeps its
+    /// dimensions, use the admin service to resize it.
+    #[arg(long = "width", env = "IPCANVAS_WIDTH", default_value = "4096",
+          value_parser = clap::value_parser!(u16).range(1..))]
+    canvas_width: u16,
 
     /// Height of the canvas in pixels.
     ///
     /// Should be a multiple of 256.
-    #[arg(long = "height", default_value = "4096")]
-    canvas_height: u32,
+    #[arg(long = "height", env = "IPCANVAS_HEIGHT", default_value = "4096",
+          value_parser = clap::value_parser!(u16).range(1..))]
+    canvas_height: u16,
+
+    /// Directory where the canvas state is persisted (snapshots and event log).
+    ///
+    /// If not set, the canvas is only kept in memory.
+    #[arg(long, env = "IPCANVAS_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Interval between two on-disk snapshots of the canvas, in seconds.
+    #[arg(long, env = "IPCANVAS_SNAPSHOT_INTERVAL", default_value = "300",
+          value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_interval: u64,
+
+    /// Number of days the players of each hour are kept for the statistics (0 to keep them forever).
+    ///
+    /// Older hours only keep the number of pixels placed and their colors.
+    #[arg(long, env = "IPCANVAS_PLAYER_RETENTION_DAYS", default_value_t = activity::DEFAULT_PLAYER_RETENTION_DAYS)]
+    player_retention_days: u32,
+
+    /// Keep the whole event log, instead of removing the events covered by a snapshot.
+    ///
+    /// The full history is needed to render a timelapse from the beginning (see ipcanvas-timelapse).
+    #[arg(long, env = "IPCANVAS_KEEP_EVENT_LOG")]
+    keep_event_log: bool,
+
+    /// TOML file of the canvases served by the instance (prefix, dimensions, rules...).
+    ///
+    /// The canvas settings of the command line are used as defaults for the canvases of the
+    /// file. If not set, a single canvas is served, receiving all the pings.
+    #[arg(long, env = "IPCANVAS_CANVASES")]
+    canvases: Option<PathBuf>,
+
+    /// PNG image painted on the canvas at startup, with its top-left corner at (0, 0).
+    ///
+    /// Transparent pixels are skipped. The image is only painted on a fresh canvas,
+    /// not on a canvas restored from the data directory.
+    #[arg(long, env = "IPCANVAS_INIT_IMAGE")]
+    init_image: Option<PathBuf>,
+
+    /// File holding the key shared with the ping listeners (leading and trailing whitespace
+    /// is ignored).
+    ///
+    /// Listeners must answer a challenge with the key before sending events,
+    /// the other connections are closed.
+    #[arg(long, env = "IPCANVAS_PING_KEY_FILE")]
+    ping_key_file: Option<PathBuf>,
+
+    /// Accept ping listeners without authenticating them, when no key is set.
+    ///
+    /// Anyone reaching the ping address can then place pixels, only use it for development.
+    #[arg(long, env = "IPCANVAS_PING_NO_AUTH")]
+    ping_no_auth: bool,
+
+    /// Prefixes of the addresses allowed to connect as ping listeners, comma-separated
+    /// (e.g. `2001:db8::/64,192.0.2.0/24`).
+    ///
+    /// Checked before the key. If not set, listeners may connect from any address.
+    #[arg(long, env = "IPCANVAS_PING_ALLOW", value_delimiter = ',',
+          value_parser = parse_allowed_prefix)]
+    ping_allow: Vec<Ipv6Prefix>,
+
+    /// Certificate chain presented to the ping listeners (PEM), enabling TLS on the ping link.
+    ///
+    /// Requires `--ping-tls-key`.
+    #[arg(long, env = "IPCANVAS_PING_TLS_CERT")]
+    ping_tls_cert: Option<PathBuf>,
+
+    /// Private key of the certificate of the ping link (PEM).
+    #[arg(long, env = "IPCANVAS_PING_TLS_KEY")]
+    ping_tls_key: Option<PathBuf>,
+
+    /// Certificates of the CAs issuing the certificates of the ping listeners (PEM).
+    ///
+    /// The TLS is then mutual: listeners without a certificate issued by these CAs are refused.
+    #[arg(long, env = "IPCANVAS_PING_TLS_CLIENT_CA")]
+    ping_tls_client_ca: Option<PathBuf>,
+
+    /// TOML file of the webhook targets, notified of the notable events of the canvases
+    /// (completed regions, rollbacks, freezes...).
+    #[arg(long, env = "IPCANVAS_WEBHOOKS")]
+    webhooks: Option<PathBuf>,
+
+    /// Interval between two diffs sent to the clients, in milliseconds.
+    #[arg(long, env = "IPCANVAS_TICK_INTERVAL_MS", default_value = "1000",
+          value_parser = clap::value_parser!(u64).range(MIN_TICK_INTERVAL_MS..=MAX_TICK_INTERVAL_MS))]
+    tick_interval_ms: u64,
+
+    /// Capacity of the queue of the events of each canvas.
+    #[arg(long, env = "IPCANVAS_EVENT_BUFFER_SIZE", default_value = "128", value_parser = buffer_size)]
+    event_buffer_size: usize,
+
+    /// Capacity of the queues of the diffs.
+    #[arg(long, env = "IPCANVAS_DIFF_BUFFER_SIZE", default_value = "10", value_parser = buffer_size)]
+    diff_buffer_size: usize,
+
+    /// Capacity of the queue of the commands of each canvas (from the HTTP and admin services).
+    #[arg(long, env = "IPCANVAS_COMMAND_BUFFER_SIZE", default_value = "32", value_parser = buffer_size)]
+    command_buffer_size: usize,
+}
+
+impl Opts {
+    /// Take the settings left to their defaults (not given on the command line or in the
+    /// environment) from a configuration file.
+    fn merge(&mut self, file: ConfigFile, matches: &ArgMatches) {
+        let defaulted = |id: &str| {
+            !matches!(
+                matches.value_source(id),
+                Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+            )
+        };
+        macro_rules! merge {
+            ($($field:ident <- $key:ident),* $(,)?) => {
+                $(
+                    if defaulted(stringify!($field)) && let Some(value) = file.$key {
+                        self.$field = value.into();
+                    }
+                )*
+            };
+        }
+        merge!(
+            ping_addr <- ping_addr,
+            websocket_addr <- websocket_addr,
+            http_addr <- http_addr,
+            admin_addr <- admin_addr,
+            admin_token_file <- admin_token_file,
+            source_prefix_len <- source_prefix_len,
+            cooldown <- cooldown,
+            cooldown_prefix_len <- cooldown_prefix_len,
+            rules <- rules,
+            canvas_width <- width,
+            canvas_height <- height,
+            data_dir <- data_dir,
+            snapshot_interval <- snapshot_interval,
+            player_retention_days <- player_retention_days,
+            keep_event_log <- keep_event_log,
+            canvases <- canvases,
+            init_image <- init_image,
+            ping_key_file <- ping_key_file,
+            ping_no_auth <- ping_no_auth,
+            ping_allow <- ping_allow,
+            ping_tls_cert <- ping_tls_cert,
+            ping_tls_key <- ping_tls_key,
+            ping_tls_client_ca <- ping_tls_client_ca,
+            webhooks <- webhooks,
+            tick_interval_ms <- tick_interval_ms,
+            event_buffer_size <- event_buffer_size,
+            diff_buffer_size <- diff_buffer_size,
+            command_buffer_size <- command_buffer_size,
+        );
+    }
+}
+
+/// Parse the capacity of a queue.
+fn buffer_size(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(size) if (1..=MAX_BUFFER_SIZE).contains(&size) => Ok(size),
+        _ => Err(format!("expected a size between 1 and {}", MAX_BUFFER_SIZE)),
+    }
 }
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> Result<()> {
-    let opts = Opts::parse();
+    let matches = Opts::command().get_matches();
+    let mut opts = Opts::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     env_logger::init();
     console_subscriber::init();
 
     info!("ipcanvas-service starting...");
+    if let Some(path) = opts.config.clone() {
+        opts.merge(ConfigFile::load(&path)?, &matches);
+        info!("Settings read from {}", path.display());
+    }
+    if opts.admin_addr.is_some() && opts.admin_token_file.is_none() {
+        bail!("The admin service needs a token, see --admin-token-file");
+    }
     info!("Ping service listening on {}", opts.ping_addr);
     info!("WebSocket service listening on {}", opts.websocket_addr);
+    info!("HTTP service listening on {}", opts.http_addr);
+    if let Some(admin_addr) = &opts.admin_addr {
+        info!("Admin service listening on {}", admin_addr);
+    }
+    let admin_token = match &opts.admin_token_file {
+        Some(path) => Some(Arc::<str>::from(load_secret(path, "admin token")?)),
+        None => None,
+    };
+    let tls = match (&opts.ping_tls_cert, &opts.ping_tls_key) {
+        (Some(cert), Some(key)) => Some(TlsFiles {
+            cert: cert.clone(),
+            key: key.clone(),
+            client_ca: opts.ping_tls_client_ca.clone(),
+        }),
+        (None, None) if opts.ping_tls_client_ca.is_none() => None,
+        _ => bail!("The TLS of the ping service needs --ping-tls-cert and --ping-tls-key"),
+    };
+    let tls_acceptor = match &tls {
+        Some(files) => {
+            let acceptor = TlsAcceptor::from(files.server_config()?);
+            match files.client_ca {
+                Some(_) => info!("Ping link encrypted with mutual TLS"),
+                None => info!("Ping link encrypted with TLS"),
+            }
+            Some(acceptor)
+        }
+        None => None,
+    };
+    let mutual_tls = tls.is_some_and(|files| files.client_ca.is_some());
+    let ping_key = match &opts.ping_key_file {
+        Some(path) => PingKey::new(load_secret(path, "ping key")?),
+        None if mutual_tls => None,
+        None if opts.ping_no_auth => {
+            warn!(
+                "Ping listeners are not authenticated, anyone reaching the ping service can place pixels"
+            );
+            None
+        }
+        None => bail!(
+            "Ping listeners must be authenticated, see --ping-key-file and --ping-tls-client-ca (or --ping-no-auth to accept any listener)"
+        ),
+    };
+    let allowlist = PingAllowlist::new(opts.ping_allow.iter().copied());
+    if !allowlist.is_empty() {
+        info!(
+            "Ping listeners allowed from {} prefixes",
+            opts.ping_allow.len()
+        );
+    }
+
+    // The command line describes the single canvas, or the defaults of the canvases file
+    let defaults = CanvasConfig {
+        id: "main".to_string(),
+        prefix: None,
+        width: opts.canvas_width,
+        height: opts.canvas_height,
+        data_dir: opts.data_dir.clone(),
+        rules: opts.rules.clone(),
+        init_image: opts.init_image.clone(),
+        cooldown: opts.cooldown,
+        cooldown_prefix_len: opts.cooldown_prefix_len,
+    };
+    let configs = match &opts.canvases {
+        Some(path) => {
+            let configs = CanvasConfig::load(path, &defaults)?;
+            info!("Serving {} canvases from {}", configs.len(), path.display());
+            configs
+        }
+        None => vec![defaults],
+    };
+    let routes = CanvasConfig::routes(&configs);
+
+    let webhooks = match &opts.webhooks {
+        Some(path) => {
+            let path = path.clone();
+            let targets = tokio::task::spawn_blocking(move || WebhookTarget::load(&path)).await??;
+            info!("Notifying {} webhook targets", targets.len());
+            Webhooks::spawn(targets)
+        }
+        None => Webhooks::default(),
+    };
 
-    let (event_sender, event_receiver) = mpsc::channel::<Event>(EVENT_BUFFER_SIZE);
-    let (diff_sender, mut diff_receiver) = mpsc::channel::<CanvasDiff>(DIFF_BUFFER_SIZE);
-    // Prepare the canvas task
-    {
-        let canvas = Canvas::new(opts.canvas_width as u16, opts.canvas_height as u16);
-
-        // Spawn the canvas management task - diff will be sent every 100ms
-        tokio::spawn(canvas_task(
-            canvas,
-            Duration::from_secs(1),
-            event_receiver,
-            diff_sender,
-        ));
+    let (diff_sender, mut diff_receiver) =
+        mpsc::channel::<(usize, CanvasDiff)>(opts.diff_buffer_size);
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let mut canvases = Vec::with_capacity(configs.len());
+    for (index, config) in configs.into_iter().enumerate() {
+        let canvas = spawn_canvas(
+            index,
+            config,
+            &opts,
+            webhooks.clone(),
+            diff_sender.clone(),
+            shutdown_receiver.clone(),
+        )
+        .await?;
+        canvases.push(canvas);
     }
+    drop(diff_sender);
+    let event_senders: Vec<_> = canvases
+        .iter()
+        .map(|canvas| canvas.events.clone())
+        .collect();
+    let metrics = canvases.iter().fold(Metrics::new(), |metrics, canvas| {
+        metrics.with_canvas(canvas.config.id.clone(), canvas.metrics.clone())
+    });
+    let metrics = Arc::new(if webhooks.is_empty() {
+        metrics
+    } else {
+        metrics.with_webhooks(webhooks.metrics())
+    });
+    let connections = PingConnections::new();
+    let instance_api = InstanceApi::new(
+        connections.clone(),
+        config_json(&opts, canvases.iter().map(|canvas| &canvas.config)),
+        canvases
+            .iter()
+            .map(|canvas| canvas.commands.clone())
+            .collect(),
+    );
 
     let ping_socket = TcpListener::bind(opts.ping_addr).await?;
+    let http_socket = TcpListener::bind(opts.http_addr).await?;
+    let admin_socket = match &opts.admin_addr {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    let (http_router, admin_router) = canvases.iter().fold(
+        (CanvasRouter::new(), CanvasRouter::new()),
+        |(http_router, admin_router), canvas| {
+            let CanvasConfig {
+                id,
+                prefix,
+                data_dir,
+                ..
+            } = &canvas.config;
+            let http_api = HttpApi::new(canvas.commands.clone())
+                .with_source_prefix_len(opts.source_prefix_len)
+                .with_feed(canvas.feed.clone())
+                .with_metrics(metrics.clone());
+            let mut admin_api = AdminApi::new(canvas.commands.clone());
+            if let Some(dir) = data_dir {
+                admin_api = admin_api.with_data_dir(dir.clone());
+            }
+            (
+                http_router.with_canvas(id.clone(), *prefix, http_api),
+                admin_router.with_canvas(id.clone(), *prefix, admin_api),
+            )
+        },
+    );
+    let (http_router, admin_router) = (Arc::new(http_router), Arc::new(admin_router));
     let ctrl_c = tokio::signal::ctrl_c();
+    let mut hangup = signal(SignalKind::hangup())?;
 
     tokio::pin!(ctrl_c);
 
@@ -82,14 +494,71 @@ async fn main() -> Result<()> {
                 info!("Received Ctrl+C signal");
                 break;
             }
+            _ = hangup.recv() => {
+                if canvases.iter().all(|canvas| canvas.config.rules.is_none()) {
+                    info!("Received SIGHUP, but there is no rules file to reload");
+                    continue;
+                }
+                for canvas in &canvases {
+                    let Some(path) = &canvas.config.rules else {
+                        continue;
+                    };
+                    match load_rules(path.clone()).await {
+                        Ok(rules) => {
+                            let _ = canvas.commands.send(CanvasCommand::SetRules { rules }).await;
+                        }
+                        Err(e) => warn!(
+                            "Failed to reload the region rules of canvas {}, keeping the current ones: {}",
+                            canvas.config.id, e
+                        ),
+                    }
+                }
+            }
             ping_sock_result = ping_socket.accept() => {
-                let sender = event_sender.clone();
+                let routes = routes.clone();
+                let senders = event_senders.clone();
+                let metrics = metrics.clone();
+                let ping_key = ping_key.clone();
+                let tls_acceptor = tls_acceptor.clone();
                 match ping_sock_result {
+                    Ok((_, addr)) if !allowlist.allows(addr.ip()) => {
+                        warn!("Refused ping connection from {}, not in the allowlist", addr);
+                        metrics.ping_refused_unlisted.inc();
+                    }
                     Ok((socket, addr)) => {
-                        info!("New ping connection from {}", addr);
+                        let mut registration = connections.register(addr, persistence::now_millis());
+                        info!("New ping connection #{} from {}", registration.info.id, addr);
                         tokio::spawn(async move {
-                            if let Err(e) = handle_ping_connection(socket, sender).await {
-                                warn!("Error handling ping connection from {}: {}", addr, e);
+                            metrics.ping_connections.inc();
+                            let key = ping_key.as_ref();
+                            let session = async {
+                                match &tls_acceptor {
+                                    Some(acceptor) => {
+                                        let handshake = acceptor.accept(socket);
+                                        let stream = match tokio::time::timeout(PING_AUTH_TIMEOUT, handshake).await {
+                                            Ok(Ok(stream)) => stream,
+                                            Ok(Err(e)) => {
+                                                metrics.ping_refused_tls.inc();
+                                                return Err(anyhow::Error::new(e).context("TLS handshake failed"));
+                                            }
+                                            Err(_) => {
+                                                metrics.ping_refused_tls.inc();
+                                                bail!("TLS handshake timed out");
+                                            }
+                                        };
+                                        serve_ping(stream, key, routes, senders, &metrics).await
+                                    }
+                                    None => serve_ping(socket, key, routes, senders, &metrics).await,
+                                }
+                            };
+                            // Dropping the connection closes it
+                            let result = tokio::select! {
+                                result = session => result,
+                                _ = &mut registration.disconnected => Ok(()),
+                            };
+                            metrics.ping_connections.dec();
+                            if let Err(e) = result {
+                                warn!("Error handling ping connection from {}: {:#}", addr, e);
                             }
                         });
                     }
@@ -98,14 +567,86 @@ async fn main() -> Result<()> {
                     }
                 }
             }
+            http_sock_result = http_socket.accept() => {
+                match http_sock_result {
+                    Ok((socket, addr)) => {
+                        debug!("New HTTP connection from {}", addr);
+                        let router = http_router.clone();
+                        let metrics = metrics.clone();
+                        tokio::spawn(async move {
+                            let handle = move |req: Request<Incoming>| {
+                                let router = router.clone();
+                                let metrics = metrics.clone();
+                                async move {
+                                    if req.uri().path() == "/metrics" {
+                                        return http::serve_metrics(&req, &metrics);
+                                    }
+                                    match router.route(req) {
+                                        Routed::Canvas(api, req) => api.handle(&req).await,
+                                        Routed::Response(response) => response,
+                                    }
+                                }
+                            };
+                            if let Err(e) = handle_http_connection(socket, handle).await {
+                                debug!("Error handling HTTP connection from {}: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Failed to accept HTTP connection: {}", e);
+                    }
+                }
+            }
+            admin_sock_result = accept(admin_socket.as_ref()) => {
+                match admin_sock_result {
+                    Ok((socket, addr)) => {
+                        info!("New admin connection from {}", addr);
+                        let router = admin_router.clone();
+                        let instance_api = instance_api.clone();
+                        let token: Arc<str> = admin_token.clone().expect("the admin service has a token");
+                        tokio::spawn(async move {
+                            let handle = move |req: Request<Incoming>| {
+                                let router = router.clone();
+                                let instance_api = instance_api.clone();
+                                let token = token.clone();
+                                async move {
+                                    if let Some(response) = admin::unauthorized(&req, &token) {
+                                        warn!("Unauthorized admin request {} {} from {}", req.method(), req.uri(), addr);
+                                        return response;
+                                    }
+                                    info!("Admin request {} {} from {}", req.method(), req.uri(), addr);
+                                    if let Some(response) = instance_api.handle(&req).await {
+                                        return response;
+                                    }
+                                    match router.route(req) {
+                                        Routed::Canvas(api, req) => api.handle(req).await,
+                                        Routed::Response(response) => response,
+                                    }
+                                }
+                            };
+                            if let Err(e) = handle_http_connection(socket, handle).await {
+                                debug!("Error handling admin connection from {}: {}", addr, e);
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        warn!("Failed to accept admin connection: {}", e);
+                    }
+                }
+            }
             diff = diff_receiver.recv() => {
                 match diff {
-                    Some(canvas_diff) => {
-                        info!("Canvas diff received with {} changed pixels", canvas_diff.changed_pixels().len());
+                    Some((index, canvas_diff)) => {
+                        info!(
+                            "Canvas {} diff received with {} changed pixels and {} changed labels",
+                            canvases[index].config.id,
+                            canvas_diff.changed_pixels().len(),
+                            canvas_diff.changed_labels().len()
+                        );
                         for pixel in canvas_diff.changed_pixels() {
                             debug!("Changed pixel at ({}, {}) with color {:?}", pixel.x, pixel.y, pixel.color);
                         }
-                        // TODO: Handle the canvas diff (e.g., send to WebSocket clients)
+                        canvases[index].feed.publish(canvas_diff);
                     }
                     None => {
                         warn!("Canvas diff sender has been closed");
@@ -117,19 +658,284 @@ async fn main() -> Result<()> {
     }
 
     info!("ipcanvas-service shutting down.");
+    // Let the canvas tasks write their final snapshot
+    let _ = shutdown_sender.send(true);
+    for canvas in canvases {
+        if let Err(e) = canvas.task.await {
+            warn!("Canvas task of {} failed: {}", canvas.config.id, e);
+        }
+    }
     Ok(())
 }
 
+/// A canvas served by the instance, with the channels to its task.
+struct CanvasHandle {
+    config: CanvasConfig,
+    metrics: Arc<CanvasMetrics>,
+    events: mpsc::Sender<SourcedEvent>,
+    commands: mpsc::Sender<CanvasCommand>,
+    /// Followers of the diffs of the canvas
+    feed: DiffFeed,
+    task: JoinHandle<()>,
+}
+
+/// Restore a canvas and spawn its task, then seed it with its initial image.
+///
+/// The diffs of the canvas are sent to `diff_sender` along with its `index`.
+async fn spawn_canvas(
+    index: usize,
+    config: CanvasConfig,
+    opts: &Opts,
+    webhooks: Webhooks,
+    diff_sender: mpsc::Sender<(usize, CanvasDiff)>,
+    shutdown_receiver: watch::Receiver<bool>,
+) -> Result<CanvasHandle> {
+    info!(
+        "Loading canvas {} ({}x{}, prefix {})",
+        config.id,
+        config.width,
+        config.height,
+        config
+            .prefix
+            .map_or("any".to_string(), |prefix| prefix.to_string())
+    );
+    let (event_sender, event_receiver) = mpsc::channel::<SourcedEvent>(opts.event_buffer_size);
+    let (command_sender, command_receiver) =
+        mpsc::channel::<CanvasCommand>(opts.command_buffer_size);
+    let (canvas_diff_sender, mut canvas_diff_receiver) =
+        mpsc::channel::<CanvasDiff>(opts.diff_buffer_size);
+
+    let canvas = Canvas::new(config.width, config.height);
+    let (snapshot, persistence) = match &config.data_dir {
+        Some(dir) => {
+            let snapshots = SnapshotStore::open(dir)?;
+            let log = EventLog::open(dir)?;
+            let (snapshot, log) = restore(
+                snapshots.clone(),
+                log,
+                canvas,
+                config.cooldown_prefix_len,
+                opts.player_retention_days,
+            )
+            .await?;
+            let persistence = Persistence {
+                snapshots,
+                log,
+                snapshot_interval: Duration::from_secs(opts.snapshot_interval),
+                keep_log: opts.keep_event_log,
+            };
+            (snapshot, Some(persistence))
+        }
+        None => {
+            let snapshot = Snapshot {
+                sequence: 0,
+                timestamp: persistence::now_millis(),
+                canvas,
+                activity: Activity::new()
+                    .with_prefix_len(config.cooldown_prefix_len)
+                    .with_player_retention_days(opts.player_retention_days),
+            };
+            (snapshot, None)
+        }
+    };
+
+    // No event has ever been applied to this canvas
+    let fresh = snapshot.sequence == 0;
+    let metrics = Arc::new(CanvasMetrics::new().with_queues(&event_sender, &canvas_diff_sender));
+    let cooldown = Cooldown::new(Duration::from_secs(config.cooldown))
+        .with_prefix_len(config.cooldown_prefix_len);
+    let rules = match &config.rules {
+        Some(path) => load_rules(path.clone()).await?,
+        None => RegionRules::default(),
+    };
+    // Spawn the canvas management task, sending a diff at each tick
+    let task = tokio::spawn(canvas_task(
+        snapshot,
+        Duration::from_millis(opts.tick_interval_ms),
+        persistence,
+        cooldown,
+        rules,
+        event_receiver,
+        command_receiver,
+        canvas_diff_sender,
+        metrics.clone(),
+        (config.id.clone(), webhooks),
+        shutdown_receiver,
+    ));
+    // Tag the diffs with their canvas
+    tokio::spawn(async move {
+        while let Some(diff) = canvas_diff_receiver.recv().await {
+            if diff_sender.send((index, diff)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Seed the canvas, through the canvas task so the import is logged like any event
+    if let Some(path) = &config.init_image {
+        if fresh {
+            let image = load_image(path.clone()).await?;
+            let (reply, response) = oneshot::channel();
+            let command = CanvasCommand::Import {
+                image,
+                x: 0,
+                y: 0,
+                reply,
+            };
+            if command_sender.send(command).await.is_ok()
+                && let Ok(ImportResponse { applied, rejected }) = response.await
+            {
+                info!(
+                    "Canvas {} initialised from {} ({} pixels, {} outside of the canvas)",
+                    config.id,
+                    path.display(),
+                    applied,
+                    rejected
+                );
+            }
+        } else {
+            info!(
+                "Canvas {} restored from the data directory, ignoring the initial image",
+                config.id
+            );
+        }
+    }
+
+    Ok(CanvasHandle {
+        config,
+        metrics,
+        events: event_sender,
+        commands: command_sender,
+        feed: DiffFeed::new(),
+        task,
+    })
+}
+
+/// On-disk persistence of the canvas state.
+struct Persistence {
+    snapshots: SnapshotStore,
+    log: EventLog,
+    snapshot_interval: Duration,
+    // Whether the log is kept whole, or compacted after each snapshot
+    keep_log: bool,
+}
+
+/// Restore the canvas state: load the latest valid snapshot, and replay the event log on top.
+///
+/// `canvas` is used as the starting point if there is no snapshot yet. The players of the
+/// activity are identified by `prefix_len` bits of their address, and kept for
+/// `player_retention_days`.
+async fn restore(
+    snapshots: SnapshotStore,
+    mut log: EventLog,
+    canvas: Canvas,
+    prefix_len: u8,
+    player_retention_days: u32,
+) -> Result<(Snapshot, EventLog)> {
+    tokio::task::spawn_blocking(move || {
+        let (snapshot, errors) = snapshots.load_latest()?;
+        for (path, e) in errors {
+            warn!("Skipping invalid snapshot {}: {}", path.display(), e);
+        }
+        let mut snapshot = match snapshot {
+            Some(snapshot) => {
+                info!(
+                    "Canvas restored from snapshot #{} ({}x{})",
+                    snapshot.sequence,
+                    snapshot.canvas.width(),
+                    snapshot.canvas.height()
+                );
+                if (snapshot.canvas.width(), snapshot.canvas.height())
+                    != (canvas.width(), canvas.height())
+                {
+                    warn!(
+                        "Canvas dimensions of the snapshot differ from the configured ones ({}x{}), keeping them",
+                        canvas.width(),
+                        canvas.height()
+                    );
+                }
+                snapshot
+            }
+            None => Snapshot {
+                sequence: 0,
+                timestamp: persistence::now_millis(),
+                canvas,
+                activity: Activity::new(),
+            },
+        };
+        snapshot.activity = std::mem::take(&mut snapshot.activity)
+            .with_prefix_len(prefix_len)
+            .with_player_retention_days(player_retention_days);
+
+        let after = snapshot.sequence;
+        let stats = log.replay(after, |entry| {
+            let placement = Placement {
+                source: entry.source,
+                timestamp: entry.timestamp,
+            };
+            let applied = snapshot
+                .activity
+                .apply(&mut snapshot.canvas, &entry.event, placement);
+            if let Err(e) = applied {
+                warn!("Failed to replay event #{}: {}", entry.sequence, e);
+            }
+            snapshot.sequence = entry.sequence;
+        })?;
+        for (path, dropped) in &stats.damaged {
+            warn!(
+                "Event log segment {} is damaged, skipped its last {} bytes",
+                path.display(),
+                dropped
+            );
+        }
+        for (first, last) in &stats.missing {
+            warn!(
+                "Events #{} to #{} are missing from the event log, they have not been replayed",
+                first, last
+            );
+        }
+        info!(
+            "Replayed {} events from the event log (up to #{})",
+            stats.replayed, snapshot.sequence
+        );
+        // Replayed changes are part of the initial state, not of the first diff
+        snapshot.canvas.take_diff();
+        Ok((snapshot, log))
+    })
+    .await?
+}
+
+/// Authenticate a ping listener with the shared key (if any), then handle its events.
+async fn serve_ping<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    key: Option<&PingKey>,
+    routes: PingRoutes,
+    events_senders: Vec<mpsc::Sender<SourcedEvent>>,
+    metrics: &Metrics,
+) -> Result<()> {
+    if let Some(key) = key {
+        if let Err(e) = authenticate(&mut stream, key).await {
+            metrics.ping_refused_unauthenticated.inc();
+            return Err(e.context("Authentication failed"));
+        }
+        debug!("Ping listener authenticated");
+    }
+    handle_ping_connection(stream, routes, events_senders, metrics).await
+}
+
 /// Handle an individual ping connection
-async fn handle_ping_connection(
-    mut socket: TcpStream,
-    events_sender: mpsc::Sender<Event>,
+///
+/// Each event is sent to the canvas it is routed to, as its index in `events_senders`.
+async fn handle_ping_connection<R: AsyncRead + Unpin>(
+    mut reader: R,
+    routes: PingRoutes,
+    events_senders: Vec<mpsc::Sender<SourcedEvent>>,
+    metrics: &Metrics,
 ) -> Result<()> {
     let span = span!(tracing::Level::TRACE, "handle_ping_connection");
     let _enter = span.enter();
 
-    let mut ping_server = PingServer::default();
-    let (mut reader, _) = socket.split();
+    let mut ping_server = PingServer::default().with_routes(routes);
 
     let mut read_buf = [0u8; 4096];
     let mut read_len = 0;
@@ -139,30 +945,32 @@ async fn handle_ping_connection(
         // Read the outputs from the server
         let to_egress = ping_server.ready_events();
         if to_egress > 0 {
-            match events_sender
-                .reserve_many(to_egress.min(EVENT_BUFFER_SIZE))
-                .await
-            {
-                Ok(mut permit) => {
-                    let n = permit.len();
-                    let events = ping_server.egress(n);
-                    for event in events {
-                        permit.next().expect("permit is allocated").send(event);
-                    }
-                    trace!("Sent {} events to event channel", n);
-                }
-                Err(e) => {
+            let events = ping_server.egress(to_egress.min(PING_BATCH_SIZE));
+            let n = events.len();
+            let mut closed = false;
+            for event in events {
+                // Waiting for a busy canvas holds back the events of the others
+                if let Err(e) = events_senders[event.canvas].send(event.into()).await {
                     warn!(
                         "Failed to send events to event channel - channel closed: {}",
                         e
                     );
+                    closed = true;
                     break;
                 }
             }
+            if closed {
+                break;
+            }
+            trace!("Sent {} events to event channels", n);
         }
 
         // Try to make progress
+        let unrouted = ping_server.unrouted_events();
         let rst = ping_server.progress();
+        metrics
+            .ping_events_unrouted
+            .add(ping_server.unrouted_events() - unrouted);
         let mut should_block_read = false;
         match rst {
             Ok(()) => {
@@ -188,7 +996,7 @@ async fn handle_ping_connection(
         if read_len == 0 {
             trace!("PingServer trying to read from socket");
             // Read from the socket (and block if no progress can be made otherwise)
-            match reader.try_read(&mut read_buf) {
+            match try_read_now(&mut reader, &mut read_buf) {
                 Ok(0) => {
                     // Connection closed
                     break;
@@ -242,66 +1050,711 @@ async fn handle_ping_connection(
         }
     }
 
+    if ping_server.unrouted_events() > 0 {
+        warn!(
+            "Dropped {} ping events sent to no canvas",
+            ping_server.unrouted_events()
+        );
+    }
+    Ok(())
+}
+
+/// Accept a connection on an optional listener, never resolves if there is no listener.
+async fn accept(
+    listener: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, std::net::SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Read a secret from a file, without its leading and trailing whitespace.
+fn load_secret(path: &Path, what: &str) -> Result<String> {
+    let secret = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read the {} from {}", what, path.display()))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        bail!("The {} file {} is empty", what, path.display());
+    }
+    Ok(secret.to_string())
+}
+
+/// Read from `reader` without waiting, failing with `WouldBlock` if no data is available.
+fn try_read_now<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut buf = ReadBuf::new(buf);
+    let mut cx = task::Context::from_waker(Waker::noop());
+    match Pin::new(reader).poll_read(&mut cx, &mut buf) {
+        Poll::Ready(Ok(())) => Ok(buf.filled().len()),
+        Poll::Ready(Err(e)) => Err(e),
+        Poll::Pending => Err(std::io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+/// Authenticate a ping listener with the shared key, see [PingKey].
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    key: &PingKey,
+) -> Result<()> {
+    let challenge = PingKey::challenge()?;
+    stream.write_all(&challenge).await?;
+    stream.flush().await?;
+    let mut response = [0; RESPONSE_LEN];
+    tokio::time::timeout(PING_AUTH_TIMEOUT, stream.read_exact(&mut response))
+        .await
+        .context("No response to the challenge")??;
+    if !key.verify(&challenge, &response) {
+        bail!("Wrong response to the challenge");
+    }
+    Ok(())
+}
+
+/// Describe the configuration of the instance, as served by `GET /config` (without the token).
+fn config_json<'a>(
+    opts: &Opts,
+    canvases: impl Iterator<Item = &'a CanvasConfig>,
+) -> serde_json::Value {
+    let canvases: Vec<_> = canvases
+        .map(|canvas| {
+            json!({
+                "id": canvas.id,
+                "prefix": canvas.prefix.map(|prefix| prefix.to_string()),
+                "width": canvas.width,
+                "height": canvas.height,
+                "data_dir": canvas.data_dir,
+                "rules": canvas.rules,
+                "init_image": canvas.init_image,
+                "cooldown": canvas.cooldown,
+                "cooldown_prefix_len": canvas.cooldown_prefix_len,
+            })
+        })
+        .collect();
+    json!({
+        "ping_addr": opts.ping_addr,
+        "websocket_addr": opts.websocket_addr,
+        "http_addr": opts.http_addr,
+        "admin_addr": opts.admin_addr,
+        "source_prefix_len": opts.source_prefix_len,
+        "snapshot_interval": opts.snapshot_interval,
+        "player_retention_days": opts.player_retention_days,
+        "keep_event_log": opts.keep_event_log,
+        "ping_authenticated": opts.ping_key_file.is_some(),
+        "ping_tls": opts.ping_tls_cert.is_some(),
+        "ping_tls_client_ca": opts.ping_tls_client_ca,
+        "webhooks": opts.webhooks,
+        "ping_allow": opts.ping_allow.iter().map(|prefix| prefix.to_string()).collect::<Vec<_>>(),
+        "tick_interval_ms": opts.tick_interval_ms,
+        "event_buffer_size": opts.event_buffer_size,
+        "diff_buffer_size": opts.diff_buffer_size,
+        "command_buffer_size": opts.command_buffer_size,
+        "canvases": canvases,
+    })
+}
+
+/// Read and decode an image to import, off the runtime threads.
+async fn load_image(path: PathBuf) -> Result<ImportImage> {
+    let image = tokio::task::spawn_blocking(move || {
+        let bytes = std::fs::read(&path)?;
+        anyhow::Ok(ImportImage::decode_png(&bytes)?)
+    })
+    .await??;
+    Ok(image)
+}
+
+/// Read the region rules, off the runtime threads.
+async fn load_rules(path: PathBuf) -> Result<RegionRules> {
+    let rules = tokio::task::spawn_blocking(move || RegionRules::load(&path)).await??;
+    info!("Loaded the region rules ({} regions)", rules.len());
+    Ok(rules)
+}
+
+/// Handle an individual HTTP connection, answering each request with `handle`
+async fn handle_http_connection<F, Fut>(socket: TcpStream, handle: F) -> Result<()>
+where
+    F: Fn(Request<Incoming>) -> Fut,
+    Fut: Future<Output = Response<Body>>,
+{
+    let service = service_fn(move |req| {
+        let response = handle(req);
+        async move { Ok::<_, Infallible>(response.await) }
+    });
+    http1::Builder::new()
+        .serve_connection(TokioIo::new(socket), service)
+        .await?;
     Ok(())
 }
 
 /// Canvas management task
 ///
 /// This task received the updates to the canvas from the ping service,
-/// calculate the new state of the canvas, and create diffs for other tasks
+/// calculate the new state of the canvas, and create diffs for other tasks.
+/// It also answers the [CanvasCommand]s of the other tasks.
+///
+/// Ingested events from players still cooling down, or breaking the rules of
+/// a region, are rejected and counted along with the other rejected events.
+///
+/// If persistence is enabled, every applied event is appended to the event log,
+/// the canvas is regularly snapshotted, and a final snapshot is written when the task stops.
+///
+/// The counters of the applied and rejected events are published to `metrics` at each tick.
+#[allow(clippy::too_many_arguments)]
 async fn canvas_task(
-    mut canvas: Canvas,
+    snapshot: Snapshot,
     update_interval: Duration,
-    mut events_listener: mpsc::Receiver<Event>,
+    mut persistence: Option<Persistence>,
+    mut cooldown: Cooldown,
+    mut rules: RegionRules,
+    mut events_listener: mpsc::Receiver<SourcedEvent>,
+    mut commands: mpsc::Receiver<CanvasCommand>,
     diff_sender: mpsc::Sender<CanvasDiff>,
+    metrics: Arc<CanvasMetrics>,
+    (id, webhooks): (String, Webhooks),
+    mut shutdown: watch::Receiver<bool>,
 ) {
     let span = span!(tracing::Level::TRACE, "canvas_task");
     let _enter = span.enter();
-    let mut prev_canvas = canvas.clone();
+    let Snapshot {
+        mut sequence,
+        mut canvas,
+        mut activity,
+        ..
+    } = snapshot;
 
-    // Diff are sent periodically (every 100ms)
+    // Diff are sent periodically (every `update_interval`)
     let mut interval = tokio::time::interval(update_interval);
+    // Snapshots are written periodically, the first one after a full period
+    let snapshot_period = persistence
+        .as_ref()
+        .map_or(Duration::from_secs(3600), |p| p.snapshot_interval);
+    let mut snapshot_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + snapshot_period,
+        snapshot_period,
+    );
+    let mut pending_snapshot: Option<JoinHandle<()>> = None;
+    let mut rejected = RejectedEvents::default();
+    // Whether the ingested events are rejected
+    let mut frozen = false;
+    // Sequence of the last applied event published to the metrics
+    let mut published = sequence;
+    // Only tracked for the webhooks
+    let region_progress = |rules: &RegionRules, canvas: &Canvas| {
+        if webhooks.is_empty() {
+            RegionProgress::default()
+        } else {
+            RegionProgress::new(rules, canvas)
+        }
+    };
+    let mut progress = region_progress(&rules, &canvas);
 
     loop {
         tokio::select! { biased;
+            _ = shutdown.changed() => {
+                info!("Canvas task shutting down");
+                break;
+            }
+            Some(command) = commands.recv() => {
+                let _timer = metrics.task_latency.start_timer();
+                match command {
+                    CanvasCommand::Crop { region, unless_version, reply } => {
+                        let _ = reply.send(CropResponse::new(&canvas, region, unless_version));
+                    }
+                    CanvasCommand::Import { image, x, y, reply } => {
+                        let mut response = ImportResponse::default();
+                        let events = image.events(x, y).map(|event| SourcedEvent { source: EventSource::Import, event });
+                        apply_events(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), events, |applied| {
+                            match applied {
+                                Ok(()) => response.applied += 1,
+                                Err(_) => response.rejected += 1,
+                            }
+                        })
+                        .await;
+                        info!(
+                            "Imported a {}x{} image at ({}, {}): {} pixels applied, {} rejected",
+                            image.width(), image.height(), x, y, response.applied, response.rejected
+                        );
+                        let _ = reply.send(response);
+                    }
+                    CanvasCommand::Resize { width, height, background, reply } => {
+                        if width < canvas.width() || height < canvas.height() {
+                            let _ = reply.send(ResizeResponse::TooSmall {
+                                width: canvas.width(),
+                                height: canvas.height(),
+                            });
+                            continue;
+                        }
+                        if (width, height) != (canvas.width(), canvas.height()) {
+                            let event = SourcedEvent {
+                                source: EventSource::Admin,
+                                event: Event::Resize { width, height, background },
+                            };
+                            apply_event(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), event)
+                                .expect("the canvas does not shrink");
+                            info!("Canvas resized to {}x{}", width, height);
+                            webhooks.notify(&id, &WebhookEvent::Resize { width, height });
+                            // Make the resize durable, and notify the clients of it on its own
+                            if let Some(persistence) = persistence.as_mut()
+                                && let Err(e) = tokio::task::block_in_place(|| persistence.log.flush())
+                            {
+                                warn!("Failed to flush the event log: {}", e);
+                            }
+                            let diff = canvas.take_diff();
+                            metrics.diff_pixels.observe(diff.changed_pixels().len() as u64);
+                            if let Err(e) = diff_sender.send(diff).await {
+                                warn!("Receiver for canvas diff has been closed: {}", e);
+                                break;
+                            }
+                        }
+                        let _ = reply.send(ResizeResponse::Resized);
+                    }
+                    CanvasCommand::Pixel { x, y, reply } => {
+                        let _ = reply.send(PixelInfo::new(&canvas, x, y));
+                    }
+                    CanvasCommand::Labels { region, reply } => {
+                        let _ = reply.send(LabelInfo::list(&canvas, region));
+                    }
+                    CanvasCommand::Tiles { tiles, reply } => {
+                        let _ = reply.send(TileSnapshot::list(&canvas, &tiles));
+                    }
+                    CanvasCommand::PlaceLabel { label, reply } => {
+                        let event = SourcedEvent {
+                            source: EventSource::Admin,
+                            event: Event::PlaceLabel { x: label.x, y: label.y, text: label.text },
+                        };
+                        let applied =
+                            apply_event(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), event);
+                        let _ = reply.send(applied.is_ok());
+                    }
+                    CanvasCommand::RasteriseLabels { color, reply } => {
+                        let mut response = RasteriseResponse::default();
+                        let labels: Vec<Label> = canvas.labels().copied().collect();
+                        let pixels = labels
+                            .iter()
+                            .flat_map(|label| label.events(color))
+                            .map(|event| SourcedEvent { source: EventSource::Admin, event });
+                        apply_events(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), pixels, |applied| {
+                            if applied.is_ok() {
+                                response.pixels += 1;
+                            }
+                        })
+                        .await;
+                        let removals = labels.iter().map(|label| SourcedEvent {
+                            source: EventSource::Admin,
+                            event: Event::PlaceLabel { x: label.x, y: label.y, text: [0; MAX_TEXT_LEN] },
+                        });
+                        apply_events(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), removals, |applied| {
+                            if applied.is_ok() {
+                                response.labels += 1;
+                            }
+                        })
+                        .await;
+                        info!(
+                            "Rasterised {} labels ({} pixels)",
+                            response.labels, response.pixels
+                        );
+                        let _ = reply.send(response);
+                    }
+                    CanvasCommand::Stats { reply } => {
+                        let _ = reply.send(CanvasStats {
+                            applied: sequence,
+                            rejected,
+                            cooling_down: cooldown.len(),
+                            frozen,
+                        });
+                    }
+                    CanvasCommand::Activity { from, to, reply } => {
+                        let _ = reply.send(activity.summary(from, to));
+                    }
+                    CanvasCommand::Heatmap { window, reply } => {
+                        let now = persistence::now_millis();
+                        let layer = activity.heatmap().layer(window, now, canvas.width(), canvas.height());
+                        let _ = reply.send(layer);
+                    }
+                    CanvasCommand::FlushLog { reply } => {
+                        let flushed = persistence.as_mut().is_some_and(|persistence| {
+                            tokio::task::block_in_place(|| persistence.log.flush())
+                                .inspect_err(|e| warn!("Failed to flush the event log: {}", e))
+                                .is_ok()
+                        });
+                        let _ = reply.send(flushed);
+                    }
+                    CanvasCommand::Rollback { filter, plan, reply } => {
+                        let mut response = RollbackResponse::default();
+                        // Skip the pixels placed again since the plan was computed, nothing else
+                        // changes the canvas while the plan is applied
+                        let (reverted, placed_again): (Vec<&RevertedPixel>, Vec<&RevertedPixel>) = plan
+                            .pixels
+                            .iter()
+                            .partition(|pixel| canvas.placement(pixel.x, pixel.y) == Some(pixel.expected));
+                        response.skipped += placed_again.len() as u64;
+                        let events = reverted.into_iter().map(|pixel| SourcedEvent {
+                            source: EventSource::Admin,
+                            event: Event::PlacePixel {
+                                x: pixel.x,
+                                y: pixel.y,
+                                color: pixel.color,
+                            },
+                        });
+                        apply_events(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), events, |applied| {
+                            match applied {
+                                Ok(()) => response.reverted += 1,
+                                Err(_) => response.skipped += 1,
+                            }
+                        })
+                        .await;
+                        info!(
+                            "Rolled back {} placements: {} pixels reverted, {} skipped",
+                            plan.matched, response.reverted, response.skipped
+                        );
+                        webhooks.notify(&id, &WebhookEvent::Rollback {
+                            filter,
+                            matched: plan.matched,
+                            reverted: response.reverted,
+                            skipped: response.skipped,
+                        });
+                        let _ = reply.send(response);
+                    }
+                    CanvasCommand::SetRules { rules: new_rules } => {
+                        info!("Region rules replaced ({} regions)", new_rules.len());
+                        rules = new_rules;
+                        progress = region_progress(&rules, &canvas);
+                    }
+                    CanvasCommand::Fill { region, color, reply } => {
+                        if canvas.region_version(region).is_none() {
+                            let _ = reply.send(FillResponse::OutOfBounds {
+                                width: canvas.width(),
+                                height: canvas.height(),
+                            });
+                            continue;
+                        }
+                        let events = (region.y..region.y + region.height).flat_map(|y| {
+                            (region.x..region.x + region.width).map(move |x| SourcedEvent {
+                                source: EventSource::Admin,
+                                event: Event::PlacePixel { x, y, color },
+                            })
+                        });
+                        let mut pixels = 0;
+                        apply_events(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), events, |applied| {
+                            if applied.is_ok() {
+                                pixels += 1;
+                            }
+                        })
+                        .await;
+                        info!(
+                            "Painted {}x{} pixels at ({}, {}) with {}",
+                            region.width, region.height, region.x, region.y, color.to_hex()
+                        );
+                        let whole = Region { x: 0, y: 0, width: canvas.width(), height: canvas.height() };
+                        let event = if region == whole {
+                            WebhookEvent::Reset { color, pixels }
+                        } else {
+                            WebhookEvent::Fill { region, color, pixels }
+                        };
+                        webhooks.notify(&id, &event);
+                        let _ = reply.send(FillResponse::Filled { pixels });
+                    }
+                    CanvasCommand::SetFrozen { frozen: requested, reply } => {
+                        if requested == frozen {
+                            let _ = reply.send(false);
+                            continue;
+                        }
+                        frozen = requested;
+                        let (action, event) = if frozen {
+                            (AdminAction::Freeze, WebhookEvent::Freeze)
+                        } else {
+                            (AdminAction::Unfreeze, WebhookEvent::Unfreeze)
+                        };
+                        record_action(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), action);
+                        webhooks.notify(&id, &event);
+                        let _ = reply.send(true);
+                    }
+                    CanvasCommand::Snapshot { reply } => {
+                        let Some(persistence) = persistence.as_mut() else {
+                            let _ = reply.send(SnapshotResponse::Disabled);
+                            continue;
+                        };
+                        if pending_snapshot.as_ref().is_some_and(|handle| !handle.is_finished()) {
+                            let _ = reply.send(SnapshotResponse::Busy);
+                            continue;
+                        }
+                        // The marker is part of the snapshot
+                        record_action(&mut canvas, &mut activity, &mut sequence, Some(&mut *persistence), AdminAction::Snapshot);
+                        if let Err(e) = tokio::task::block_in_place(|| persistence.log.rotate()) {
+                            warn!("Failed to rotate the event log: {}", e);
+                        }
+                        pending_snapshot = Some(write_snapshot(persistence, sequence, &canvas, &activity, Some(reply)));
+                    }
+                    CanvasCommand::Mark { action } => {
+                        record_action(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), action);
+                    }
+                }
+            }
             event = events_listener.recv() => {
                 event!(tracing::Level::TRACE, "Received canvas event");
-                match event {
-                    Some(Event::PlacePixel { x, y, color }) => {
-                        if let Err(_) = canvas.set_pixel(x, y, color) {
-                            warn!("Failed to place pixel at ({}, {}): out of bounds", x, y);
+                let Some(event) = event else {
+                    // Channel closed, exit the task
+                    break;
+                };
+                metrics.events_ingested.inc();
+                let _timer = metrics.task_latency.start_timer();
+                if frozen {
+                    rejected.frozen += 1;
+                    continue;
+                }
+                let now = persistence::now_millis();
+                if let Err(e) = cooldown.check(event.source, now) {
+                    debug!("Rejected event: {}", e);
+                    rejected.cooldown += 1;
+                    continue;
+                }
+                let source = event.source;
+                let pixel = match event.event {
+                    Event::PlacePixel { x, y, color } => Some((x, y, color)),
+                    _ => None,
+                };
+                if let Some((x, y, color)) = pixel
+                    && let Err(violation) = rules.check(source, x, y, color, now)
+                {
+                    debug!("Rejected event: {}", violation);
+                    match violation.rule {
+                        Rule::ReadOnly => rejected.read_only += 1,
+                        Rule::Cooldown(_) => rejected.region_cooldown += 1,
+                        Rule::Palette(_) => rejected.palette += 1,
+                        Rule::AllowedPrefixes => rejected.allowed_prefixes += 1,
+                    }
+                    continue;
+                }
+                match apply_event(&mut canvas, &mut activity, &mut sequence, persistence.as_mut(), event) {
+                    Ok(()) => {
+                        cooldown.record(source, now);
+                        if let Some((x, y, _)) = pixel {
+                            rules.record(source, x, y, now);
+                            for event in progress.record(x, y) {
+                                if let WebhookEvent::RegionCompleted { name, .. } = &event {
+                                    info!("Region {:?} completed", name);
+                                }
+                                webhooks.notify(&id, &event);
+                            }
                         }
                     }
-                    Some(Event::PlaceLabel { .. }) => {
-                        todo!("Handle PlaceLabel event");
+                    Err(ApplyError::OutOfBounds { x, y }) => {
+                        warn!("Failed to place pixel at ({}, {}): out of bounds", x, y);
+                        rejected.out_of_bounds += 1;
                     }
-                    Some(_) => { unimplemented!() }
-                    None => {
-                        // Channel closed, exit the task
-                        break;
+                    Err(e @ (ApplyError::Unsupported | ApplyError::InvalidSize { .. })) => {
+                        warn!("Failed to apply event: {}", e);
+                        rejected.unsupported += 1;
                     }
                 }
             }
             _ = interval.tick() => {
                 event!(tracing::Level::TRACE, "Canvas update interval ticked");
-                // Calculate the diff between the current canvas and the previous canvas
-                let diff = prev_canvas.diff(&canvas);
+                // Make the events of this tick durable
+                if let Some(persistence) = persistence.as_mut()
+                    && let Err(e) = tokio::task::block_in_place(|| persistence.log.flush())
+                {
+                    warn!("Failed to flush the event log: {}", e);
+                }
+                metrics.events_applied.add(sequence - published);
+                published = sequence;
+                metrics.set_rejected(rejected);
+                // Drain the changes made since the previous tick
+                let diff = canvas.take_diff();
                 if diff.is_empty() {
                     // No changes, skip sending
                     continue;
                 }
+                metrics.diff_pixels.observe(diff.changed_pixels().len() as u64);
                 // Send the diff to other tasks
                 if let Err(e) = diff_sender.send(diff).await {
                     warn!("Receiver for canvas diff has been closed: {}", e);
                     break;
                 }
-                // Update the previous canvas
-                prev_canvas = canvas.clone();
+            }
+            _ = snapshot_interval.tick(), if persistence.is_some() => {
+                if pending_snapshot.as_ref().is_some_and(|handle| !handle.is_finished()) {
+                    debug!("Previous canvas snapshot still in progress, skipping");
+                    continue;
+                }
+                let persistence = persistence.as_mut().expect("persistence is enabled");
+                // Start a new log segment, so the previous ones can be compacted
+                if let Err(e) = tokio::task::block_in_place(|| persistence.log.rotate()) {
+                    warn!("Failed to rotate the event log: {}", e);
+                }
+                pending_snapshot = Some(write_snapshot(persistence, sequence, &canvas, &activity, None));
             }
         }
     }
 
-    // On channel closure, send the final diff
-    let diff = prev_canvas.diff(&canvas);
-    let _ = diff_sender.send(diff).await;
+    // On exit, send the final diff
+    let diff = canvas.take_diff();
+    if !diff.is_empty() {
+        let _ = diff_sender.send(diff).await;
+    }
+
+    // And write the final snapshot
+    if let Some(mut persistence) = persistence {
+        if let Some(handle) = pending_snapshot {
+            let _ = handle.await;
+        }
+        if let Err(e) = tokio::task::block_in_place(|| persistence.log.rotate()) {
+            warn!("Failed to rotate the event log: {}", e);
+        }
+        let _ = write_snapshot(&persistence, sequence, &canvas, &activity, None).await;
+    }
+}
+
+/// Apply an event to the canvas, recording its placement and the activity of the players,
+/// and append it to the event log once applied.
+fn apply_event(
+    canvas: &mut Canvas,
+    activity: &mut Activity,
+    sequence: &mut u64,
+    persistence: Option<&mut Persistence>,
+    event: SourcedEvent,
+) -> Result<(), ApplyError> {
+    let entry = apply_unlogged(canvas, activity, sequence, event)?;
+    if let Some(persistence) = persistence {
+        append_entry(&mut persistence.log, &entry);
+    }
+    Ok(())
+}
+
+/// Apply many events to the canvas like [apply_event], calling `f` with the outcome of each.
+///
+/// The events are applied by chunks of [BULK_CHUNK_SIZE]: the entries of a chunk are appended
+/// to the event log at once, then the task yields, so a large fill or import does not hold
+/// the worker thread for its whole duration.
+async fn apply_events(
+    canvas: &mut Canvas,
+    activity: &mut Activity,
+    sequence: &mut u64,
+    mut persistence: Option<&mut Persistence>,
+    events: impl IntoIterator<Item = SourcedEvent>,
+    mut f: impl FnMut(Result<(), ApplyError>),
+) {
+    let mut events = events.into_iter().peekable();
+    let mut entries = Vec::with_capacity(BULK_CHUNK_SIZE);
+    while events.peek().is_some() {
+        for event in events.by_ref().take(BULK_CHUNK_SIZE) {
+            match apply_unlogged(canvas, activity, sequence, event) {
+                Ok(entry) => {
+                    entries.push(entry);
+                    f(Ok(()));
+                }
+                Err(e) => f(Err(e)),
+            }
+        }
+        if let Some(persistence) = persistence.as_deref_mut() {
+            tokio::task::block_in_place(|| {
+                for entry in &entries {
+                    append_entry(&mut persistence.log, entry);
+                }
+            });
+        }
+        entries.clear();
+        tokio::task::yield_now().await;
+    }
+}
+
+/// Apply an event to the canvas, recording its placement and the activity of the players,
+/// and return the entry to append to the event log.
+fn apply_unlogged(
+    canvas: &mut Canvas,
+    activity: &mut Activity,
+    sequence: &mut u64,
+    SourcedEvent { source, event }: SourcedEvent,
+) -> Result<LogEntry, ApplyError> {
+    let timestamp = persistence::now_millis();
+    activity.apply(canvas, &event, Placement { source, timestamp })?;
+    *sequence += 1;
+    Ok(LogEntry {
+        sequence: *sequence,
+        timestamp,
+        source,
+        event,
+    })
+}
+
+/// Append an entry to the event log, a failure is only reported.
+fn append_entry(log: &mut EventLog, entry: &LogEntry) {
+    if let Err(e) = log.append(entry) {
+        warn!(
+            "Failed to append event #{} to the event log: {}",
+            entry.sequence, e
+        );
+    }
+}
+
+/// Record an operator action in the event log, as an [Event::AdminMarker].
+fn record_action(
+    canvas: &mut Canvas,
+    activity: &mut Activity,
+    sequence: &mut u64,
+    persistence: Option<&mut Persistence>,
+    action: AdminAction,
+) {
+    let event = SourcedEvent {
+        source: EventSource::Admin,
+        event: Event::AdminMarker { action },
+    };
+    apply_event(canvas, activity, sequence, persistence, event)
+        .expect("markers do not change the canvas");
+    info!(
+        "Operator action recorded as event #{}: {}",
+        sequence, action
+    );
+}
+
+/// Write a snapshot of the canvas in the background,
+/// and compact the event log once it is durable (unless the whole log is kept).
+///
+/// The outcome is sent to `reply`, if any.
+fn write_snapshot(
+    persistence: &Persistence,
+    sequence: u64,
+    canvas: &Canvas,
+    activity: &Activity,
+    reply: Option<oneshot::Sender<SnapshotResponse>>,
+) -> JoinHandle<()> {
+    let snapshot = Snapshot {
+        sequence,
+        timestamp: persistence::now_millis(),
+        canvas: canvas.clone(),
+        activity: activity.clone(),
+    };
+    let store = persistence.snapshots.clone();
+    let log_dir = (!persistence.keep_log).then(|| persistence.log.dir().to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        match store.write(&snapshot) {
+            Ok(path) => {
+                info!("Canvas snapshot written to {}", path.display());
+                if let Some(reply) = reply {
+                    let _ = reply.send(SnapshotResponse::Written { sequence, path });
+                }
+            }
+            Err(e) => {
+                warn!("Failed to write canvas snapshot: {}", e);
+                if let Some(reply) = reply {
+                    let _ = reply.send(SnapshotResponse::Failed);
+                }
+                return;
+            }
+        }
+        let Some(log_dir) = log_dir else {
+            return;
+        };
+        // Keep the entries after the oldest snapshot, in case the newer ones turn out to be invalid
+        let oldest = match store.list() {
+            Ok(sequences) => sequences.first().copied().unwrap_or(sequence),
+            Err(e) => {
+                warn!("Failed to list the canvas snapshots: {}", e);
+                return;
+            }
+        };
+        match EventLog::compact(&log_dir, oldest) {
+            Ok(0) => {}
+            Ok(n) => debug!("Compacted {} event log segments", n),
+            Err(e) => warn!("Failed to compact the event log: {}", e),
+        }
+    })
 }
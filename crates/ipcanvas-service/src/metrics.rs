@@ -0,0 +1,510 @@
+//! Metrics: counters, gauges and histograms of the service, in the Prometheus text format.
+//!
+//! The metrics are plain atomics, updated by the tasks of the service and read when
+//! [Metrics::render] is called (by `GET /metrics`).
+//!
+//! Metrics of the service:
+//! - `ipcanvas_ping_connections`: open connections from ping listeners.
+//! - `ipcanvas_ping_events_unrouted_total`: ping events sent to no canvas.
+//! - `ipcanvas_viewers`: viewers following the diffs of a canvas (`GET /events`).
+//! - `ipcanvas_webhook_deliveries_total`: webhook events delivered, failed or dropped, labelled
+//!   with their `result`.
+//!
+//! Metrics of each canvas, labelled with `canvas`:
+//! - `ipcanvas_events_ingested_total`: ping events received by the canvas task.
+//! - `ipcanvas_events_applied_total`: events applied to the canvas, from any source.
+//! - `ipcanvas_events_rejected_total`: ingested events rejected, labelled with their `reason`.
+//! - `ipcanvas_diff_pixels`: histogram of the changed pixels of the diffs sent to the clients.
+//! - `ipcanvas_canvas_task_seconds`: histogram of the time spent by the canvas task on
+//!   an event or a command.
+//! - `ipcanvas_queue_length` and `ipcanvas_queue_capacity`: occupancy of the `events` and `diffs`
+//!   queues of the canvas task, labelled with `queue`.
+
+use std::{
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicI64, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    canvas::diff::CanvasDiff, command::RejectedEvents, events::SourcedEvent,
+    webhooks::WebhookMetrics,
+};
+
+/// Bounds of the buckets of `ipcanvas_diff_pixels`.
+const DIFF_PIXELS_BUCKETS: &[u64] = &[0, 10, 100, 1_000, 10_000, 100_000, 1_000_000];
+/// Bounds of the buckets of `ipcanvas_canvas_task_seconds`, in microseconds.
+const TASK_MICROS_BUCKETS: &[u64] = &[10, 50, 100, 500, 1_000, 5_000, 10_000, 50_000, 100_000];
+
+/// A monotonic counter.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+/// A value going up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+/// Distribution of observed values, in cumulative buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    // Upper bounds of the buckets, in observed units
+    bounds: &'static [u64],
+    // Number of observations of each bucket (not cumulative), and above the last bound
+    counts: Vec<AtomicU64>,
+    sum: AtomicU64,
+    // Value of an observed unit, when rendered
+    unit: f64,
+}
+
+/// Records the time elapsed since its creation in a histogram of microseconds, when dropped.
+pub struct HistogramTimer<'a> {
+    histogram: &'a Histogram,
+    started: Instant,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Histogram {
+    /// Create a histogram with the given (increasing) bucket bounds, each observed unit being
+    /// rendered as `unit`.
+    pub fn new(bounds: &'static [u64], unit: f64) -> Self {
+        Self {
+            bounds,
+            counts: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            unit,
+        }
+    }
+
+    pub fn observe(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Start timing, the elapsed microseconds are observed when the timer is dropped.
+    pub fn start_timer(&self) -> HistogramTimer<'_> {
+        HistogramTimer {
+            histogram: self,
+            started: Instant::now(),
+        }
+    }
+
+    /// Get the cumulative count of each bucket, the last one being `+Inf`.
+    fn cumulative_counts(&self) -> Vec<u64> {
+        self.counts
+            .iter()
+            .scan(0, |total, count| {
+                *total += count.load(Ordering::Relaxed);
+                Some(*total)
+            })
+            .collect()
+    }
+}
+
+impl Drop for HistogramTimer<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed().min(Duration::from_secs(3600));
+        self.histogram.observe(elapsed.as_micros() as u64);
+    }
+}
+
+/// Metrics of the whole service.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub ping_connections: Gauge,
+    /// Connections from addresses missing from the allowlist
+    pub ping_refused_unlisted: Counter,
+    /// Connections whose TLS handshake failed
+    pub ping_refused_tls: Counter,
+    /// Connections closed as the listener failed to authenticate
+    pub ping_refused_unauthenticated: Counter,
+    pub ping_events_unrouted: Counter,
+    pub viewers: Gauge,
+    canvases: Vec<(String, Arc<CanvasMetrics>)>,
+    webhooks: Option<Arc<WebhookMetrics>>,
+}
+
+/// Metrics of a canvas, updated by its canvas task.
+#[derive(Debug)]
+pub struct CanvasMetrics {
+    pub events_ingested: Counter,
+    pub events_applied: Counter,
+    pub diff_pixels: Histogram,
+    pub task_latency: Histogram,
+    // Published by the canvas task, which owns the authoritative counters
+    rejected: Mutex<RejectedEvents>,
+    // Weak, so the metrics do not keep the queues open
+    events_queue: Option<mpsc::WeakSender<SourcedEvent>>,
+    diffs_queue: Option<mpsc::WeakSender<CanvasDiff>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the metrics of a canvas, labelled with its id.
+    pub fn with_canvas(mut self, id: impl Into<String>, metrics: Arc<CanvasMetrics>) -> Self {
+        self.canvases.push((id.into(), metrics));
+        self
+    }
+
+    /// Add the metrics of the webhooks.
+    pub fn with_webhooks(mut self, metrics: Arc<WebhookMetrics>) -> Self {
+        self.webhooks = Some(metrics);
+        self
+    }
+
+    /// Render the metrics in the Prometheus text format (version 0.0.4).
+    pub fn render(&self) -> String {
+        let mut out = Renderer::default();
+        out.family(
+            "ipcanvas_ping_connections",
+            "Open connections from ping listeners.",
+            "gauge",
+        );
+        out.sample(
+            "ipcanvas_ping_connections",
+            &[],
+            self.ping_connections.get(),
+        );
+        out.family(
+            "ipcanvas_ping_connections_refused_total",
+            "Connections from ping listeners refused, by reason.",
+            "counter",
+        );
+        for (reason, counter) in [
+            ("allowlist", &self.ping_refused_unlisted),
+            ("tls", &self.ping_refused_tls),
+            ("authentication", &self.ping_refused_unauthenticated),
+        ] {
+            out.sample(
+                "ipcanvas_ping_connections_refused_total",
+                &[("reason", reason)],
+                counter.get(),
+            );
+        }
+        out.family(
+            "ipcanvas_ping_events_unrouted_total",
+            "Ping events sent to no canvas.",
+            "counter",
+        );
+        out.sample(
+            "ipcanvas_ping_events_unrouted_total",
+            &[],
+            self.ping_events_unrouted.get(),
+        );
+        out.family(
+            "ipcanvas_viewers",
+            "Viewers following the diffs of a canvas.",
+            "gauge",
+        );
+        out.sample("ipcanvas_viewers", &[], self.viewers.get());
+        if let Some(webhooks) = &self.webhooks {
+            out.family(
+                "ipcanvas_webhook_deliveries_total",
+                "Webhook events delivered, failed or dropped.",
+                "counter",
+            );
+            for (result, counter) in [
+                ("delivered", &webhooks.delivered),
+                ("failed", &webhooks.failed),
+                ("dropped", &webhooks.dropped),
+            ] {
+                out.sample(
+                    "ipcanvas_webhook_deliveries_total",
+                    &[("result", result)],
+                    counter.get(),
+                );
+            }
+        }
+
+        out.family(
+            "ipcanvas_events_ingested_total",
+            "Ping events received by the canvas task.",
+            "counter",
+        );
+        for (id, canvas) in &self.canvases {
+            out.sample(
+                "ipcanvas_events_ingested_total",
+                &[("canvas", id)],
+                canvas.events_ingested.get(),
+            );
+        }
+        out.family(
+            "ipcanvas_events_applied_total",
+            "Events applied to the canvas.",
+            "counter",
+        );
+        for (id, canvas) in &self.canvases {
+            out.sample(
+                "ipcanvas_events_applied_total",
+                &[("canvas", id)],
+                canvas.events_applied.get(),
+            );
+        }
+        out.family(
+            "ipcanvas_events_rejected_total",
+            "Ingested events rejected, by reason.",
+            "counter",
+        );
+        for (id, canvas) in &self.canvases {
+            let rejected = *canvas
+                .rejected
+                .lock()
+                .expect("metrics lock is not poisoned");
+            for (reason, count) in rejected.by_reason() {
+                out.sample(
+                    "ipcanvas_events_rejected_total",
+                    &[("canvas", id), ("reason", reason)],
+                    count,
+                );
+            }
+        }
+        out.family(
+            "ipcanvas_diff_pixels",
+            "Changed pixels of the diffs sent to the clients.",
+            "histogram",
+        );
+        for (id, canvas) in &self.canvases {
+            out.histogram("ipcanvas_diff_pixels", id, &canvas.diff_pixels);
+        }
+        out.family(
+            "ipcanvas_canvas_task_seconds",
+            "Time spent by the canvas task on an event or a command.",
+            "histogram",
+        );
+        for (id, canvas) in &self.canvases {
+            out.histogram("ipcanvas_canvas_task_seconds", id, &canvas.task_latency);
+        }
+
+        let queues: Vec<_> = self
+            .canvases
+            .iter()
+            .flat_map(|(id, canvas)| {
+                [
+                    ("events", occupancy(canvas.events_queue.as_ref())),
+                    ("diffs", occupancy(canvas.diffs_queue.as_ref())),
+                ]
+                .into_iter()
+                .filter_map(move |(queue, occupancy)| Some((id, queue, occupancy?)))
+            })
+            .collect();
+        out.family(
+            "ipcanvas_queue_length",
+            "Messages waiting in a queue of the canvas task.",
+            "gauge",
+        );
+        for (id, queue, (length, _)) in &queues {
+            out.sample(
+                "ipcanvas_queue_length",
+                &[("canvas", id), ("queue", queue)],
+                length,
+            );
+        }
+        out.family(
+            "ipcanvas_queue_capacity",
+            "Capacity of a queue of the canvas task.",
+            "gauge",
+        );
+        for (id, queue, (_, capacity)) in &queues {
+            out.sample(
+                "ipcanvas_queue_capacity",
+                &[("canvas", id), ("queue", queue)],
+                capacity,
+            );
+        }
+        out.0
+    }
+}
+
+impl CanvasMetrics {
+    /// Create the metrics of a canvas, without queues.
+    pub fn new() -> Self {
+        Self {
+            events_ingested: Counter::default(),
+            events_applied: Counter::default(),
+            diff_pixels: Histogram::new(DIFF_PIXELS_BUCKETS, 1.0),
+            task_latency: Histogram::new(TASK_MICROS_BUCKETS, 1e-6),
+            rejected: Mutex::new(RejectedEvents::default()),
+            events_queue: None,
+            diffs_queue: None,
+        }
+    }
+
+    /// Report the occupancy of the queues of the canvas task.
+    pub fn with_queues(
+        mut self,
+        events: &mpsc::Sender<SourcedEvent>,
+        diffs: &mpsc::Sender<CanvasDiff>,
+    ) -> Self {
+        self.events_queue = Some(events.downgrade());
+        self.diffs_queue = Some(diffs.downgrade());
+        self
+    }
+
+    /// Publish the counters of the rejected events.
+    pub fn set_rejected(&self, rejected: RejectedEvents) {
+        *self.rejected.lock().expect("metrics lock is not poisoned") = rejected;
+    }
+}
+
+impl Default for CanvasMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Get the length and capacity of a queue, None if it is closed.
+fn occupancy<T>(queue: Option<&mpsc::WeakSender<T>>) -> Option<(usize, usize)> {
+    let sender = queue?.upgrade()?;
+    Some((
+        sender.max_capacity() - sender.capacity(),
+        sender.max_capacity(),
+    ))
+}
+
+/// Writer of the Prometheus text format.
+#[derive(Default)]
+struct Renderer(String);
+
+impl Renderer {
+    fn family(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            self.0.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.0.push(',');
+                }
+                let value = value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n");
+                let _ = write!(self.0, "{}=\"{}\"", key, value);
+            }
+            self.0.push('}');
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+
+    fn histogram(&mut self, name: &str, canvas: &str, histogram: &Histogram) {
+        let counts = histogram.cumulative_counts();
+        let bucket = format!("{}_bucket", name);
+        for (bound, count) in histogram.bounds.iter().zip(&counts) {
+            let le = (*bound as f64 * histogram.unit).to_string();
+            self.sample(&bucket, &[("canvas", canvas), ("le", &le)], count);
+        }
+        let total = counts.last().copied().unwrap_or(0);
+        self.sample(&bucket, &[("canvas", canvas), ("le", "+Inf")], total);
+        let sum = histogram.sum.load(Ordering::Relaxed) as f64 * histogram.unit;
+        self.sample(&format!("{}_sum", name), &[("canvas", canvas)], sum);
+        self.sample(&format!("{}_count", name), &[("canvas", canvas)], total);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_text_format() {
+        let (events, _events) = mpsc::channel(8);
+        let (diffs, _diffs) = mpsc::channel(4);
+        let canvas = Arc::new(CanvasMetrics::new().with_queues(&events, &diffs));
+        let metrics = Metrics::new().with_canvas("main", canvas.clone());
+
+        metrics.ping_connections.inc();
+        metrics.ping_refused_unauthenticated.inc();
+        canvas.events_ingested.add(3);
+        canvas.set_rejected(RejectedEvents {
+            cooldown: 2,
+            ..Default::default()
+        });
+        canvas.diff_pixels.observe(5);
+        canvas.diff_pixels.observe(5000);
+        events
+            .try_send(SourcedEvent {
+                source: crate::events::EventSource::Admin,
+                event: crate::events::Event::PlacePixel {
+                    x: 0,
+                    y: 0,
+                    color: crate::canvas::colors::RED,
+                },
+            })
+            .unwrap();
+
+        let text = metrics.render();
+        for line in [
+            "# TYPE ipcanvas_ping_connections gauge",
+            "ipcanvas_ping_connections 1",
+            "ipcanvas_ping_connections_refused_total{reason=\"allowlist\"} 0",
+            "ipcanvas_ping_connections_refused_total{reason=\"authentication\"} 1",
+            "ipcanvas_events_ingested_total{canvas=\"main\"} 3",
+            "ipcanvas_events_rejected_total{canvas=\"main\",reason=\"cooldown\"} 2",
+            "ipcanvas_events_rejected_total{canvas=\"main\",reason=\"palette\"} 0",
+            "ipcanvas_diff_pixels_bucket{canvas=\"main\",le=\"10\"} 1",
+            "ipcanvas_diff_pixels_bucket{canvas=\"main\",le=\"1000\"} 1",
+            "ipcanvas_diff_pixels_bucket{canvas=\"main\",le=\"10000\"} 2",
+            "ipcanvas_diff_pixels_bucket{canvas=\"main\",le=\"+Inf\"} 2",
+            "ipcanvas_diff_pixels_sum{canvas=\"main\"} 5005",
+            "ipcanvas_queue_length{canvas=\"main\",queue=\"events\"} 1",
+            "ipcanvas_queue_capacity{canvas=\"main\",queue=\"diffs\"} 4",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "Missing {:?} in:\n{}",
+                line,
+                text
+            );
+        }
+
+        // Closed queues are not reported
+        drop((events, diffs));
+        assert!(!metrics.render().contains("ipcanvas_queue_length{"));
+    }
+
+    #[test]
+    fn timer_observes_micros() {
+        let histogram = Histogram::new(TASK_MICROS_BUCKETS, 1e-6);
+        drop(histogram.start_timer());
+        assert_eq!(histogram.cumulative_counts().last(), Some(&1));
+    }
+}
@@ -0,0 +1,304 @@
+//! Moderation: undo the placements of a range of sources.
+//!
+//! A rollback is planned from the history of the canvas, the latest snapshot preceding
+//! the event log and the log itself: every pixel whose last placement matches the
+//! [RollbackFilter] gets the color it would have had without the matching events.
+//!
+//! The plan is computed outside of the canvas task, which then applies it like any other
+//! event ([EventSource::Admin]), so the reverted pixels are logged and part of the next diff.
+//! Pixels placed again since the plan was computed are left untouched.
+
+use std::{collections::HashMap, io, path::Path};
+
+use ipcanvas_ping_common::Ipv6Prefix;
+
+use crate::{
+    canvas::{
+        Canvas, PixelColor, colors,
+        provenance::{Placement, SOURCE_PREFIX_LEN},
+    },
+    events::{Event, EventSource, mask_address},
+    persistence::{EventLog, LogEntry, SnapshotError, SnapshotStore},
+};
+
+/// Placements to undo: the ones from a source prefix within a time window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RollbackFilter {
+    /// Prefix of the sources to undo
+    pub prefix: Ipv6Prefix,
+    /// Start of the window (inclusive), in milliseconds since the Unix epoch
+    pub from: u64,
+    /// End of the window (exclusive), in milliseconds since the Unix epoch
+    pub to: u64,
+}
+
+impl RollbackFilter {
+    /// Check if the entry is one of the placements to undo.
+    pub fn matches(&self, entry: &LogEntry) -> bool {
+        let EventSource::Ping(address) = entry.source else {
+            return false;
+        };
+        (self.from..self.to).contains(&entry.timestamp) && self.prefix.matches(&address)
+    }
+}
+
+/// A pixel to revert.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RevertedPixel {
+    pub x: u16,
+    pub y: u16,
+    /// Color of the pixel without the undone placements
+    pub color: PixelColor,
+    /// Current placement of the pixel, the one to undo (see [Canvas::placement])
+    pub expected: Placement,
+}
+
+/// Pixels to revert to undo the placements matching a [RollbackFilter].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RollbackPlan {
+    /// Pixels to revert, in the order of their last placement
+    pub pixels: Vec<RevertedPixel>,
+    /// Number of placements matching the filter in the history
+    pub matched: u64,
+    /// Time of the oldest placement of the history, in milliseconds since the Unix epoch
+    ///
+    /// Placements before it can not be undone. None if the history goes back to a blank canvas.
+    pub history_since: Option<u64>,
+}
+
+impl RollbackPlan {
+    /// Plan a rollback from the history of the canvas.
+    ///
+    /// `base` is the state of the canvas before the first entry, None if the entries
+    /// start from a blank canvas.
+    pub fn new(base: Option<&Canvas>, entries: &[LogEntry], filter: &RollbackFilter) -> Self {
+        // Last placement of each pixel touched by the filter, and the last one to keep
+        type Placements<'a> = (Option<&'a LogEntry>, Option<&'a LogEntry>);
+        let mut touched: HashMap<(u16, u16), Placements> = HashMap::new();
+        let mut matched = 0;
+        for entry in entries {
+            let Event::PlacePixel { x, y, .. } = entry.event else {
+                continue;
+            };
+            if filter.matches(entry) {
+                matched += 1;
+                touched.entry((x, y)).or_default();
+            }
+        }
+        for entry in entries {
+            let Event::PlacePixel { x, y, .. } = entry.event else {
+                continue;
+            };
+            if let Some((last, kept)) = touched.get_mut(&(x, y)) {
+                *last = Some(entry);
+                if !filter.matches(entry) {
+                    *kept = Some(entry);
+                }
+            }
+        }
+
+        // Resizes of the history, the first one containing a pixel added it
+        let resizes: Vec<_> = entries
+            .iter()
+            .filter_map(|entry| match entry.event {
+                Event::Resize {
+                    width,
+                    height,
+                    background,
+                } => Some((width, height, background)),
+                _ => None,
+            })
+            .collect();
+
+        let mut pixels: Vec<_> = touched
+            .into_iter()
+            .filter_map(|((x, y), (last, kept))| {
+                // Pixels placed again since are already right
+                let last = last.filter(|last| filter.matches(last))?;
+                let color = match kept.map(|kept| &kept.event) {
+                    Some(&Event::PlacePixel { color, .. }) => color,
+                    // The dimensions of a blank canvas are unknown, the pixel is assumed
+                    // to be part of it
+                    _ => match base.map(|base| base.get_pixel(x, y)) {
+                        Some(Some(color)) => color,
+                        Some(None) => {
+                            let (_, _, background) =
+                                resizes.iter().find(|(w, h, _)| x < *w && y < *h)?;
+                            *background
+                        }
+                        None => colors::WHITE,
+                    },
+                };
+                // As kept by the canvas: the prefix of the source, to the second
+                let source = match last.source {
+                    EventSource::Ping(address) => {
+                        EventSource::Ping(mask_address(address, SOURCE_PREFIX_LEN))
+                    }
+                    source => source,
+                };
+                let expected = Placement {
+                    source,
+                    timestamp: last.timestamp / 1000 * 1000,
+                };
+                Some((
+                    last.sequence,
+                    RevertedPixel {
+                        x,
+                        y,
+                        color,
+                        expected,
+                    },
+                ))
+            })
+            .collect();
+        pixels.sort_unstable_by_key(|(sequence, _)| *sequence);
+
+        Self {
+            pixels: pixels.into_iter().map(|(_, pixel)| pixel).collect(),
+            matched,
+            history_since: base.and(entries.first()).map(|entry| entry.timestamp),
+        }
+    }
+
+    /// Plan a rollback from the history kept in a data directory.
+    ///
+    /// The history starts at the first entry of the event log, on top of the snapshot
+    /// preceding it (or a blank canvas if the log starts with the first event ever applied).
+    /// The data directory is not modified, this is safe while the service is running.
+    pub fn from_data_dir(dir: &Path, filter: &RollbackFilter) -> Result<Self, SnapshotError> {
+        let mut entries = Vec::new();
+        EventLog::read(dir, 0, |entry| entries.push(entry))?;
+        let base = match entries.first().map(|entry| entry.sequence) {
+            None | Some(1) => None,
+            Some(first) => {
+                let snapshots = SnapshotStore::open_read_only(dir);
+                let Some(sequence) = snapshots.list()?.into_iter().find(|&s| s + 1 >= first) else {
+                    return Err(SnapshotError::Io(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "no snapshot precedes the event log",
+                    )));
+                };
+                entries.retain(|entry| entry.sequence > sequence);
+                Some(snapshots.load(sequence)?.canvas)
+            }
+        };
+        Ok(Self::new(base.as_ref(), &entries, filter))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn place(sequence: u64, source: &str, x: u16, color: PixelColor) -> LogEntry {
+        LogEntry {
+            sequence,
+            timestamp: sequence * 1000,
+            source: EventSource::Ping(source.parse().unwrap()),
+            event: Event::PlacePixel { x, y: 0, color },
+        }
+    }
+
+    #[test]
+    fn rollback_plan() {
+        let vandal = "2001:db8:bad::1";
+        let artist = "2001:db8:1::1";
+        let entries = [
+            place(1, artist, 0, colors::RED),
+            place(2, artist, 1, colors::GREEN),
+            place(3, vandal, 0, colors::BLACK),
+            place(4, vandal, 1, colors::BLACK),
+            place(5, vandal, 2, colors::BLACK),
+            place(6, artist, 1, colors::BLUE),
+            place(7, "2001:db8:bad::2", 3, colors::BLACK),
+            // Outside of the window
+            place(20, vandal, 4, colors::BLACK),
+        ];
+        let filter = RollbackFilter {
+            prefix: "2001:db8:bad::/48".parse().unwrap(),
+            from: 2000,
+            to: 10_000,
+        };
+
+        let plan = RollbackPlan::new(None, &entries, &filter);
+        assert_eq!(plan.matched, 4);
+        assert_eq!(plan.history_since, None);
+        let reverted: Vec<_> = plan.pixels.iter().map(|p| (p.x, p.color)).collect();
+        assert_eq!(
+            reverted,
+            vec![(0, colors::RED), (2, colors::WHITE), (3, colors::WHITE)],
+            "Pixel 1 has been placed again since"
+        );
+        assert_eq!(
+            plan.pixels[0].expected,
+            Placement {
+                source: EventSource::Ping("2001:db8:bad::".parse().unwrap()),
+                timestamp: 3000,
+            }
+        );
+
+        // Pixels never placed in the history keep their color of the base canvas
+        let mut base = Canvas::new(8, 1);
+        base.set_pixel(2, 0, colors::YELLOW).unwrap();
+        let plan = RollbackPlan::new(Some(&base), &entries[2..], &filter);
+        assert_eq!(plan.history_since, Some(3000));
+        assert_eq!(plan.pixels[0].color, colors::WHITE);
+        assert_eq!(plan.pixels[1].color, colors::YELLOW);
+
+        // Pixels added by a resize get back its background
+        let resize = LogEntry {
+            sequence: 2,
+            timestamp: 2000,
+            source: EventSource::Admin,
+            event: Event::Resize {
+                width: 16,
+                height: 1,
+                background: colors::CYAN,
+            },
+        };
+        let entries = [resize, place(3, vandal, 12, colors::BLACK)];
+        let plan = RollbackPlan::new(Some(&base), &entries, &filter);
+        assert_eq!(plan.pixels[0].color, colors::CYAN);
+    }
+
+    #[test]
+    fn rollback_plan_matches_canvas() {
+        let vandal = "2001:db8:bad:1:dead:beef:1:2";
+        let entries = [
+            place(1, "2001:db8:1::1", 0, colors::RED),
+            place(2, vandal, 0, colors::BLACK),
+            place(3, vandal, 1, colors::BLACK),
+            LogEntry {
+                timestamp: 3500,
+                ..place(4, vandal, 2, colors::BLACK)
+            },
+        ];
+        let mut canvas = Canvas::new(4, 1);
+        for entry in &entries {
+            let Event::PlacePixel { x, y, color } = entry.event else {
+                unreachable!();
+            };
+            let placement = Placement {
+                source: entry.source,
+                timestamp: entry.timestamp,
+            };
+            canvas.place_pixel(x, y, color, placement).unwrap();
+        }
+        let filter = RollbackFilter {
+            prefix: "2001:db8:bad::/48".parse().unwrap(),
+            from: 0,
+            to: 10_000,
+        };
+
+        let plan = RollbackPlan::new(None, &entries, &filter);
+        assert_eq!(plan.pixels.len(), 3);
+        for pixel in &plan.pixels {
+            assert_eq!(
+                canvas.placement(pixel.x, pixel.y),
+                Some(pixel.expected),
+                "Pixel {} is still the one to undo",
+                pixel.x
+            );
+        }
+    }
+}
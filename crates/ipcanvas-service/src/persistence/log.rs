@@ -0,0 +1,767 @@
+//! Append-only, segmented log of the events applied to the canvas.
+//!
+//! Together with the latest [snapshot](super::snapshot), the log allows to rebuild the
+//! canvas exactly as it was before a restart or a crash: every event applied after the
+//! snapshot is replayed on top of it.
+//!
+//! # File format (version 1)
+//!
+//! The log is split into segments, named after the sequence number of their first entry.
+//! Each segment starts with `magic: b"IPCL"` and `version: u16`, followed by records:
+//!
+//! | Size | Field      | Description                        |
+//! |------|------------|------------------------------------|
+//! | 4    | `len`      | Length of the body, in bytes       |
+//! | 4    | `checksum` | CRC-32 of the body                 |
+//! | len  | `body`     | Encoded [LogEntry]                 |
+//!
+//! A body is `sequence: u64`, `timestamp: u64`, the source and the event:
+//!
+//! - source `0` (ping): followed by the 16 bytes of the IPv6 address.
+//! - source `1` (image import): no payload.
+//! - source `2` (other operator action): no payload.
+//! - event `0` (place pixel): followed by `x: u16, y: u16, r, g, b`.
+//! - event `1` (place label): followed by `x: u16, y: u16` and the 8 bytes of text.
+//! - event `2` (resize): followed by `width: u16, height: u16, r, g, b` (background color).
+//! - event `3` (admin marker): followed by the action: `0` (freeze), `1` (unfreeze),
+//!   `2` (snapshot), or `3` (disconnect) followed by the 16 bytes of the IPv6 address
+//!   (IPv4-mapped for an IPv4 peer) and `port: u16` of the peer.
+//!
+//! All integers are big-endian.
+//!
+//! A record cut short or with a bad checksum marks the end of the valid part of a segment.
+//! This is expected at the tail of the last segment after a crash: the record is dropped
+//! and the segment is truncated to its valid part. After a failed write, the log moves on to
+//! a new segment, so a torn record can also end an older segment, and the entries lost with it
+//! leave a gap in the sequence numbers.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    canvas::PixelColor,
+    events::{AdminAction, Event, EventSource},
+};
+
+/// Magic bytes at the start of every log segment.
+pub const MAGIC: [u8; 4] = *b"IPCL";
+/// Current version of the log segment format.
+pub const VERSION: u16 = 1;
+
+const SEGMENT_HEADER_SIZE: usize = 6;
+const RECORD_HEADER_SIZE: usize = 8;
+const FILE_PREFIX: &str = "log-";
+const FILE_EXTENSION: &str = "ipcl";
+
+/// An event applied to the canvas, as recorded in the log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    /// Sequence number of the event, starting at 1 for the first event ever applied.
+    pub sequence: u64,
+    /// Time the event has been applied, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Origin of the event.
+    pub source: EventSource,
+    /// The event itself.
+    pub event: Event,
+}
+
+impl LogEntry {
+    /// Encode the entry into a record body.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.sequence.to_be_bytes());
+        buf.extend_from_slice(&self.timestamp.to_be_bytes());
+        match self.source {
+            EventSource::Ping(address) => {
+                buf.push(0);
+                buf.extend_from_slice(&address.octets());
+            }
+            EventSource::Import => buf.push(1),
+            EventSource::Admin => buf.push(2),
+        }
+        match &self.event {
+            Event::PlacePixel { x, y, color } => {
+                buf.push(0);
+                buf.extend_from_slice(&x.to_be_bytes());
+                buf.extend_from_slice(&y.to_be_bytes());
+                buf.extend_from_slice(&[color.r, color.g, color.b]);
+            }
+            Event::PlaceLabel { x, y, text } => {
+                buf.push(1);
+                buf.extend_from_slice(&x.to_be_bytes());
+                buf.extend_from_slice(&y.to_be_bytes());
+                buf.extend_from_slice(text);
+            }
+            Event::Resize {
+                width,
+                height,
+                background,
+            } => {
+                buf.push(2);
+                buf.extend_from_slice(&width.to_be_bytes());
+                buf.extend_from_slice(&height.to_be_bytes());
+                buf.extend_from_slice(&[background.r, background.g, background.b]);
+            }
+            Event::AdminMarker { action } => {
+                buf.push(3);
+                match action {
+                    AdminAction::Freeze => buf.push(0),
+                    AdminAction::Unfreeze => buf.push(1),
+                    AdminAction::Snapshot => buf.push(2),
+                    AdminAction::Disconnect { peer } => {
+                        buf.push(3);
+                        let address = match peer.ip() {
+                            IpAddr::V4(address) => address.to_ipv6_mapped(),
+                            IpAddr::V6(address) => address,
+                        };
+                        buf.extend_from_slice(&address.octets());
+                        buf.extend_from_slice(&peer.port().to_be_bytes());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decode an entry from a record body.
+    fn decode(body: &[u8]) -> Option<Self> {
+        let mut body = body;
+        let mut take = |n: usize| -> Option<&[u8]> {
+            if body.len() < n {
+                return None;
+            }
+            let (head, tail) = body.split_at(n);
+            body = tail;
+            Some(head)
+        };
+        let u16_at = |b: &[u8], i: usize| u16::from_be_bytes([b[i], b[i + 1]]);
+
+        let sequence = u64::from_be_bytes(take(8)?.try_into().ok()?);
+        let timestamp = u64::from_be_bytes(take(8)?.try_into().ok()?);
+        let source = match take(1)?[0] {
+            0 => {
+                let octets: [u8; 16] = take(16)?.try_into().ok()?;
+                EventSource::Ping(Ipv6Addr::from(octets))
+            }
+            1 => EventSource::Import,
+            2 => EventSource::Admin,
+            _ => return None,
+        };
+        let event = match take(1)?[0] {
+            0 => {
+                let b = take(7)?;
+                Event::PlacePixel {
+                    x: u16_at(b, 0),
+                    y: u16_at(b, 2),
+                    color: PixelColor {
+                        r: b[4],
+                        g: b[5],
+                        b: b[6],
+                    },
+                }
+            }
+            1 => {
+                let b = take(12)?;
+                Event::PlaceLabel {
+                    x: u16_at(b, 0),
+                    y: u16_at(b, 2),
+                    text: b[4..12].try_into().ok()?,
+                }
+            }
+            2 => {
+                let b = take(7)?;
+                Event::Resize {
+                    width: u16_at(b, 0),
+                    height: u16_at(b, 2),
+                    background: PixelColor {
+                        r: b[4],
+                        g: b[5],
+                        b: b[6],
+                    },
+                }
+            }
+            3 => {
+                let action = match take(1)?[0] {
+                    0 => AdminAction::Freeze,
+                    1 => AdminAction::Unfreeze,
+                    2 => AdminAction::Snapshot,
+                    3 => {
+                        let b = take(18)?;
+                        let address = Ipv6Addr::from(<[u8; 16]>::try_from(&b[..16]).ok()?);
+                        let address = match address.to_ipv4_mapped() {
+                            Some(address) => IpAddr::V4(address),
+                            None => IpAddr::V6(address),
+                        };
+                        AdminAction::Disconnect {
+                            peer: SocketAddr::new(address, u16_at(b, 16)),
+                        }
+                    }
+                    _ => return None,
+                };
+                Event::AdminMarker { action }
+            }
+            _ => return None,
+        };
+        if !body.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            sequence,
+            timestamp,
+            source,
+            event,
+        })
+    }
+}
+
+/// Outcome of a [EventLog::replay].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    /// Number of entries passed to the callback.
+    pub replayed: u64,
+    /// Number of valid entries skipped, as they were already part of the snapshot.
+    pub skipped: u64,
+    /// Sequence number of the last valid entry of the log, if any.
+    pub last_sequence: Option<u64>,
+    /// Segments whose valid part ended early, with the number of bytes dropped.
+    pub damaged: Vec<(PathBuf, u64)>,
+    /// Ranges of sequence numbers missing from the log (first and last, inclusive),
+    /// lost to failed writes.
+    pub missing: Vec<(u64, u64)>,
+}
+
+/// Segmented write-ahead log of the events applied to the canvas.
+///
+/// All operations are blocking.
+#[derive(Debug)]
+pub struct EventLog {
+    dir: PathBuf,
+    max_segment_size: u64,
+    // Segment currently appended to, with its size
+    current: Option<(BufWriter<File>, u64)>,
+}
+
+impl EventLog {
+    /// Default maximum size of a segment, before a new one is started.
+    pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+    /// Open the log in the given directory, creating it if needed.
+    ///
+    /// New entries are always appended to a new segment.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            max_segment_size: Self::DEFAULT_MAX_SEGMENT_SIZE,
+            current: None,
+        })
+    }
+
+    /// Set the maximum size of a segment.
+    pub fn with_max_segment_size(mut self, max_segment_size: u64) -> Self {
+        self.max_segment_size = max_segment_size;
+        self
+    }
+
+    /// Get the directory of the log.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Replay all the entries with a sequence number greater than `after`, in order.
+    ///
+    /// A damaged segment (truncated or corrupted record) is read up to its last valid record.
+    /// The last segment is also truncated to it, so later appends and replays start from a
+    /// clean state. Entries lost to failed writes are reported in [ReplayStats::missing].
+    ///
+    /// The log must still start right after `after`, otherwise all the events in between
+    /// would be lost (e.g. replaying after an older snapshot than the one the log was compacted
+    /// to): an error of kind [io::ErrorKind::InvalidData] is returned, and no entry is replayed.
+    pub fn replay(&mut self, after: u64, f: impl FnMut(LogEntry)) -> io::Result<ReplayStats> {
+        let first = list_segments(&self.dir)?.first().copied();
+        if let Some(first) = first.filter(|&first| first > after + 1) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "event log is missing entries: expected #{} after #{}, the log starts at #{}",
+                    after + 1,
+                    after,
+                    first
+                ),
+            ));
+        }
+        scan(&self.dir, after, true, f)
+    }
+
+    /// Read all the entries with a sequence number greater than `after`, in order,
+    /// without opening the log.
+    ///
+    /// Unlike [EventLog::replay], damaged segments are left untouched, so this is safe
+    /// to use on the log of a running service (e.g. to export its history).
+    pub fn read(dir: &Path, after: u64, f: impl FnMut(LogEntry)) -> io::Result<ReplayStats> {
+        scan(dir, after, false, f)
+    }
+
+    /// Append an entry to the log.
+    ///
+    /// The entry is buffered, call [EventLog::flush] to make it durable.
+    /// On failure, the current segment is closed and the next entry starts a new one.
+    pub fn append(&mut self, entry: &LogEntry) -> io::Result<()> {
+        let appended = self.write_entry(entry);
+        if appended.is_err() {
+            self.discard();
+        }
+        appended
+    }
+
+    fn write_entry(&mut self, entry: &LogEntry) -> io::Result<()> {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(_, size)| *size >= self.max_segment_size)
+        {
+            self.rotate()?;
+        }
+        if self.current.is_none() {
+            let path = segment_path(&self.dir, entry.sequence);
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            let mut writer = BufWriter::new(file);
+            writer.write_all(&MAGIC)?;
+            writer.write_all(&VERSION.to_be_bytes())?;
+            self.current = Some((writer, SEGMENT_HEADER_SIZE as u64));
+        }
+
+        let mut body = Vec::with_capacity(48);
+        entry.encode(&mut body);
+        let (writer, size) = self.current.as_mut().expect("segment is open");
+        writer.write_all(&(body.len() as u32).to_be_bytes())?;
+        writer.write_all(&crc32fast::hash(&body).to_be_bytes())?;
+        writer.write_all(&body)?;
+        *size += (RECORD_HEADER_SIZE + body.len()) as u64;
+        Ok(())
+    }
+
+    /// Flush the buffered entries and sync them to disk.
+    ///
+    /// On failure, the current segment is closed and the next entry starts a new one.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Some((writer, _)) = self.current.as_mut() else {
+            return Ok(());
+        };
+        let flushed = writer.flush().and_then(|()| writer.get_ref().sync_data());
+        if flushed.is_err() {
+            self.discard();
+        }
+        flushed
+    }
+
+    /// Close the current segment after a failed write, dropping what is left of its buffer.
+    ///
+    /// Its tail may be a torn record, nothing must be written after it.
+    fn discard(&mut self) {
+        if let Some((writer, _)) = self.current.take() {
+            drop(writer.into_parts());
+        }
+    }
+
+    /// Close the current segment, the next entry will start a new one.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.current = None;
+        Ok(())
+    }
+
+    /// Remove the segments that only hold entries with a sequence number up to `sequence`,
+    /// typically the sequence number of the oldest snapshot kept, so any of them can be restored.
+    ///
+    /// The most recent segment is always kept. Returns the number of removed segments.
+    pub fn compact(dir: &Path, sequence: u64) -> io::Result<usize> {
+        let segments = list_segments(dir)?;
+        let mut removed = 0;
+        for pair in segments.windows(2) {
+            // The segment ends right before the next one starts
+            if pair[1] <= sequence + 1 {
+                fs::remove_file(segment_path(dir, pair[0]))?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Read the entries of all the segments in the directory, with a sequence number greater
+/// than `after`.
+///
+/// If `repair` is set, the last segment is truncated to its valid part if it is damaged,
+/// or removed if its header is invalid. Older segments are never modified: the entries after
+/// a torn record (if any) are in the next segment.
+fn scan(
+    dir: &Path,
+    after: u64,
+    repair: bool,
+    mut f: impl FnMut(LogEntry),
+) -> io::Result<ReplayStats> {
+    let mut stats = ReplayStats::default();
+    let segments = list_segments(dir)?;
+    let mut expected = after + 1;
+    for (i, &first_sequence) in segments.iter().enumerate() {
+        let path = segment_path(dir, first_sequence);
+        let bytes = fs::read(&path)?;
+        let repair = repair && i + 1 == segments.len();
+
+        if bytes.len() < SEGMENT_HEADER_SIZE
+            || bytes[..4] != MAGIC
+            || bytes[4..6] != VERSION.to_be_bytes()
+        {
+            stats.damaged.push((path.clone(), bytes.len() as u64));
+            if repair {
+                fs::remove_file(&path)?;
+            }
+            continue;
+        }
+
+        let mut offset = SEGMENT_HEADER_SIZE;
+        while let Some((entry, next)) = read_record(&bytes, offset) {
+            offset = next;
+            stats.last_sequence = Some(entry.sequence);
+            if entry.sequence <= after {
+                stats.skipped += 1;
+                continue;
+            }
+            if entry.sequence > expected {
+                stats.missing.push((expected, entry.sequence - 1));
+            }
+            expected = entry.sequence + 1;
+            stats.replayed += 1;
+            f(entry);
+        }
+
+        if offset < bytes.len() {
+            stats
+                .damaged
+                .push((path.clone(), (bytes.len() - offset) as u64));
+            if repair {
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(offset as u64)?;
+                file.sync_all()?;
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Read the record at the given offset, returning the entry and the offset of the next record.
+fn read_record(bytes: &[u8], offset: usize) -> Option<(LogEntry, usize)> {
+    let header = bytes.get(offset..offset + RECORD_HEADER_SIZE)?;
+    let len = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+    let checksum = u32::from_be_bytes(header[4..].try_into().ok()?);
+    let start = offset + RECORD_HEADER_SIZE;
+    let body = bytes.get(start..start.checked_add(len)?)?;
+    if crc32fast::hash(body) != checksum {
+        return None;
+    }
+    Some((LogEntry::decode(body)?, start + len))
+}
+
+/// List the first sequence numbers of the segments in the directory, in increasing order.
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension() != Some(FILE_EXTENSION.as_ref()) {
+            continue;
+        }
+        let sequence = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix(FILE_PREFIX))
+            .and_then(|seq| seq.parse::<u64>().ok());
+        if let Some(sequence) = sequence {
+            segments.push(sequence);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+fn segment_path(dir: &Path, first_sequence: u64) -> PathBuf {
+    dir.join(format!(
+        "{}{:020}.{}",
+        FILE_PREFIX, first_sequence, FILE_EXTENSION
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::colors;
+
+    fn entry(sequence: u64) -> LogEntry {
+        LogEntry {
+            sequence,
+            timestamp: 1_700_000_000_000 + sequence,
+            source: EventSource::Ping("2001:db8::1".parse().unwrap()),
+            event: Event::PlacePixel {
+                x: sequence as u16,
+                y: 2,
+                color: colors::RED,
+            },
+        }
+    }
+
+    fn replay_all(log: &mut EventLog, after: u64) -> (Vec<u64>, ReplayStats) {
+        let mut sequences = Vec::new();
+        let stats = log.replay(after, |e| sequences.push(e.sequence)).unwrap();
+        (sequences, stats)
+    }
+
+    #[test]
+    fn entry_encoding_roundtrip() {
+        let label = LogEntry {
+            event: Event::PlaceLabel {
+                x: 1,
+                y: 2,
+                text: *b"hello\0\0\0",
+            },
+            ..entry(7)
+        };
+        let import = LogEntry {
+            source: EventSource::Import,
+            ..entry(8)
+        };
+        let admin = LogEntry {
+            source: EventSource::Admin,
+            ..entry(9)
+        };
+        let resize = LogEntry {
+            event: Event::Resize {
+                width: 8192,
+                height: 4096,
+                background: colors::BLACK,
+            },
+            ..admin.clone()
+        };
+        let freeze = LogEntry {
+            event: Event::AdminMarker {
+                action: AdminAction::Freeze,
+            },
+            ..admin.clone()
+        };
+        let disconnect = LogEntry {
+            event: Event::AdminMarker {
+                action: AdminAction::Disconnect {
+                    peer: "192.0.2.1:4242".parse().unwrap(),
+                },
+            },
+            ..admin.clone()
+        };
+        let disconnect_v6 = LogEntry {
+            event: Event::AdminMarker {
+                action: AdminAction::Disconnect {
+                    peer: "[2001:db8::1]:4242".parse().unwrap(),
+                },
+            },
+            ..admin.clone()
+        };
+        for entry in [
+            entry(3),
+            label,
+            import,
+            admin,
+            resize,
+            freeze,
+            disconnect,
+            disconnect_v6,
+        ] {
+            let mut body = Vec::new();
+            entry.encode(&mut body);
+            assert_eq!(LogEntry::decode(&body), Some(entry));
+        }
+    }
+
+    #[test]
+    fn log_replays_entries_after_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        for sequence in 1..=5 {
+            log.append(&entry(sequence)).unwrap();
+        }
+        log.rotate().unwrap();
+        for sequence in 6..=8 {
+            log.append(&entry(sequence)).unwrap();
+        }
+        log.flush().unwrap();
+
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let (sequences, stats) = replay_all(&mut log, 4);
+        assert_eq!(sequences, vec![5, 6, 7, 8]);
+        assert_eq!(stats.skipped, 4);
+        assert_eq!(stats.last_sequence, Some(8));
+        assert!(stats.damaged.is_empty());
+    }
+
+    #[test]
+    fn log_skips_truncated_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        for sequence in 1..=3 {
+            log.append(&entry(sequence)).unwrap();
+        }
+        log.flush().unwrap();
+        drop(log);
+
+        // Simulate a crash in the middle of the last record
+        let path = segment_path(dir.path(), 1);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 5).unwrap();
+
+        // Reading does not repair the log
+        let mut sequences = Vec::new();
+        let stats = EventLog::read(dir.path(), 0, |e| sequences.push(e.sequence)).unwrap();
+        assert_eq!(sequences, vec![1, 2]);
+        assert_eq!(stats.damaged.len(), 1);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len - 5);
+
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let (sequences, stats) = replay_all(&mut log, 0);
+        assert_eq!(sequences, vec![1, 2]);
+        assert_eq!(stats.damaged.len(), 1);
+
+        // The log has been repaired, and can be appended to
+        log.append(&entry(3)).unwrap();
+        log.flush().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let (sequences, stats) = replay_all(&mut log, 0);
+        assert_eq!(sequences, vec![1, 2, 3]);
+        assert!(stats.damaged.is_empty());
+    }
+
+    #[test]
+    fn log_stops_segment_at_corrupted_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        for sequence in 1..=3 {
+            log.append(&entry(sequence)).unwrap();
+        }
+        log.flush().unwrap();
+        drop(log);
+
+        let path = segment_path(dir.path(), 1);
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        fs::write(&path, bytes).unwrap();
+
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let (sequences, stats) = replay_all(&mut log, 0);
+        assert_eq!(sequences, vec![1, 2]);
+        assert_eq!(stats.damaged.len(), 1);
+    }
+
+    #[test]
+    fn log_compacts_segments_covered_by_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        for sequence in 1..=9 {
+            log.append(&entry(sequence)).unwrap();
+            if sequence % 3 == 0 {
+                log.rotate().unwrap();
+            }
+        }
+        assert_eq!(list_segments(dir.path()).unwrap(), vec![1, 4, 7]);
+
+        // Snapshot at 5: segment 1 (1..=3) is covered, segment 4 (4..=6) is not
+        assert_eq!(EventLog::compact(dir.path(), 5).unwrap(), 1);
+        assert_eq!(list_segments(dir.path()).unwrap(), vec![4, 7]);
+
+        // Snapshot at 9: the last segment is always kept
+        assert_eq!(EventLog::compact(dir.path(), 9).unwrap(), 1);
+        assert_eq!(list_segments(dir.path()).unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn log_replay_fails_on_missing_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        for sequence in 1..=9 {
+            log.append(&entry(sequence)).unwrap();
+            if sequence % 3 == 0 {
+                log.rotate().unwrap();
+            }
+        }
+        EventLog::compact(dir.path(), 3).unwrap();
+
+        // Entries 1 to 3 are gone, the canvas can no longer be restored from scratch
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let mut sequences = Vec::new();
+        let err = log.replay(0, |e| sequences.push(e.sequence)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(sequences.is_empty());
+        assert_eq!(replay_all(&mut log, 3).0, vec![4, 5, 6, 7, 8, 9]);
+
+        // Entries missing in the middle of the log are reported, the others are replayed
+        fs::remove_file(segment_path(dir.path(), 7)).unwrap();
+        log.append(&entry(10)).unwrap();
+        log.flush().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let (sequences, stats) = replay_all(&mut log, 3);
+        assert_eq!(sequences, vec![4, 5, 6, 10]);
+        assert_eq!(stats.missing, vec![(7, 9)]);
+    }
+
+    #[test]
+    fn log_skips_torn_record_before_last_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path()).unwrap();
+        for sequence in 1..=3 {
+            log.append(&entry(sequence)).unwrap();
+        }
+        log.rotate().unwrap();
+
+        // A write failed in the middle of entry 3, the log moved on to a new segment
+        let path = segment_path(dir.path(), 1);
+        let len = fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 5).unwrap();
+        for sequence in 4..=5 {
+            log.append(&entry(sequence)).unwrap();
+        }
+        log.flush().unwrap();
+
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let (sequences, stats) = replay_all(&mut log, 0);
+        assert_eq!(sequences, vec![1, 2, 4, 5]);
+        assert_eq!(stats.damaged.len(), 1);
+        assert_eq!(stats.missing, vec![(3, 3)]);
+        // Only the last segment is repaired
+        assert_eq!(fs::metadata(&path).unwrap().len(), len - 5);
+        assert_eq!(replay_all(&mut log, 0).0, vec![1, 2, 4, 5]);
+    }
+
+    #[test]
+    fn log_rotates_full_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut log = EventLog::open(dir.path())
+            .unwrap()
+            .with_max_segment_size(100);
+        for sequence in 1..=6 {
+            log.append(&entry(sequence)).unwrap();
+        }
+        log.flush().unwrap();
+        assert!(list_segments(dir.path()).unwrap().len() > 1);
+
+        let mut log = EventLog::open(dir.path()).unwrap();
+        let (sequences, _) = replay_all(&mut log, 0);
+        assert_eq!(sequences, vec![1, 2, 3, 4, 5, 6]);
+    }
+}
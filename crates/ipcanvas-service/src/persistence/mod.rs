@@ -0,0 +1,17 @@
+//! Persistence of the canvas state on disk.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod log;
+pub mod snapshot;
+
+pub use log::{EventLog, LogEntry, ReplayStats};
+pub use snapshot::{Snapshot, SnapshotError, SnapshotStore};
+
+/// Get the current time, in milliseconds since the Unix epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,506 @@
+//! Periodic on-disk snapshots of the canvas.
+//!
+//! # File format (version 5)
+//!
+//! | Offset | Size | Field            | Description                                            |
+//! |--------|------|------------------|--------------------------------------------------------|
+//! | 0      | 4    | `magic`          | Always `b"IPCS"`                                       |
+//! | 4      | 2    | `version`        | File format version, currently `5`                     |
+//! | 6      | 8    | `sequence`       | Number of events applied to the canvas                 |
+//! | 14     | 8    | `timestamp`      | Time of the snapshot, in ms since the Unix epoch       |
+//! | 22     | 8    | `canvas_version` | Version of the canvas (see [Canvas::version])          |
+//! | 30     | 8    | `payload_len`    | Length of the payload, in bytes                        |
+//! | 38     | n    | `payload`        | Canvas snapshot in the [wire format](crate::canvas::encoding) |
+//! |        |      | sections         | Length (`u64`) and content of each section             |
+//! |        | 4    | `checksum`       | CRC-32 of all the previous bytes                       |
+//!
+//! The sections are, in order:
+//!
+//! 1. the [provenance](crate::canvas::provenance) of the pixels and labels,
+//! 2. the [activity](crate::activity) of the players,
+//! 3. the [heatmap](crate::heatmap) of their pixels.
+//!
+//! All integers are big-endian.
+//!
+//! Older versions have fewer sections: version 1 files have none (the canvas is loaded with no
+//! provenance), version 2 and 3 files only have the provenance (version 2 files have no labels),
+//! and version 4 files have no heatmap. The missing activity and heatmap are seeded from the
+//! provenance.
+//!
+//! Snapshots are named after their sequence number, and written atomically:
+//! the file is first written under a temporary name, synced, and then renamed.
+
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    activity::Activity,
+    canvas::{Canvas, encoding, provenance},
+    cooldown,
+    heatmap::Heatmap,
+};
+
+/// Magic bytes at the start of every snapshot file.
+pub const MAGIC: [u8; 4] = *b"IPCS";
+/// Current version of the snapshot file format.
+pub const VERSION: u16 = 5;
+
+const HEADER_SIZE: usize = 38;
+const SECTION_LEN_SIZE: usize = 8;
+const CHECKSUM_SIZE: usize = 4;
+const FILE_PREFIX: &str = "snapshot-";
+const FILE_EXTENSION: &str = "ipcs";
+const TMP_EXTENSION: &str = "tmp";
+
+/// A snapshot of the canvas state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// Number of events applied to the canvas when the snapshot was taken.
+    pub sequence: u64,
+    /// Time of the snapshot, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// State of the canvas.
+    pub canvas: Canvas,
+    /// Activity of the players.
+    ///
+    /// It is seeded from the provenance of the canvas for the snapshots written without it.
+    pub activity: Activity,
+}
+
+impl Snapshot {
+    /// Serialize the snapshot into the snapshot file format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let payload = encoding::Encoder::default().encode_snapshot(&self.canvas);
+        let mut provenance = Vec::new();
+        provenance::encode(&self.canvas, &mut provenance);
+        let mut activity = Vec::new();
+        self.activity.encode(&mut activity);
+        let mut heatmap = Vec::new();
+        self.activity.heatmap().encode(&mut heatmap);
+        let sections = [provenance, activity, heatmap];
+
+        let sections_len: usize = sections.iter().map(|s| SECTION_LEN_SIZE + s.len()).sum();
+        let mut bytes =
+            Vec::with_capacity(HEADER_SIZE + payload.len() + sections_len + CHECKSUM_SIZE);
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&VERSION.to_be_bytes());
+        bytes.extend_from_slice(&self.sequence.to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&self.canvas.version().to_be_bytes());
+        bytes.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        for section in &sections {
+            bytes.extend_from_slice(&(section.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(section);
+        }
+        let checksum = crc32fast::hash(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    /// Deserialize a snapshot from the snapshot file format.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        if bytes.len() < HEADER_SIZE + CHECKSUM_SIZE {
+            return Err(SnapshotError::Truncated);
+        }
+        if bytes[..4] != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let version = u16::from_be_bytes(bytes[4..6].try_into().expect("2-byte slice = u16"));
+        if !(1..=VERSION).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let read_u64 = |offset: usize| {
+            u64::from_be_bytes(
+                bytes[offset..offset + 8]
+                    .try_into()
+                    .expect("8-byte slice = u64"),
+            )
+        };
+        let sequence = read_u64(6);
+        let timestamp = read_u64(14);
+        let canvas_version = read_u64(22);
+        let payload_len = read_u64(30);
+        // Range of the payload, then of each section
+        let end = (bytes.len() - CHECKSUM_SIZE) as u64;
+        let mut ranges = Vec::with_capacity(4);
+        let mut offset = HEADER_SIZE as u64;
+        let mut len = payload_len;
+        let sections = match version {
+            1 => 0,
+            2 | 3 => 1,
+            4 => 2,
+            _ => 3,
+        };
+        for _ in 0..sections {
+            if len.saturating_add(SECTION_LEN_SIZE as u64) > end - offset {
+                return Err(SnapshotError::Truncated);
+            }
+            ranges.push(offset as usize..(offset + len) as usize);
+            offset += len;
+            len = read_u64(offset as usize);
+            offset += SECTION_LEN_SIZE as u64;
+        }
+        if len != end - offset {
+            return Err(SnapshotError::Truncated);
+        }
+        ranges.push(offset as usize..end as usize);
+
+        let (content, checksum) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+        let checksum = u32::from_be_bytes(checksum.try_into().expect("4-byte slice = u32"));
+        if crc32fast::hash(content) != checksum {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        let mut canvas = encoding::decode_snapshot(&content[ranges[0].clone()])
+            .map_err(SnapshotError::Decode)?;
+        canvas.set_version(canvas_version);
+        if let Some(provenance) = ranges.get(1) {
+            provenance::decode(&mut canvas, &content[provenance.clone()])
+                .ok_or(SnapshotError::InvalidProvenance)?;
+        }
+        let activity = match ranges.get(2) {
+            Some(activity) => {
+                let activity = Activity::decode(&content[activity.clone()])
+                    .ok_or(SnapshotError::InvalidActivity)?;
+                let heatmap = match ranges.get(3) {
+                    Some(heatmap) => Heatmap::decode(&content[heatmap.clone()])
+                        .ok_or(SnapshotError::InvalidHeatmap)?,
+                    None => Heatmap::from_canvas(&canvas),
+                };
+                activity.with_heatmap(heatmap)
+            }
+            // Along with its heatmap
+            None => Activity::from_canvas(&canvas, cooldown::DEFAULT_PREFIX_LEN),
+        };
+        Ok(Self {
+            sequence,
+            timestamp,
+            canvas,
+            activity,
+        })
+    }
+}
+
+/// Errors that can occur while reading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The snapshot file could not be read
+    Io(io::Error),
+    /// The file does not start with [MAGIC]
+    InvalidMagic,
+    /// The file uses a format version this service does not know
+    UnsupportedVersion(u16),
+    /// The file is shorter than announced
+    Truncated,
+    /// The checksum does not match the content of the file
+    ChecksumMismatch,
+    /// The canvas payload could not be decoded
+    Decode(encoding::DecodeError),
+    /// The provenance of the pixels could not be decoded
+    InvalidProvenance,
+    /// The activity of the players could not be decoded
+    InvalidActivity,
+    /// The heatmap of the pixels could not be decoded
+    InvalidHeatmap,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "I/O error: {}", e),
+            SnapshotError::InvalidMagic => write!(f, "Invalid magic bytes"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported snapshot format version {}", v)
+            }
+            SnapshotError::Truncated => write!(f, "Snapshot file is truncated"),
+            SnapshotError::ChecksumMismatch => write!(f, "Snapshot checksum mismatch"),
+            SnapshotError::Decode(e) => write!(f, "Invalid canvas payload: {}", e),
+            SnapshotError::InvalidProvenance => write!(f, "Invalid pixel provenance"),
+            SnapshotError::InvalidActivity => write!(f, "Invalid player activity"),
+            SnapshotError::InvalidHeatmap => write!(f, "Invalid heatmap"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// A snapshot file that could not be loaded, with the reason why.
+pub type SkippedSnapshot = (PathBuf, SnapshotError);
+
+/// Directory of snapshot files.
+///
+/// All operations are blocking, and should be run outside of the async runtime
+/// (e.g. with `tokio::task::spawn_blocking`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    retain: usize,
+}
+
+impl SnapshotStore {
+    /// Number of snapshots kept on disk by default.
+    pub const DEFAULT_RETAIN: usize = 3;
+
+    /// Open the snapshot store in the given directory, creating it if needed.
+    ///
+    /// Leftover temporary files from an interrupted write are removed.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if is_snapshot_file(&path) && path.extension() == Some(TMP_EXTENSION.as_ref()) {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(Self {
+            dir,
+            retain: Self::DEFAULT_RETAIN,
+        })
+    }
+
+    /// Open the snapshot store in the given directory, without creating it
+    /// or removing leftover temporary files.
+    ///
+    /// This is meant to inspect the snapshots of a running service, the store
+    /// should not be written to.
+    pub fn open_read_only(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            retain: Self::DEFAULT_RETAIN,
+        }
+    }
+
+    /// Set the number of snapshots kept on disk (at least one).
+    pub fn with_retain(mut self, retain: usize) -> Self {
+        self.retain = retain.max(1);
+        self
+    }
+
+    /// Get the directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Atomically write a snapshot, and remove the oldest snapshots beyond the retention.
+    ///
+    /// Returns the path of the new snapshot file.
+    pub fn write(&self, snapshot: &Snapshot) -> io::Result<PathBuf> {
+        let path = self.path_for(snapshot.sequence);
+        let tmp_path = path.with_extension(TMP_EXTENSION);
+
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&snapshot.to_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp_path, &path)?;
+        // Make the rename durable
+        File::open(&self.dir)?.sync_all()?;
+
+        let sequences = self.list()?;
+        for &sequence in sequences.iter().rev().skip(self.retain) {
+            fs::remove_file(self.path_for(sequence))?;
+        }
+        Ok(path)
+    }
+
+    /// Load the most recent valid snapshot, if any.
+    ///
+    /// Invalid snapshots (truncated, corrupted, ...) are skipped and reported in the
+    /// returned list of errors, so an older valid snapshot can still be used.
+    pub fn load_latest(&self) -> io::Result<(Option<Snapshot>, Vec<SkippedSnapshot>)> {
+        let mut errors = Vec::new();
+        for sequence in self.list()?.into_iter().rev() {
+            match self.load(sequence) {
+                Ok(snapshot) => return Ok((Some(snapshot), errors)),
+                Err(e) => errors.push((self.path_for(sequence), e)),
+            }
+        }
+        Ok((None, errors))
+    }
+
+    /// Load the snapshot with the given sequence number.
+    pub fn load(&self, sequence: u64) -> Result<Snapshot, SnapshotError> {
+        let bytes = fs::read(self.path_for(sequence))?;
+        Snapshot::from_bytes(&bytes)
+    }
+
+    /// List the sequence numbers of the snapshots in the store, in increasing order.
+    pub fn list(&self) -> io::Result<Vec<u64>> {
+        let mut sequences = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !is_snapshot_file(&path) || path.extension() != Some(FILE_EXTENSION.as_ref()) {
+                continue;
+            }
+            let sequence = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.strip_prefix(FILE_PREFIX))
+                .and_then(|seq| seq.parse::<u64>().ok());
+            if let Some(sequence) = sequence {
+                sequences.push(sequence);
+            }
+        }
+        sequences.sort_unstable();
+        Ok(sequences)
+    }
+
+    fn path_for(&self, sequence: u64) -> PathBuf {
+        self.dir.join(format!(
+            "{}{:020}.{}",
+            FILE_PREFIX, sequence, FILE_EXTENSION
+        ))
+    }
+}
+
+fn is_snapshot_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(FILE_PREFIX))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        canvas::{colors, label::Label, provenance::Placement},
+        events::{Event, EventSource},
+    };
+
+    fn snapshot(sequence: u64) -> Snapshot {
+        let mut canvas = Canvas::new(300, 200);
+        canvas.set_pixel(10, 20, colors::RED).unwrap();
+        canvas.set_pixel(299, 199, colors::BLUE).unwrap();
+        Snapshot {
+            sequence,
+            timestamp: 1_700_000_000_000,
+            canvas,
+            activity: Activity::default(),
+        }
+    }
+
+    #[test]
+    fn snapshot_bytes_roundtrip() {
+        let snapshot = snapshot(42);
+        let restored = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(restored, snapshot);
+        assert_eq!(restored.canvas.version(), 2);
+    }
+
+    #[test]
+    fn snapshot_bytes_keep_provenance() {
+        let mut snapshot = snapshot(42);
+        let placement = Placement {
+            source: EventSource::Ping("2001:db8::1".parse().unwrap()),
+            timestamp: 1_700_000_000_000,
+        };
+        let event = Event::PlacePixel {
+            x: 11,
+            y: 20,
+            color: colors::GREEN,
+        };
+        snapshot
+            .activity
+            .apply(&mut snapshot.canvas, &event, placement)
+            .unwrap();
+        let label = Label::new(1, 2, "hello").unwrap();
+        snapshot.canvas.place_label(label, placement).unwrap();
+        let restored = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        // Sources are kept to their prefix
+        let placement = Placement {
+            source: EventSource::Ping("2001:db8::".parse().unwrap()),
+            ..placement
+        };
+        assert_eq!(restored.canvas.placement(11, 20), Some(placement));
+        assert_eq!(restored.canvas.placement(10, 20), None);
+        assert_eq!(restored.canvas.label(1, 2), Some(label));
+        assert_eq!(restored.canvas.label_placement(1, 2), Some(placement));
+        assert_eq!(restored.activity, snapshot.activity);
+        assert_eq!(restored.activity.summary(None, None).pixels(), 1);
+    }
+
+    #[test]
+    fn snapshot_bytes_version_1() {
+        let snapshot = snapshot(42);
+        let payload = encoding::Encoder::default().encode_snapshot(&snapshot.canvas);
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&1u16.to_be_bytes());
+        bytes.extend_from_slice(&snapshot.sequence.to_be_bytes());
+        bytes.extend_from_slice(&snapshot.timestamp.to_be_bytes());
+        bytes.extend_from_slice(&snapshot.canvas.version().to_be_bytes());
+        bytes.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_be_bytes());
+        assert_eq!(Snapshot::from_bytes(&bytes).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn snapshot_bytes_detect_corruption() {
+        let bytes = snapshot(42).to_bytes();
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_SIZE + 3] ^= 0xFF;
+        assert!(matches!(
+            Snapshot::from_bytes(&corrupted),
+            Err(SnapshotError::ChecksumMismatch)
+        ));
+
+        assert!(matches!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn store_writes_and_loads_latest_valid_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SnapshotStore::open(dir.path()).unwrap().with_retain(2);
+
+        store.write(&snapshot(1)).unwrap();
+        store.write(&snapshot(5)).unwrap();
+        let latest = store.write(&snapshot(9)).unwrap();
+        assert_eq!(
+            store.list().unwrap(),
+            vec![5, 9],
+            "Oldest snapshot is removed"
+        );
+
+        let (loaded, errors) = store.load_latest().unwrap();
+        assert_eq!(loaded.unwrap().sequence, 9);
+        assert!(errors.is_empty());
+
+        // Corrupt the latest snapshot, the previous one should be used
+        let mut bytes = fs::read(&latest).unwrap();
+        bytes.truncate(bytes.len() / 2);
+        fs::write(&latest, bytes).unwrap();
+        let (loaded, errors) = store.load_latest().unwrap();
+        assert_eq!(loaded.unwrap().sequence, 5);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn store_removes_leftover_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let tmp = dir.path().join("snapshot-00000000000000000003.tmp");
+        fs::write(&tmp, b"partial").unwrap();
+
+        let store = SnapshotStore::open(dir.path()).unwrap();
+        assert!(!tmp.exists());
+        let (loaded, errors) = store.load_latest().unwrap();
+        assert!(loaded.is_none());
+        assert!(errors.is_empty());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{fmt::Debug, net::IpAddr};
+
+use hmac::{Hmac, Mac};
+use ipcanvas_ping_common::Ipv6Prefix;
+use sha2::Sha256;
+
+/// Length of the challenge sent to a ping listener.
+pub const CHALLENGE_LEN: usize = 32;
+/// Length of the response of a ping listener to its challenge.
+pub const RESPONSE_LEN: usize = 32;
+
+/// Context of the responses, so a tag computed with the key for anything else is not a response.
+const RESPONSE_CONTEXT: &[u8] = b"ipcanvas-ping/1";
+
+/// Key shared with the ping listeners, authenticating their connections.
+///
+/// Before sending its events, a listener proves it knows the key: the service sends a random
+/// challenge of [CHALLENGE_LEN] bytes, and the listener answers with the HMAC-SHA256 of
+/// `ipcanvas-ping/1` followed by the challenge, keyed with the shared key ([RESPONSE_LEN] bytes).
+/// The service closes the connection if the response is wrong.
+#[derive(Clone)]
+pub struct PingKey {
+    key: Vec<u8>,
+}
+
+impl PingKey {
+    /// Create a key from its secret bytes, None if it is empty.
+    pub fn new(key: impl Into<Vec<u8>>) -> Option<Self> {
+        let key = key.into();
+        (!key.is_empty()).then_some(Self { key })
+    }
+
+    /// Draw a new random challenge.
+    pub fn challenge() -> Result<[u8; CHALLENGE_LEN], getrandom::Error> {
+        let mut challenge = [0; CHALLENGE_LEN];
+        getrandom::fill(&mut challenge)?;
+        Ok(challenge)
+    }
+
+    /// Compute the expected response to a challenge.
+    pub fn respond(&self, challenge: &[u8; CHALLENGE_LEN]) -> [u8; RESPONSE_LEN] {
+        self.mac(challenge).finalize().into_bytes().into()
+    }
+
+    /// Check the response of a listener to a challenge, in a time independent of its content.
+    pub fn verify(&self, challenge: &[u8; CHALLENGE_LEN], response: &[u8; RESPONSE_LEN]) -> bool {
+        self.mac(challenge).verify_slice(response).is_ok()
+    }
+
+    fn mac(&self, challenge: &[u8; CHALLENGE_LEN]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any length");
+        mac.update(RESPONSE_CONTEXT);
+        mac.update(challenge);
+        mac
+    }
+}
+
+impl Debug for PingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PingKey").finish_non_exhaustive()
+    }
+}
+
+/// Addresses allowed to connect as ping listeners.
+///
+/// IPv4 addresses are matched as IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`).
+/// An empty allowlist allows every address.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PingAllowlist {
+    prefixes: Vec<Ipv6Prefix>,
+}
+
+impl PingAllowlist {
+    /// Allow the addresses within the given prefixes.
+    pub fn new(prefixes: impl IntoIterator<Item = Ipv6Prefix>) -> Self {
+        Self {
+            prefixes: prefixes.into_iter().collect(),
+        }
+    }
+
+    /// Check whether a listener may connect from `address`.
+    pub fn allows(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V4(address) => address.to_ipv6_mapped(),
+            IpAddr::V6(address) => address,
+        };
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| prefix.matches(&address))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prefixes.is_empty()
+    }
+}
+
+/// Parse a prefix of the allowlist: an IPv6 prefix (`2001:db8::/32`), an IPv4 one
+/// (`192.0.2.0/24`, matched as an IPv4-mapped prefix) or a single address.
+pub fn parse_allowed_prefix(value: &str) -> Result<Ipv6Prefix, String> {
+    let invalid = || format!("invalid prefix {:?}, expected <address>[/<length>]", value);
+    let (address, len) = match value.split_once('/') {
+        Some((address, len)) => (address, Some(len.parse::<u8>().map_err(|_| invalid())?)),
+        None => (value, None),
+    };
+    let prefix = match address.parse::<IpAddr>().map_err(|_| invalid())? {
+        IpAddr::V4(address) => {
+            let len = len.unwrap_or(32);
+            if len > 32 {
+                return Err(invalid());
+            }
+            (address.to_ipv6_mapped(), 96 + len)
+        }
+        IpAddr::V6(address) => {
+            let len = len.unwrap_or(128);
+            if len > 128 {
+                return Err(invalid());
+            }
+            (address, len)
+        }
+    };
+    Ok(Ipv6Prefix::from(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_response() {
+        let key = PingKey::new("s3cret").unwrap();
+        let challenge = PingKey::challenge().unwrap();
+        assert_ne!(challenge, PingKey::challenge().unwrap());
+
+        let response = key.respond(&challenge);
+        assert!(key.verify(&challenge, &response));
+        assert!(!PingKey::new("other").unwrap().verify(&challenge, &response));
+        let mut forged = response;
+        forged[0] ^= 1;
+        assert!(!key.verify(&challenge, &forged));
+        assert!(PingKey::new("").is_none());
+
+        // Listeners implement the same computation
+        let challenge = std::array::from_fn(|i| i as u8);
+        let response: String = key
+            .respond(&challenge)
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            response,
+            "8f7a6e82f47c17199787a1cd41fda256363672b1cd1897e3ae556016c2c2e2ff"
+        );
+    }
+
+    #[test]
+    fn allowlist() {
+        let allowlist = PingAllowlist::new([
+            parse_allowed_prefix("2001:db8::/32").unwrap(),
+            parse_allowed_prefix("192.0.2.0/24").unwrap(),
+            parse_allowed_prefix("::1").unwrap(),
+        ]);
+        let allows = |address: &str| allowlist.allows(address.parse().unwrap());
+        assert!(allows("2001:db8::1"));
+        assert!(allows("192.0.2.10"));
+        assert!(allows("::ffff:192.0.2.10"));
+        assert!(allows("::1"));
+        assert!(!allows("2001:db9::1"));
+        assert!(!allows("198.51.100.1"));
+        assert!(!allows("127.0.0.1"));
+        assert!(PingAllowlist::default().allows("198.51.100.1".parse().unwrap()));
+
+        assert!(parse_allowed_prefix("192.0.2.0/33").is_err());
+        assert!(parse_allowed_prefix("2001:db8::/129").is_err());
+        assert!(parse_allowed_prefix("localhost").is_err());
+    }
+}
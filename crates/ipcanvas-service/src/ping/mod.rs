@@ -1,4 +1,8 @@
 //! PingServer: sans-io server that ingests raw data from the Ping listener and produces Canvas Events.
 
+mod auth;
+mod routes;
 mod server;
+pub use auth::*;
+pub use routes::*;
 pub use server::*;
@@ -0,0 +1,80 @@
+use std::net::Ipv6Addr;
+
+use ipcanvas_ping_common::Ipv6Prefix;
+
+/// Routes of the ping events to the canvases, by destination prefix.
+///
+/// An event goes to the canvas with the longest prefix matching its destination, or to the
+/// canvas without prefix (if any) when no prefix matches. Canvases are identified by their index.
+///
+/// The default routes send every event to the canvas 0.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PingRoutes {
+    // Sorted by decreasing prefix length
+    prefixes: Vec<(Ipv6Prefix, usize)>,
+    // Canvas of the events matching no prefix
+    fallback: Option<usize>,
+}
+
+impl PingRoutes {
+    /// Create routes sending the events nowhere.
+    pub fn new() -> Self {
+        Self {
+            prefixes: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Send the events to the given prefix to a canvas, or the events matching no prefix
+    /// if `prefix` is None.
+    pub fn with_route(mut self, prefix: Option<Ipv6Prefix>, canvas: usize) -> Self {
+        match prefix {
+            Some(prefix) => {
+                let at = self
+                    .prefixes
+                    .partition_point(|(other, _)| other.prefix_len >= prefix.prefix_len);
+                self.prefixes.insert(at, (prefix, canvas));
+            }
+            None => self.fallback = Some(canvas),
+        }
+        self
+    }
+
+    /// Get the canvas of an event sent to `destination`, None if the event goes nowhere.
+    pub fn route(&self, destination: &Ipv6Addr) -> Option<usize> {
+        self.prefixes
+            .iter()
+            .find(|(prefix, _)| prefix.matches(destination))
+            .map(|&(_, canvas)| canvas)
+            .or(self.fallback)
+    }
+}
+
+impl Default for PingRoutes {
+    fn default() -> Self {
+        Self::new().with_route(None, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let routes = PingRoutes::new()
+            .with_route(Some("2001:db8::/32".parse().unwrap()), 0)
+            .with_route(Some("2001:db8:1::/48".parse().unwrap()), 1);
+        let route = |address: &str| routes.route(&address.parse().unwrap());
+        assert_eq!(route("2001:db8:1:a::1"), Some(1));
+        assert_eq!(route("2001:db8:2:a::1"), Some(0));
+        assert_eq!(route("2001:db9::1"), None);
+
+        let routes = routes.with_route(None, 2);
+        assert_eq!(routes.route(&"2001:db9::1".parse().unwrap()), Some(2));
+        assert_eq!(
+            PingRoutes::default().route(&"2001:db9::1".parse().unwrap()),
+            Some(0)
+        );
+    }
+}
@@ -2,20 +2,49 @@ use std::{fmt::Display, mem};
 
 use ipcanvas_ping_common::PingEvent;
 
-use crate::{canvas::PixelColor, events::Event};
+use crate::{
+    canvas::PixelColor,
+    events::{Event, EventSource, SourcedEvent},
+    ping::PingRoutes,
+};
 
 /// PingServer: sans-io server that ingests raw data from the Ping listener and produces Canvas Events.
 ///
 /// The PingServer maintains two internal buffers:
 /// - Ingest buffer: holds raw data ingested from the Ping listener
-/// - Egress buffer: holds processed Canvas [Event] ready to be consumed by the application
+/// - Egress buffer: holds processed Canvas [Event], along with their source and canvas, ready
+///   to be consumed by the application
+///
+/// Each event is routed to a canvas by the destination of its ping, see [PingRoutes].
+/// Events routed nowhere are dropped, and counted.
 ///
 /// The server comes with internal buffers of configurable sizes for both ingest and egress.
 /// The user is responsible for ensuring that the buffers are sized appropriately for their use case.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PingServer {
     ingest: Vec<u8>,
-    egress: Vec<Event>,
+    egress: Vec<RoutedEvent>,
+    routes: PingRoutes,
+    // Number of events dropped as they were routed nowhere
+    unrouted: u64,
+}
+
+/// A Canvas [Event], along with its source and the canvas it is routed to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoutedEvent {
+    /// Index of the canvas, in the [PingRoutes]
+    pub canvas: usize,
+    pub source: EventSource,
+    pub event: Event,
+}
+
+impl From<RoutedEvent> for SourcedEvent {
+    fn from(routed: RoutedEvent) -> Self {
+        SourcedEvent {
+            source: routed.source,
+            event: routed.event,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -59,9 +88,17 @@ impl PingServer {
         PingServer {
             ingest: Vec::with_capacity(ingest_capacity),
             egress: Vec::with_capacity(egress_capacity),
+            routes: PingRoutes::default(),
+            unrouted: 0,
         }
     }
 
+    /// Set the routes of the events to the canvases (all to the canvas 0 by default).
+    pub fn with_routes(mut self, routes: PingRoutes) -> Self {
+        self.routes = routes;
+        self
+    }
+
     /// Ingest raw data into the server's ingest buffer
     pub fn ingest(&mut self, data: &[u8]) -> Result<(), PingServerError> {
         // Ingest should never exceed the vec capacity
@@ -135,8 +172,18 @@ impl PingServer {
             }
 
             // Otherwise, push events to egress buffer
-            self.egress.extend(events);
             offset += 32;
+            let Some(canvas) = self.routes.route(&ping_event.destination()) else {
+                self.unrouted += events.len() as u64;
+                continue;
+            };
+            let source = EventSource::Ping(ping_event.source());
+            self.egress
+                .extend(events.into_iter().map(|event| RoutedEvent {
+                    canvas,
+                    source,
+                    event,
+                }));
         }
 
         // Remove processed data from ingest buffer
@@ -151,9 +198,9 @@ impl PingServer {
     }
 
     /// Egress processed events from the server's egress buffer
-    pub fn egress(&mut self, max_events: usize) -> Vec<Event> {
+    pub fn egress(&mut self, max_events: usize) -> Vec<RoutedEvent> {
         let to_egress = self.egress.len().min(max_events);
-        let events: Vec<Event> = self.egress.drain(..to_egress).collect();
+        let events: Vec<RoutedEvent> = self.egress.drain(..to_egress).collect();
         events
     }
 
@@ -161,6 +208,11 @@ impl PingServer {
     pub fn ready_events(&self) -> usize {
         self.egress.len()
     }
+
+    /// Get the number of events dropped so far, as they were routed to no canvas
+    pub fn unrouted_events(&self) -> u64 {
+        self.unrouted
+    }
 }
 
 impl Default for PingServer {
@@ -447,17 +499,18 @@ mod tests {
     #[test]
     fn ping_server_handle_incoming_ping_event() {
         // Currently only one event type is supported, so this test is simple
+        let source_address = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
         let redx10y0 = PingEvent {
             destination_address: [0, 0, 0, 0, 0, 0, 0, 10, 0, 0, 0, 255, 0, 0, 0, 0],
-            source_address: [0; 16],
+            source_address,
         };
         let bluex20y30 = PingEvent {
             destination_address: [0, 0, 0, 0, 0, 0, 0, 20, 0, 10, 0, 0, 0, 0, 0, 255],
-            source_address: [0; 16],
+            source_address,
         };
         let whitex256y256 = PingEvent {
             destination_address: [0, 0, 0, 0, 0, 0, 1, 0, 1, 0, 0, 255, 0, 255, 0, 255],
-            source_address: [0; 16],
+            source_address,
         };
 
         let mut server = PingServer::new(96, 4); // Enough for 3 PingEvents
@@ -475,6 +528,14 @@ mod tests {
 
         let events = server.egress(3);
         assert_eq!(events.len(), 3, "Expected 3 events egressed");
+        for event in &events {
+            assert_eq!(
+                event.source,
+                EventSource::Ping(redx10y0.source()),
+                "Source address mismatch"
+            );
+        }
+        let events: Vec<Event> = events.into_iter().map(|e| e.event).collect();
         assert_eq!(
             events[0],
             Event::PlacePixel {
@@ -507,4 +568,36 @@ mod tests {
             "White pixel event mismatch"
         );
     }
+
+    #[test]
+    fn ping_server_routes_events() {
+        let source_address = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        let main = PingEvent {
+            destination_address: [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 1, 0, 2, 0, 255, 0, 0, 0, 0],
+            source_address,
+        };
+        let sandbox = PingEvent {
+            destination_address: [0x20, 0x01, 0x0d, 0xb8, 0, 1, 0, 1, 0, 2, 0, 255, 0, 0, 0, 0],
+            source_address,
+        };
+        let elsewhere = PingEvent {
+            destination_address: [0x20, 0x01, 0x0d, 0xb9, 0, 1, 0, 1, 0, 2, 0, 255, 0, 0, 0, 0],
+            source_address,
+        };
+        let routes = PingRoutes::new()
+            .with_route(Some("2001:db8::/48".parse().unwrap()), 0)
+            .with_route(Some("2001:db8:1::/48".parse().unwrap()), 1);
+
+        let mut server = PingServer::new(96, 4).with_routes(routes);
+        let mut buf = [0u8; 96];
+        buf[0..32].copy_from_slice(sandbox.as_bytes());
+        buf[32..64].copy_from_slice(elsewhere.as_bytes());
+        buf[64..96].copy_from_slice(main.as_bytes());
+        server.ingest(&buf).unwrap();
+        server.progress().unwrap();
+
+        let canvases: Vec<usize> = server.egress(4).iter().map(|e| e.canvas).collect();
+        assert_eq!(canvases, vec![1, 0]);
+        assert_eq!(server.unrouted_events(), 1);
+    }
 }
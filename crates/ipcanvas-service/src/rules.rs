@@ -0,0 +1,396 @@
+//! Region rules: protect areas of the canvas, or apply specific rules to them.
+//!
+//! The rules are read from a TOML file, as a list of rectangular regions each with
+//! a rule set:
+//!
+//! ```toml
+//! # Event logo, nobody may change it
+//! [[region]]
+//! name = "logo"
+//! x = 0
+//! y = 0
+//! width = 256
+//! height = 128
+//! read_only = true
+//!
+//! [[region]]
+//! name = "sponsors"
+//! x = 3840
+//! y = 3968
+//! width = 256
+//! height = 128
+//! cooldown = 60                          # seconds, on top of the global cooldown
+//! cooldown_prefix_len = 56               # bits identifying a player (64 by default)
+//! palette = ["#000000", "#ffffff"]
+//! allowed_prefixes = ["2001:db8::/32"]
+//! ```
+//!
+//! A placement must satisfy the rules of every region containing its pixel, they are
+//! checked in the order of the file. Only placements from pings are subject to the rules,
+//! the operator (image imports, rollbacks) may change any pixel.
+
+use std::{fmt::Display, io, path::Path, time::Duration};
+
+use ipcanvas_ping_common::Ipv6Prefix;
+use serde::Deserialize;
+
+use crate::{
+    canvas::{PixelColor, Region},
+    cooldown::{self, Cooldown, CoolingDown},
+    events::EventSource,
+};
+
+/// Rules of the regions of the canvas.
+#[derive(Clone, Debug, Default)]
+pub struct RegionRules {
+    regions: Vec<RuledRegion>,
+}
+
+/// A region and its rule set.
+#[derive(Clone, Debug)]
+struct RuledRegion {
+    name: String,
+    region: Region,
+    read_only: bool,
+    // Disabled if the region has no cooldown
+    cooldown: Cooldown,
+    palette: Option<Vec<PixelColor>>,
+    allowed_prefixes: Option<Vec<Ipv6Prefix>>,
+}
+
+/// A placement rejected by the rules of a region.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleViolation {
+    /// Name of the region whose rules rejected the placement
+    pub region: String,
+    /// Rule which rejected the placement
+    pub rule: Rule,
+}
+
+/// A rule of a region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    /// The region can not be changed.
+    ReadOnly,
+    /// The player is still cooling down in the region.
+    Cooldown(CoolingDown),
+    /// The color is not part of the palette of the region.
+    Palette(PixelColor),
+    /// The source is not within the prefixes allowed in the region.
+    AllowedPrefixes,
+}
+
+/// Error while loading region rules.
+#[derive(Debug)]
+pub enum RulesError {
+    /// The rules file could not be read.
+    Io(io::Error),
+    /// The rules file is not valid TOML, or has unknown fields.
+    Parse(toml::de::Error),
+    /// A region has an invalid rule.
+    Invalid { region: String, reason: String },
+}
+
+/// On-disk format of the rules file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    region: Vec<RegionEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegionEntry {
+    name: String,
+    x: u16,
+    y: u16,
+    width: u16,
+    height: u16,
+    #[serde(default)]
+    read_only: bool,
+    // In seconds
+    cooldown: Option<u64>,
+    cooldown_prefix_len: Option<u8>,
+    palette: Option<Vec<String>>,
+    allowed_prefixes: Option<Vec<String>>,
+}
+
+impl Display for RuleViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.rule {
+            Rule::ReadOnly => write!(f, "region {:?} is read-only", self.region),
+            Rule::Cooldown(cooling_down) => {
+                write!(f, "in region {:?}, {}", self.region, cooling_down)
+            }
+            Rule::Palette(color) => write!(
+                f,
+                "color {} is not in the palette of region {:?}",
+                color.to_hex(),
+                self.region
+            ),
+            Rule::AllowedPrefixes => {
+                write!(f, "source is not allowed in region {:?}", self.region)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RuleViolation {}
+
+impl Display for RulesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RulesError::Io(e) => write!(f, "I/O error: {}", e),
+            RulesError::Parse(e) => write!(f, "Invalid rules file: {}", e),
+            RulesError::Invalid { region, reason } => {
+                write!(f, "Invalid rules for region {:?}: {}", region, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RulesError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RulesError::Io(e) => Some(e),
+            RulesError::Parse(e) => Some(e),
+            RulesError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for RulesError {
+    fn from(e: io::Error) -> Self {
+        RulesError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for RulesError {
+    fn from(e: toml::de::Error) -> Self {
+        RulesError::Parse(e)
+    }
+}
+
+impl RegionRules {
+    /// Read the rules from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, RulesError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse the rules from the content of a TOML file.
+    pub fn parse(content: &str) -> Result<Self, RulesError> {
+        let file: RulesFile = toml::from_str(content)?;
+        let regions = file
+            .region
+            .into_iter()
+            .map(RuledRegion::try_from)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { regions })
+    }
+
+    /// Get the number of regions.
+    pub fn len(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// Check if there is no region.
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// Iterate over the regions, as their name and area, in the order of the file.
+    pub fn regions(&self) -> impl Iterator<Item = (&str, Region)> {
+        self.regions.iter().map(|r| (r.name.as_str(), r.region))
+    }
+
+    /// Check whether the source may place the pixel at (x, y) at `now` (in ms since the Unix epoch).
+    pub fn check(
+        &self,
+        source: EventSource,
+        x: u16,
+        y: u16,
+        color: PixelColor,
+        now: u64,
+    ) -> Result<(), RuleViolation> {
+        let EventSource::Ping(address) = source else {
+            return Ok(());
+        };
+        for ruled in self.regions.iter().filter(|r| r.region.contains(x, y)) {
+            let violation = |rule| RuleViolation {
+                region: ruled.name.clone(),
+                rule,
+            };
+            if ruled.read_only {
+                return Err(violation(Rule::ReadOnly));
+            }
+            if let Some(prefixes) = &ruled.allowed_prefixes
+                && !prefixes.iter().any(|prefix| prefix.matches(&address))
+            {
+                return Err(violation(Rule::AllowedPrefixes));
+            }
+            if let Some(palette) = &ruled.palette
+                && !palette.contains(&color)
+            {
+                return Err(violation(Rule::Palette(color)));
+            }
+            if let Err(cooling_down) = ruled.cooldown.check(source, now) {
+                return Err(violation(Rule::Cooldown(cooling_down)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Start the cooldowns of the regions containing (x, y), after the source placed
+    /// a pixel there at `now` (in ms since the Unix epoch).
+    pub fn record(&mut self, source: EventSource, x: u16, y: u16, now: u64) {
+        for ruled in self.regions.iter_mut().filter(|r| r.region.contains(x, y)) {
+            ruled.cooldown.record(source, now);
+        }
+    }
+}
+
+impl TryFrom<RegionEntry> for RuledRegion {
+    type Error = RulesError;
+
+    fn try_from(entry: RegionEntry) -> Result<Self, Self::Error> {
+        let invalid = |reason: String| RulesError::Invalid {
+            region: entry.name.clone(),
+            reason,
+        };
+        let region = Region {
+            x: entry.x,
+            y: entry.y,
+            width: entry.width,
+            height: entry.height,
+        };
+        if region.is_empty() {
+            return Err(invalid("the region has no pixel".to_string()));
+        }
+        let prefix_len = entry
+            .cooldown_prefix_len
+            .unwrap_or(cooldown::DEFAULT_PREFIX_LEN);
+        if prefix_len > 128 {
+            return Err(invalid(format!("invalid prefix length {}", prefix_len)));
+        }
+        let cooldown = Cooldown::new(Duration::from_secs(entry.cooldown.unwrap_or(0)))
+            .with_prefix_len(prefix_len);
+        let palette = match &entry.palette {
+            Some(colors) => Some(
+                colors
+                    .iter()
+                    .map(|color| {
+                        PixelColor::from_hex(color)
+                            .ok_or_else(|| invalid(format!("invalid color {:?}", color)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        let allowed_prefixes = match &entry.allowed_prefixes {
+            Some(prefixes) => Some(
+                prefixes
+                    .iter()
+                    .map(|prefix| {
+                        prefix
+                            .parse::<Ipv6Prefix>()
+                            .ok()
+                            .filter(|prefix| prefix.prefix_len <= 128)
+                            .ok_or_else(|| invalid(format!("invalid prefix {:?}", prefix)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            name: entry.name,
+            region,
+            read_only: entry.read_only,
+            cooldown,
+            palette,
+            allowed_prefixes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::colors;
+
+    const RULES: &str = r##"
+        [[region]]
+        name = "logo"
+        x = 0
+        y = 0
+        width = 16
+        height = 16
+        read_only = true
+
+        [[region]]
+        name = "sponsors"
+        x = 8
+        y = 0
+        width = 32
+        height = 32
+        cooldown = 10
+        palette = ["#000000", "#FFFFFF"]
+        allowed_prefixes = ["2001:db8::/32"]
+    "##;
+
+    fn ping(address: &str) -> EventSource {
+        EventSource::Ping(address.parse().unwrap())
+    }
+
+    #[test]
+    fn region_rules() {
+        let mut rules = RegionRules::parse(RULES).unwrap();
+        assert_eq!(rules.len(), 2);
+        let alice = ping("2001:db8::1");
+        let rule = |result: Result<(), RuleViolation>| result.map_err(|v| (v.region, v.rule));
+
+        // The first region containing the pixel rejects it
+        assert_eq!(
+            rule(rules.check(alice, 8, 8, colors::BLACK, 0)),
+            Err(("logo".to_string(), Rule::ReadOnly))
+        );
+        assert_eq!(
+            rule(rules.check(ping("2001:db9::1"), 20, 20, colors::BLACK, 0)),
+            Err(("sponsors".to_string(), Rule::AllowedPrefixes))
+        );
+        assert_eq!(
+            rule(rules.check(alice, 20, 20, colors::RED, 0)),
+            Err(("sponsors".to_string(), Rule::Palette(colors::RED)))
+        );
+        rules.check(alice, 20, 20, colors::BLACK, 0).unwrap();
+        rules.record(alice, 20, 20, 0);
+        assert!(matches!(
+            rule(rules.check(alice, 21, 20, colors::WHITE, 5_000)),
+            Err((
+                _,
+                Rule::Cooldown(CoolingDown {
+                    remaining: 5_000,
+                    ..
+                })
+            ))
+        ));
+
+        // Outside of the regions, and operator actions, are not limited
+        rules.check(alice, 100, 100, colors::RED, 5_000).unwrap();
+        rules
+            .check(EventSource::Import, 0, 0, colors::RED, 5_000)
+            .unwrap();
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for content in [
+            "[[region]]\nname = \"a\"\nx = 0\ny = 0\nwidth = 0\nheight = 1",
+            "[[region]]\nname = \"a\"\nx = 0\ny = 0\nwidth = 1\nheight = 1\npalette = [\"red\"]",
+            "[[region]]\nname = \"a\"\nx = 0\ny = 0\nwidth = 1\nheight = 1\nlocked = true",
+        ] {
+            assert!(RegionRules::parse(content).is_err(), "{}", content);
+        }
+        assert!(RegionRules::parse("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,468 @@
+//! Timelapse: render the history of the canvas as an animated GIF or APNG.
+//!
+//! Frames are rendered by replaying [LogEntry]s on top of a starting canvas, either every
+//! given number of events or every given amount of (event) time. The output only depends
+//! on its inputs, so it can be compared against golden files.
+
+use std::{fmt::Display, io::Write};
+
+use crate::{
+    canvas::{Canvas, Region},
+    persistence::LogEntry,
+};
+
+/// Output format of a timelapse.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Animated GIF, each frame with its own 256-color palette.
+    Gif,
+    /// Animated PNG, lossless.
+    Apng,
+}
+
+/// How often a frame is rendered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameInterval {
+    /// A frame every given number of events.
+    Events(u64),
+    /// A frame every given number of milliseconds, according to the event timestamps.
+    ///
+    /// Idle periods (without any event) are collapsed into a single frame.
+    Millis(u64),
+}
+
+/// Options of a timelapse.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimelapseOptions {
+    pub format: Format,
+    pub interval: FrameInterval,
+    /// Region of the canvas to render, the whole canvas if None.
+    pub region: Option<Region>,
+    /// Scale factor applied to the region (nearest neighbour).
+    pub scale: f64,
+    /// Display time of each frame, in milliseconds.
+    pub frame_delay_ms: u16,
+}
+
+impl Default for TimelapseOptions {
+    fn default() -> Self {
+        Self {
+            format: Format::Gif,
+            interval: FrameInterval::Events(1000),
+            region: None,
+            scale: 1.0,
+            frame_delay_ms: 100,
+        }
+    }
+}
+
+/// Errors that can occur while rendering a timelapse.
+#[derive(Debug)]
+pub enum TimelapseError {
+    /// The frame interval is zero
+    InvalidInterval,
+    /// The region is not within the starting canvas
+    InvalidRegion,
+    /// The scale factor is not a positive number
+    InvalidScale,
+    /// The scaled frames are too large for the format
+    FrameTooLarge,
+    /// The GIF could not be encoded (or written)
+    Gif(gif::EncodingError),
+    /// The APNG could not be encoded (or written)
+    Png(png::EncodingError),
+}
+
+impl Display for TimelapseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimelapseError::InvalidInterval => write!(f, "Frame interval must not be zero"),
+            TimelapseError::InvalidRegion => write!(f, "Region is not within the canvas"),
+            TimelapseError::InvalidScale => write!(f, "Scale must be a positive number"),
+            TimelapseError::FrameTooLarge => write!(f, "Scaled frames are too large"),
+            TimelapseError::Gif(e) => write!(f, "Failed to encode the GIF: {}", e),
+            TimelapseError::Png(e) => write!(f, "Failed to encode the APNG: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TimelapseError {}
+
+impl From<gif::EncodingError> for TimelapseError {
+    fn from(e: gif::EncodingError) -> Self {
+        TimelapseError::Gif(e)
+    }
+}
+
+impl From<png::EncodingError> for TimelapseError {
+    fn from(e: png::EncodingError) -> Self {
+        TimelapseError::Png(e)
+    }
+}
+
+/// Render the timelapse of `entries` applied on top of `start`, and write it to `out`.
+///
+/// The first frame is the starting canvas, and the last one the canvas after all the entries.
+/// Entries which cannot be applied (e.g. out of bounds) are skipped, as in the service.
+pub fn render<W: Write>(
+    start: &Canvas,
+    entries: &[LogEntry],
+    options: &TimelapseOptions,
+    out: W,
+) -> Result<(), TimelapseError> {
+    if matches!(
+        options.interval,
+        FrameInterval::Events(0) | FrameInterval::Millis(0)
+    ) {
+        return Err(TimelapseError::InvalidInterval);
+    }
+    let region = options.region.unwrap_or_else(|| start.bounds());
+    if !start.contains_region(region) {
+        return Err(TimelapseError::InvalidRegion);
+    }
+    let width = scaled(region.width, options.scale)?;
+    let height = scaled(region.height, options.scale)?;
+
+    let frames = count_frames(entries, options.interval);
+    let mut encoder = FrameEncoder::new(options, out, width, height, frames)?;
+    let mut canvas = start.clone();
+    let mut schedule = Schedule::new(options.interval);
+    let mut buf = Vec::with_capacity(width as usize * height as usize * 3);
+
+    render_frame(&canvas, region, width, height, &mut buf);
+    encoder.write_frame(&buf)?;
+    for entry in entries {
+        if schedule.frame_before(entry) {
+            render_frame(&canvas, region, width, height, &mut buf);
+            encoder.write_frame(&buf)?;
+        }
+        let _ = entry.event.apply(&mut canvas);
+    }
+    if schedule.has_pending() {
+        render_frame(&canvas, region, width, height, &mut buf);
+        encoder.write_frame(&buf)?;
+    }
+    encoder.finish()
+}
+
+/// Count the frames of the timelapse of `entries`, including the first and last frames.
+pub fn count_frames(entries: &[LogEntry], interval: FrameInterval) -> u32 {
+    let mut schedule = Schedule::new(interval);
+    let mut frames = 1 + entries
+        .iter()
+        .filter(|entry| schedule.frame_before(entry))
+        .count() as u32;
+    if schedule.has_pending() {
+        frames += 1;
+    }
+    frames
+}
+
+/// Decides when the frames are rendered.
+struct Schedule {
+    interval: FrameInterval,
+    // Entries applied since the last frame
+    pending: u64,
+    // Timestamp of the next frame, set by the first entry
+    next_frame_at: Option<u64>,
+}
+
+impl Schedule {
+    fn new(interval: FrameInterval) -> Self {
+        Self {
+            interval,
+            pending: 0,
+            next_frame_at: None,
+        }
+    }
+
+    /// Check if a frame must be rendered before applying the given entry.
+    fn frame_before(&mut self, entry: &LogEntry) -> bool {
+        let frame = match self.interval {
+            FrameInterval::Events(n) => self.pending >= n,
+            FrameInterval::Millis(ms) => {
+                let next = *self
+                    .next_frame_at
+                    .get_or_insert(entry.timestamp.saturating_add(ms));
+                if entry.timestamp >= next {
+                    // Skip the periods without any entry
+                    let periods = (entry.timestamp - next) / ms + 1;
+                    self.next_frame_at = Some(next.saturating_add(periods.saturating_mul(ms)));
+                    self.pending > 0
+                } else {
+                    false
+                }
+            }
+        };
+        if frame {
+            self.pending = 0;
+        }
+        self.pending += 1;
+        frame
+    }
+
+    /// Check if entries have been applied since the last frame.
+    fn has_pending(&self) -> bool {
+        self.pending > 0
+    }
+}
+
+/// Get a dimension of the frames, once scaled.
+fn scaled(size: u16, scale: f64) -> Result<u16, TimelapseError> {
+    if !(scale.is_finite() && scale > 0.0) {
+        return Err(TimelapseError::InvalidScale);
+    }
+    let size = (size as f64 * scale).round().max(1.0);
+    if size > u16::MAX as f64 {
+        return Err(TimelapseError::FrameTooLarge);
+    }
+    Ok(size as u16)
+}
+
+/// Render a region of the canvas, scaled to the given dimensions, as RGB data.
+fn render_frame(canvas: &Canvas, region: Region, width: u16, height: u16, buf: &mut Vec<u8>) {
+    buf.clear();
+    for y in 0..height as u32 {
+        let sy = region.y + (y * region.height as u32 / height as u32) as u16;
+        for x in 0..width as u32 {
+            let sx = region.x + (x * region.width as u32 / width as u32) as u16;
+            let color = canvas
+                .get_pixel(sx, sy)
+                .expect("region is within the canvas");
+            buf.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+    }
+}
+
+/// Streaming encoder of the frames.
+enum FrameEncoder<W: Write> {
+    Gif {
+        encoder: gif::Encoder<W>,
+        width: u16,
+        height: u16,
+        // In hundredths of a second
+        delay: u16,
+    },
+    Apng(png::Writer<W>),
+}
+
+impl<W: Write> FrameEncoder<W> {
+    fn new(
+        options: &TimelapseOptions,
+        out: W,
+        width: u16,
+        height: u16,
+        frames: u32,
+    ) -> Result<Self, TimelapseError> {
+        match options.format {
+            Format::Gif => {
+                let mut encoder = gif::Encoder::new(out, width, height, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Ok(FrameEncoder::Gif {
+                    encoder,
+                    width,
+                    height,
+                    delay: options.frame_delay_ms / 10,
+                })
+            }
+            Format::Apng => {
+                let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+                encoder.set_color(png::ColorType::Rgb);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(frames, 0)?;
+                encoder.set_frame_delay(options.frame_delay_ms, 1000)?;
+                Ok(FrameEncoder::Apng(encoder.write_header()?))
+            }
+        }
+    }
+
+    fn write_frame(&mut self, rgb: &[u8]) -> Result<(), TimelapseError> {
+        match self {
+            FrameEncoder::Gif {
+                encoder,
+                width,
+                height,
+                delay,
+            } => {
+                let mut frame = gif::Frame::from_rgb_speed(*width, *height, rgb, 10);
+                frame.delay = *delay;
+                encoder.write_frame(&frame)?;
+            }
+            FrameEncoder::Apng(writer) => writer.write_image_data(rgb)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), TimelapseError> {
+        match self {
+            FrameEncoder::Gif { encoder, .. } => {
+                encoder.into_inner()?;
+            }
+            FrameEncoder::Apng(writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        canvas::{PixelColor, colors},
+        events::{Event, EventSource},
+    };
+    use std::path::PathBuf;
+
+    /// Draw a colored diagonal on a 16x16 canvas, one pixel per second.
+    fn history() -> Vec<LogEntry> {
+        let palette = [colors::RED, colors::GREEN, colors::BLUE, colors::BLACK];
+        (0..16u16)
+            .map(|i| LogEntry {
+                sequence: i as u64 + 1,
+                // A pause of a minute halfway
+                timestamp: 1_700_000_000_000 + i as u64 * 1000 + if i >= 8 { 60_000 } else { 0 },
+                source: EventSource::Import,
+                event: Event::PlacePixel {
+                    x: i,
+                    y: i,
+                    color: palette[i as usize % palette.len()],
+                },
+            })
+            .collect()
+    }
+
+    /// Compare the output with its golden file, or update it if `IPCANVAS_UPDATE_GOLDEN` is set.
+    fn check_golden(name: &str, output: &[u8]) {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/timelapse/testdata")
+            .join(name);
+        if std::env::var_os("IPCANVAS_UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, output).unwrap();
+        }
+        let golden = std::fs::read(&path).unwrap();
+        assert!(
+            golden == output,
+            "{} does not match its golden file (set IPCANVAS_UPDATE_GOLDEN=1 to update it)",
+            name
+        );
+    }
+
+    #[test]
+    fn timelapse_frame_count() {
+        let entries = history();
+        assert_eq!(count_frames(&entries, FrameInterval::Events(4)), 5);
+        assert_eq!(count_frames(&entries, FrameInterval::Events(5)), 5);
+        assert_eq!(count_frames(&entries, FrameInterval::Events(100)), 2);
+        // Every 2s: 4 frames before the pause, a single one for the pause, 4 after
+        assert_eq!(count_frames(&entries, FrameInterval::Millis(2000)), 9);
+        assert_eq!(count_frames(&[], FrameInterval::Events(1)), 1);
+    }
+
+    #[test]
+    fn timelapse_gif_golden() {
+        let options = TimelapseOptions {
+            format: Format::Gif,
+            interval: FrameInterval::Events(4),
+            region: Some(Region {
+                x: 4,
+                y: 4,
+                width: 8,
+                height: 8,
+            }),
+            scale: 2.0,
+            frame_delay_ms: 200,
+        };
+        let mut output = Vec::new();
+        render(&Canvas::new(16, 16), &history(), &options, &mut output).unwrap();
+
+        let mut decoder = gif::DecodeOptions::new();
+        decoder.set_color_output(gif::ColorOutput::RGBA);
+        let mut decoder = decoder.read_info(output.as_slice()).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (16, 16));
+        let mut frames = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!(frame.delay, 20);
+            frames.push(frame.buffer.to_vec());
+        }
+        assert_eq!(frames.len(), 5);
+        // (4, 4) is drawn in the second batch, scaled to 2x2 pixels at the top left
+        let pixel = |frame: &[u8], x: usize, y: usize| {
+            let i = (y * 16 + x) * 4;
+            PixelColor {
+                r: frame[i],
+                g: frame[i + 1],
+                b: frame[i + 2],
+            }
+        };
+        assert_eq!(pixel(&frames[1], 1, 1), colors::WHITE);
+        assert_eq!(pixel(&frames[2], 1, 1), colors::RED);
+        assert_eq!(pixel(&frames[2], 2, 2), colors::GREEN);
+
+        check_golden("timelapse.gif", &output);
+    }
+
+    #[test]
+    fn timelapse_apng_golden() {
+        let options = TimelapseOptions {
+            format: Format::Apng,
+            interval: FrameInterval::Millis(2000),
+            ..TimelapseOptions::default()
+        };
+        let mut output = Vec::new();
+        render(&Canvas::new(16, 16), &history(), &options, &mut output).unwrap();
+
+        let decoder = png::Decoder::new(std::io::Cursor::new(&output));
+        let mut reader = decoder.read_info().unwrap();
+        let control = reader.info().animation_control().unwrap();
+        assert_eq!(control.num_frames, 9);
+        let mut buf = vec![0; reader.output_buffer_size().unwrap()];
+        let mut frames = 0;
+        while reader.next_frame(&mut buf).is_ok() {
+            frames += 1;
+        }
+        assert_eq!(frames, 9);
+        // The last frame has the whole diagonal
+        assert_eq!(&buf[(15 * 16 + 15) * 3..], &[0, 0, 0]);
+
+        check_golden("timelapse.apng", &output);
+    }
+
+    #[test]
+    fn timelapse_rejects_invalid_options() {
+        let canvas = Canvas::new(16, 16);
+        let render = |options: TimelapseOptions| render(&canvas, &[], &options, Vec::new());
+        assert!(matches!(
+            render(TimelapseOptions {
+                interval: FrameInterval::Events(0),
+                ..Default::default()
+            }),
+            Err(TimelapseError::InvalidInterval)
+        ));
+        assert!(matches!(
+            render(TimelapseOptions {
+                region: Some(Region {
+                    x: 8,
+                    y: 0,
+                    width: 9,
+                    height: 1
+                }),
+                ..Default::default()
+            }),
+            Err(TimelapseError::InvalidRegion)
+        ));
+        assert!(matches!(
+            render(TimelapseOptions {
+                scale: f64::NAN,
+                ..Default::default()
+            }),
+            Err(TimelapseError::InvalidScale)
+        ));
+        assert!(matches!(
+            render(TimelapseOptions {
+                scale: 10000.0,
+                ..Default::default()
+            }),
+            Err(TimelapseError::FrameTooLarge)
+        ));
+    }
+}
@@ -0,0 +1,140 @@
+//! TLS: encryption of the ping link between the listeners and the service.
+//!
+//! The service presents a certificate to the ping listeners (`--ping-tls-cert` and
+//! `--ping-tls-key`, PEM files). With a client CA (`--ping-tls-client-ca`), the TLS is mutual:
+//! every listener must present a certificate issued by this CA, or the handshake fails.
+//!
+//! The TLS only protects the transport, the events and the challenge of the [PingKey]
+//! (if any) are then exchanged as on a plain TCP connection.
+//!
+//! [PingKey]: crate::ping::PingKey
+
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use rustls::{
+    RootCertStore, ServerConfig,
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{VerifierBuilderError, WebPkiClientVerifier},
+};
+
+/// Paths of the PEM files configuring the TLS of the ping link.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TlsFiles {
+    /// Certificate chain of the service, leaf first
+    pub cert: PathBuf,
+    /// Private key of the certificate (PKCS#8, PKCS#1 or SEC1)
+    pub key: PathBuf,
+    /// Certificates of the CAs issuing the certificates of the listeners,
+    /// None to accept listeners without certificate
+    pub client_ca: Option<PathBuf>,
+}
+
+/// Error while loading the TLS configuration.
+#[derive(Debug)]
+pub enum TlsError {
+    /// A PEM file could not be read or decoded.
+    Pem {
+        path: PathBuf,
+        error: rustls::pki_types::pem::Error,
+    },
+    /// A PEM file has no certificate.
+    NoCertificate { path: PathBuf },
+    /// The client CA file has an invalid certificate.
+    ClientCa(VerifierBuilderError),
+    /// The certificate and key are not usable (e.g. they do not match).
+    Rustls(rustls::Error),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Pem { path, error } => {
+                write!(f, "Failed to load {}: {}", path.display(), error)
+            }
+            TlsError::NoCertificate { path } => {
+                write!(f, "No certificate found in {}", path.display())
+            }
+            TlsError::ClientCa(e) => write!(f, "Invalid client CA: {}", e),
+            TlsError::Rustls(e) => write!(f, "Invalid TLS configuration: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Pem { error, .. } => Some(error),
+            TlsError::ClientCa(e) => Some(e),
+            TlsError::Rustls(e) => Some(e),
+            TlsError::NoCertificate { .. } => None,
+        }
+    }
+}
+
+impl From<VerifierBuilderError> for TlsError {
+    fn from(e: VerifierBuilderError) -> Self {
+        TlsError::ClientCa(e)
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+impl TlsFiles {
+    /// Load the files into the TLS configuration of the ping service.
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, TlsError> {
+        let provider = crypto_provider();
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(path)? {
+                    roots.add(cert)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(|error| TlsError::Pem {
+            path: self.key.clone(),
+            error,
+        })?;
+        let config = builder.with_single_cert(load_certs(&self.cert)?, key)?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// Get the provider of the cryptography used by the TLS of the ping link.
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Read the certificates of a PEM file, failing if it has none.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem = |error| TlsError::Pem {
+        path: path.to_path_buf(),
+        error,
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(pem)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem)?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate {
+            path: path.to_path_buf(),
+        });
+    }
+    Ok(certs)
+}
@@ -0,0 +1,202 @@
+//! Viewport: the tiles of the canvas a viewer is looking at.
+//!
+//! A viewer following the canvas through a viewport only receives the changes of these tiles
+//! (see [Viewport::filter]), and a snapshot of each tile coming into view as it pans
+//! (see the `GET /events` endpoint of the [HttpApi](crate::http::HttpApi)).
+
+use std::{collections::BTreeSet, fmt::Display};
+
+use crate::canvas::{
+    Region,
+    diff::CanvasDiff,
+    tile::{TILE_SIZE, TileId},
+};
+
+/// Maximum number of tiles of a viewport.
+///
+/// Viewers seeing more of the canvas should follow the whole canvas instead.
+pub const MAX_VIEWPORT_TILES: usize = 64;
+
+/// Set of tiles followed by a viewer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Viewport {
+    tiles: BTreeSet<TileId>,
+}
+
+/// Error while building a viewport.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ViewportError {
+    /// The viewport covers no tile.
+    Empty,
+    /// The viewport covers more than [MAX_VIEWPORT_TILES] tiles.
+    TooLarge,
+}
+
+impl Viewport {
+    /// Create a viewport of the given tiles.
+    pub fn from_tiles(tiles: impl IntoIterator<Item = TileId>) -> Result<Self, ViewportError> {
+        let tiles: BTreeSet<_> = tiles.into_iter().collect();
+        if tiles.is_empty() {
+            return Err(ViewportError::Empty);
+        }
+        if tiles.len() > MAX_VIEWPORT_TILES {
+            return Err(ViewportError::TooLarge);
+        }
+        Ok(Self { tiles })
+    }
+
+    /// Create a viewport of the tiles overlapping a region of the canvas.
+    ///
+    /// The region may extend beyond the canvas, e.g. to follow the area added by a future resize.
+    pub fn from_region(region: Region) -> Result<Self, ViewportError> {
+        if region.is_empty() {
+            return Err(ViewportError::Empty);
+        }
+        let first = TileId::of_pixel(region.x, region.y);
+        let last_x = (region.x as u32 + region.width as u32 - 1).min(u16::MAX as u32) as u16;
+        let last_y = (region.y as u32 + region.height as u32 - 1).min(u16::MAX as u32) as u16;
+        let last = TileId::of_pixel(last_x, last_y);
+        let count = (last.tx - first.tx + 1) as usize * (last.ty - first.ty + 1) as usize;
+        if count > MAX_VIEWPORT_TILES {
+            return Err(ViewportError::TooLarge);
+        }
+        let tiles = (first.ty..=last.ty)
+            .flat_map(|ty| (first.tx..=last.tx).map(move |tx| TileId { tx, ty }))
+            .collect();
+        Ok(Self { tiles })
+    }
+
+    /// Get the tiles of the viewport, in row-major order.
+    pub fn tiles(&self) -> impl ExactSizeIterator<Item = TileId> + '_ {
+        // TileId is ordered by column first
+        let mut tiles: Vec<_> = self.tiles.iter().copied().collect();
+        tiles.sort_by_key(|tile| (tile.ty, tile.tx));
+        tiles.into_iter()
+    }
+
+    /// Check if the pixel at the given coordinates is in view.
+    pub fn contains(&self, x: u16, y: u16) -> bool {
+        self.tiles.contains(&TileId::of_pixel(x, y))
+    }
+
+    /// Get the tiles of `next` which are not in this viewport, in row-major order.
+    pub fn entering<'a>(&'a self, next: &'a Viewport) -> impl Iterator<Item = TileId> + 'a {
+        next.tiles().filter(|tile| !self.tiles.contains(tile))
+    }
+
+    /// Keep the changes of a diff which are in view, or None if there is none.
+    ///
+    /// A resize of the canvas is always kept.
+    pub fn filter(&self, diff: &CanvasDiff) -> Option<CanvasDiff> {
+        let filtered = CanvasDiff {
+            changed_pixels: diff
+                .changed_pixels()
+                .filter(|pixel| self.contains(pixel.x, pixel.y))
+                .copied()
+                .collect(),
+            changed_labels: diff
+                .changed_labels()
+                .filter(|label| self.contains(label.x, label.y))
+                .copied()
+                .collect(),
+            resized: diff.resized(),
+        };
+        if filtered.is_empty() {
+            None
+        } else {
+            Some(filtered)
+        }
+    }
+}
+
+impl Display for ViewportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ViewportError::Empty => write!(f, "The viewport covers no tile"),
+            ViewportError::TooLarge => write!(
+                f,
+                "The viewport covers more than {} tiles of {}x{} pixels",
+                MAX_VIEWPORT_TILES, TILE_SIZE, TILE_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ViewportError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{Canvas, colors, label::Label};
+
+    #[test]
+    fn viewport_from_region() {
+        let region = Region {
+            x: 200,
+            y: 300,
+            width: 100,
+            height: 10,
+        };
+        let viewport = Viewport::from_region(region).unwrap();
+        let tiles: Vec<_> = viewport.tiles().collect();
+        assert_eq!(
+            tiles,
+            vec![TileId { tx: 0, ty: 1 }, TileId { tx: 1, ty: 1 }]
+        );
+
+        let huge = Region {
+            x: 0,
+            y: 0,
+            width: 4096,
+            height: 4096,
+        };
+        assert_eq!(Viewport::from_region(huge), Err(ViewportError::TooLarge));
+        let edge = Region {
+            x: u16::MAX,
+            y: u16::MAX,
+            width: u16::MAX,
+            height: 1,
+        };
+        assert_eq!(Viewport::from_region(edge).unwrap().tiles().len(), 1);
+        assert_eq!(Viewport::from_tiles([]), Err(ViewportError::Empty));
+    }
+
+    #[test]
+    fn viewport_filter_and_entering() {
+        let mut canvas = Canvas::new(512, 512);
+        canvas.take_diff();
+        canvas.set_pixel(10, 10, colors::RED).unwrap();
+        canvas.set_pixel(300, 10, colors::BLUE).unwrap();
+        let label = Label::new(260, 20, "hello").unwrap();
+        canvas.set_label(label).unwrap();
+        let diff = canvas.take_diff();
+
+        let left = Viewport::from_tiles([TileId { tx: 0, ty: 0 }]).unwrap();
+        let filtered = left.filter(&diff).unwrap();
+        let pixels: Vec<_> = filtered
+            .changed_pixels()
+            .map(|pixel| (pixel.x, pixel.y))
+            .collect();
+        assert_eq!(pixels, vec![(10, 10)]);
+        assert_eq!(filtered.changed_labels().len(), 0);
+
+        let right = Viewport::from_tiles([TileId { tx: 1, ty: 0 }]).unwrap();
+        let filtered = right.filter(&diff).unwrap();
+        assert_eq!(filtered.changed_pixels().len(), 1);
+        assert_eq!(
+            filtered.changed_labels().copied().collect::<Vec<_>>(),
+            vec![label]
+        );
+
+        let bottom = Viewport::from_tiles([TileId { tx: 0, ty: 1 }]).unwrap();
+        assert_eq!(bottom.filter(&diff), None);
+
+        let both =
+            Viewport::from_tiles([TileId { tx: 0, ty: 0 }, TileId { tx: 1, ty: 0 }]).unwrap();
+        assert_eq!(
+            left.entering(&both).collect::<Vec<_>>(),
+            vec![TileId { tx: 1, ty: 0 }]
+        );
+        assert_eq!(both.entering(&left).count(), 0);
+    }
+}
@@ -0,0 +1,819 @@
+//! Webhooks: notify external services (e.g. a chat bot) of the notable events of the canvases.
+//!
+//! The targets are read from a TOML file, each with the events (and canvases) it wants:
+//!
+//! ```toml
+//! [[webhook]]
+//! url = "https://bot.example.org/ipcanvas"
+//! secret_file = "bot.secret"                   # relative to the file
+//! events = ["region_completed", "rollback"]    # every event if absent
+//! canvases = ["main"]                          # every canvas if absent
+//! ```
+//!
+//! Each event is POSTed as a JSON object to the matching targets:
+//!
+//! ```json
+//! {"id":"5f0c…","event":"freeze","canvas":"main","timestamp":1700000000000,"data":{}}
+//! ```
+//!
+//! The body is signed with the HMAC-SHA256 of the secret of the target, in the
+//! `X-Ipcanvas-Signature: sha256=<hex>` header. The event and the delivery id are also given in
+//! the `X-Ipcanvas-Event` and `X-Ipcanvas-Delivery` headers, a delivery retried keeps its id.
+//!
+//! The canvas tasks only queue the events ([Webhooks::notify] never waits): each target has
+//! its own queue and delivery task, so a slow target does not delay the others. A delivery is
+//! retried with an exponential backoff on connection errors, timeouts, `429` and `5xx` answers,
+//! and given up after [MAX_ATTEMPTS]. Events are dropped while the queue of a target is full.
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::{Method, Request, StatusCode, Uri, header};
+use hyper_util::rt::TokioIo;
+use rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, warn};
+
+use crate::{
+    canvas::{Canvas, PixelColor, Region},
+    events::EventSource,
+    metrics::Counter,
+    moderation::RollbackFilter,
+    persistence,
+    rules::RegionRules,
+};
+
+/// Maximum number of attempts of a delivery.
+pub const MAX_ATTEMPTS: u32 = 6;
+/// Delay before the second attempt of a delivery, doubled after each failed attempt.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Maximum delay between two attempts of a delivery.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+/// Time given to a target to answer a delivery, connection included.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of events waiting for delivery to a target, before they are dropped.
+const QUEUE_SIZE: usize = 256;
+
+/// Kind of a notable event, as named in the filters and the payloads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WebhookEventKind {
+    RegionCompleted,
+    Rollback,
+    Freeze,
+    Unfreeze,
+    Fill,
+    Reset,
+    Resize,
+}
+
+/// A notable event of a canvas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// Every pixel of a region of the rules has been placed by the players.
+    RegionCompleted { name: String, region: Region },
+    /// The placements of a prefix were undone.
+    Rollback {
+        filter: RollbackFilter,
+        matched: u64,
+        reverted: u64,
+        skipped: u64,
+    },
+    /// The canvas stopped accepting placements.
+    Freeze,
+    /// The canvas accepts placements again.
+    Unfreeze,
+    /// The operator painted a region of the canvas.
+    Fill {
+        region: Region,
+        color: PixelColor,
+        pixels: u64,
+    },
+    /// The operator painted the whole canvas.
+    Reset { color: PixelColor, pixels: u64 },
+    /// The canvas grew.
+    Resize { width: u16, height: u16 },
+}
+
+/// A target of the webhooks.
+#[derive(Clone, Debug)]
+pub struct WebhookTarget {
+    url: Uri,
+    secret: Vec<u8>,
+    // None for every event
+    events: Option<HashSet<WebhookEventKind>>,
+    // None for every canvas
+    canvases: Option<Vec<String>>,
+}
+
+/// Error while loading the webhooks.
+#[derive(Debug)]
+pub enum WebhooksError {
+    /// The webhooks file, or a secret file, could not be read.
+    Io { path: PathBuf, error: io::Error },
+    /// The webhooks file is not valid TOML, or has unknown fields.
+    Parse(toml::de::Error),
+    /// A webhook is invalid.
+    Invalid { url: String, reason: String },
+}
+
+/// On-disk format of the webhooks file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhooksFile {
+    #[serde(default)]
+    webhook: Vec<WebhookEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhookEntry {
+    url: String,
+    secret_file: PathBuf,
+    events: Option<Vec<String>>,
+    canvases: Option<Vec<String>>,
+}
+
+/// Queues the events of the canvases for delivery to the webhook targets.
+///
+/// It is cheap to clone. The default value has no target.
+#[derive(Clone, Debug, Default)]
+pub struct Webhooks {
+    targets: Arc<[QueuedTarget]>,
+    metrics: Arc<WebhookMetrics>,
+}
+
+/// Outcome of the deliveries, for the metrics.
+#[derive(Debug, Default)]
+pub struct WebhookMetrics {
+    pub delivered: Counter,
+    /// Deliveries given up after their last attempt, or refused by the target
+    pub failed: Counter,
+    /// Events dropped as the queue of the target was full
+    pub dropped: Counter,
+}
+
+#[derive(Debug)]
+struct QueuedTarget {
+    target: Arc<WebhookTarget>,
+    queue: mpsc::Sender<Arc<Delivery>>,
+}
+
+/// A payload to deliver.
+#[derive(Debug)]
+struct Delivery {
+    id: String,
+    kind: WebhookEventKind,
+    body: Bytes,
+}
+
+/// Progress of the regions of the rules towards their completion, every pixel of the
+/// region placed by the players (pings) at least once.
+#[derive(Clone, Debug, Default)]
+pub struct RegionProgress {
+    regions: Vec<Progress>,
+}
+
+#[derive(Clone, Debug)]
+struct Progress {
+    name: String,
+    region: Region,
+    // One bit per pixel of the region, set once placed by a player
+    placed: Vec<u64>,
+    remaining: usize,
+}
+
+impl WebhookEventKind {
+    pub const ALL: [WebhookEventKind; 7] = [
+        WebhookEventKind::RegionCompleted,
+        WebhookEventKind::Rollback,
+        WebhookEventKind::Freeze,
+        WebhookEventKind::Unfreeze,
+        WebhookEventKind::Fill,
+        WebhookEventKind::Reset,
+        WebhookEventKind::Resize,
+    ];
+
+    /// Get the name of the kind, as used in the filters and the payloads.
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEventKind::RegionCompleted => "region_completed",
+            WebhookEventKind::Rollback => "rollback",
+            WebhookEventKind::Freeze => "freeze",
+            WebhookEventKind::Unfreeze => "unfreeze",
+            WebhookEventKind::Fill => "fill",
+            WebhookEventKind::Reset => "reset",
+            WebhookEventKind::Resize => "resize",
+        }
+    }
+
+    /// Find a kind from its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.name() == name)
+    }
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> WebhookEventKind {
+        match self {
+            WebhookEvent::RegionCompleted { .. } => WebhookEventKind::RegionCompleted,
+            WebhookEvent::Rollback { .. } => WebhookEventKind::Rollback,
+            WebhookEvent::Freeze => WebhookEventKind::Freeze,
+            WebhookEvent::Unfreeze => WebhookEventKind::Unfreeze,
+            WebhookEvent::Fill { .. } => WebhookEventKind::Fill,
+            WebhookEvent::Reset { .. } => WebhookEventKind::Reset,
+            WebhookEvent::Resize { .. } => WebhookEventKind::Resize,
+        }
+    }
+
+    /// Get the details of the event, the `data` of its payload.
+    pub fn data(&self) -> Value {
+        let region = |region: &Region| {
+            json!({
+                "x": region.x,
+                "y": region.y,
+                "width": region.width,
+                "height": region.height,
+            })
+        };
+        match self {
+            WebhookEvent::RegionCompleted { name, region: area } => {
+                json!({ "region": name, "area": region(area) })
+            }
+            WebhookEvent::Rollback {
+                filter,
+                matched,
+                reverted,
+                skipped,
+            } => json!({
+                "prefix": filter.prefix.to_string(),
+                "from": filter.from,
+                "to": filter.to,
+                "matched": matched,
+                "reverted": reverted,
+                "skipped": skipped,
+            }),
+            WebhookEvent::Freeze | WebhookEvent::Unfreeze => json!({}),
+            WebhookEvent::Fill {
+                region: area,
+                color,
+                pixels,
+            } => json!({ "area": region(area), "color": color.to_hex(), "pixels": pixels }),
+            WebhookEvent::Reset { color, pixels } => {
+                json!({ "color": color.to_hex(), "pixels": pixels })
+            }
+            WebhookEvent::Resize { width, height } => json!({ "width": width, "height": height }),
+        }
+    }
+}
+
+impl Display for WebhooksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebhooksError::Io { path, error } => {
+                write!(f, "Failed to read {}: {}", path.display(), error)
+            }
+            WebhooksError::Parse(e) => write!(f, "Invalid webhooks file: {}", e),
+            WebhooksError::Invalid { url, reason } => {
+                write!(f, "Invalid webhook {:?}: {}", url, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WebhooksError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WebhooksError::Io { error, .. } => Some(error),
+            WebhooksError::Parse(e) => Some(e),
+            WebhooksError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl From<toml::de::Error> for WebhooksError {
+    fn from(e: toml::de::Error) -> Self {
+        WebhooksError::Parse(e)
+    }
+}
+
+impl WebhookTarget {
+    /// Create a target for every event of every canvas.
+    ///
+    /// Only `http` and `https` URLs are supported.
+    pub fn new(url: &str, secret: impl Into<Vec<u8>>) -> Result<Self, WebhooksError> {
+        let invalid = |reason: &str| WebhooksError::Invalid {
+            url: url.to_string(),
+            reason: reason.to_string(),
+        };
+        let uri: Uri = url.parse().map_err(|_| invalid("not a valid URL"))?;
+        if !matches!(uri.scheme_str(), Some("http" | "https")) {
+            return Err(invalid("the scheme must be http or https"));
+        }
+        if uri.host().is_none_or(str::is_empty) {
+            return Err(invalid("the URL has no host"));
+        }
+        let secret = secret.into();
+        if secret.is_empty() {
+            return Err(invalid("the secret is empty"));
+        }
+        Ok(Self {
+            url: uri,
+            secret,
+            events: None,
+            canvases: None,
+        })
+    }
+
+    /// Only notify these events.
+    pub fn with_events(mut self, events: impl IntoIterator<Item = WebhookEventKind>) -> Self {
+        self.events = Some(events.into_iter().collect());
+        self
+    }
+
+    /// Only notify the events of these canvases.
+    pub fn with_canvases(mut self, canvases: impl IntoIterator<Item = String>) -> Self {
+        self.canvases = Some(canvases.into_iter().collect());
+        self
+    }
+
+    /// Check whether the target wants an event of a canvas.
+    pub fn matches(&self, canvas: &str, kind: WebhookEventKind) -> bool {
+        self.events
+            .as_ref()
+            .is_none_or(|events| events.contains(&kind))
+            && self
+                .canvases
+                .as_ref()
+                .is_none_or(|canvases| canvases.iter().any(|id| id == canvas))
+    }
+
+    pub fn url(&self) -> &Uri {
+        &self.url
+    }
+
+    /// Compute the signature of a body, the value of the `X-Ipcanvas-Signature` header.
+    pub fn sign(&self, body: &[u8]) -> String {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(body);
+        let signature: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("sha256={}", signature)
+    }
+
+    /// Read the targets from a TOML file.
+    ///
+    /// The secret files are relative to the directory of the file.
+    pub fn load(path: &Path) -> Result<Vec<Self>, WebhooksError> {
+        let content = std::fs::read_to_string(path).map_err(|error| WebhooksError::Io {
+            path: path.to_path_buf(),
+            error,
+        })?;
+        Self::parse(&content, path.parent().unwrap_or(Path::new("")))
+    }
+
+    /// Parse the targets from the content of a TOML file, reading their secrets relative to `dir`.
+    pub fn parse(content: &str, dir: &Path) -> Result<Vec<Self>, WebhooksError> {
+        let file: WebhooksFile = toml::from_str(content)?;
+        file.webhook
+            .into_iter()
+            .map(|entry| {
+                let invalid = |reason: String| WebhooksError::Invalid {
+                    url: entry.url.clone(),
+                    reason,
+                };
+                let path = dir.join(&entry.secret_file);
+                let secret = std::fs::read_to_string(&path)
+                    .map_err(|error| WebhooksError::Io { path, error })?;
+                let mut target = Self::new(&entry.url, secret.trim())?;
+                if let Some(events) = &entry.events {
+                    let kinds = events
+                        .iter()
+                        .map(|name| {
+                            WebhookEventKind::from_name(name)
+                                .ok_or_else(|| invalid(format!("unknown event {:?}", name)))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    target = target.with_events(kinds);
+                }
+                if let Some(canvases) = entry.canvases {
+                    target = target.with_canvases(canvases);
+                }
+                Ok(target)
+            })
+            .collect()
+    }
+}
+
+impl Webhooks {
+    /// Spawn the delivery tasks of the targets.
+    pub fn spawn(targets: Vec<WebhookTarget>) -> Self {
+        let metrics = Arc::new(WebhookMetrics::default());
+        let client = Client::new();
+        let targets = targets
+            .into_iter()
+            .map(|target| {
+                let target = Arc::new(target);
+                let (queue, deliveries) = mpsc::channel(QUEUE_SIZE);
+                tokio::spawn(deliver_all(
+                    target.clone(),
+                    deliveries,
+                    client.clone(),
+                    metrics.clone(),
+                ));
+                QueuedTarget { target, queue }
+            })
+            .collect();
+        Self { targets, metrics }
+    }
+
+    pub fn metrics(&self) -> Arc<WebhookMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Queue an event of a canvas for delivery to the targets wanting it, without waiting.
+    pub fn notify(&self, canvas: &str, event: &WebhookEvent) {
+        let kind = event.kind();
+        let mut targets = self
+            .targets
+            .iter()
+            .filter(|queued| queued.target.matches(canvas, kind))
+            .peekable();
+        if targets.peek().is_none() {
+            return;
+        }
+        let id = delivery_id();
+        let payload = json!({
+            "id": id,
+            "event": kind.name(),
+            "canvas": canvas,
+            "timestamp": persistence::now_millis(),
+            "data": event.data(),
+        });
+        let delivery = Arc::new(Delivery {
+            id,
+            kind,
+            body: Bytes::from(payload.to_string()),
+        });
+        for queued in targets {
+            if queued.queue.try_send(delivery.clone()).is_err() {
+                self.metrics.dropped.inc();
+                warn!(
+                    "Webhook {} is lagging, dropped the {} event of canvas {}",
+                    queued.target.url,
+                    kind.name(),
+                    canvas
+                );
+            }
+        }
+    }
+}
+
+impl RegionProgress {
+    /// Track the regions of the rules, from the placements already on the canvas.
+    pub fn new(rules: &RegionRules, canvas: &Canvas) -> Self {
+        let regions = rules
+            .regions()
+            .map(|(name, region)| {
+                let area = region.width as usize * region.height as usize;
+                let mut progress = Progress {
+                    name: name.to_string(),
+                    region,
+                    placed: vec![0; area.div_ceil(64)],
+                    remaining: area,
+                };
+                for y in region.y..region.y.saturating_add(region.height) {
+                    for x in region.x..region.x.saturating_add(region.width) {
+                        let placement = canvas.placement(x, y);
+                        if placement.is_some_and(|p| matches!(p.source, EventSource::Ping(_))) {
+                            progress.place(x, y);
+                        }
+                    }
+                }
+                progress
+            })
+            .collect();
+        Self { regions }
+    }
+
+    /// Record a pixel placed by a player, returning the regions it completed.
+    pub fn record(&mut self, x: u16, y: u16) -> Vec<WebhookEvent> {
+        self.regions
+            .iter_mut()
+            .filter(|progress| progress.region.contains(x, y))
+            .filter_map(|progress| {
+                (progress.place(x, y) && progress.remaining == 0).then_some(&*progress)
+            })
+            .map(|progress| WebhookEvent::RegionCompleted {
+                name: progress.name.clone(),
+                region: progress.region,
+            })
+            .collect()
+    }
+}
+
+impl Progress {
+    /// Mark a pixel of the region as placed, returning whether it was not already.
+    fn place(&mut self, x: u16, y: u16) -> bool {
+        let index = (y - self.region.y) as usize * self.region.width as usize
+            + (x - self.region.x) as usize;
+        let (word, bit) = (index / 64, 1 << (index % 64));
+        if self.placed[word] & bit != 0 {
+            return false;
+        }
+        self.placed[word] |= bit;
+        self.remaining -= 1;
+        true
+    }
+}
+
+/// Draw a random id for a delivery.
+fn delivery_id() -> String {
+    let mut id = [0u8; 16];
+    if getrandom::fill(&mut id).is_err() {
+        // Still unique enough to tell the deliveries apart
+        id[..8].copy_from_slice(&persistence::now_millis().to_be_bytes());
+    }
+    id.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Deliver the events queued for a target, one at a time, until the queue is closed.
+async fn deliver_all(
+    target: Arc<WebhookTarget>,
+    mut deliveries: mpsc::Receiver<Arc<Delivery>>,
+    client: Client,
+    metrics: Arc<WebhookMetrics>,
+) {
+    while let Some(delivery) = deliveries.recv().await {
+        let mut delay = MIN_RETRY_DELAY;
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = tokio::time::timeout(REQUEST_TIMEOUT, client.post(&target, &delivery))
+                .await
+                .unwrap_or_else(|_| Err("timed out".into()));
+            let error = match result {
+                Ok(status) if status.is_success() => {
+                    debug!(
+                        "Delivered the {} event {} to {}",
+                        delivery.kind.name(),
+                        delivery.id,
+                        target.url
+                    );
+                    metrics.delivered.inc();
+                    break;
+                }
+                Ok(status)
+                    if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() =>
+                {
+                    warn!(
+                        "Webhook {} refused the {} event {}: {}",
+                        target.url,
+                        delivery.kind.name(),
+                        delivery.id,
+                        status
+                    );
+                    metrics.failed.inc();
+                    break;
+                }
+                Ok(status) => status.to_string(),
+                Err(e) => e.to_string(),
+            };
+            if attempt == MAX_ATTEMPTS {
+                warn!(
+                    "Gave up delivering the {} event {} to {} after {} attempts: {}",
+                    delivery.kind.name(),
+                    delivery.id,
+                    target.url,
+                    attempt,
+                    error
+                );
+                metrics.failed.inc();
+                break;
+            }
+            debug!(
+                "Attempt {} to deliver the {} event {} to {} failed ({}), retrying in {:?}",
+                attempt,
+                delivery.kind.name(),
+                delivery.id,
+                target.url,
+                error,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+type ClientError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Minimal HTTP/1.1 client, a connection per request.
+#[derive(Clone)]
+struct Client {
+    tls: TlsConnector,
+}
+
+impl Client {
+    fn new() -> Self {
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        let config = ClientConfig::builder_with_provider(crate::tls::crypto_provider())
+            .with_safe_default_protocol_versions()
+            .expect("the provider supports the default versions")
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Self {
+            tls: TlsConnector::from(Arc::new(config)),
+        }
+    }
+
+    /// POST a delivery to the target, returning the status of the answer.
+    async fn post(
+        &self,
+        target: &WebhookTarget,
+        delivery: &Delivery,
+    ) -> Result<StatusCode, ClientError> {
+        let url = &target.url;
+        let https = url.scheme_str() == Some("https");
+        let host = url.host().unwrap_or_default();
+        let port = url.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let authority = url.authority().map_or(host, |authority| authority.as_str());
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url.path_and_query().map_or("/", |path| path.as_str()))
+            .header(header::HOST, authority)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(
+                header::USER_AGENT,
+                concat!("ipcanvas-service/", env!("CARGO_PKG_VERSION")),
+            )
+            .header("X-Ipcanvas-Event", delivery.kind.name())
+            .header("X-Ipcanvas-Delivery", &delivery.id)
+            .header("X-Ipcanvas-Signature", target.sign(&delivery.body))
+            .body(Full::new(delivery.body.clone()))?;
+
+        // IPv6 addresses are bracketed in URLs
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let socket = TcpStream::connect((host, port)).await?;
+        if https {
+            let name = ServerName::try_from(host.to_string())?;
+            let stream = self.tls.connect(name, socket).await?;
+            send(stream, request).await
+        } else {
+            send(socket, request).await
+        }
+    }
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client").finish_non_exhaustive()
+    }
+}
+
+async fn send<S>(stream: S, request: Request<Full<Bytes>>) -> Result<StatusCode, ClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) =
+        hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Webhook connection failed: {}", e);
+        }
+    });
+    let response = sender.send_request(request).await?;
+    Ok(response.status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{colors, provenance::Placement};
+
+    #[test]
+    fn webhooks_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("bot.secret"), "s3cret\n").unwrap();
+        let targets = WebhookTarget::parse(
+            r#"
+            [[webhook]]
+            url = "https://bot.example.org/ipcanvas?token=1"
+            secret_file = "bot.secret"
+            events = ["region_completed", "rollback"]
+
+            [[webhook]]
+            url = "http://[::1]:8080/hook"
+            secret_file = "bot.secret"
+            canvases = ["sandbox"]
+            "#,
+            dir.path(),
+        )
+        .unwrap();
+        assert_eq!(targets.len(), 2);
+        assert!(targets[0].matches("main", WebhookEventKind::Rollback));
+        assert!(!targets[0].matches("main", WebhookEventKind::Freeze));
+        assert!(targets[1].matches("sandbox", WebhookEventKind::Freeze));
+        assert!(!targets[1].matches("main", WebhookEventKind::Freeze));
+
+        // Signed with the trimmed secret
+        assert_eq!(
+            targets[0].sign(b"{}"),
+            "sha256=adbde1ce40c89c14215687d5d762a47df6dfaefcfad61e2e86718ffc8498571b"
+        );
+
+        for (content, error) in [
+            (
+                "[[webhook]]\nurl = \"ftp://example.org\"\nsecret_file = \"bot.secret\"",
+                "the scheme must be http or https",
+            ),
+            (
+                "[[webhook]]\nurl = \"https://example.org\"\nsecret_file = \"bot.secret\"\nevents = [\"explode\"]",
+                "unknown event \"explode\"",
+            ),
+            (
+                "[[webhook]]\nurl = \"https://example.org\"\nsecret_file = \"missing\"",
+                "Failed to read",
+            ),
+        ] {
+            let e = WebhookTarget::parse(content, dir.path()).unwrap_err();
+            assert!(e.to_string().contains(error), "{}", e);
+        }
+    }
+
+    #[test]
+    fn region_progress() {
+        let rules = RegionRules::parse(
+            r#"
+            [[region]]
+            name = "flag"
+            x = 2
+            y = 2
+            width = 2
+            height = 2
+            "#,
+        )
+        .unwrap();
+        let mut canvas = Canvas::new(16, 16);
+        let player = EventSource::Ping("2001:db8::1".parse().unwrap());
+        let place = |canvas: &mut Canvas, x, y, source| {
+            canvas
+                .place_pixel(
+                    x,
+                    y,
+                    colors::RED,
+                    Placement {
+                        source,
+                        timestamp: 0,
+                    },
+                )
+                .unwrap();
+        };
+        place(&mut canvas, 2, 2, player);
+        place(&mut canvas, 3, 2, EventSource::Admin);
+
+        let mut progress = RegionProgress::new(&rules, &canvas);
+        assert!(progress.record(2, 2).is_empty());
+        assert!(progress.record(3, 2).is_empty());
+        assert!(progress.record(2, 3).is_empty());
+        assert_eq!(
+            progress.record(3, 3),
+            vec![WebhookEvent::RegionCompleted {
+                name: "flag".to_string(),
+                region: Region {
+                    x: 2,
+                    y: 2,
+                    width: 2,
+                    height: 2
+                },
+            }]
+        );
+        // Completed once
+        assert!(progress.record(3, 3).is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+//! TLS of the ping link, with certificates generated for each test.
+
+use std::{path::PathBuf, sync::Arc};
+
+use ipcanvas_service::{
+    ping::{CHALLENGE_LEN, PingKey, RESPONSE_LEN},
+    tls::{TlsFiles, crypto_provider, load_certs},
+};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use rustls::{
+    ClientConfig, RootCertStore,
+    pki_types::{PrivateKeyDer, ServerName, pem::PemObject},
+};
+use tempfile::TempDir;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// A CA, issuing certificates written as PEM files in a temporary directory.
+struct Pki {
+    dir: TempDir,
+    ca: CertifiedIssuer<'static, KeyPair>,
+}
+
+impl Pki {
+    fn new() -> Self {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap();
+        let pki = Self {
+            dir: TempDir::new().unwrap(),
+            ca,
+        };
+        std::fs::write(pki.ca_path(), pki.ca.pem()).unwrap();
+        pki
+    }
+
+    fn ca_path(&self) -> PathBuf {
+        self.dir.path().join("ca.pem")
+    }
+
+    /// Issue a certificate for `name`, returning the paths of the certificate and its key.
+    fn issue(&self, name: &str) -> (PathBuf, PathBuf) {
+        let key = KeyPair::generate().unwrap();
+        let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+        let cert = params.signed_by(&key, &self.ca).unwrap();
+        let cert_path = self.dir.path().join(format!("{}.pem", name));
+        let key_path = self.dir.path().join(format!("{}.key", name));
+        std::fs::write(&cert_path, cert.pem()).unwrap();
+        std::fs::write(&key_path, key.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+}
+
+fn client_config(pki: &Pki, identity: Option<(PathBuf, PathBuf)>) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(&pki.ca_path()).unwrap() {
+        roots.add(cert).unwrap();
+    }
+    let builder = ClientConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(
+                load_certs(&cert).unwrap(),
+                PrivateKeyDer::from_pem_file(&key).unwrap(),
+            )
+            .unwrap(),
+        None => builder.with_no_client_auth(),
+    };
+    Arc::new(config)
+}
+
+/// Serve a single ping connection: TLS handshake, then the challenge of `key`.
+///
+/// Returns whether the listener was authenticated.
+async fn serve(listener: TcpListener, acceptor: TlsAcceptor, key: PingKey) -> bool {
+    let (socket, _) = listener.accept().await.unwrap();
+    let Ok(mut stream) = acceptor.accept(socket).await else {
+        return false;
+    };
+    let challenge = PingKey::challenge().unwrap();
+    stream.write_all(&challenge).await.unwrap();
+    stream.flush().await.unwrap();
+    let mut response = [0; RESPONSE_LEN];
+    if stream.read_exact(&mut response).await.is_err() {
+        return false;
+    }
+    key.verify(&challenge, &response)
+}
+
+/// Connect as a ping listener, and answer the challenge with `key`.
+async fn connect(
+    addr: std::net::SocketAddr,
+    config: Arc<ClientConfig>,
+    key: &PingKey,
+) -> std::io::Result<()> {
+    let socket = TcpStream::connect(addr).await?;
+    let server_name = ServerName::try_from("localhost").unwrap();
+    let mut stream = TlsConnector::from(config)
+        .connect(server_name, socket)
+        .await?;
+    let mut challenge = [0; CHALLENGE_LEN];
+    stream.read_exact(&mut challenge).await?;
+    stream.write_all(&key.respond(&challenge)).await?;
+    stream.flush().await
+}
+
+/// Run a connection between a service configured with `files` and a listener.
+async fn exchange(files: &TlsFiles, client: Arc<ClientConfig>, client_key: &PingKey) -> bool {
+    let key = PingKey::new("s3cret").unwrap();
+    let acceptor = TlsAcceptor::from(files.server_config().unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(serve(listener, acceptor, key));
+    let _ = connect(addr, client, client_key).await;
+    server.await.unwrap()
+}
+
+#[tokio::test]
+async fn mutual_tls() {
+    let pki = Pki::new();
+    let (cert, key) = pki.issue("localhost");
+    let files = TlsFiles {
+        cert,
+        key,
+        client_ca: Some(pki.ca_path()),
+    };
+    let ping_key = PingKey::new("s3cret").unwrap();
+
+    let identity = pki.issue("listener");
+    assert!(
+        exchange(
+            &files,
+            client_config(&pki, Some(identity.clone())),
+            &ping_key
+        )
+        .await
+    );
+
+    // The challenge is still checked within the TLS
+    let wrong_key = PingKey::new("other").unwrap();
+    assert!(!exchange(&files, client_config(&pki, Some(identity)), &wrong_key).await);
+
+    // Listeners without certificate, or with one issued by another CA, are refused
+    assert!(!exchange(&files, client_config(&pki, None), &ping_key).await);
+    let other = Pki::new();
+    let foreign = other.issue("listener");
+    let client = {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(load_certs(&pki.ca_path()).unwrap().remove(0))
+            .unwrap();
+        let config = ClientConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(
+                load_certs(&foreign.0).unwrap(),
+                PrivateKeyDer::from_pem_file(&foreign.1).unwrap(),
+            )
+            .unwrap();
+        Arc::new(config)
+    };
+    assert!(!exchange(&files, client, &ping_key).await);
+}
+
+#[tokio::test]
+async fn server_only_tls() {
+    let pki = Pki::new();
+    let (cert, key) = pki.issue("localhost");
+    let files = TlsFiles {
+        cert,
+        key,
+        client_ca: None,
+    };
+    let ping_key = PingKey::new("s3cret").unwrap();
+    assert!(exchange(&files, client_config(&pki, None), &ping_key).await);
+
+    // The listener refuses a service whose certificate is not issued by its CA
+    let other = Pki::new();
+    assert!(!exchange(&files, client_config(&other, None), &ping_key).await);
+}
+
+#[test]
+fn invalid_files() {
+    let pki = Pki::new();
+    let (cert, key) = pki.issue("localhost");
+    let (_, other_key) = pki.issue("other");
+
+    let files = TlsFiles {
+        cert: cert.clone(),
+        key: other_key,
+        client_ca: None,
+    };
+    assert!(files.server_config().is_err());
+
+    let files = TlsFiles {
+        cert: key.clone(),
+        key: key.clone(),
+        client_ca: None,
+    };
+    let error = files.server_config().unwrap_err().to_string();
+    assert!(error.starts_with("No certificate found in"), "{}", error);
+
+    let files = TlsFiles {
+        cert,
+        key,
+        client_ca: Some(pki.dir.path().join("missing.pem")),
+    };
+    assert!(files.server_config().is_err());
+}
@@ -2,13 +2,30 @@
 #-*- coding: utf-8 -*-
 
 # This script sends ping events to the ipcanvas-service.
+#
+# The key shared with the service is read from the file named by IPCANVAS_PING_KEY_FILE
+# (unless the service runs with --ping-no-auth).
 
+import hashlib
+import hmac
+import os
 import socket
 import time
 
 HOST = '127.0.0.1' # The server's hostname or IP address
 PORT = 7894        # The port used by the server
 SRC_ADDR = "2001:0db8:85a3:0000:0000:8a2e:0370:7334"
+KEY_FILE = os.environ.get("IPCANVAS_PING_KEY_FILE")
+
+def authenticate(s, key):
+    # Answer the challenge of the service with the HMAC-SHA256 of the context and the challenge
+    challenge = b""
+    while len(challenge) < 32:
+        chunk = s.recv(32 - len(challenge))
+        if not chunk:
+            raise ConnectionError("Connection closed before the challenge")
+        challenge += chunk
+    s.sendall(hmac.new(key, b"ipcanvas-ping/1" + challenge, hashlib.sha256).digest())
 
 def create_ping_event(src_addr, dst_addr):
     # Create a simple ping event packet
@@ -25,6 +42,9 @@ if __name__ == "__main__":
     # Create a TCP socket
     with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as s:
         s.connect((HOST, PORT))
+        if KEY_FILE:
+            with open(KEY_FILE, "rb") as f:
+                authenticate(s, f.read().strip())
         redx10y20 = create_pixel_ping(10,20,255,0,0)
         yellowx15y25 = create_pixel_ping(15,25,255,255,0)
         whitex30y40 = create_pixel_ping(30,40,255,255,255)